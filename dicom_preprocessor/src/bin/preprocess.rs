@@ -0,0 +1,561 @@
+use dicom_preprocessor::brick::BrickGrid;
+use dicom_preprocessor::brick_config::{AtlasPrecision, BrickGridConfig};
+use dicom_preprocessor::buf3d::Buf3D;
+use dicom_preprocessor::compression::CompressionMetrics;
+use dicom_preprocessor::grid::Grid;
+use dicom_preprocessor::volume::{DenseGrid, NamedFiles, VolumeReadError};
+use dicom_preprocessor::progress::{LoadPhase, LoadProgress};
+use dicom_preprocessor::{metaimage, mitsuba, mrc, nifti, nrrd, pbrt, read_dicom_series_with_progress, split_dicom_series, tiff_stack, utils, vdb, vtk, zarr};
+use dicom_preprocessor::dicom_slice::stream_dicom_series;
+use dicom_preprocessor::image_sequence::{parse_image_sequence, SliceChannel};
+use glam::{UVec3, Vec3};
+use serde_json::json;
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+
+// Bakes volumes into brick grids ahead of time, so the viewer can load them without constructing the grid itself.
+
+const USAGE: &str = "\
+Usage: volxel-preprocess <input> [options]
+
+The input is a DICOM file, a folder or zip of DICOM files, or a volume in one of the other supported
+formats: NIfTI (.nii, .nii.gz), NRRD, MetaImage, OpenVDB, MRC, VTK, Zarr, TIFF or image stacks,
+Mitsuba .vol, PBRT scenes and .volxel files.
+
+Options:
+  -o, --output <path>     output file for volxel, output folder for raw (default: next to the input)
+  -f, --format <format>   volxel: a single .volxel file (default)
+                          raw: a folder with one little endian file per buffer and metadata.json
+      --series <series>   DICOM series to use, by index or series instance UID (default: the largest)
+      --list-series       list the DICOM series of the input and exit
+      --name <name>       name of the OpenVDB grid or PBRT medium to read
+      --spacing <x,y,z>   voxel spacing of image and TIFF stacks
+      --brick-size <n>    voxels along each axis of a brick, 4, 8, 16 or 32 (default: 8)
+      --mip-levels <n>    number of range mipmap levels (default: 3)
+      --pointer-bits <x,y,z>
+                          bits per axis of the brick pointers, 32 at most (default: 10,10,10)
+      --atlas-precision <precision>
+                          u8, u16 or f16 voxels in the atlas (default: u8)
+      --max-atlas-size <n>
+                          voxels along each axis of an atlas page at most, like the max 3D texture
+                          size of the target GPU (default: 2048)
+      --error-metrics     compare the brick grid with its source and report the error, the raw
+                          format also gets the max and mean error per brick. DICOM series are then
+                          loaded as a whole instead of being bricked while decoding.
+  -q, --quiet             don't log progress
+  -h, --help              print this message";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Volxel,
+    Raw,
+}
+
+struct Options {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+    series: Option<String>,
+    list_series: bool,
+    name: Option<String>,
+    spacing: Option<Vec3>,
+    config: BrickGridConfig,
+    error_metrics: bool,
+    quiet: bool,
+}
+
+fn parse_args() -> Result<Option<Options>, String> {
+    let mut args = std::env::args().skip(1);
+    let mut input = None;
+    let mut options = Options {
+        input: PathBuf::new(),
+        output: None,
+        format: OutputFormat::Volxel,
+        series: None,
+        list_series: false,
+        name: None,
+        spacing: None,
+        config: BrickGridConfig::default(),
+        error_metrics: false,
+        quiet: false,
+    };
+    let mut brick_size = options.config.brick_size();
+    let mut mip_levels = options.config.mip_levels();
+    let mut pointer_bits = options.config.pointer_bits();
+    let mut atlas_precision = options.config.atlas_precision;
+    let mut max_atlas_size = options.config.max_atlas_size();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => options.output = Some(PathBuf::from(value()?)),
+            "-f" | "--format" => {
+                options.format = match value()?.as_str() {
+                    "volxel" => OutputFormat::Volxel,
+                    "raw" => OutputFormat::Raw,
+                    format => return Err(format!("Unknown output format \"{}\"", format)),
+                }
+            }
+            "--series" => options.series = Some(value()?),
+            "--list-series" => options.list_series = true,
+            "--name" => options.name = Some(value()?),
+            "--spacing" => {
+                let spacing = value()?;
+                let values = spacing.split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>();
+                options.spacing = match values.as_deref() {
+                    Ok([x, y, z]) => Some(Vec3::new(*x, *y, *z)),
+                    _ => return Err(format!("Invalid spacing \"{}\", expected x,y,z", spacing)),
+                };
+            }
+            "--brick-size" => {
+                let size = value()?;
+                brick_size = size.parse().map_err(|_| format!("Invalid brick size \"{}\"", size))?;
+            }
+            "--mip-levels" => {
+                let levels = value()?;
+                mip_levels = levels.parse().map_err(|_| format!("Invalid mip levels \"{}\"", levels))?;
+            }
+            "--pointer-bits" => {
+                let bits = value()?;
+                let values = bits.split(',').map(|v| v.trim().parse::<u32>()).collect::<Result<Vec<_>, _>>();
+                pointer_bits = match values.as_deref() {
+                    Ok([x, y, z]) => UVec3::new(*x, *y, *z),
+                    _ => return Err(format!("Invalid pointer bits \"{}\", expected x,y,z", bits)),
+                };
+            }
+            "--atlas-precision" => {
+                atlas_precision = match value()?.as_str() {
+                    "u8" => AtlasPrecision::U8,
+                    "u16" => AtlasPrecision::U16,
+                    "f16" => AtlasPrecision::F16,
+                    precision => return Err(format!("Unknown atlas precision \"{}\"", precision)),
+                }
+            }
+            "--max-atlas-size" => {
+                let size = value()?;
+                max_atlas_size = size.parse().map_err(|_| format!("Invalid max atlas size \"{}\"", size))?;
+            }
+            "--error-metrics" => options.error_metrics = true,
+            "-q" | "--quiet" => options.quiet = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_none() => input = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    options.input = input.ok_or("No input given")?;
+    options.config = BrickGridConfig::new(brick_size, mip_levels, pointer_bits.x, pointer_bits.y, pointer_bits.z).map_err(|e| e.message())?;
+    options.config.atlas_precision = atlas_precision;
+    options.config.set_max_atlas_size(max_atlas_size).map_err(|e| e.message())?;
+    Ok(Some(options))
+}
+
+/// A loaded volume, either still to be bricked or already a brick grid, or the files of DICOM
+/// series that are only decoded once the series is chosen
+enum Source {
+    Volume(Box<dyn Grid>),
+    Bricked(Box<BrickGrid>),
+    Dicom(Vec<(String, Vec<u8>)>),
+}
+
+fn read_error(error: VolumeReadError) -> String {
+    error.message()
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().to_lowercase()).unwrap_or_default()
+}
+
+/// All files below `root`, named by their path relative to it
+fn read_folder(root: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut files = Vec::new();
+    let mut folders = vec![root.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for entry in std::fs::read_dir(&folder).map_err(|e| format!("Couldn't read {}: {}", folder.display(), e))? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.is_dir() {
+                folders.push(path);
+                continue;
+            }
+            let name = path.strip_prefix(root).unwrap().components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/");
+            files.push((name, std::fs::read(&path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?));
+        }
+    }
+    Ok(files)
+}
+
+/// Files next to a detached header, which may hold its data
+fn sibling_files(path: &Path) -> Result<NamedFiles, String> {
+    let mut names = Vec::new();
+    let mut files = Vec::new();
+    let folder = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    for entry in std::fs::read_dir(folder).map_err(|e| e.to_string())? {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        if path.is_file() {
+            names.push(file_name(&path));
+            files.push(std::fs::read(&path).map_err(|e| e.to_string())?);
+        }
+    }
+    Ok(NamedFiles::new(names, files))
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+    extensions.iter().any(|extension| name.to_lowercase().ends_with(extension))
+}
+
+fn dense(volume: Result<DenseGrid, VolumeReadError>) -> Result<Source, String> {
+    Ok(Source::Volume(Box::new(volume.map_err(read_error)?)))
+}
+
+/// Reads a set of files, from a folder or zip, by the kind of files it contains
+fn load_files(files: Vec<(String, Vec<u8>)>, options: &Options) -> Result<Source, String> {
+    let any = |extensions: &[&str]| files.iter().any(|(name, _)| has_extension(name, extensions));
+    let all = |extensions: &[&str]| files.iter().all(|(name, _)| has_extension(name, extensions));
+    if files.is_empty() {
+        return Err("The input doesn't contain any files".to_string());
+    }
+    if any(&[".zarray"]) {
+        return Ok(Source::Bricked(Box::new(zarr::zarr_to_grid(files, &options.config).map_err(read_error)?)));
+    }
+    if any(&[".mha", ".mhd"]) {
+        let (names, files) = files.into_iter().unzip();
+        return dense(metaimage::parse_metaimage_files(&NamedFiles::new(names, files)));
+    }
+    if let Some(header) = files.iter().position(|(name, _)| has_extension(name, &[".nhdr", ".nrrd"])) {
+        let header = files[header].1.clone();
+        let (names, files) = files.into_iter().unzip();
+        return dense(nrrd::parse_nrrd(&header, &NamedFiles::new(names, files)));
+    }
+    if all(&[".tif", ".tiff"]) {
+        return dense(tiff_stack::parse_tiff_stack(files, options.spacing));
+    }
+    if all(&[".png", ".jpg", ".jpeg", ".bmp", ".exr", ".hdr"]) {
+        return dense(parse_image_sequence(files, options.spacing.unwrap_or(Vec3::ONE), SliceChannel::Luminance));
+    }
+    Ok(Source::Dicom(files))
+}
+
+/// Prints the index, size, UID and description of each DICOM series among `files`
+fn list_series(files: Vec<(String, Vec<u8>)>) -> Result<(), String> {
+    let all_series = split_dicom_series(files);
+    if all_series.is_empty() {
+        return Err("The input doesn't contain any DICOM series".to_string());
+    }
+    for (index, series) in all_series.iter().enumerate() {
+        println!("{:>3}  {:>5} files  {}  {}", index, series.files.len(), series.series_instance_uid, series.description);
+    }
+    Ok(())
+}
+
+fn load_dicom_series(files: Vec<(String, Vec<u8>)>, options: &Options) -> Result<Source, String> {
+    let mut all_series = split_dicom_series(files);
+    if all_series.is_empty() {
+        return Err("The input doesn't contain any DICOM series".to_string());
+    }
+    let index = match &options.series {
+        Some(selected) => selected
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < all_series.len())
+            .or_else(|| all_series.iter().position(|series| &series.series_instance_uid == selected))
+            .ok_or_else(|| format!("There is no series \"{}\", see --list-series", selected))?,
+        None => {
+            let largest = (0..all_series.len()).max_by_key(|index| all_series[*index].files.len()).unwrap();
+            if all_series.len() > 1 {
+                utils::log_to_console(&format!("Found {} series, using the largest one, see --list-series", all_series.len()));
+            }
+            largest
+        }
+    };
+    let series = all_series.swap_remove(index);
+    utils::log_to_console(&format!("Using series {} \"{}\" with {} files", series.series_instance_uid, series.description, series.files.len()));
+    let files = series.files;
+    if options.error_metrics {
+        // the metrics need the source to compare against
+        let dicom = read_dicom_series_with_progress(files.iter().map(|(_, bytes)| bytes.as_slice()), progress(options)).map_err(|e| e.message())?;
+        return Ok(Source::Volume(Box::new(dicom)));
+    }
+    // bricked while decoding, so the whole series is never held as a volume
+    let grid = stream_dicom_series(files.len(), &|index| Cow::Borrowed(files[index].1.as_slice()), options.config, progress(options)).map_err(read_error)?;
+    Ok(Source::Bricked(Box::new(grid)))
+}
+
+/// Overwrites one line of stderr with the progress of the current phase
+fn print_progress(phase: LoadPhase, done: usize, total: usize) {
+    let name = match phase {
+        LoadPhase::ReadingHeaders => "reading headers",
+        LoadPhase::DecodingFiles => "decoding files",
+        LoadPhase::BuildingBricks => "building bricks",
+        LoadPhase::BuildingMipmaps => "building mipmaps",
+    };
+    eprint!("\r  {:<16}{:>6} / {}", name, done, total);
+    if done == total {
+        eprintln!();
+    }
+    let _ = std::io::stderr().flush();
+}
+
+fn progress(options: &Options) -> LoadProgress<'static> {
+    if options.quiet {
+        LoadProgress::none()
+    } else {
+        LoadProgress::none().with_callback(&print_progress)
+    }
+}
+
+fn load(options: &Options) -> Result<Source, String> {
+    let path = &options.input;
+    if path.is_dir() {
+        return load_files(read_folder(path)?, options);
+    }
+    let bytes = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let name = file_name(path);
+    let volume_name = options.name.as_deref();
+    match name {
+        _ if has_extension(&name, &[".zip"]) => load_files(NamedFiles::from_zip(bytes).map_err(read_error)?.into_files(), options),
+        _ if has_extension(&name, &[".volxel"]) => Ok(Source::Bricked(Box::new(BrickGrid::from_volxel(&bytes).map_err(read_error)?))),
+        _ if has_extension(&name, &[".nii", ".nii.gz"]) => dense(nifti::parse_nifti(&bytes)),
+        _ if has_extension(&name, &[".nrrd"]) => dense(nrrd::parse_nrrd(&bytes, &NamedFiles::new(Vec::new(), Vec::new()))),
+        _ if has_extension(&name, &[".nhdr"]) => dense(nrrd::parse_nrrd(&bytes, &sibling_files(path)?)),
+        _ if has_extension(&name, &[".mha"]) => dense(metaimage::parse_metaimage(&bytes, &NamedFiles::new(Vec::new(), Vec::new()))),
+        _ if has_extension(&name, &[".mhd"]) => dense(metaimage::parse_metaimage(&bytes, &sibling_files(path)?)),
+        _ if has_extension(&name, &[".vdb"]) => Ok(Source::Volume(Box::new(vdb::parse_vdb(&bytes, volume_name).map_err(read_error)?))),
+        _ if has_extension(&name, &[".mrc", ".map", ".ccp4", ".rec"]) => dense(mrc::parse_mrc(&bytes)),
+        _ if has_extension(&name, &[".vtk", ".vti"]) => dense(vtk::parse_vtk(&bytes)),
+        _ if has_extension(&name, &[".vol"]) => dense(mitsuba::parse_mitsuba_vol(&bytes)),
+        _ if has_extension(&name, &[".pbrt"]) => dense(pbrt::parse_pbrt_medium(&String::from_utf8_lossy(&bytes), volume_name)),
+        _ if has_extension(&name, &[".tif", ".tiff"]) => dense(tiff_stack::parse_tiff_stack(vec![(name, bytes)], options.spacing)),
+        _ => Ok(Source::Dicom(vec![(name, bytes)])),
+    }
+}
+
+fn default_output(options: &Options) -> PathBuf {
+    let input = options.input.as_path();
+    let mut name = input.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_else(|| "volume".to_string());
+    for extension in [".nii.gz", ".gz", ".zip"] {
+        if let Some(stripped) = name.strip_suffix(extension) {
+            name = stripped.to_string();
+        }
+    }
+    if !input.is_dir() && let Some((stem, _)) = name.rsplit_once('.') {
+        name = stem.to_string();
+    }
+    match options.format {
+        OutputFormat::Volxel => input.with_file_name(format!("{}.volxel", name)),
+        OutputFormat::Raw => input.with_file_name(format!("{}_bricks", name)),
+    }
+}
+
+fn precision_name(precision: AtlasPrecision) -> &'static str {
+    match precision {
+        AtlasPrecision::U8 => "u8",
+        AtlasPrecision::U16 => "u16",
+        AtlasPrecision::F16 => "f16",
+    }
+}
+
+fn u32_bytes(buffer: &Buf3D<u32>) -> Vec<u8> {
+    buffer.data.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+fn stride(buffer_stride: glam::UVec3) -> serde_json::Value {
+    json!(buffer_stride.to_array())
+}
+
+fn f32_bytes(buffer: &Buf3D<f32>) -> Vec<u8> {
+    buffer.data.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+/// Writes each buffer to its own file, with a metadata.json describing them
+fn write_raw(grid: &BrickGrid, metrics: Option<&CompressionMetrics>, folder: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(folder).map_err(|e| format!("Couldn't create {}: {}", folder.display(), e))?;
+    let write = |name: &str, bytes: &[u8]| std::fs::write(folder.join(name), bytes).map_err(|e| format!("Couldn't write {}: {}", name, e));
+
+    write("indirection.u32", &u32_bytes(grid.indirection_buffer()))?;
+    write("range.u32", &u32_bytes(grid.range_buffer()))?;
+    let atlas = grid.atlas_buffer();
+    let mut pages = Vec::new();
+    for page in 0..atlas.pages() {
+        let name = format!("atlas_{}.{}", page, precision_name(atlas.precision()));
+        write(&name, &atlas.page_to_le_bytes(page))?;
+        pages.push(json!({ "file": name, "stride": stride(atlas.page_stride(page)) }));
+    }
+    let mut mipmaps = Vec::new();
+    for (index, mipmap) in grid.range_mipmap_buffers().iter().enumerate() {
+        let name = format!("range_mipmap_{}.u32", index);
+        write(&name, &u32_bytes(mipmap))?;
+        mipmaps.push(json!({ "file": name, "stride": stride(mipmap.stride) }));
+    }
+    write("histogram.u32", &Grid::histogram(grid).iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>())?;
+    let (gradient, gradient_min, gradient_max) = Grid::histogram_gradient(grid);
+    write("histogram_gradient.i32", &gradient.iter().flat_map(|value| value.to_le_bytes()).collect::<Vec<u8>>())?;
+
+    let (minorant, majorant) = grid.minorant_majorant();
+    let mut metadata = json!({
+        "index_extent": grid.index_extent().to_array(),
+        "brick_size": grid.config().brick_size(),
+        "pointer_bits": grid.config().pointer_bits().to_array(),
+        "brick_count": grid.brick_count().to_array(),
+        "allocated_bricks": grid.allocated_bricks(),
+        "minorant": minorant,
+        "majorant": majorant,
+        "transform": Grid::transform(grid).to_cols_array(),
+        "indirection": { "file": "indirection.u32", "stride": stride(grid.indirection_buffer().stride) },
+        "range": { "file": "range.u32", "stride": stride(grid.range_buffer().stride) },
+        "atlas": { "pages": pages, "precision": precision_name(atlas.precision()), "max_size": grid.config().max_atlas_size() },
+        "range_mipmaps": mipmaps,
+        "histogram": { "file": "histogram.u32" },
+        "histogram_gradient": { "file": "histogram_gradient.i32", "min": gradient_min, "max": gradient_max },
+    });
+    if let Some(metrics) = metrics {
+        write("brick_max_error.f32", &f32_bytes(metrics.brick_max_error_buffer()))?;
+        write("brick_mean_error.f32", &f32_bytes(metrics.brick_mean_error_buffer()))?;
+        metadata["error_metrics"] = json!({
+            "max_error": metrics.max_error(),
+            "mean_error": metrics.mean_error(),
+            // JSON has no infinity, a lossless grid has no PSNR
+            "psnr": Some(metrics.psnr()).filter(|psnr| psnr.is_finite()),
+            "brick_max_error": { "file": "brick_max_error.f32", "stride": stride(metrics.brick_max_error_buffer().stride) },
+            "brick_mean_error": { "file": "brick_mean_error.f32", "stride": stride(metrics.brick_mean_error_buffer().stride) },
+        });
+    }
+    write("metadata.json", serde_json::to_string_pretty(&metadata).unwrap().as_bytes())
+}
+
+fn human_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+    }
+}
+
+/// Highest resident memory of this process, only available on Linux
+fn peak_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kibibytes: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kibibytes * 1024)
+}
+
+fn report(grid: &BrickGrid, source_bytes: Option<usize>, metrics: Option<&CompressionMetrics>, timings: &[(&str, f64)]) {
+    println!("Timings");
+    for (name, milliseconds) in timings {
+        println!("  {:<16}{:>10.0} ms", name, milliseconds);
+    }
+
+    let extent = grid.index_extent();
+    let brick_count = grid.brick_count();
+    let total_bricks = (brick_count.x * brick_count.y * brick_count.z) as usize;
+    let allocated = grid.allocated_bricks();
+    println!("Bricks");
+    println!("  {:<16}{} x {} x {}", "volume", extent.x, extent.y, extent.z);
+    println!("  {:<16}{} x {} x {} ({} bricks)", "grid", brick_count.x, brick_count.y, brick_count.z, total_bricks);
+    println!("  {:<16}{} ({:.1} %)", "allocated", allocated, 100.0 * allocated as f64 / total_bricks.max(1) as f64);
+    println!("  {:<16}{}", "uniform", total_bricks - allocated);
+    println!("  {:<16}{}", "brick size", grid.config().brick_size());
+    println!("  {:<16}{}", "mip levels", grid.range_mipmap_buffers().len());
+    println!("  {:<16}{}", "atlas precision", precision_name(grid.atlas_buffer().precision()));
+    let atlas = grid.atlas_buffer();
+    for page in 0..atlas.pages() {
+        let stride = atlas.page_stride(page);
+        println!("  {:<16}{} x {} x {}", format!("atlas page {}", page), stride.x, stride.y, stride.z);
+    }
+
+    let buffer_size = |buffer: &Buf3D<u32>| buffer.data.len() * size_of::<u32>();
+    println!("Memory");
+    if let Some(source_bytes) = source_bytes {
+        println!("  {:<16}{}", "source", human_bytes(source_bytes));
+    }
+    println!("  {:<16}{}", "indirection", human_bytes(buffer_size(grid.indirection_buffer())));
+    println!("  {:<16}{}", "range", human_bytes(buffer_size(grid.range_buffer())));
+    println!("  {:<16}{}", "range mipmaps", human_bytes(grid.range_mipmap_buffers().iter().map(buffer_size).sum()));
+    println!("  {:<16}{} ({} in use)", "atlas", human_bytes(grid.atlas_buffer().size_bytes()), human_bytes(allocated * grid.config().voxels_per_brick() * grid.atlas_buffer().precision().bytes_per_voxel()));
+    println!("  {:<16}{}", "grid in use", human_bytes(grid.size_bytes()));
+    if let Some(peak) = peak_memory() {
+        println!("  {:<16}{}", "peak resident", human_bytes(peak));
+    }
+
+    if let Some(metrics) = metrics {
+        let worst = metrics.brick_max_error_buffer();
+        let worst_index = (0..worst.data.len()).max_by(|a, b| worst.data[*a].total_cmp(&worst.data[*b])).unwrap_or(0);
+        let worst_brick = worst.calculate_coord(worst_index);
+        println!("Error");
+        println!("  {:<16}{:.3e}", "max", metrics.max_error());
+        println!("  {:<16}{:.3e}", "mean", metrics.mean_error());
+        println!("  {:<16}{:.2} dB", "PSNR", metrics.psnr());
+        println!("  {:<16}{}, {}, {}", "worst brick", worst_brick.x, worst_brick.y, worst_brick.z);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let mut timings = Vec::new();
+    let start = Instant::now();
+    let source = match load(options)? {
+        Source::Dicom(files) if options.list_series => return list_series(files),
+        _ if options.list_series => return Err("--list-series needs DICOM files as input".to_string()),
+        Source::Dicom(files) => load_dicom_series(files, options)?,
+        source => source,
+    };
+    timings.push(("load", start.elapsed().as_secs_f64() * 1000.0));
+
+    let (grid, source_bytes, metrics) = match source {
+        Source::Volume(volume) => {
+            let start = Instant::now();
+            let grid = BrickGrid::construct_with_config(volume.as_ref(), options.config, progress(options)).map_err(|e| e.message())?;
+            timings.push(("construct", start.elapsed().as_secs_f64() * 1000.0));
+            let metrics = options.error_metrics.then(|| {
+                let start = Instant::now();
+                let metrics = CompressionMetrics::measure(volume.as_ref(), &grid);
+                timings.push(("error metrics", start.elapsed().as_secs_f64() * 1000.0));
+                metrics
+            });
+            (grid, Some(volume.size_bytes()), metrics)
+        }
+        Source::Bricked(grid) => {
+            if options.error_metrics {
+                utils::log_to_console("The input is already bricked, so there is no source to compute error metrics against");
+            }
+            (*grid, None, None)
+        }
+        Source::Dicom(_) => unreachable!("DICOM series are loaded above"),
+    };
+
+    let output = options.output.clone().unwrap_or_else(|| default_output(options));
+    let start = Instant::now();
+    match options.format {
+        OutputFormat::Volxel => std::fs::write(&output, grid.to_volxel()).map_err(|e| format!("Couldn't write {}: {}", output.display(), e))?,
+        OutputFormat::Raw => write_raw(&grid, metrics.as_ref(), &output)?,
+    }
+    timings.push(("write", start.elapsed().as_secs_f64() * 1000.0));
+
+    report(&grid, source_bytes, metrics.as_ref(), &timings);
+    println!("Written to {}", output.display());
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let options = match parse_args() {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+    if options.quiet {
+        utils::set_logger(|_| {});
+    }
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
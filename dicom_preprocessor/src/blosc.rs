@@ -0,0 +1,243 @@
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use std::io::Read;
+
+// Decoder for the Blosc1 container format, as used by OpenVDB and Zarr.
+// Format description: https://github.com/Blosc/c-blosc/blob/main/README_CHUNK_FORMAT.rst
+
+const HEADER_SIZE: usize = 16;
+const FLAG_SHUFFLE: u8 = 0x1;
+const FLAG_MEMCPYED: u8 = 0x2;
+const FLAG_BITSHUFFLE: u8 = 0x4;
+const FLAG_DONT_SPLIT: u8 = 0x10;
+const MAX_SPLITS: usize = 16;
+const MIN_BUFFERSIZE: usize = 128;
+
+const CODEC_BLOSCLZ: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZLIB: u8 = 3;
+const CODEC_ZSTD: u8 = 4;
+
+fn error(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, message)
+}
+
+/// `bytes[offset..offset + len]`, failing instead of overflowing for sizes read from a corrupt buffer
+fn slice(bytes: &[u8], offset: usize, len: usize) -> Result<&[u8], VolumeReadError> {
+    offset
+        .checked_add(len)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or_else(|| error("Blosc buffer is truncated"))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32, VolumeReadError> {
+    slice(bytes, offset, 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+/// Decompresses a complete blosc buffer
+pub fn decompress(src: &[u8]) -> Result<Vec<u8>, VolumeReadError> {
+    if src.len() < HEADER_SIZE {
+        return Err(error("Blosc buffer is smaller than its header"));
+    }
+    let flags = src[2];
+    let typesize = (src[3] as usize).max(1);
+    let nbytes = read_u32(src, 4)? as usize;
+    let blocksize = read_u32(src, 8)? as usize;
+    let codec = flags >> 5;
+
+    if flags & FLAG_MEMCPYED != 0 {
+        return slice(src, HEADER_SIZE, nbytes).map(<[u8]>::to_vec);
+    }
+    if flags & FLAG_BITSHUFFLE != 0 {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "Blosc bitshuffle is not supported"));
+    }
+    if blocksize == 0 {
+        return Err(error("Blosc block size is zero"));
+    }
+
+    let mut dest = vec![0u8; nbytes];
+    let block_count = nbytes.div_ceil(blocksize);
+    let mut block = Vec::with_capacity(blocksize);
+    for block_index in 0..block_count {
+        let block_start = read_u32(src, HEADER_SIZE + block_index * 4)? as usize;
+        let block_size = blocksize.min(nbytes - block_index * blocksize);
+        let leftover = block_size != blocksize;
+
+        // blocks may be split into one stream per byte of the type
+        let split = flags & FLAG_DONT_SPLIT == 0 && !leftover && typesize <= MAX_SPLITS && block_size / typesize >= MIN_BUFFERSIZE;
+        let splits = if split { typesize } else { 1 };
+        let split_size = block_size / splits;
+
+        block.clear();
+        let mut offset = block_start;
+        for _ in 0..splits {
+            let compressed_size = read_u32(src, offset)? as usize;
+            offset += 4;
+            let compressed = slice(src, offset, compressed_size)?;
+            offset += compressed_size;
+            if compressed_size == split_size {
+                block.extend_from_slice(compressed);
+            } else {
+                let decompressed = decompress_stream(codec, compressed, split_size)?;
+                if decompressed.len() != split_size {
+                    return Err(error("Blosc stream decompressed to an unexpected size"));
+                }
+                block.extend(decompressed);
+            }
+        }
+
+        let dest_block = &mut dest[block_index * blocksize..block_index * blocksize + block_size];
+        if flags & FLAG_SHUFFLE != 0 && typesize > 1 {
+            unshuffle(typesize, &block, dest_block);
+        } else {
+            dest_block.copy_from_slice(&block);
+        }
+    }
+    Ok(dest)
+}
+
+fn decompress_stream(codec: u8, src: &[u8], size: usize) -> Result<Vec<u8>, VolumeReadError> {
+    match codec {
+        CODEC_BLOSCLZ => blosclz_decompress(src, size),
+        CODEC_LZ4 => lz4_flex::block::decompress(src, size).map_err(|e| error(e.to_string())),
+        CODEC_ZLIB => {
+            let mut decompressed = Vec::with_capacity(size);
+            flate2::read::ZlibDecoder::new(src).read_to_end(&mut decompressed).map_err(|e| error(e.to_string()))?;
+            Ok(decompressed)
+        }
+        CODEC_ZSTD => zstd::bulk::decompress(src, size).map_err(|e| error(e.to_string())),
+        _ => Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported blosc codec {}", codec))),
+    }
+}
+
+/// Reverses the byte shuffle filter, which groups the n-th byte of every element together
+fn unshuffle(typesize: usize, src: &[u8], dest: &mut [u8]) {
+    let elements = src.len() / typesize;
+    for element in 0..elements {
+        for byte in 0..typesize {
+            dest[element * typesize + byte] = src[byte * elements + element];
+        }
+    }
+    // bytes that don't make up a whole element aren't shuffled
+    let shuffled = elements * typesize;
+    dest[shuffled..].copy_from_slice(&src[shuffled..]);
+}
+
+/// BloscLZ is a variant of FastLZ (level 2)
+fn blosclz_decompress(src: &[u8], size: usize) -> Result<Vec<u8>, VolumeReadError> {
+    const MAX_DISTANCE: usize = 8191;
+    let truncated = || error("BloscLZ stream is truncated");
+
+    let mut dest: Vec<u8> = Vec::with_capacity(size);
+    let mut ip = 0;
+    let mut ctrl = (*src.get(ip).ok_or_else(truncated)? & 31) as usize;
+    ip += 1;
+    loop {
+        if ctrl >= 32 {
+            // back reference
+            let mut len = (ctrl >> 5) - 1;
+            let mut ofs = (ctrl & 31) << 8;
+            if len == 7 - 1 {
+                loop {
+                    let code = *src.get(ip).ok_or_else(truncated)?;
+                    ip += 1;
+                    len += code as usize;
+                    if code != 255 {
+                        break;
+                    }
+                }
+            }
+            let code = *src.get(ip).ok_or_else(truncated)? as usize;
+            ip += 1;
+            len += 3;
+            let mut distance = ofs + code + 1;
+            if code == 255 && ofs == (31 << 8) {
+                // match from a 16 bit distance
+                ofs = (*src.get(ip).ok_or_else(truncated)? as usize) << 8;
+                ofs += *src.get(ip + 1).ok_or_else(truncated)? as usize;
+                ip += 2;
+                distance = ofs + MAX_DISTANCE + 1;
+            }
+            if distance > dest.len() || dest.len() + len > size {
+                return Err(error("BloscLZ back reference is out of bounds"));
+            }
+            let start = dest.len() - distance;
+            for i in 0..len {
+                dest.push(dest[start + i]);
+            }
+        } else {
+            // literal run
+            let len = ctrl + 1;
+            let literal = src.get(ip..ip + len).ok_or_else(truncated)?;
+            if dest.len() + len > size {
+                return Err(error("BloscLZ literal run is out of bounds"));
+            }
+            dest.extend_from_slice(literal);
+            ip += len;
+        }
+        if ip >= src.len() {
+            break;
+        }
+        ctrl = src[ip] as usize;
+        ip += 1;
+    }
+    Ok(dest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// blosc header for `nbytes` bytes in a single block, followed by the offset of that block
+    fn header(flags: u8, typesize: u8, nbytes: u32) -> Vec<u8> {
+        let mut bytes = vec![2, 1, flags, typesize];
+        for value in [nbytes, nbytes, 0, (HEADER_SIZE + 4) as u32] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn memcpyed_buffers_are_copied() {
+        let mut src = header(FLAG_MEMCPYED, 1, 4);
+        src.truncate(HEADER_SIZE);
+        src.extend([1, 2, 3, 4]);
+        assert_eq!(decompress(&src).unwrap_or_else(|e| panic!("{}", e.message())), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn blosclz_streams_are_decoded() {
+        // a literal run of "ab", followed by a back reference of six bytes at a distance of two
+        let mut src = header(FLAG_DONT_SPLIT, 1, 8);
+        src.extend(5u32.to_le_bytes());
+        src.extend([1, b'a', b'b', 4 << 5, 1]);
+        assert_eq!(decompress(&src).unwrap_or_else(|e| panic!("{}", e.message())), b"abababab");
+    }
+
+    #[test]
+    fn shuffled_streams_are_unshuffled() {
+        let mut src = header(FLAG_DONT_SPLIT | FLAG_SHUFFLE, 2, 8);
+        src.extend(9u32.to_le_bytes());
+        src.extend([7, 1, 2, 3, 4, 0, 0, 0, 0]);
+        let values: Vec<u8> = [1u16, 2, 3, 4].iter().flat_map(|value| value.to_le_bytes()).collect();
+        assert_eq!(decompress(&src).unwrap_or_else(|e| panic!("{}", e.message())), values);
+    }
+
+    #[test]
+    fn corrupt_buffers_are_errors() {
+        let failed = |src: &[u8]| matches!(decompress(src).err().map(|e| e.0), Some(VolumeReadErrorType::DecompressionFailed));
+
+        let mut huge = header(FLAG_MEMCPYED, 1, u32::MAX);
+        huge.truncate(HEADER_SIZE);
+        assert!(failed(&huge));
+
+        let mut truncated = header(FLAG_DONT_SPLIT, 1, 8);
+        truncated.extend(u32::MAX.to_le_bytes());
+        assert!(failed(&truncated));
+
+        // back reference before the start of the output
+        let mut src = header(FLAG_DONT_SPLIT, 1, 8);
+        src.extend(5u32.to_le_bytes());
+        src.extend([1, b'a', b'b', 4 << 5, 7]);
+        assert!(failed(&src));
+    }
+}
@@ -1,195 +1,406 @@
+use crate::brick_config::{AtlasLayout, AtlasPrecision, BrickGridConfig};
 use crate::buf3d::Buf3D;
 use crate::grid::Grid;
-use glam::{IVec3, Mat4, UVec3, Vec2, Vec3};
+use crate::progress::{CancellationToken, LoadCancelled, LoadPhase, LoadProgress};
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use glam::{IVec3, Mat4, UVec3, Vec2};
 use half::f16;
-use js_sys::{Float32Array, Int32Array, Uint16Array, Uint32Array, Uint8Array};
+use rayon::prelude::*;
+#[cfg(feature = "wasm")]
+use js_sys::{Float32Array, Int32Array, Object, Uint16Array, Uint32Array, Uint8Array};
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::wasm_bindgen;
-// constants
-
-const BRICK_SIZE: u32 = 8;
-const BITS_PER_AXIS: u32 = 10;
-const MAX_BRICKS: u32 = 1 << BITS_PER_AXIS;
-const VOXELS_PER_BRICK: u32 = BRICK_SIZE * BRICK_SIZE * BRICK_SIZE;
-const NUM_MIPMAPS: u32 = 3;
-
-// ---
-
 // encoding
 
-fn encode_range(x: f32, y: f32) -> u32 {
+pub(crate) fn encode_range(x: f32, y: f32) -> u32 {
     let x = f16::from_f32(x).to_bits() as u32;
     let y = f16::from_f32(y).to_bits() as u32;
     (x << 16) | (y)
 }
-fn decode_range(data: u32) -> Vec2 {
+pub(crate) fn decode_range(data: u32) -> Vec2 {
     let x = (data >> 16) as u16;
     let y = data as u16;
     Vec2::new(f16::from_bits(x).to_f32(), f16::from_bits(y).to_f32())
 }
 
-fn encode_ptr(ptr: &UVec3) -> u32 {
-    assert!(ptr.x < MAX_BRICKS && ptr.y < MAX_BRICKS && ptr.z < MAX_BRICKS);
-    ptr.x.clamp(0, MAX_BRICKS - 1) << (0 * BITS_PER_AXIS) |
-        ptr.y.clamp(0, MAX_BRICKS - 1) << (1 * BITS_PER_AXIS) |
-        ptr.z.clamp(0, MAX_BRICKS - 1) << (2 * BITS_PER_AXIS)
+/// A voxel of the atlas, holding its value normalized to the range of its brick
+pub(crate) trait AtlasVoxel: Copy + Default + Send + Sync {
+    fn encode(normalized: f32) -> Self;
+    fn decode(self) -> f32;
+}
+
+impl AtlasVoxel for u8 {
+    fn encode(normalized: f32) -> Self {
+        (255f32 * normalized).round() as u8 // TODO: Check whether this does the conversion correctly
+    }
+
+    fn decode(self) -> f32 {
+        self as f32 * (1.0 / 255.0)
+    }
 }
 
-fn decode_ptr(data: u32) -> UVec3 {
-    UVec3::new(
-        data >> (0 * BITS_PER_AXIS) & (MAX_BRICKS - 1),
-        data >> (1 * BITS_PER_AXIS) & (MAX_BRICKS - 1),
-        data >> (2 * BITS_PER_AXIS) & (MAX_BRICKS - 1)
-    )
+impl AtlasVoxel for u16 {
+    fn encode(normalized: f32) -> Self {
+        (65535f32 * normalized).round() as u16
+    }
+
+    fn decode(self) -> f32 {
+        self as f32 * (1.0 / 65535.0)
+    }
+}
+
+impl AtlasVoxel for f16 {
+    fn encode(normalized: f32) -> Self {
+        f16::from_f32(normalized)
+    }
+
+    fn decode(self) -> f32 {
+        self.to_f32()
+    }
 }
 
-fn encode_voxel(value: f32, range: &Vec2) -> u8 {
+fn encode_voxel<T: AtlasVoxel>(value: f32, range: &Vec2) -> T {
     let normalized = ((value - range.x) / (range.y - range.x)).clamp(0.0, 1.0);
-    (255f32 * normalized).round() as u8 // TODO: Check whether this does the conversion correctly
+    T::encode(normalized)
 }
 
-fn decode_voxel(data: u8, range: &Vec2) -> f32 {
-    range.x + data as f32 * (1.0 / 255.0) * (range.y - range.x)
+fn decode_voxel<T: AtlasVoxel>(data: T, range: &Vec2) -> f32 {
+    range.x + data.decode() * (range.y - range.x)
 }
 
-fn div_round_up(num: UVec3, denom: UVec3) -> UVec3 {
-    let div = (Vec3::new(num.x as f32, num.y as f32, num.z as f32) / Vec3::new(denom.x as f32, denom.y as f32, denom.z as f32)).ceil();
-    UVec3::new(div.x as u32, div.y as u32, div.z as u32)
+/// Returned for grids with more allocated bricks than the atlas pages of `config` hold
+pub(crate) fn exceeds_atlas(bricks: usize, config: &BrickGridConfig) -> VolumeReadError {
+    let page_bricks = config.max_page_bricks();
+    VolumeReadError::new(VolumeReadErrorType::Unsupported, format!(
+        "{} bricks that aren't uniform don't fit into {} atlas pages of {} x {} x {} bricks, try a larger max atlas size or fewer pointer bits to address more pages",
+        bricks, config.max_pages(), page_bricks.x, page_bricks.y, page_bricks.z
+    ))
 }
 
-// ---
+/// Min and max of a brick, dilated by two voxels on each side
+pub(crate) fn dilated_brick_range(lookup: &(dyn Fn(UVec3) -> f32 + Sync), brick_size: u32, brick_coord: UVec3) -> (f32, f32) {
+    let mut local_min = f32::MAX;
+    let mut local_max = f32::MIN;
+    for local_z in -2..(brick_size as i32) + 2 {
+        for local_y in -2..(brick_size as i32) + 2 {
+            for local_x in -2..(brick_size as i32) + 2 {
+                // TODO: This whole uvec -> ivec stuff seems weird, ask about this
+                let i_pos = brick_coord * brick_size;
+                let i_pos = IVec3::new(i_pos.x as i32, i_pos.y as i32, i_pos.z as i32) + IVec3::new(local_x, local_y, local_z);
+                let looked_up = lookup(UVec3::new(i_pos.x as u32, i_pos.y as u32, i_pos.z as u32));
+                local_min = local_min.min(looked_up);
+                local_max = local_max.max(looked_up);
+            }
+        }
+    }
+    (local_min, local_max)
+}
 
-#[wasm_bindgen]
-pub struct BrickGrid {
-    brick_count: UVec3,
-    min_maj: (f32, f32),
-    brick_counter: usize, // TODO: This was somehow used for multithreading
-    indirection: Buf3D<u32>,
-    range: Buf3D<u32>,
-    atlas: Buf3D<u8>,
-    range_mipmaps: Vec<Buf3D<u32>>,
-    transform: Mat4,
-    histogram: Vec<u32>,
-    histogram_gradient: (Vec<i32>, u32, u32),
+/// Voxels of a brick quantized to its range, x fastest
+fn encode_brick<T: AtlasVoxel>(lookup: &(dyn Fn(UVec3) -> f32 + Sync), brick_size: u32, brick_coord: UVec3, range: &Vec2) -> Vec<T> {
+    let mut voxels = Vec::with_capacity((brick_size * brick_size * brick_size) as usize);
+    for local_z in 0..brick_size {
+        for local_y in 0..brick_size {
+            for local_x in 0..brick_size {
+                voxels.push(encode_voxel(lookup(brick_coord * brick_size + UVec3::new(local_x, local_y, local_z)), range));
+            }
+        }
+    }
+    voxels
 }
 
-impl BrickGrid {
-    pub fn construct(from: &dyn Grid) -> Self {
-        let brick_count = div_round_up(div_round_up(from.index_extent(), UVec3::splat(BRICK_SIZE)), UVec3::splat(1 << NUM_MIPMAPS)) * (1 << NUM_MIPMAPS);
+/// Copies a brick into the atlas at the place `pointer` refers to, one row of voxels at a time
+fn store_brick<T: AtlasVoxel>(atlas: &mut Buf3D<T>, brick_size: u32, pointer: UVec3, brick: &[T]) {
+    for (row, voxels) in brick.chunks_exact(brick_size as usize).enumerate() {
+        let row_start = pointer * brick_size + UVec3::new(0, row as u32 % brick_size, row as u32 / brick_size);
+        let atlas_index = atlas.calculate_index(row_start);
+        atlas.data[atlas_index..atlas_index + brick_size as usize].copy_from_slice(voxels);
+    }
+}
 
-        if brick_count.x >= MAX_BRICKS || brick_count.y >= MAX_BRICKS || brick_count.z >= MAX_BRICKS {
-            panic!("Exceeded max brick count")
+/// Encodes bricks in parallel and appends them to a page that holds one brick after another
+/// along z, each given by its coordinate in the grid and the range it is quantized to
+fn append_bricks<T: AtlasVoxel>(appended: &mut Buf3D<T>, lookup: &(dyn Fn(UVec3) -> f32 + Sync), brick_size: u32, bricks: &[(UVec3, Vec2)]) {
+    let encoded: Vec<Vec<T>> = bricks
+        .par_iter()
+        .map(|(brick_coord, range)| encode_brick(lookup, brick_size, *brick_coord, range))
+        .collect();
+    for brick in &encoded {
+        appended.data.extend_from_slice(brick);
+    }
+    appended.stride.z += brick_size * bricks.len() as u32;
+}
+
+/// Copies appended bricks into pages laid out as `layout` says. The last page is filled first,
+/// so the appended bricks can be dropped as they are copied and the atlas isn't held twice.
+fn pack_bricks<T: AtlasVoxel>(mut appended: Buf3D<T>, layout: &AtlasLayout, brick_size: u32) -> Vec<Buf3D<T>> {
+    let voxels_per_brick = (brick_size * brick_size * brick_size) as usize;
+    let mut pages = Vec::with_capacity(layout.pages());
+    for page in (0..layout.pages()).rev() {
+        let mut buf = Buf3D::new(layout.page_bricks(page) * brick_size);
+        let bricks = layout.page_range(page);
+        for index in bricks.clone() {
+            let (_, pointer) = layout.pointer(index);
+            store_brick(&mut buf, brick_size, pointer, &appended.data[index * voxels_per_brick..(index + 1) * voxels_per_brick]);
         }
+        appended.data.truncate(bricks.start * voxels_per_brick);
+        appended.data.shrink_to_fit();
+        pages.push(buf);
+    }
+    pages.reverse();
+    pages
+}
 
-        let mut indirection = Buf3D::new(brick_count);
-        let mut range = Buf3D::new(brick_count);
-        let mut atlas = Buf3D::new(brick_count * BRICK_SIZE);
-
-        let mut brick_counter = 0;
-
-        // Fill range, indirection and atlas buffers
-        for brick_z in 0..brick_count.z { // TODO: This was multithreaded in the original voldata
-            for brick_y in 0..brick_count.y {
-                for brick_x in 0..brick_count.x {
-                    let brick_coord = UVec3::new(brick_x, brick_y, brick_z);
-                    // store an empty brick first
-                    let indirection_brick_index = indirection.calculate_index(brick_coord);
-                    indirection.data[indirection_brick_index] = 0;
-
-                    // compute local range over dilated (TODO: ?) Brick
-                    let mut local_min = f32::MAX;
-                    let mut local_max = f32::MIN;
-                    for local_z in -2..(BRICK_SIZE as i32) + 2 {
-                        for local_y in -2..(BRICK_SIZE as i32) + 2 {
-                            for local_x in -2..(BRICK_SIZE as i32) + 2 {
-                                // TODO: This whole uvec -> ivec stuff seems weird, ask about this
-                                let i_pos = brick_coord * BRICK_SIZE;
-                                let i_pos = IVec3::new(i_pos.x as i32, i_pos.y as i32, i_pos.z as i32) + IVec3::new(local_x, local_y, local_z);
-                                let looked_up = from.lookup(UVec3::new(i_pos.x as u32, i_pos.y as u32, i_pos.z as u32));
-                                local_min = local_min.min(looked_up);
-                                local_max = local_max.max(looked_up);
-                            }
-                        }
-                    }
+/// Lays out the appended bricks of a grid in pages and points the indirection buffer at them.
+/// `allocated` holds the indirection index of each appended brick.
+pub(crate) fn pack_atlas(atlas: Atlas, allocated: &[usize], indirection: &mut Buf3D<u32>, config: &BrickGridConfig) -> Result<Atlas, VolumeReadError> {
+    let layout = config.atlas_layout(allocated.len()).ok_or_else(|| exceeds_atlas(allocated.len(), config))?;
+    for (index, indirection_index) in allocated.iter().enumerate() {
+        let (page, pointer) = layout.pointer(index);
+        indirection.data[*indirection_index] = config.encode_ptr(page, &pointer).ok_or_else(|| exceeds_atlas(allocated.len(), config))?;
+    }
+    Ok(atlas.pack(&layout, config.brick_size()))
+}
 
-                    // now we know the min and max of the block we're considering.
-                    // We can skip storing things in the atlas and indirection buffers
-                    // if we know these are equal. But we still need to know what density the entire
-                    // block has, so we need to store the information in the range buffer
-                    let range_brick_index = range.calculate_index(brick_coord);
-                    range.data[range_brick_index] = encode_range(local_min, local_max);
-                    if local_min == local_max { continue; }
-
-                    // If we reach this point, min and max are different, so we need to store an entry
-                    // in the atlas and point to it in the indirection buffer
-
-                    // TODO: In the multithreaded version, this was done via atomics
-                    // This essentially "allocates" a new brick in the indirection and atlas
-                    let brick_index = brick_counter;
-                    brick_counter += 1;
-                    let indirection_pointer = indirection.calculate_coord(brick_index);
-
-                    // stores the pointer to the brick in the indirection buffer
-                    indirection.data[indirection_brick_index] = encode_ptr(&indirection_pointer);
-
-                    // stores the actual data in the atlas
-                    // we decode the range again here because the intermittent conversion to f16 may have changed the values a bit TODO: CHeck whether that's right
-                    let local_range = decode_range(range.data[range_brick_index]);
-                    for local_z in 0..BRICK_SIZE {
-                        for local_y in 0..BRICK_SIZE {
-                            for local_x in 0..BRICK_SIZE {
-                                let atlas_pos = indirection_pointer * BRICK_SIZE + UVec3::new(local_x, local_y, local_z);
-                                let atlas_index = atlas.calculate_index(atlas_pos);
-                                atlas.data[atlas_index] = encode_voxel(from.lookup(brick_coord * BRICK_SIZE + UVec3::new(local_x, local_y, local_z)), &local_range);
-                            }
-                        }
+/// Range buffers with half the resolution of the previous one along each axis, holding the min
+/// and max of the 2x2x2 ranges below them
+pub(crate) fn build_range_mipmaps(range: &Buf3D<u32>, mip_levels: u32, progress: LoadProgress) -> Result<Vec<Buf3D<u32>>, LoadCancelled> {
+    let mut range_mipmaps: Vec<Buf3D<u32>> = Vec::new();
+    for mipmap_level in 0..mip_levels as usize {
+        progress.step(LoadPhase::BuildingMipmaps, mipmap_level, mip_levels as usize)?;
+        let mip_size = range.stride / (1 << (mipmap_level + 1));
+        let mut buf = Buf3D::new(mip_size);
+
+        let source = if mipmap_level == 0 {
+            range
+        } else {
+            &range_mipmaps[mipmap_level - 1]
+        };
+
+        let data = (0..buf.data.len()).into_par_iter().map(|buffer_index| {
+            let brick = buf.calculate_coord(buffer_index);
+            let mut local_min = f32::MAX;
+            let mut local_max = f32::MIN;
+            for z in 0..2 {
+                for y in 0..2 {
+                    for x in 0..2 {
+                        let source_at = brick * 2 + UVec3::new(x, y, z);
+                        let source_index = source.calculate_index(source_at);
+                        let current_range = decode_range(source.data[source_index]);
+                        local_min = local_min.min(current_range.x);
+                        local_max = local_max.max(current_range.y);
                     }
                 }
             }
+            encode_range(local_min, local_max)
+        }).collect();
+        buf.data = data;
+
+        range_mipmaps.push(buf);
+    }
+    progress.report(LoadPhase::BuildingMipmaps, mip_levels as usize, mip_levels as usize);
+    Ok(range_mipmaps)
+}
+
+// ---
+
+/// The voxels of the allocated bricks, stored with the [`AtlasPrecision`] of the grid, in one or
+/// more pages that each fit into a 3D texture
+pub enum Atlas {
+    U8(Vec<Buf3D<u8>>),
+    U16(Vec<Buf3D<u16>>),
+    F16(Vec<Buf3D<f16>>),
+}
+
+macro_rules! each_atlas {
+    ($atlas:expr, $pages:ident => $body:expr) => {
+        match $atlas {
+            Atlas::U8($pages) => $body,
+            Atlas::U16($pages) => $body,
+            Atlas::F16($pages) => $body,
+        }
+    };
+}
+
+impl Atlas {
+    /// An atlas that bricks are appended to while a grid is constructed, one after another along
+    /// z in a single page, until it is packed
+    pub(crate) fn new(precision: AtlasPrecision, brick_size: u32) -> Self {
+        let stride = UVec3::new(brick_size, brick_size, 0);
+        match precision {
+            AtlasPrecision::U8 => Atlas::U8(vec![Buf3D::new(stride)]),
+            AtlasPrecision::U16 => Atlas::U16(vec![Buf3D::new(stride)]),
+            AtlasPrecision::F16 => Atlas::F16(vec![Buf3D::new(stride)]),
         }
+    }
 
-        // Since some bricks are empty/constant it may be that we didn't fill up the entire atlas, so we can prune it
-        atlas.prune((BRICK_SIZE as f32 * (brick_counter as f32 / (brick_count.x * brick_count.y) as f32).ceil().round()) as usize);
+    pub fn precision(&self) -> AtlasPrecision {
+        match self {
+            Atlas::U8(_) => AtlasPrecision::U8,
+            Atlas::U16(_) => AtlasPrecision::U16,
+            Atlas::F16(_) => AtlasPrecision::F16,
+        }
+    }
 
-        // To speed up lookups (and possibly for delta tracking), we can create mipmaps for the range buffer
-        let mut range_mipmaps = Vec::new();
-        for mipmap_level in 0..NUM_MIPMAPS as usize {
-            let mip_size = brick_count / (1 << (mipmap_level + 1));
-            let mut buf = Buf3D::new(mip_size);
-
-            let source = if mipmap_level == 0 {
-                &range
-            } else {
-                &range_mipmaps[mipmap_level - 1]
-            };
-
-            // TODO: This was multithreaded
-            for brick_z in 0..mip_size.z {
-                for brick_y in 0..mip_size.y {
-                    for brick_x in 0..mip_size.x {
-                        let brick = UVec3::new(brick_x, brick_y, brick_z);
-                        let mut local_min = f32::MAX;
-                        let mut local_max = f32::MIN;
-                        for z in 0..2 {
-                            for y in 0..2 {
-                                for x in 0..2 {
-                                    let source_at = brick * 2 + UVec3::new(x, y, z);
-                                    let source_index = source.calculate_index(source_at);
-                                    let current_range = decode_range(source.data[source_index]);
-                                    local_min = local_min.min(current_range.x);
-                                    local_max = local_max.max(current_range.y);
-                                }
+    pub fn pages(&self) -> usize {
+        each_atlas!(self, pages => pages.len())
+    }
+
+    pub fn page_stride(&self, page: usize) -> UVec3 {
+        each_atlas!(self, pages => pages[page].stride)
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        each_atlas!(self, pages => pages.iter().map(|buf| buf.data.len()).sum::<usize>()) * self.precision().bytes_per_voxel()
+    }
+
+    /// Value of a voxel of a page, decoded with the range of its brick
+    pub(crate) fn lookup(&self, page: usize, voxel: UVec3, range: &Vec2) -> f32 {
+        each_atlas!(self, pages => decode_voxel(pages[page].data[pages[page].calculate_index(voxel)], range))
+    }
+
+    /// Encodes bricks with the precision of the atlas and appends them, see [`append_bricks`]
+    pub(crate) fn append_bricks(&mut self, lookup: &(dyn Fn(UVec3) -> f32 + Sync), brick_size: u32, bricks: &[(UVec3, Vec2)]) {
+        each_atlas!(self, pages => append_bricks(&mut pages[0], lookup, brick_size, bricks))
+    }
+
+    /// Lays out the appended bricks in pages, see [`pack_bricks`]
+    fn pack(self, layout: &AtlasLayout, brick_size: u32) -> Self {
+        match self {
+            Atlas::U8(mut pages) => Atlas::U8(pack_bricks(pages.remove(0), layout, brick_size)),
+            Atlas::U16(mut pages) => Atlas::U16(pack_bricks(pages.remove(0), layout, brick_size)),
+            Atlas::F16(mut pages) => Atlas::F16(pack_bricks(pages.remove(0), layout, brick_size)),
+        }
+    }
+
+    /// The voxels of a page as little endian bytes, x fastest
+    pub fn page_to_le_bytes(&self, page: usize) -> Vec<u8> {
+        each_atlas!(self, pages => pages[page].data.iter().flat_map(|voxel| voxel.to_le_bytes()).collect())
+    }
+
+    /// Reads pages written by [`Atlas::page_to_le_bytes`], given by their stride and bytes
+    pub(crate) fn from_le_bytes(precision: AtlasPrecision, pages: &[(UVec3, &[u8])]) -> Self {
+        match precision {
+            AtlasPrecision::U8 => Atlas::U8(pages.iter().map(|(stride, bytes)| Buf3D { stride: *stride, data: bytes.to_vec() }).collect()),
+            AtlasPrecision::U16 => Atlas::U16(pages.iter().map(|(stride, bytes)| Buf3D { stride: *stride, data: bytes.chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]])).collect() }).collect()),
+            AtlasPrecision::F16 => Atlas::F16(pages.iter().map(|(stride, bytes)| Buf3D { stride: *stride, data: bytes.chunks_exact(2).map(|v| f16::from_le_bytes([v[0], v[1]])).collect() }).collect()),
+        }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct BrickGrid {
+    pub(crate) config: BrickGridConfig,
+    pub(crate) brick_count: UVec3,
+    pub(crate) min_maj: (f32, f32),
+    /// number of bricks allocated in the atlas
+    pub(crate) brick_counter: usize,
+    pub(crate) indirection: Buf3D<u32>,
+    pub(crate) range: Buf3D<u32>,
+    pub(crate) atlas: Atlas,
+    pub(crate) range_mipmaps: Vec<Buf3D<u32>>,
+    pub(crate) transform: Mat4,
+    pub(crate) histogram: Vec<u32>,
+    pub(crate) histogram_gradient: (Vec<i32>, u32, u32),
+}
+
+impl BrickGrid {
+    /// Fails if the allocated bricks don't fit into the atlas pages of the default layout, see
+    /// [`BrickGridConfig::atlas_layout`]
+    pub fn construct(from: &dyn Grid) -> Result<Self, VolumeReadError> {
+        Self::construct_with_progress(from, LoadProgress::none())
+    }
+
+    /// Like [`BrickGrid::construct`], reporting each slab of bricks and mipmap level and
+    /// checking for cancellation in between
+    pub fn construct_with_progress(from: &dyn Grid, progress: LoadProgress) -> Result<Self, VolumeReadError> {
+        Self::construct_with_config(from, BrickGridConfig::default(), progress)
+    }
+
+    /// Like [`BrickGrid::construct_with_progress`], with the brick size, mipmap levels and pointer
+    /// layout of `config` instead of the default ones
+    pub fn construct_with_config(from: &dyn Grid, config: BrickGridConfig, progress: LoadProgress) -> Result<Self, VolumeReadError> {
+        let lookup = |ipos: UVec3| from.lookup(ipos);
+        let brick_size = config.brick_size();
+        let extent = from.index_extent();
+        let brick_count = config.brick_count(extent);
+
+        let mut indirection = Buf3D::new(brick_count);
+        let mut range = Buf3D::new(brick_count);
+        let mut atlas = Atlas::new(config.atlas_precision, brick_size);
+        // indirection index of every brick appended to the atlas, pointed to once the atlas is packed
+        let mut allocated = Vec::new();
+
+        let brick_rows = (brick_count.y * brick_count.z) as usize;
+        let slab_size = (brick_count.x * brick_count.y) as usize;
+
+        // the progress callback can't be shared between threads, the token is checked per row of bricks
+        let token = progress.token();
+
+        // Fill range, indirection and atlas buffers one slab of bricks along z at a time, the bricks
+        // within a slab are processed in parallel. Going slab by slab keeps lookups close together,
+        // which grids that decode their data lazily rely on.
+        for brick_z in 0..brick_count.z {
+            progress.step(LoadPhase::BuildingBricks, (brick_z * brick_count.y) as usize, brick_rows)?;
+            let slab_offset = slab_size * brick_z as usize;
+            let slab_coord = |index: usize| UVec3::new(index as u32 % brick_count.x, index as u32 / brick_count.x, brick_z);
+
+            // bricks the grid knows to be uniform, like the empty space of sparse grids, aren't looked up
+            let slab_rows: Vec<Vec<(f32, f32)>> = (0..brick_count.y as usize)
+                .into_par_iter()
+                .map(|brick_y| {
+                    token.map_or(Ok(()), CancellationToken::check)?;
+                    let row_offset = brick_y * brick_count.x as usize;
+                    Ok((row_offset..row_offset + brick_count.x as usize)
+                        .into_par_iter()
+                        .map(|index| {
+                            let brick_min = (slab_coord(index) * brick_size).as_ivec3();
+                            match from.uniform_value(brick_min - 2, brick_min + brick_size as i32 + 2) {
+                                Some(value) => (value, value),
+                                None => dilated_brick_range(&lookup, brick_size, slab_coord(index)),
                             }
-                        }
-                        let buffer_index = buf.calculate_index(brick);
-                        buf.data[buffer_index] = encode_range(local_min, local_max);
-                    }
-                }
+                        })
+                        .collect())
+                })
+                .collect::<Result<_, LoadCancelled>>()?;
+            let slab_ranges = slab_rows.into_iter().flatten();
+
+            // now we know the min and max of the blocks we're considering.
+            // We can skip storing things in the atlas and indirection buffers
+            // if we know these are equal. But we still need to know what density the entire
+            // block has, so we need to store the information in the range buffer.
+            // The other bricks are "allocated" in the atlas in order, like a prefix sum over the
+            // occupied bricks, so the atlas layout doesn't depend on how the threads were scheduled.
+            let mut occupied = Vec::new();
+            for (index, (local_min, local_max)) in slab_ranges.enumerate() {
+                range.data[slab_offset + index] = encode_range(local_min, local_max);
+                if local_min == local_max { continue; }
+
+                allocated.push(slab_offset + index);
+                // we decode the range again here because the intermittent conversion to f16 may have changed the values a bit TODO: CHeck whether that's right
+                occupied.push((slab_coord(index), decode_range(range.data[slab_offset + index])));
+            }
+
+            // stops before encoding bricks that could never be stored
+            if allocated.len() as u64 > config.max_atlas_bricks() {
+                return Err(exceeds_atlas(allocated.len(), &config));
             }
 
-            range_mipmaps.push(buf);
+            // stores the actual data in the atlas
+            progress.check()?;
+            atlas.append_bricks(&lookup, brick_size, &occupied);
         }
 
-        Self {
+        // Only now that all allocated bricks are known, the atlas can be laid out to fit them exactly
+        let brick_counter = allocated.len();
+        let atlas = pack_atlas(atlas, &allocated, &mut indirection, &config)?;
+
+        // To speed up lookups (and possibly for delta tracking), we can create mipmaps for the range buffer
+        progress.step(LoadPhase::BuildingBricks, brick_rows, brick_rows)?;
+        let range_mipmaps = build_range_mipmaps(&range, config.mip_levels(), progress)?;
+
+        Ok(Self {
+            config,
             brick_count,
             min_maj: from.minorant_majorant(),
             range,
@@ -200,7 +411,42 @@ impl BrickGrid {
             transform: from.transform(),
             histogram: from.histogram(),
             histogram_gradient: from.histogram_gradient()
-        }
+        })
+    }
+
+    /// number of bricks along each axis
+    pub fn brick_count(&self) -> UVec3 {
+        self.brick_count
+    }
+
+    /// number of bricks that are stored in the atlas, the others have a uniform density
+    pub fn allocated_bricks(&self) -> usize {
+        self.brick_counter
+    }
+
+    pub fn indirection_buffer(&self) -> &Buf3D<u32> {
+        &self.indirection
+    }
+
+    pub fn range_buffer(&self) -> &Buf3D<u32> {
+        &self.range
+    }
+
+    pub fn atlas_buffer(&self) -> &Atlas {
+        &self.atlas
+    }
+
+    pub fn range_mipmap_buffers(&self) -> &[Buf3D<u32>] {
+        &self.range_mipmaps
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl BrickGrid {
+    /// the layout the grid was constructed with
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn config(&self) -> BrickGridConfig {
+        self.config
     }
 }
 
@@ -208,25 +454,29 @@ impl Grid for BrickGrid {
     fn lookup(&self, ipos: UVec3) -> f32 {
         // Note: this forgoes mipmap lookup of ranges
 
-        // basically a division by 8, which cuts of the index into the brick,
+        // basically a division by the brick size, which cuts of the index into the brick,
         // so all that remains is the index of the brick
-        let brick_coord = ipos >> 3;
+        let brick_shift = self.config.brick_shift();
+        let brick_coord = ipos >> brick_shift;
 
         // resolve the indirection to find out where in the atlas the brick data is stored
         let indirection_index = self.indirection.calculate_index(brick_coord);
-        let indirection_pointer = decode_ptr(self.indirection.data[indirection_index]);
+        let (page, indirection_pointer) = self.config.decode_ptr(self.indirection.data[indirection_index]);
 
         // resolve the range of the brick
         let range_index = self.range.calculate_index(brick_coord);
         let minmax = decode_range(self.range.data[range_index]);
+        // uniform bricks aren't stored in the atlas, which may even be empty
+        if minmax.x == minmax.y {
+            return minmax.x;
+        }
 
         // calculate the position of the specific voxel in the atlas by offsetting into the brick
         // in the atlas with the lower bits of the passed position
-        let voxel = (indirection_pointer << 3) + UVec3::new(ipos.x & 7, ipos.y & 7, ipos.z & 7);
+        let voxel = (indirection_pointer << brick_shift) + (ipos & (self.config.brick_size() - 1));
 
-        // Actually looks up the u8 compressed data in the atlas, then decodes it with the range
-        let atlas_index = self.atlas.calculate_index(voxel);
-        decode_voxel(self.atlas.data[atlas_index], &minmax)
+        // Actually looks up the compressed data in the atlas, then decodes it with the range
+        self.atlas.lookup(page, voxel, &minmax)
     }
 
     fn minorant_majorant(&self) -> (f32, f32) {
@@ -234,18 +484,18 @@ impl Grid for BrickGrid {
     }
 
     fn index_extent(&self) -> UVec3 {
-        self.brick_count * BRICK_SIZE
+        self.brick_count * self.config.brick_size()
     }
 
     fn num_voxels(&self) -> usize {
-        self.brick_counter * VOXELS_PER_BRICK as usize
+        self.brick_counter * self.config.voxels_per_brick()
     }
 
     fn size_bytes(&self) -> usize {
         let dense_bricks = (self.brick_count.x * self.brick_count.y * self.brick_count.z) as usize;
         let size_indirection = dense_bricks * size_of::<u32>();
         let size_range = dense_bricks * size_of::<u32>();
-        let size_atlas = self.brick_counter * VOXELS_PER_BRICK as usize * size_of::<u8>();
+        let size_atlas = self.brick_counter * self.config.voxels_per_brick() * self.config.atlas_precision.bytes_per_voxel();
 
         let mut size_mipmaps: usize = 0;
         for mipmap in &self.range_mipmaps {
@@ -264,12 +514,13 @@ impl Grid for BrickGrid {
     }
 
     fn transform(&self) -> Mat4 {
-        self.transform.clone()
+        self.transform
     }
 }
 
 // wasm stuff
 
+#[cfg(feature = "wasm")]
 #[wasm_bindgen]
 impl BrickGrid {
     pub fn ind_x(&self) -> u32 {
@@ -292,16 +543,6 @@ impl BrickGrid {
         self.range.stride.z
     }
 
-    pub fn atlas_x(&self) -> u32 {
-        self.atlas.stride.x
-    }
-    pub fn atlas_y(&self) -> u32 {
-        self.atlas.stride.y
-    }
-    pub fn atlas_z(&self) -> u32 {
-        self.atlas.stride.z
-    }
-
     pub fn transform(&self) -> Float32Array {
         Float32Array::from(self.transform.to_cols_array().as_slice())
     }
@@ -357,9 +598,110 @@ impl BrickGrid {
     pub fn range_data(&self) -> Uint16Array {
         Uint16Array::from(bytemuck::cast_slice(self.range.data.as_slice()))
     }
-    pub fn atlas_data(&self) -> Uint8Array {
-        Uint8Array::from(self.atlas.data.as_slice())
+
+    pub fn atlas_pages(&self) -> usize {
+        self.atlas.pages()
+    }
+    /// `Uint8Array` for u8 atlases, `Uint16Array` for u16 and f16 ones, f16 voxels as their bits
+    #[wasm_bindgen(unchecked_return_type = "Uint8Array | Uint16Array")]
+    pub fn atlas_page(&self, page: usize) -> Object {
+        match &self.atlas {
+            Atlas::U8(pages) => Uint8Array::from(pages[page].data.as_slice()).into(),
+            Atlas::U16(pages) => Uint16Array::from(pages[page].data.as_slice()).into(),
+            Atlas::F16(pages) => Uint16Array::from(pages[page].data.iter().map(|voxel| voxel.to_bits()).collect::<Vec<u16>>().as_slice()).into(),
+        }
+    }
+    pub fn atlas_page_stride_x(&self, page: usize) -> u32 {
+        self.atlas.page_stride(page).x
+    }
+    pub fn atlas_page_stride_y(&self, page: usize) -> u32 {
+        self.atlas.page_stride(page).y
+    }
+    pub fn atlas_page_stride_z(&self, page: usize) -> u32 {
+        self.atlas.page_stride(page).z
     }
 }
 
-// ---
\ No newline at end of file
+// ---
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phantom::{read_phantom, PhantomOptions, PhantomShape};
+    use crate::volume::DenseGrid;
+
+    fn assert_identical(parallel: &BrickGrid, sequential: &BrickGrid) {
+        assert_eq!(parallel.brick_counter, sequential.brick_counter);
+        assert!(parallel.indirection.data == sequential.indirection.data, "indirection differs");
+        assert!(parallel.range.data == sequential.range.data, "range differs");
+        assert_eq!(parallel.range_mipmaps.len(), sequential.range_mipmaps.len());
+        for (parallel, sequential) in parallel.range_mipmaps.iter().zip(&sequential.range_mipmaps) {
+            assert!(parallel.stride == sequential.stride && parallel.data == sequential.data, "mipmap differs");
+        }
+        assert_eq!(parallel.atlas.pages(), sequential.atlas.pages());
+        for page in 0..parallel.atlas.pages() {
+            assert_eq!(parallel.atlas.page_stride(page), sequential.atlas.page_stride(page));
+            assert!(parallel.atlas.page_to_le_bytes(page) == sequential.atlas.page_to_le_bytes(page), "atlas page {} differs", page);
+        }
+    }
+
+    #[test]
+    fn parallel_construction_matches_a_single_thread() {
+        let dicom = read_phantom(&PhantomOptions::new(PhantomShape::SheppLogan, 40, 36, 30));
+        let single_thread = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let many_threads = rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        for precision in [AtlasPrecision::U8, AtlasPrecision::U16, AtlasPrecision::F16] {
+            let mut config = BrickGridConfig::new(4, 2, 4, 4, 4).unwrap_or_else(|e| panic!("{}", e.message()));
+            config.atlas_precision = precision;
+            config.set_max_atlas_size(24).unwrap_or_else(|e| panic!("{}", e.message()));
+            let construct = || BrickGrid::construct_with_config(&dicom, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+            let parallel = many_threads.install(construct);
+            let sequential = single_thread.install(construct);
+            assert!(parallel.atlas.pages() > 1, "the phantom should need several pages");
+            assert_identical(&parallel, &sequential);
+        }
+    }
+
+    /// Values that are multiples of 1/1024 once normalized, which f16 holds exactly, so the ranges of
+    /// the bricks aren't rounded and only the atlas quantizes
+    fn pattern_grid() -> DenseGrid {
+        let mut data = Buf3D::new(UVec3::new(20, 17, 12));
+        data.data = (0..data.data.len()).map(|index| {
+            let coord = data.calculate_coord(index);
+            ((coord.x * 37 + coord.y * 101 + coord.z * 13) % 1025) as f32
+        }).collect();
+        data.data[0] = 0.0;
+        data.data[1] = 1024.0;
+        DenseGrid::new(data, Mat4::IDENTITY)
+    }
+
+    #[test]
+    fn atlases_decode_within_a_quantization_step() {
+        let source = pattern_grid();
+        let mut max_errors = Vec::new();
+        for (precision, steps) in [(AtlasPrecision::U8, 255.0), (AtlasPrecision::U16, 65535.0), (AtlasPrecision::F16, 2048.0)] {
+            let mut config = BrickGridConfig::new(4, 1, 10, 10, 10).unwrap_or_else(|e| panic!("{}", e.message()));
+            config.atlas_precision = precision;
+            let grid = BrickGrid::construct_with_config(&source, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+            assert_eq!(grid.atlas.precision(), precision);
+
+            let mut max_error = 0f32;
+            let extent = source.index_extent();
+            for z in 0..extent.z {
+                for y in 0..extent.y {
+                    for x in 0..extent.x {
+                        let ipos = UVec3::new(x, y, z);
+                        let range = decode_range(grid.range.data[grid.range.calculate_index(ipos >> config.brick_shift())]);
+                        // f16 holds values in [0, 1] with at least 11 significant bits
+                        let step = (range.y - range.x) / steps;
+                        let error = (grid.lookup(ipos) - source.lookup(ipos)).abs();
+                        assert!(error <= step, "{:?} voxel {} is off by {}, more than a step of {}", precision, ipos, error, step);
+                        max_error = max_error.max(error);
+                    }
+                }
+            }
+            max_errors.push(max_error);
+        }
+        assert!(max_errors[1] < max_errors[0] / 200.0, "u16 isn't finer than u8: {:?}", max_errors);
+        assert!(max_errors[2] < 5e-4, "f16 isn't close to exact: {:?}", max_errors);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::brick::{build_range_mipmaps, dilated_brick_range, encode_range, exceeds_atlas, pack_atlas, Atlas, BrickGrid};
+use crate::brick_config::BrickGridConfig;
+use crate::buf3d::Buf3D;
+use crate::grid::compute_histogram_gradient;
+use crate::progress::LoadProgress;
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec2};
+use rayon::prelude::*;
+use std::collections::VecDeque;
+
+// Bricks are built from the two voxels around them as well, see `dilated_brick_range`, so a slab
+// of bricks can be built once the two slices after it are there, and needs the two slices before it.
+const DILATION: u32 = 2;
+
+/// Builds a [`BrickGrid`] from slices along z that are pushed one at a time, in order.
+///
+/// Each slab of bricks is built as soon as its slices are there, after which the slices no later
+/// slab needs are dropped. Only about one slab of source voxels is held at once, instead of the
+/// whole volume that [`BrickGrid::construct`] needs.
+///
+/// Values are bricked as they are pushed and scaled once the grid is finished, so sources that
+/// only know their normalization at the end, like a DICOM series, don't need a second pass.
+/// Voxels are quantized against their brick's unscaled range, so they can differ from the ones
+/// [`BrickGrid::construct`] quantizes against the `f16` range by one step, plus the rounding of the
+/// range's ends to `f16`, which outweighs the steps of 16 bit atlases.
+pub struct BrickGridBuilder {
+    config: BrickGridConfig,
+    index_extent: UVec3,
+    brick_count: UVec3,
+    transform: Mat4,
+    window: SliceWindow,
+    /// number of slices pushed so far
+    pushed: u32,
+    /// next slab of bricks along z to build
+    next_slab: u32,
+    /// unscaled min and max of every brick, dilated like the range buffer
+    ranges: Vec<(f32, f32)>,
+    indirection: Buf3D<u32>,
+    /// the allocated bricks one after another, packed into pages once the grid is finished
+    atlas: Atlas,
+    /// indirection index of every allocated brick
+    allocated: Vec<usize>,
+}
+
+impl BrickGridBuilder {
+    /// Starts a grid of the given size, with the slices of `index_extent.x` by `index_extent.y` voxels
+    pub fn new(index_extent: UVec3, transform: Mat4) -> Self {
+        Self::with_config(index_extent, transform, BrickGridConfig::default())
+    }
+
+    /// Like [`BrickGridBuilder::new`], laying out the grid as `config` says
+    pub fn with_config(index_extent: UVec3, transform: Mat4, config: BrickGridConfig) -> Self {
+        let brick_count = config.brick_count(index_extent);
+        Self {
+            config,
+            index_extent,
+            brick_count,
+            transform,
+            window: SliceWindow { slices: VecDeque::new(), first_slice: 0, index_extent },
+            pushed: 0,
+            next_slab: 0,
+            ranges: vec![(0.0, 0.0); (brick_count.x * brick_count.y * brick_count.z) as usize],
+            indirection: Buf3D::new(brick_count),
+            atlas: Atlas::new(config.atlas_precision, config.brick_size()),
+            allocated: Vec::new(),
+        }
+    }
+
+    pub fn index_extent(&self) -> UVec3 {
+        self.index_extent
+    }
+
+    /// number of slices pushed so far
+    pub fn pushed_slices(&self) -> u32 {
+        self.pushed
+    }
+
+    /// Adds the next slice, x fastest, and builds every slab of bricks that is complete with it.
+    /// Fails for slices of the wrong size and for more slices than the grid has.
+    pub fn push_slice(&mut self, slice: Vec<f32>) -> Result<(), VolumeReadError> {
+        if self.pushed >= self.index_extent.z {
+            return Err(VolumeReadError::new(VolumeReadErrorType::InvalidHeader, format!("Pushed more than the {} slices the grid has", self.index_extent.z)));
+        }
+        let slice_size = (self.index_extent.x as usize) * (self.index_extent.y as usize);
+        if slice.len() != slice_size {
+            return Err(VolumeReadError::new(VolumeReadErrorType::InvalidHeader, format!("Slice of {} voxels doesn't have the grid's {}", slice.len(), slice_size)));
+        }
+        self.window.slices.push_back(slice);
+        self.pushed += 1;
+
+        // slices past the extent are never pushed, the slabs that need them are built when finishing
+        while (self.next_slab + 1) * self.config.brick_size() + DILATION <= self.pushed {
+            self.build_slab()?;
+        }
+        Ok(())
+    }
+
+    /// Builds the remaining slabs and the range buffers, with every value multiplied by `scale`.
+    /// The histogram and minorant and majorant are the source's, as for [`BrickGrid::construct`].
+    /// Fails if not all slices were pushed, or if the allocated bricks don't fit into the atlas
+    /// pages of the config.
+    pub fn finish(mut self, scale: f32, min_maj: (f32, f32), histogram: Vec<u32>, progress: LoadProgress) -> Result<BrickGrid, VolumeReadError> {
+        if self.pushed != self.index_extent.z {
+            return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Only {} of the grid's {} slices were pushed", self.pushed, self.index_extent.z)));
+        }
+        while self.next_slab < self.brick_count.z {
+            progress.check()?;
+            self.build_slab()?;
+        }
+
+        let mut range = Buf3D::new(self.brick_count);
+        range.data = self.ranges.iter().map(|(min, max)| encode_range(min * scale, max * scale)).collect();
+        let atlas = pack_atlas(self.atlas, &self.allocated, &mut self.indirection, &self.config)?;
+        let range_mipmaps = build_range_mipmaps(&range, self.config.mip_levels(), progress)?;
+        let histogram_gradient = compute_histogram_gradient(&histogram);
+
+        Ok(BrickGrid {
+            config: self.config,
+            brick_count: self.brick_count,
+            min_maj,
+            brick_counter: self.allocated.len(),
+            indirection: self.indirection,
+            range,
+            atlas,
+            range_mipmaps,
+            transform: self.transform,
+            histogram,
+            histogram_gradient,
+        })
+    }
+
+    /// Fails once more bricks are allocated than the atlas pages of the config hold
+    fn build_slab(&mut self) -> Result<(), VolumeReadError> {
+        let brick_z = self.next_slab;
+        let slab_size = (self.brick_count.x * self.brick_count.y) as usize;
+        let slab_offset = slab_size * brick_z as usize;
+        let brick_count = self.brick_count;
+        let brick_size = self.config.brick_size();
+        let slab_coord = |index: usize| UVec3::new(index as u32 % brick_count.x, index as u32 / brick_count.x, brick_z);
+
+        let window = &self.window;
+        let lookup = |ipos: UVec3| window.lookup(ipos);
+        let slab_ranges: Vec<(f32, f32)> = (0..slab_size)
+            .into_par_iter()
+            .map(|index| dilated_brick_range(&lookup, brick_size, slab_coord(index)))
+            .collect();
+
+        // allocated in order, like in BrickGrid::construct, so both lay out the atlas the same way
+        let mut occupied = Vec::new();
+        for (index, (local_min, local_max)) in slab_ranges.iter().enumerate() {
+            if local_min == local_max { continue; }
+            self.allocated.push(slab_offset + index);
+            occupied.push((slab_coord(index), Vec2::new(*local_min, *local_max)));
+        }
+        if self.allocated.len() as u64 > self.config.max_atlas_bricks() {
+            return Err(exceeds_atlas(self.allocated.len(), &self.config));
+        }
+
+        self.atlas.append_bricks(&lookup, brick_size, &occupied);
+        self.ranges[slab_offset..slab_offset + slab_size].copy_from_slice(&slab_ranges);
+
+        // the next slab starts two slices before its first one
+        self.next_slab += 1;
+        self.window.drop_before((self.next_slab * brick_size).saturating_sub(DILATION));
+        Ok(())
+    }
+}
+
+/// The slices that slabs still to be built need
+struct SliceWindow {
+    /// the first slice is slice `first_slice` of the volume
+    slices: VecDeque<Vec<f32>>,
+    first_slice: u32,
+    index_extent: UVec3,
+}
+
+impl SliceWindow {
+    /// Value of a voxel among the held slices, zero outside of the volume like a dense grid's lookup
+    fn lookup(&self, ipos: UVec3) -> f32 {
+        if ipos.x >= self.index_extent.x || ipos.y >= self.index_extent.y || ipos.z >= self.index_extent.z {
+            return 0.0;
+        }
+        let slice = &self.slices[(ipos.z - self.first_slice) as usize];
+        slice[(ipos.y * self.index_extent.x + ipos.x) as usize]
+    }
+
+    fn drop_before(&mut self, slice: u32) {
+        while self.first_slice < slice && !self.slices.is_empty() {
+            self.slices.pop_front();
+            self.first_slice += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::decode_range;
+    use crate::brick_config::AtlasPrecision;
+    use crate::dicom_slice::stream_dicom_series;
+    use crate::grid::Grid;
+    use crate::phantom::{generate_phantom, PhantomOptions, PhantomShape};
+    use crate::read_dicom_series;
+    use std::borrow::Cow;
+
+    #[test]
+    fn streamed_grids_match_constructed_ones_within_a_step() {
+        let mut options = PhantomOptions::new(PhantomShape::SheppLogan, 30, 26, 21);
+        options.shuffle_slices = true;
+        let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        let dicom = read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).unwrap_or_else(|e| panic!("{}", e.message()));
+
+        for (precision, steps) in [(AtlasPrecision::U8, 255.0), (AtlasPrecision::U16, 65535.0)] {
+            let mut config = BrickGridConfig::new(4, 2, 4, 4, 4).unwrap_or_else(|e| panic!("{}", e.message()));
+            config.atlas_precision = precision;
+            let constructed = BrickGrid::construct_with_config(&dicom, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+            let streamed = stream_dicom_series(files.len(), &|index| Cow::Borrowed(files[index].1.as_slice()), config, LoadProgress::none())
+                .unwrap_or_else(|e| panic!("{}", e.message()));
+
+            assert_eq!(streamed.brick_count, constructed.brick_count);
+            assert_eq!(streamed.brick_counter, constructed.brick_counter);
+            assert!(streamed.indirection.data == constructed.indirection.data, "bricks are allocated differently");
+            assert_eq!(Grid::transform(&streamed), Grid::transform(&constructed));
+
+            let extent = dicom.index_extent();
+            for z in 0..extent.z {
+                for y in 0..extent.y {
+                    for x in 0..extent.x {
+                        let ipos = UVec3::new(x, y, z);
+                        let brick = constructed.range.calculate_index(ipos >> config.brick_shift());
+                        let range = decode_range(constructed.range.data[brick]);
+                        // f16 keeps 11 significant bits, both ends of the range can be rounded
+                        let tolerance = (range.y - range.x) / steps + 2.0 * range.x.abs().max(range.y.abs()) * 2f32.powi(-11) + 1e-6;
+                        let difference = (streamed.lookup(ipos) - constructed.lookup(ipos)).abs();
+                        assert!(difference <= tolerance, "voxel {} differs by {}, more than {}", ipos, difference, tolerance);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn misused_builders_return_errors() {
+        let extent = UVec3::new(4, 3, 2);
+        let mut builder = BrickGridBuilder::new(extent, Mat4::IDENTITY);
+        assert!(builder.push_slice(vec![0.0; 5]).is_err());
+        builder.push_slice(vec![0.5; 12]).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(builder.pushed_slices(), 1);
+        assert!(BrickGridBuilder::new(extent, Mat4::IDENTITY).finish(1.0, (0.0, 1.0), vec![0; 4], LoadProgress::none()).is_err());
+
+        builder.push_slice(vec![0.5; 12]).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert!(builder.push_slice(vec![0.5; 12]).is_err());
+        let grid = builder.finish(1.0, (0.0, 1.0), vec![0; 4], LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(grid.lookup(UVec3::new(1, 1, 1)), 0.5);
+    }
+}
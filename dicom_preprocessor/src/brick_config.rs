@@ -0,0 +1,368 @@
+use glam::UVec3;
+use std::ops::Range;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+const BRICK_SIZES: [u32; 4] = [4, 8, 16, 32];
+const MAX_MIP_LEVELS: u32 = 8;
+/// max 3D texture size of most GPUs
+const DEFAULT_MAX_ATLAS_SIZE: u32 = 2048;
+/// atlas pages the viewer samples from, see `MAX_ATLAS_PAGES` in viewer.ts
+pub const MAX_ATLAS_PAGES: usize = 4;
+
+/// Returned when a [`BrickGridConfig`] can't be used
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub struct BrickGridConfigError(String);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl BrickGridConfigError {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn message(self) -> String {
+        format!("Invalid brick grid config: {}", self.0)
+    }
+}
+
+/// How the voxels of a brick are stored in the atlas, relative to the range of their brick
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtlasPrecision {
+    /// 256 steps between the min and max of a brick
+    U8,
+    /// 65536 steps, for bricks with a high dynamic range, like CT bricks spanning air and metal
+    U16,
+    /// half floats, with finer steps close to the min of a brick
+    F16,
+}
+
+impl AtlasPrecision {
+    pub fn bytes_per_voxel(self) -> usize {
+        match self {
+            AtlasPrecision::U8 => 1,
+            AtlasPrecision::U16 | AtlasPrecision::F16 => 2,
+        }
+    }
+}
+
+/// The layout of a brick grid, chosen when it is constructed. Smaller bricks skip more empty
+/// space and need less atlas memory, but make the indirection and range buffers larger and
+/// traversal take more steps.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BrickGridConfig {
+    brick_size: u32,
+    mip_levels: u32,
+    /// bits of the x, y and z coordinate of a brick pointer in the indirection buffer, the bits
+    /// left over select the atlas page
+    pointer_bits: UVec3,
+    /// precision of the atlas voxels, [`AtlasPrecision::U8`] unless set
+    pub atlas_precision: AtlasPrecision,
+    /// voxels along each axis of an atlas page at most
+    max_atlas_size: u32,
+}
+
+impl Default for BrickGridConfig {
+    fn default() -> Self {
+        Self { brick_size: 8, mip_levels: 3, pointer_bits: UVec3::splat(10), atlas_precision: AtlasPrecision::U8, max_atlas_size: DEFAULT_MAX_ATLAS_SIZE }
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl BrickGridConfig {
+    /// `brick_size` is 4, 8, 16 or 32 voxels, pointers have at least one bit per axis and at most
+    /// 32 bits together. Grids have at most [`MAX_ATLAS_PAGES`] atlas pages, however many the bits
+    /// left for the page could address.
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(brick_size: u32, mip_levels: u32, pointer_bits_x: u32, pointer_bits_y: u32, pointer_bits_z: u32) -> Result<BrickGridConfig, BrickGridConfigError> {
+        if !BRICK_SIZES.contains(&brick_size) {
+            return Err(BrickGridConfigError(format!("Brick size {} isn't one of {:?}", brick_size, BRICK_SIZES)));
+        }
+        if mip_levels > MAX_MIP_LEVELS {
+            return Err(BrickGridConfigError(format!("{} mip levels are more than the supported {}", mip_levels, MAX_MIP_LEVELS)));
+        }
+        let pointer_bits = UVec3::new(pointer_bits_x, pointer_bits_y, pointer_bits_z);
+        if pointer_bits.min_element() == 0 || pointer_bits.element_sum() > 32 {
+            return Err(BrickGridConfigError(format!("Pointer bits {} need at least one bit per axis and at most 32 bits together", pointer_bits)));
+        }
+        Ok(Self { brick_size, mip_levels, pointer_bits, atlas_precision: AtlasPrecision::U8, max_atlas_size: DEFAULT_MAX_ATLAS_SIZE })
+    }
+
+    /// the layout grids have when none is chosen
+    pub fn default_config() -> BrickGridConfig {
+        Self::default()
+    }
+
+    /// voxels along each axis of a brick
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn brick_size(&self) -> u32 {
+        self.brick_size
+    }
+
+    /// number of range mipmap levels above the range buffer
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn pointer_bits_x(&self) -> u32 {
+        self.pointer_bits.x
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn pointer_bits_y(&self) -> u32 {
+        self.pointer_bits.y
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn pointer_bits_z(&self) -> u32 {
+        self.pointer_bits.z
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_atlas_size(&self) -> u32 {
+        self.max_atlas_size
+    }
+
+    /// Limits atlas pages to `max_atlas_size` voxels along each axis, like the max 3D texture size
+    /// of a GPU, 2048 by default. Atlases that would be larger are split into several pages.
+    pub fn set_max_atlas_size(&mut self, max_atlas_size: u32) -> Result<(), BrickGridConfigError> {
+        if max_atlas_size < self.brick_size {
+            return Err(BrickGridConfigError(format!("Max atlas size {} is smaller than a brick of {} voxels", max_atlas_size, self.brick_size)));
+        }
+        self.max_atlas_size = max_atlas_size;
+        Ok(())
+    }
+}
+
+impl BrickGridConfig {
+    pub fn pointer_bits(&self) -> UVec3 {
+        self.pointer_bits
+    }
+
+    /// shift that turns a voxel coordinate into the coordinate of its brick
+    pub(crate) fn brick_shift(&self) -> u32 {
+        self.brick_size.trailing_zeros()
+    }
+
+    pub fn voxels_per_brick(&self) -> usize {
+        (self.brick_size * self.brick_size * self.brick_size) as usize
+    }
+
+    /// number of bricks along each axis of an atlas page that pointers can address
+    pub fn max_bricks(&self) -> UVec3 {
+        UVec3::ONE << self.pointer_bits
+    }
+
+    /// number of pointer bits that select the atlas page
+    pub fn page_bits(&self) -> u32 {
+        32 - self.pointer_bits.element_sum()
+    }
+
+    /// number of atlas pages that pointers can address and the viewer can sample from
+    pub fn max_pages(&self) -> usize {
+        (1usize << self.page_bits()).min(MAX_ATLAS_PAGES)
+    }
+
+    /// number of bricks along each axis of an atlas page, as far as the max atlas size and pointers allow
+    pub fn max_page_bricks(&self) -> UVec3 {
+        UVec3::splat(self.max_atlas_size / self.brick_size).min(self.max_bricks())
+    }
+
+    /// Lays out `bricks` allocated bricks in the atlas, or `None` if they need more pages than
+    /// [`BrickGridConfig::max_pages`]
+    pub fn atlas_layout(&self, bricks: usize) -> Option<AtlasLayout> {
+        let layout = AtlasLayout::new(bricks, self.max_page_bricks());
+        (layout.pages() <= self.max_pages()).then_some(layout)
+    }
+
+    /// number of bricks per axis needed for a grid, padded so every mipmap level divides evenly
+    pub fn brick_count(&self, index_extent: UVec3) -> UVec3 {
+        let mip_divisor = UVec3::splat(1 << self.mip_levels);
+        div_round_up(div_round_up(index_extent, UVec3::splat(self.brick_size)), mip_divisor) * mip_divisor
+    }
+
+    /// number of bricks that aren't uniform the atlas pages hold at most
+    pub fn max_atlas_bricks(&self) -> u64 {
+        volume(self.max_page_bricks()).saturating_mul(self.max_pages() as u64)
+    }
+
+    /// whether a grid of this size fits into the atlas in case none of its bricks are uniform
+    pub fn fits(&self, index_extent: UVec3) -> bool {
+        volume(self.brick_count(index_extent)) <= self.max_atlas_bricks()
+    }
+
+    /// the pointer to a brick in `page` of the atlas, page in the highest bits, or `None` if
+    /// pointers can't address it
+    pub(crate) fn encode_ptr(&self, page: usize, ptr: &UVec3) -> Option<u32> {
+        if !ptr.cmplt(self.max_bricks()).all() || page >= self.max_pages() {
+            return None;
+        }
+        let page_shift = self.pointer_bits.element_sum();
+        Some(ptr.x | ptr.y << self.pointer_bits.x | ptr.z << (self.pointer_bits.x + self.pointer_bits.y) | (page as u32).checked_shl(page_shift).unwrap_or(0))
+    }
+
+    pub(crate) fn decode_ptr(&self, data: u32) -> (usize, UVec3) {
+        let mask = self.max_bricks() - 1;
+        let page = data.checked_shr(self.pointer_bits.element_sum()).unwrap_or(0);
+        (page as usize, UVec3::new(
+            data,
+            data >> self.pointer_bits.x,
+            data >> (self.pointer_bits.x + self.pointer_bits.y)
+        ) & mask)
+    }
+}
+
+/// Where the allocated bricks of a grid are in its atlas. The bricks are split evenly into as few
+/// pages as fit, each a near-cubic block of bricks, filled x first, then y, then z.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AtlasLayout {
+    bricks: usize,
+    pages: usize,
+    /// bricks per page, all pages but the last are full
+    page_capacity: usize,
+    /// bricks along each axis of a page, the last page can be shorter along z
+    page_bricks: UVec3,
+}
+
+impl AtlasLayout {
+    fn new(bricks: usize, max_page_bricks: UVec3) -> Self {
+        let max_capacity = volume(max_page_bricks);
+        let pages = (bricks as u64).div_ceil(max_capacity).max(1) as usize;
+        let page_capacity = bricks.div_ceil(pages);
+
+        // the most constrained axes first, so the others can make up for them
+        let mut axes = [0, 1, 2];
+        axes.sort_by_key(|axis| max_page_bricks[*axis]);
+        let mut page_bricks = UVec3::ONE;
+        let mut remaining = page_capacity.max(1) as u64;
+        for (index, axis) in axes.into_iter().enumerate() {
+            let length = root_ceil(remaining, 3 - index as u32).min(max_page_bricks[axis] as u64);
+            page_bricks[axis] = length as u32;
+            remaining = remaining.div_ceil(length);
+        }
+        Self { bricks, pages, page_capacity, page_bricks }
+    }
+
+    pub fn bricks(&self) -> usize {
+        self.bricks
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// number of bricks along each axis of a page, just enough along z for the bricks in it
+    pub fn page_bricks(&self, page: usize) -> UVec3 {
+        let bricks_in_page = self.bricks.saturating_sub(page * self.page_capacity).min(self.page_capacity);
+        let slab = (self.page_bricks.x * self.page_bricks.y) as usize;
+        UVec3::new(self.page_bricks.x, self.page_bricks.y, bricks_in_page.div_ceil(slab) as u32)
+    }
+
+    /// indices of the allocated bricks in a page
+    pub fn page_range(&self, page: usize) -> Range<usize> {
+        (page * self.page_capacity).min(self.bricks)..((page + 1) * self.page_capacity).min(self.bricks)
+    }
+
+    /// page and place in it of the `index`th allocated brick
+    pub fn pointer(&self, index: usize) -> (usize, UVec3) {
+        let page = index / self.page_capacity;
+        let index = (index % self.page_capacity) as u32;
+        (page, UVec3::new(index % self.page_bricks.x, index / self.page_bricks.x % self.page_bricks.y, index / (self.page_bricks.x * self.page_bricks.y)))
+    }
+}
+
+/// number of cells in a block of the given size
+fn volume(size: UVec3) -> u64 {
+    (size.x as u64).saturating_mul(size.y as u64).saturating_mul(size.z as u64)
+}
+
+/// smallest integer whose `n`th power is at least `value`
+fn root_ceil(value: u64, n: u32) -> u64 {
+    let mut root = ((value as f64).powf(1.0 / n as f64).round() as u64).max(1);
+    while root.pow(n) < value {
+        root += 1;
+    }
+    while root > 1 && (root - 1).pow(n) >= value {
+        root -= 1;
+    }
+    root
+}
+
+fn div_round_up(num: UVec3, denom: UVec3) -> UVec3 {
+    UVec3::new(num.x.div_ceil(denom.x), num.y.div_ceil(denom.y), num.z.div_ceil(denom.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(pointer_bits: UVec3, max_atlas_size: u32) -> BrickGridConfig {
+        let mut config = BrickGridConfig::new(8, 3, pointer_bits.x, pointer_bits.y, pointer_bits.z).unwrap_or_else(|e| panic!("{}", e.message()));
+        config.set_max_atlas_size(max_atlas_size).unwrap_or_else(|e| panic!("{}", e.message()));
+        config
+    }
+
+    #[test]
+    fn pointers_are_decoded_as_encoded() {
+        for pointer_bits in [UVec3::splat(10), UVec3::new(11, 11, 10), UVec3::new(1, 2, 29), UVec3::new(4, 5, 6)] {
+            let config = config(pointer_bits, 1 << 16);
+            let max = config.max_bricks() - 1;
+            for page in 0..config.max_pages() {
+                for pointer in [UVec3::ZERO, max, UVec3::new(max.x, 0, max.z), UVec3::new(1, max.y, 0) & max] {
+                    let encoded = config.encode_ptr(page, &pointer).unwrap_or_else(|| panic!("{} of page {} can't be encoded with {}", pointer, page, pointer_bits));
+                    assert_eq!(config.decode_ptr(encoded), (page, pointer), "with pointer bits {}", pointer_bits);
+                }
+            }
+            assert_eq!(config.encode_ptr(config.max_pages(), &UVec3::ZERO), None);
+            assert_eq!(config.encode_ptr(0, &(max + UVec3::X)), None);
+        }
+    }
+
+    #[test]
+    fn pages_are_limited_by_pointers_and_the_viewer() {
+        assert_eq!(config(UVec3::new(11, 11, 10), 2048).max_pages(), 1);
+        assert_eq!(config(UVec3::new(11, 10, 10), 2048).max_pages(), 2);
+        assert_eq!(config(UVec3::splat(10), 2048).max_pages(), MAX_ATLAS_PAGES);
+        assert_eq!(config(UVec3::splat(4), 2048).max_pages(), MAX_ATLAS_PAGES);
+
+        // pointers of 4 bits address 16 bricks per axis, fewer than a page of 2048 voxels holds
+        let small = config(UVec3::splat(4), 2048);
+        assert_eq!(small.max_page_bricks(), UVec3::splat(16));
+        assert_eq!(small.max_atlas_bricks(), 16 * 16 * 16 * MAX_ATLAS_PAGES as u64);
+        assert!(small.atlas_layout(16 * 16 * 16 * MAX_ATLAS_PAGES).is_some());
+        assert!(small.atlas_layout(16 * 16 * 16 * MAX_ATLAS_PAGES + 1).is_none());
+    }
+
+    #[test]
+    fn brick_counts_round_up_exactly() {
+        let config = BrickGridConfig::default();
+        assert_eq!(config.brick_count(UVec3::new(1, 64, 65)), UVec3::new(8, 8, 16));
+        // too large to be exact as f32, 2^24 + 1 bricks of 8 voxels round up to 2^24 + 8
+        let extent = UVec3::splat(((1 << 24) + 1) * 8);
+        assert_eq!(config.brick_count(extent), UVec3::splat((1 << 24) + 8));
+    }
+
+    #[test]
+    fn atlas_layouts_are_near_cubic_and_hold_every_brick_once() {
+        assert_eq!(AtlasLayout::new(1000, UVec3::splat(256)).page_bricks(0), UVec3::splat(10));
+        assert_eq!(AtlasLayout::new(0, UVec3::splat(256)).pages(), 1);
+
+        for (bricks, max_page_bricks) in [(1, UVec3::splat(4)), (1000, UVec3::splat(256)), (1001, UVec3::splat(8)), (700, UVec3::new(2, 64, 5)), (4096, UVec3::splat(16))] {
+            let layout = AtlasLayout::new(bricks, max_page_bricks);
+            assert_eq!(layout.pages(), bricks.div_ceil(volume(max_page_bricks) as usize).max(1));
+            let mut pointers = std::collections::HashSet::new();
+            for index in 0..bricks {
+                let (page, pointer) = layout.pointer(index);
+                let page_bricks = layout.page_bricks(page);
+                assert!(layout.page_range(page).contains(&index));
+                assert!(page_bricks.cmple(max_page_bricks).all(), "page of {} bricks is larger than {}", page_bricks, max_page_bricks);
+                assert!(pointer.cmplt(page_bricks).all(), "brick {} at {} lies outside of its page of {}", index, pointer, page_bricks);
+                assert!(pointers.insert((page, pointer)), "brick {} shares {} of page {}", index, pointer, page);
+            }
+            // pages hold their share of the bricks, not much more
+            let capacity = volume(layout.page_bricks(0)) as usize;
+            assert!(capacity < layout.page_range(0).len() + (layout.page_bricks(0).x * layout.page_bricks(0).y) as usize);
+        }
+    }
+}
@@ -19,7 +19,7 @@ impl<T : Default + Clone> Buf3D<T> {
     }
 
     pub fn resize(&mut self, stride: &UVec3) {
-        self.stride = stride.clone();
+        self.stride = *stride;
         self.data.resize_with((self.stride.x * self.stride.y * self.stride.z) as usize, Default::default);
     }
 
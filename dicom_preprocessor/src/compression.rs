@@ -0,0 +1,200 @@
+use crate::brick::BrickGrid;
+use crate::buf3d::Buf3D;
+use crate::grid::Grid;
+use glam::UVec3;
+use rayon::prelude::*;
+#[cfg(feature = "wasm")]
+use js_sys::Float32Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Bricking is lossy, ranges are stored as f16 and atlas voxels are quantized to the range of their
+// brick. These metrics compare a brick grid against the grid it was built from, voxel by voxel.
+
+/// How far the lookups of a brick grid are off from the lookups of its source
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct CompressionMetrics {
+    max_error: f32,
+    mean_error: f32,
+    mean_squared_error: f64,
+    psnr: f32,
+    /// max absolute error per brick, strided like the indirection buffer
+    brick_max_error: Buf3D<f32>,
+    /// mean absolute error per brick, over the voxels of the brick inside the source
+    brick_mean_error: Buf3D<f32>,
+}
+
+/// Absolute errors of the voxels of one brick
+#[derive(Clone, Copy, Default)]
+struct BrickErrors {
+    max: f32,
+    sum: f64,
+    squared_sum: f64,
+    voxels: u64,
+}
+
+fn brick_errors(source: &dyn Grid, grid: &BrickGrid, brick_coord: UVec3) -> BrickErrors {
+    let brick_size = grid.config().brick_size();
+    let start = brick_coord * brick_size;
+    let end = (start + brick_size).min(source.index_extent());
+    let mut errors = BrickErrors::default();
+    for z in start.z..end.z {
+        for y in start.y..end.y {
+            for x in start.x..end.x {
+                let ipos = UVec3::new(x, y, z);
+                let error = (grid.lookup(ipos) - source.lookup(ipos)).abs();
+                errors.max = errors.max.max(error);
+                errors.sum += error as f64;
+                errors.squared_sum += error as f64 * error as f64;
+                errors.voxels += 1;
+            }
+        }
+    }
+    errors
+}
+
+impl CompressionMetrics {
+    /// Compares every voxel of `source` with the same voxel of `grid`, which was constructed from it.
+    /// The PSNR is relative to the range between the minorant and majorant of the source.
+    pub fn measure(source: &dyn Grid, grid: &BrickGrid) -> Self {
+        let mut brick_max_error = Buf3D::new(grid.brick_count());
+        let mut brick_mean_error = Buf3D::new(grid.brick_count());
+
+        let bricks: Vec<BrickErrors> = (0..brick_max_error.data.len())
+            .into_par_iter()
+            .map(|index| brick_errors(source, grid, brick_max_error.calculate_coord(index)))
+            .collect();
+
+        let mut total = BrickErrors::default();
+        for (index, errors) in bricks.iter().enumerate() {
+            brick_max_error.data[index] = errors.max;
+            brick_mean_error.data[index] = if errors.voxels > 0 { (errors.sum / errors.voxels as f64) as f32 } else { 0.0 };
+            total.max = total.max.max(errors.max);
+            total.sum += errors.sum;
+            total.squared_sum += errors.squared_sum;
+            total.voxels += errors.voxels;
+        }
+
+        let voxels = total.voxels.max(1) as f64;
+        let mean_squared_error = total.squared_sum / voxels;
+        let (minorant, majorant) = source.minorant_majorant();
+        let peak = (majorant - minorant) as f64;
+        let psnr = if mean_squared_error == 0.0 {
+            f32::INFINITY
+        } else {
+            (10.0 * (peak * peak / mean_squared_error).log10()) as f32
+        };
+
+        Self {
+            max_error: total.max,
+            mean_error: (total.sum / voxels) as f32,
+            mean_squared_error,
+            psnr,
+            brick_max_error,
+            brick_mean_error,
+        }
+    }
+
+    pub fn mean_squared_error(&self) -> f64 {
+        self.mean_squared_error
+    }
+
+    pub fn brick_max_error_buffer(&self) -> &Buf3D<f32> {
+        &self.brick_max_error
+    }
+
+    pub fn brick_mean_error_buffer(&self) -> &Buf3D<f32> {
+        &self.brick_mean_error
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl CompressionMetrics {
+    /// largest absolute error of any voxel
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn max_error(&self) -> f32 {
+        self.max_error
+    }
+
+    /// mean absolute error over all voxels of the source
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn mean_error(&self) -> f32 {
+        self.mean_error
+    }
+
+    /// peak signal to noise ratio in dB, infinite if the grid is lossless
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn psnr(&self) -> f32 {
+        self.psnr
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl CompressionMetrics {
+    pub fn brick_max_error(&self) -> Float32Array {
+        Float32Array::from(self.brick_max_error.data.as_slice())
+    }
+    pub fn brick_mean_error(&self) -> Float32Array {
+        Float32Array::from(self.brick_mean_error.data.as_slice())
+    }
+    pub fn brick_x(&self) -> u32 {
+        self.brick_max_error.stride.x
+    }
+    pub fn brick_y(&self) -> u32 {
+        self.brick_max_error.stride.y
+    }
+    pub fn brick_z(&self) -> u32 {
+        self.brick_max_error.stride.z
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick_config::{AtlasPrecision, BrickGridConfig};
+    use crate::progress::LoadProgress;
+    use crate::volume::DenseGrid;
+    use glam::Mat4;
+
+    /// A single brick of zeros with a voxel at the max and one at `value`, out of 1024
+    fn brick_with(value: f32) -> DenseGrid {
+        let mut data = Buf3D::new(UVec3::splat(4));
+        let (max, other) = (data.calculate_index(UVec3::new(3, 3, 3)), data.calculate_index(UVec3::new(1, 2, 3)));
+        data.data[max] = 1024.0;
+        data.data[other] = value;
+        DenseGrid::new(data, Mat4::IDENTITY)
+    }
+
+    fn measure(source: &DenseGrid, precision: AtlasPrecision) -> CompressionMetrics {
+        let mut config = BrickGridConfig::new(4, 0, 10, 10, 10).unwrap_or_else(|e| panic!("{}", e.message()));
+        config.atlas_precision = precision;
+        let grid = BrickGrid::construct_with_config(source, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+        CompressionMetrics::measure(source, &grid)
+    }
+
+    #[test]
+    fn lossy_grids_have_their_quantization_error() {
+        // 2/1024 is less than half of a u8 step of the brick's [0, 1] range, so it is stored as 0
+        let metrics = measure(&brick_with(2.0), AtlasPrecision::U8);
+        let error = 2.0f64 / 1024.0;
+        assert_eq!(metrics.max_error(), error as f32);
+        assert!((metrics.mean_error() as f64 - error / 64.0).abs() < 1e-9, "mean error {}", metrics.mean_error());
+        assert!((metrics.mean_squared_error() - error * error / 64.0).abs() < 1e-12, "mean squared error {}", metrics.mean_squared_error());
+        let psnr = 10.0 * (64.0 / (error * error)).log10();
+        assert!((metrics.psnr() as f64 - psnr).abs() < 1e-3, "PSNR {} != {}", metrics.psnr(), psnr);
+        assert_eq!(metrics.brick_max_error_buffer().data, vec![error as f32]);
+        assert_eq!(metrics.brick_mean_error_buffer().data, vec![(error / 64.0) as f32]);
+
+        // the same voxel is kept far more closely by a u16 atlas
+        let finer = measure(&brick_with(2.0), AtlasPrecision::U16);
+        assert!(finer.max_error() > 0.0 && finer.max_error() < 1e-6 && finer.psnr() > metrics.psnr() + 40.0);
+    }
+
+    #[test]
+    fn lossless_grids_have_no_error() {
+        let metrics = measure(&brick_with(0.0), AtlasPrecision::U8);
+        assert_eq!((metrics.max_error(), metrics.mean_error(), metrics.mean_squared_error()), (0.0, 0.0, 0.0));
+        assert_eq!(metrics.psnr(), f32::INFINITY);
+    }
+}
@@ -1,8 +1,35 @@
-use crate::grid::Grid;
+use crate::dicom_export::{write_derived_series, DicomWriteError, RESCALE_INTERCEPT, RESCALE_SLOPE};
+use crate::grid::{compute_histogram_gradient, Grid};
+use crate::nifti::write_nifti_gz;
+use crate::nrrd::write_nrrd;
 use crate::utils::log_to_console;
 use crate::DicomDataInternal;
+use dicom_core::Tag;
 use glam::{Mat4, UVec3};
 
+impl DicomDataInternal {
+    /// slope and intercept that map stored sample values to real values, like Hounsfield units
+    pub fn rescale(&self) -> (f32, f32) {
+        let value = |tag: Tag| self.context.get(tag).and_then(|element| element.to_str().ok()).and_then(|value| value.trim().parse::<f32>().ok());
+        (value(RESCALE_SLOPE).unwrap_or(1.0), value(RESCALE_INTERCEPT).unwrap_or(0.0))
+    }
+
+    /// the decoded volume with its stored sample values and rescale as a `.nii.gz` file
+    pub fn to_nifti_gz(&self) -> Result<Vec<u8>, DicomWriteError> {
+        write_nifti_gz(&self.data, self.transform, self.rescale())
+    }
+
+    /// the decoded volume as a `.nrrd` file, see [`write_nrrd`] for how it is rescaled
+    pub fn to_nrrd(&self) -> Vec<u8> {
+        write_nrrd(&self.data, self.transform, self.rescale())
+    }
+
+    /// the decoded volume as a zip of a derived DICOM series, keeping the source's patient and study
+    pub fn to_dicom_series(&self, series_description: &str) -> Result<Vec<u8>, DicomWriteError> {
+        write_derived_series(&self.data, self.transform, &self.context, series_description)
+    }
+}
+
 impl Grid for DicomDataInternal {
     fn lookup(&self, ipos: UVec3) -> f32 {
         if ipos.z >= self.data.stride.z || ipos.y >= self.data.stride.y || ipos.x >= self.data.stride.x {
@@ -12,6 +39,10 @@ impl Grid for DicomDataInternal {
         let index = self.data.calculate_index(ipos);
         if index >= self.data.data.len() { log_to_console(&format!("index: {}\nipos: {}\nstride: {}", index, ipos, self.data.stride)); }
         let raw = self.data.data[index];
+        // all-black series have a max of 0, they stay at 0 instead of dividing by it
+        if self.max == 0 {
+            return 0.0;
+        }
         // TODO: The lib used by voldata has this built in?
         (raw as f32) / (self.max as f32)
     }
@@ -21,15 +52,15 @@ impl Grid for DicomDataInternal {
     }
 
     fn index_extent(&self) -> UVec3 {
-        self.data.stride.clone()
+        self.data.stride
     }
 
     fn num_voxels(&self) -> usize {
-        (self.data.stride.x * self.data.stride.y * self.data.stride.z) as usize
+        (self.data.stride.x as usize) * (self.data.stride.y as usize) * (self.data.stride.z as usize)
     }
 
     fn size_bytes(&self) -> usize {
-        todo!()
+        self.data.data.len() * size_of::<u16>() + self.histogram.len() * size_of::<u32>()
     }
 
     fn histogram(&self) -> Vec<u32> {
@@ -37,35 +68,10 @@ impl Grid for DicomDataInternal {
     }
 
     fn histogram_gradient(&self) -> (Vec<i32>, u32, u32) {
-        let mut gradient: Vec<i32> = Vec::with_capacity(self.histogram.len());
-        let mut last: u32 = 0;
-        let mut gradmin: u32 = u32::MAX;
-        let mut gradmax: u32 = u32::MIN;
-        for histogram_step in &self.histogram {
-            let gradient_step: i32 = histogram_step.clone() as i32 - last as i32;
-            let abs_step = gradient_step.abs_diff(0);
-            if abs_step > gradmax {
-                gradmax = abs_step;
-            }
-            if abs_step < gradmin {
-                gradmin = abs_step;
-            }
-            gradient.push(gradient_step);
-            last = histogram_step.clone();
-        }
-
-        // smoothes the gradient a bit for nicer display
-        let mut smoothed: Vec<i32> = Vec::with_capacity(gradient.len());
-        smoothed.push(gradient[0]);
-        for i in 1..(gradient.len() - 1) {
-            let avg = gradient[i - 1] + gradient[i] + gradient[i + 1];
-            smoothed.push(avg / 3);
-        }
-        smoothed.push(gradient[gradient.len() - 1]);
-        (smoothed, gradmin, gradmax)
+        compute_histogram_gradient(&self.histogram)
     }
 
     fn transform(&self) -> Mat4 {
-        self.transform.clone()
+        self.transform
     }
 }
\ No newline at end of file
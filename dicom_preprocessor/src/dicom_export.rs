@@ -0,0 +1,299 @@
+use crate::buf3d::Buf3D;
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_object::{FileMetaTableBuilder, InMemDicomObject};
+use glam::{Mat4, Vec3};
+use std::io::{Cursor, Write};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+use zip::write::SimpleFileOptions;
+
+// Writes a processed volume as a derived DICOM series, one single-frame instance per slice.
+
+pub(crate) const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+pub(crate) const SECONDARY_CAPTURE_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.7";
+/// offset added to the original series number, so derived series sort after their source
+const SERIES_NUMBER_OFFSET: i32 = 1000;
+
+pub(crate) const SPECIFIC_CHARACTER_SET: Tag = Tag(0x0008, 0x0005);
+pub(crate) const IMAGE_TYPE: Tag = Tag(0x0008, 0x0008);
+pub(crate) const SOP_CLASS_UID: Tag = Tag(0x0008, 0x0016);
+pub(crate) const SOP_INSTANCE_UID: Tag = Tag(0x0008, 0x0018);
+pub(crate) const MODALITY: Tag = Tag(0x0008, 0x0060);
+pub(crate) const CONVERSION_TYPE: Tag = Tag(0x0008, 0x0064);
+pub(crate) const SERIES_DESCRIPTION: Tag = Tag(0x0008, 0x103E);
+pub(crate) const DERIVATION_DESCRIPTION: Tag = Tag(0x0008, 0x2111);
+pub(crate) const SLICE_THICKNESS: Tag = Tag(0x0018, 0x0050);
+pub(crate) const STUDY_INSTANCE_UID: Tag = Tag(0x0020, 0x000D);
+pub(crate) const SERIES_INSTANCE_UID: Tag = Tag(0x0020, 0x000E);
+pub(crate) const SERIES_NUMBER: Tag = Tag(0x0020, 0x0011);
+pub(crate) const INSTANCE_NUMBER: Tag = Tag(0x0020, 0x0013);
+pub(crate) const IMAGE_POSITION_PATIENT: Tag = Tag(0x0020, 0x0032);
+pub(crate) const IMAGE_ORIENTATION_PATIENT: Tag = Tag(0x0020, 0x0037);
+pub(crate) const FRAME_OF_REFERENCE_UID: Tag = Tag(0x0020, 0x0052);
+pub(crate) const SAMPLES_PER_PIXEL: Tag = Tag(0x0028, 0x0002);
+pub(crate) const PHOTOMETRIC_INTERPRETATION: Tag = Tag(0x0028, 0x0004);
+pub(crate) const ROWS: Tag = Tag(0x0028, 0x0010);
+pub(crate) const COLUMNS: Tag = Tag(0x0028, 0x0011);
+pub(crate) const PIXEL_SPACING: Tag = Tag(0x0028, 0x0030);
+pub(crate) const BITS_ALLOCATED: Tag = Tag(0x0028, 0x0100);
+pub(crate) const BITS_STORED: Tag = Tag(0x0028, 0x0101);
+pub(crate) const HIGH_BIT: Tag = Tag(0x0028, 0x0102);
+pub(crate) const PIXEL_REPRESENTATION: Tag = Tag(0x0028, 0x0103);
+pub(crate) const RESCALE_INTERCEPT: Tag = Tag(0x0028, 0x1052);
+pub(crate) const RESCALE_SLOPE: Tag = Tag(0x0028, 0x1053);
+pub(crate) const PIXEL_DATA: Tag = Tag(0x7FE0, 0x0010);
+
+/// Patient, study and acquisition attributes that are carried over from the source series
+pub const CONTEXT_TAGS: [Tag; 23] = [
+    SPECIFIC_CHARACTER_SET,
+    SOP_CLASS_UID,
+    Tag(0x0008, 0x0020), // Study Date
+    Tag(0x0008, 0x0030), // Study Time
+    Tag(0x0008, 0x0050), // Accession Number
+    MODALITY,
+    Tag(0x0008, 0x0070), // Manufacturer
+    Tag(0x0008, 0x0090), // Referring Physician's Name
+    Tag(0x0008, 0x1030), // Study Description
+    SERIES_DESCRIPTION,
+    Tag(0x0010, 0x0010), // Patient's Name
+    Tag(0x0010, 0x0020), // Patient ID
+    Tag(0x0010, 0x0030), // Patient's Birth Date
+    Tag(0x0010, 0x0040), // Patient's Sex
+    Tag(0x0010, 0x1010), // Patient's Age
+    Tag(0x0018, 0x0015), // Body Part Examined
+    Tag(0x0018, 0x5100), // Patient Position
+    STUDY_INSTANCE_UID,
+    Tag(0x0020, 0x0010), // Study ID
+    SERIES_NUMBER,
+    FRAME_OF_REFERENCE_UID,
+    RESCALE_INTERCEPT,
+    RESCALE_SLOPE,
+];
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub enum DicomWriteErrorType {
+    InvalidOptions,
+    InvalidMeta,
+    WriteFailed,
+    ZipFailed,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct DicomWriteError(DicomWriteErrorType, Option<String>);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl DicomWriteError {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn message(self) -> String {
+        format!("{:?}: {}", self.0, self.1.unwrap_or_else(|| "No Message Specified".to_string()))
+    }
+}
+
+pub(crate) fn error(error_type: DicomWriteErrorType, error: impl ToString) -> DicomWriteError {
+    DicomWriteError(error_type, Some(error.to_string()))
+}
+
+/// Copies the attributes in [`CONTEXT_TAGS`] of a source instance
+pub fn extract_context(source: &InMemDicomObject) -> InMemDicomObject {
+    let mut context = InMemDicomObject::new_empty();
+    for tag in CONTEXT_TAGS {
+        if let Some(element) = source.get(tag) {
+            context.put(element.clone());
+        }
+    }
+    context
+}
+
+/// Turns 128 random bits into a UID, using the 2.25 root for UUID derived UIDs
+pub(crate) fn uid_from_random(random: u128) -> String {
+    // mark as a version 4 UUID
+    let uuid = (random & !(0xF << 76) & !(0x3 << 62)) | (0x4 << 76) | (0x2 << 62);
+    format!("2.25.{}", uuid)
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub(crate) fn generate_uid() -> String {
+    let mut random: u128 = 0;
+    for _ in 0..4 {
+        random = (random << 32) | (js_sys::Math::random() * u32::MAX as f64) as u128;
+    }
+    uid_from_random(random)
+}
+
+/// The standard library seeds every `RandomState` with fresh random keys, which is enough for UIDs
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+pub(crate) fn generate_uid() -> String {
+    use std::hash::{BuildHasher, RandomState};
+    let random = || RandomState::new().hash_one(crate::utils::now().to_bits()) as u128;
+    uid_from_random((random() << 64) | random())
+}
+
+pub(crate) fn decimal_string(values: &[f32]) -> String {
+    values.iter().map(|value| format!("{}", value)).collect::<Vec<_>>().join("\\")
+}
+
+pub(crate) fn put_string(object: &mut InMemDicomObject, tag: Tag, vr: VR, value: impl Into<String>) {
+    object.put(DataElement::new(tag, vr, PrimitiveValue::from(value.into())));
+}
+
+pub(crate) fn put_u16(object: &mut InMemDicomObject, tag: Tag, value: u16) {
+    object.put(DataElement::new(tag, VR::US, PrimitiveValue::from(value)));
+}
+
+/// Wraps an object into a file with the given transfer syntax, and encodes it
+pub(crate) fn write_file(object: InMemDicomObject, transfer_syntax: &str, sop_class_uid: &str, sop_instance_uid: &str) -> Result<Vec<u8>, DicomWriteError> {
+    let file = object
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .transfer_syntax(transfer_syntax)
+                .media_storage_sop_class_uid(sop_class_uid)
+                .media_storage_sop_instance_uid(sop_instance_uid),
+        )
+        .map_err(|e| error(DicomWriteErrorType::InvalidMeta, e))?;
+    let mut bytes = Vec::new();
+    file.write_all(&mut bytes).map_err(|e| error(DicomWriteErrorType::WriteFailed, e))?;
+    Ok(bytes)
+}
+
+/// Packs named files into a deflate compressed zip, in the given order
+pub(crate) fn write_zip(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, DicomWriteError> {
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for (name, bytes) in files {
+        zip.start_file(name.as_str(), options).map_err(|e| error(DicomWriteErrorType::ZipFailed, e))?;
+        zip.write_all(bytes).map_err(|e| error(DicomWriteErrorType::ZipFailed, e))?;
+    }
+    let zip = zip.finish().map_err(|e| error(DicomWriteErrorType::ZipFailed, e))?;
+    Ok(zip.into_inner())
+}
+
+/// Writes the volume as a zip of Secondary Capture DICOM files, one per slice, as the modules the
+/// source's SOP class requires aren't known. Patient and study attributes are kept from `context`,
+/// see [`extract_context`], while the series gets new UIDs. The transform maps voxel indices to
+/// patient coordinates in millimeters, like the one of the loaded source, so the series stays in the
+/// source's frame of reference. Sources without a study or frame of reference get new ones.
+pub fn write_derived_series(data: &Buf3D<u16>, transform: Mat4, context: &InMemDicomObject, series_description: &str) -> Result<Vec<u8>, DicomWriteError> {
+    // ROWS and COLUMNS are 16 bit
+    let (rows, columns) = match (u16::try_from(data.stride.y), u16::try_from(data.stride.x)) {
+        (Ok(rows), Ok(columns)) if rows > 0 && columns > 0 && data.stride.z > 0 => (rows, columns),
+        _ => return Err(error(DicomWriteErrorType::InvalidOptions, format!("Can't write a volume of {} voxels as DICOM slices", data.stride))),
+    };
+    let string = |tag: Tag| context.get(tag).and_then(|element| element.to_str().ok()).map(|value| value.trim().to_string());
+    let series_number = string(SERIES_NUMBER).and_then(|number| number.parse::<i32>().ok()).unwrap_or(0) + SERIES_NUMBER_OFFSET;
+    let study_instance_uid = string(STUDY_INSTANCE_UID).filter(|uid| !uid.is_empty()).unwrap_or_else(generate_uid);
+    let series_instance_uid = generate_uid();
+    let frame_of_reference_uid = string(FRAME_OF_REFERENCE_UID).filter(|uid| !uid.is_empty()).unwrap_or_else(generate_uid);
+
+    let row_direction = transform.x_axis.truncate();
+    let column_direction = transform.y_axis.truncate();
+    let slice_direction = transform.z_axis.truncate();
+    let orientation = decimal_string(&[row_direction.normalize().to_array(), column_direction.normalize().to_array()].concat());
+    // pixel spacing is the distance between rows, then between columns
+    let pixel_spacing = decimal_string(&[column_direction.length(), row_direction.length()]);
+
+    let mut files = Vec::new();
+    let slice_size = rows as usize * columns as usize;
+    for (slice, pixels) in data.data.chunks_exact(slice_size).enumerate() {
+        let sop_instance_uid = generate_uid();
+        let mut object = context.clone();
+        put_string(&mut object, IMAGE_TYPE, VR::CS, "DERIVED\\SECONDARY");
+        put_string(&mut object, SOP_CLASS_UID, VR::UI, SECONDARY_CAPTURE_IMAGE_STORAGE);
+        put_string(&mut object, SOP_INSTANCE_UID, VR::UI, sop_instance_uid.as_str());
+        if context.get(MODALITY).is_none() {
+            put_string(&mut object, MODALITY, VR::CS, "OT");
+        }
+        // workstation, as the images were derived on one
+        put_string(&mut object, CONVERSION_TYPE, VR::CS, "WSD");
+        put_string(&mut object, STUDY_INSTANCE_UID, VR::UI, study_instance_uid.as_str());
+        put_string(&mut object, SERIES_DESCRIPTION, VR::LO, series_description);
+        put_string(&mut object, DERIVATION_DESCRIPTION, VR::ST, "Processed volume exported from Volxel");
+        put_string(&mut object, SERIES_INSTANCE_UID, VR::UI, series_instance_uid.as_str());
+        put_string(&mut object, SERIES_NUMBER, VR::IS, series_number.to_string());
+        put_string(&mut object, INSTANCE_NUMBER, VR::IS, (slice + 1).to_string());
+        put_string(&mut object, FRAME_OF_REFERENCE_UID, VR::UI, frame_of_reference_uid.as_str());
+        put_string(&mut object, IMAGE_POSITION_PATIENT, VR::DS, decimal_string(&transform.transform_point3(Vec3::new(0.0, 0.0, slice as f32)).to_array()));
+        put_string(&mut object, IMAGE_ORIENTATION_PATIENT, VR::DS, orientation.as_str());
+        put_string(&mut object, PIXEL_SPACING, VR::DS, pixel_spacing.as_str());
+        put_string(&mut object, SLICE_THICKNESS, VR::DS, decimal_string(&[slice_direction.length()]));
+        if context.get(RESCALE_INTERCEPT).is_none() || context.get(RESCALE_SLOPE).is_none() {
+            put_string(&mut object, RESCALE_INTERCEPT, VR::DS, "0");
+            put_string(&mut object, RESCALE_SLOPE, VR::DS, "1");
+        }
+        put_u16(&mut object, SAMPLES_PER_PIXEL, 1);
+        put_string(&mut object, PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2");
+        put_u16(&mut object, ROWS, rows);
+        put_u16(&mut object, COLUMNS, columns);
+        put_u16(&mut object, BITS_ALLOCATED, 16);
+        put_u16(&mut object, BITS_STORED, 16);
+        put_u16(&mut object, HIGH_BIT, 15);
+        put_u16(&mut object, PIXEL_REPRESENTATION, 0);
+        let pixel_bytes: Vec<u8> = pixels.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        object.put(DataElement::new(PIXEL_DATA, VR::OW, PrimitiveValue::from(pixel_bytes)));
+
+        let bytes = write_file(object, EXPLICIT_VR_LITTLE_ENDIAN, SECONDARY_CAPTURE_IMAGE_STORAGE, &sop_instance_uid)?;
+        files.push((format!("IM{:05}.dcm", slice + 1), bytes));
+    }
+    write_zip(&files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phantom::{generate_phantom, PhantomOptions, PhantomShape};
+    use crate::read_dicom_series;
+    use crate::zip::read_dicom_zip;
+    use std::io::Read;
+
+    fn string(object: &InMemDicomObject, tag: Tag) -> Option<String> {
+        object.get(tag).and_then(|element| element.to_str().ok()).map(|value| value.trim().to_string())
+    }
+
+    fn first_file(zip: &[u8]) -> InMemDicomObject {
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+        let mut bytes = Vec::new();
+        archive.by_index(0).unwrap().read_to_end(&mut bytes).unwrap();
+        dicom_object::from_reader(bytes.as_slice()).unwrap().into_inner()
+    }
+
+    #[test]
+    fn derived_series_stays_in_the_source_frame_of_reference() {
+        let mut options = PhantomOptions::new(PhantomShape::Spheres, 8, 6, 5);
+        options.spacing_z = 2.5;
+        options.rotation_z = 30.0;
+        let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        let source = read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).unwrap_or_else(|e| panic!("{}", e.message()));
+
+        let zip = source.to_dicom_series("Derived").unwrap_or_else(|e| panic!("{}", e.message()));
+        let derived = read_dicom_zip(&zip).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(derived.data.data, source.data.data);
+        assert!(derived.transform.abs_diff_eq(source.transform, 1e-4), "{} != {}", derived.transform, source.transform);
+
+        let file = first_file(&zip);
+        assert_eq!(string(&file, SOP_CLASS_UID).as_deref(), Some(SECONDARY_CAPTURE_IMAGE_STORAGE));
+        assert_eq!(string(&file, CONVERSION_TYPE).as_deref(), Some("WSD"));
+        assert_eq!(string(&file, FRAME_OF_REFERENCE_UID), string(&source.context, FRAME_OF_REFERENCE_UID));
+        assert_eq!(string(&file, STUDY_INSTANCE_UID), string(&source.context, STUDY_INSTANCE_UID));
+        assert_ne!(string(&file, SERIES_INSTANCE_UID), string(&source.context, SERIES_INSTANCE_UID));
+    }
+
+    #[test]
+    fn derived_series_without_study_gets_new_uids() {
+        let mut data = Buf3D::new(glam::UVec3::new(2, 2, 2));
+        data.data = vec![0, 1, 2, 3, 4, 5, 6, 7];
+        let zip = write_derived_series(&data, Mat4::IDENTITY, &InMemDicomObject::new_empty(), "Derived").unwrap_or_else(|e| panic!("{}", e.message()));
+
+        let file = first_file(&zip);
+        assert!(string(&file, STUDY_INSTANCE_UID).is_some_and(|uid| uid.starts_with("2.25.")));
+        assert!(string(&file, FRAME_OF_REFERENCE_UID).is_some_and(|uid| uid.starts_with("2.25.")));
+        assert_eq!(string(&file, MODALITY).as_deref(), Some("OT"));
+    }
+
+    #[test]
+    fn extents_dicom_slices_cant_hold_are_errors() {
+        for extent in [glam::UVec3::new(65536, 1, 1), glam::UVec3::new(0, 2, 2)] {
+            let data = Buf3D::new(extent);
+            assert!(write_derived_series(&data, Mat4::IDENTITY, &InMemDicomObject::new_empty(), "Derived").is_err());
+        }
+    }
+}
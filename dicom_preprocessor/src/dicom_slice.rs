@@ -0,0 +1,637 @@
+use crate::brick::BrickGrid;
+use crate::brick_config::BrickGridConfig;
+use crate::brick_builder::BrickGridBuilder;
+use crate::buf3d::Buf3D;
+use crate::dicom_export::{
+    decimal_string, extract_context, generate_uid, put_string, write_file, DicomWriteError, EXPLICIT_VR_LITTLE_ENDIAN, RESCALE_INTERCEPT, RESCALE_SLOPE,
+    SECONDARY_CAPTURE_IMAGE_STORAGE, SOP_CLASS_UID,
+};
+use crate::progress::{LoadPhase, LoadProgress};
+use crate::utils::{debug_print_tags, log_to_console, now};
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use crate::{DicomDataInternal, DICOMDIR_IMAGE_REFERENCE, DICOMDIR_IMAGE_SEQUENCE, PIXEL_SPACING, SLICE_THICKNESS};
+#[cfg(feature = "wasm")]
+use crate::DicomResult;
+use dicom_core::{Tag, VR};
+use dicom_object::InMemDicomObject;
+use dicom_pixeldata::{PixelDecoder, PixelRepresentation};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Loading a series is split into decoding each file into a slice, which is independent per file and
+// runs in parallel, and assembling the slices into a volume once all are decoded.
+//
+// Slice records are slices as bytes, so they can be passed between workers. Layout, little endian:
+//   magic "VXSLICE\0", format version (u32)
+//   columns, rows, frames (u32), max (u16), spacing (3 x f32)
+//   presence flags (u32): position, orientation, instance number
+//   position (3 x f32), orientation (6 x f32), instance number (i32)
+//   histogram length (u32), histogram (u32), samples (u16)
+//   context length (u32), context as DICOM file
+
+const IMAGE_POSITION_PATIENT: Tag = Tag(0x0020, 0x0032);
+const IMAGE_ORIENTATION_PATIENT: Tag = Tag(0x0020, 0x0037);
+const INSTANCE_NUMBER: Tag = Tag(0x0020, 0x0013);
+const NUMBER_OF_FRAMES: Tag = Tag(0x0028, 0x0008);
+const ROWS: Tag = Tag(0x0028, 0x0010);
+const COLUMNS: Tag = Tag(0x0028, 0x0011);
+
+const RECORD_MAGIC: &[u8; 8] = b"VXSLICE\0";
+const RECORD_VERSION: u32 = 1;
+
+const HAS_POSITION: u32 = 1 << 0;
+const HAS_ORIENTATION: u32 = 1 << 1;
+const HAS_INSTANCE_NUMBER: u32 = 1 << 2;
+
+/// One decoded DICOM file, to be assembled into a volume with the other files of its series
+pub struct DicomSlice {
+    data: Buf3D<u16>,
+    histogram: Vec<u32>,
+    max: u16,
+    /// distance between columns, rows and slices
+    spacing: Vec3,
+    placement: SlicePlacement,
+    context: InMemDicomObject,
+}
+
+/// Where a slice lies in its series, used to sort the slices
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SlicePlacement {
+    /// Image Position (Patient), the center of the first voxel
+    pub position: Option<Vec3>,
+    /// Image Orientation (Patient), directions of the rows and columns
+    pub orientation: Option<[Vec3; 2]>,
+    pub instance_number: Option<i32>,
+}
+
+impl SlicePlacement {
+    fn read(object: &InMemDicomObject) -> Self {
+        let position = parse_floats(object, IMAGE_POSITION_PATIENT).and_then(|values| match values[..] {
+            [x, y, z] => Some(Vec3::new(x, y, z)),
+            _ => None,
+        });
+        let orientation = parse_floats(object, IMAGE_ORIENTATION_PATIENT).and_then(|values| match values[..] {
+            [rx, ry, rz, cx, cy, cz] => Some([Vec3::new(rx, ry, rz), Vec3::new(cx, cy, cz)]),
+            _ => None,
+        });
+        let instance_number = object
+            .get(INSTANCE_NUMBER)
+            .and_then(|element| element.to_str().ok())
+            .and_then(|value| value.trim().parse::<i32>().ok());
+        Self { position, orientation, instance_number }
+    }
+}
+
+/// The size and placement of a DICOM file's image, read without decoding its pixel data
+#[derive(Clone, Copy, Debug)]
+pub struct DicomSliceHeader {
+    /// columns, rows and frames
+    pub size: UVec3,
+    pub placement: SlicePlacement,
+}
+
+impl From<DicomSlice> for DicomDataInternal {
+    /// A single slice as volume, an empty slice keeps the full value range
+    fn from(slice: DicomSlice) -> Self {
+        if slice.data.data.is_empty() {
+            return DicomDataInternal {
+                data: slice.data,
+                histogram: vec![],
+                max: u16::MAX,
+                transform: Mat4::IDENTITY,
+                context: slice.context
+            };
+        }
+        DicomDataInternal {
+            transform: series_transform(&slice.placement, &slice.placement, 0, slice.spacing),
+            data: slice.data,
+            histogram: slice.histogram,
+            max: slice.max,
+            context: slice.context
+        }
+    }
+}
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+fn unsupported(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::Unsupported, message)
+}
+
+fn parse_floats(object: &InMemDicomObject, tag: Tag) -> Option<Vec<f32>> {
+    let strings = object.get(tag)?.strings().ok()?;
+    strings.iter().map(|value| value.trim().parse::<f32>().ok()).collect()
+}
+
+/// Decodes the pixel data and position of a DICOM file. Files that reference others, like a
+/// DICOMDIR, decode to an empty slice that is skipped during assembly.
+pub fn decode_dicom_slice(bytes: &[u8], debug_print: bool) -> Result<DicomSlice, VolumeReadError> {
+    let result_obj = dicom_object::from_reader(bytes).map_err(|e| invalid(format!("Couldn't read DICOM file: {}", e)))?;
+    let sequence = result_obj.get(DICOMDIR_IMAGE_SEQUENCE);
+
+    if let Some(Some(sequence)) = sequence.map(|seq| seq.items()) {
+        for item in sequence {
+            let image_reference = item.get(DICOMDIR_IMAGE_REFERENCE).and_then(|reference| reference.strings().ok());
+            if let Some(reference) = image_reference {
+                log_to_console(&format!("Reference: {}", reference.join("/")));
+            } else {
+                log_to_console("No Image Reference, printing debug");
+                log_to_console(debug_print_tags(item, 0).as_str());
+            }
+        }
+
+        return Ok(DicomSlice {
+            data: Buf3D::empty(),
+            histogram: vec![],
+            max: u16::MAX,
+            spacing: Vec3::ONE,
+            placement: SlicePlacement::default(),
+            context: InMemDicomObject::new_empty(),
+        });
+    }
+
+    // the result object does not contain an image sequence, so we assume it is an image
+    let pixel_data = result_obj
+        .decode_pixel_data()
+        .map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, format!("Couldn't decode pixel data: {}", e)))?;
+
+    if pixel_data.samples_per_pixel() != 1 {
+        return Err(unsupported("More than one sample per pixel not currently supported"));
+    }
+    let bits_stored = pixel_data.bits_stored().clamp(1, 16) as u32;
+    let samples: Vec<u16> = match pixel_data.bits_allocated() {
+        8 => pixel_data.data().iter().map(|sample| *sample as u16).collect(),
+        16 => pixel_data.data().chunks_exact(2).map(|sample| u16::from_ne_bytes([sample[0], sample[1]])).collect(),
+        bits => return Err(unsupported(format!("Currently only 8 and 16 bit samples are supported, not {}", bits))),
+    };
+    let size = UVec3::new(pixel_data.columns(), pixel_data.rows(), pixel_data.number_of_frames());
+    let expected = (size.x as usize) * (size.y as usize) * (size.z as usize);
+    if samples.len() < expected {
+        return Err(VolumeReadError::new(
+            VolumeReadErrorType::NotEnoughData,
+            format!("Expected {} samples for an image of {}, got {}", expected, size, samples.len()),
+        ));
+    }
+
+    // Signed samples are shifted by half their range to be unsigned, so the lowest value becomes 0.
+    // The rescale intercept of the context is moved along, so rescaled values stay the same.
+    let signed = pixel_data.pixel_representation() == PixelRepresentation::Signed;
+    let offset = if signed { 1i32 << (bits_stored - 1) } else { 0 };
+    let mut context = extract_context(&result_obj);
+    if signed {
+        shift_rescale_intercept(&mut context, offset);
+    }
+
+    let max_density = 2usize.pow(bits_stored);
+
+    let mut histogram: Vec<u32> = vec![0; max_density];
+
+    let unused_bits = 16 - bits_stored;
+    let mut max_sample = u16::MIN;
+    let collected_data: Vec<u16> = samples
+        .into_iter()
+        .take(expected)
+        .map(|sample| {
+            // only the stored bits hold the value, signed values are two's complement within them
+            let sample = if signed {
+                (((sample << unused_bits) as i16 >> unused_bits) as i32 + offset) as u16
+            } else {
+                sample & (u16::MAX >> unused_bits)
+            };
+            histogram[sample as usize] += 1;
+            max_sample = max_sample.max(sample);
+            sample
+        })
+        .collect();
+
+    let pixel_spacing = result_obj
+        .get(PIXEL_SPACING)
+        .ok_or_else(|| invalid("Image did not contain pixel spacing information"))?;
+    // the distance between rows comes first, then the one between columns
+    let pixel_spacing = pixel_spacing.strings().map_err(|_| invalid("Pixel Spacing was not a String sequence"))?;
+    let [y, x] = pixel_spacing else {
+        return Err(invalid("Pixel spacing did not contain two values y and x"));
+    };
+    let pixel_sizing_x: f32 = x.trim().parse().map_err(|_| invalid(format!("Couldn't parse x spacing {:?} to float", x)))?;
+    let pixel_sizing_y: f32 = y.trim().parse().map_err(|_| invalid(format!("Couldn't parse y spacing {:?} to float", y)))?;
+
+    let slice_thickness = match result_obj.get(SLICE_THICKNESS) {
+        Some(thickness) => {
+            let thickness = thickness.strings().map_err(|_| invalid("Slice thickness was not a string sequence"))?;
+            let thickness = thickness.first().ok_or_else(|| invalid("Slice thickness didn't contain anything"))?;
+            thickness.trim().parse::<f32>().map_err(|_| invalid(format!("Couldn't parse slice thickness {:?} to float", thickness)))?
+        }
+        None => pixel_sizing_x.min(pixel_sizing_y),
+    };
+
+    if debug_print {
+        log_to_console(&format!("Pixel Spacing: x={}, y={}, z={}", pixel_sizing_x, pixel_sizing_y, slice_thickness));
+    }
+
+    let mut data = Buf3D::new(size);
+    data.data = collected_data;
+
+    Ok(DicomSlice {
+        data,
+        histogram,
+        max: max_sample,
+        spacing: Vec3::new(pixel_sizing_x, pixel_sizing_y, slice_thickness),
+        placement: SlicePlacement::read(&result_obj),
+        context,
+    })
+}
+
+/// Lowers the rescale intercept of a context by `offset` stored values, for samples that were
+/// raised by `offset`
+fn shift_rescale_intercept(context: &mut InMemDicomObject, offset: i32) {
+    let value = |tag: Tag| context.get(tag).and_then(|element| element.to_str().ok()).and_then(|value| value.trim().parse::<f32>().ok());
+    let slope = value(RESCALE_SLOPE).unwrap_or(1.0);
+    let intercept = value(RESCALE_INTERCEPT).unwrap_or(0.0) - slope * offset as f32;
+    put_string(context, RESCALE_SLOPE, VR::DS, decimal_string(&[slope]));
+    put_string(context, RESCALE_INTERCEPT, VR::DS, decimal_string(&[intercept]));
+}
+
+/// Reads the size and placement of a DICOM file's image. Files without one, like a DICOMDIR,
+/// or that can't be parsed, have no header.
+pub fn read_dicom_slice_header(bytes: &[u8]) -> Option<DicomSliceHeader> {
+    let object = dicom_object::from_reader(bytes).ok()?;
+    if object.get(DICOMDIR_IMAGE_SEQUENCE).is_some() {
+        return None;
+    }
+    let columns = object.get(COLUMNS)?.to_int::<u32>().ok()?;
+    let rows = object.get(ROWS)?.to_int::<u32>().ok()?;
+    let frames = object.get(NUMBER_OF_FRAMES).and_then(|element| element.to_int::<u32>().ok()).unwrap_or(1);
+    Some(DicomSliceHeader {
+        size: UVec3::new(columns, rows, frames),
+        placement: SlicePlacement::read(&object),
+    })
+}
+
+/// Decodes files in parallel, the slices are in the same order as the files. The first file that
+/// can't be decoded fails the whole batch.
+pub fn decode_dicom_slices<B: AsRef<[u8]> + Sync>(files: &[B]) -> Result<Vec<DicomSlice>, VolumeReadError> {
+    files.par_iter().map(|bytes| decode_dicom_slice(bytes.as_ref(), false)).collect()
+}
+
+/// How many files are decoded in parallel at once, loaders report progress and check for
+/// cancellation between batches
+pub(crate) fn decode_batch_size() -> usize {
+    rayon::current_num_threads() * 2
+}
+
+/// Maps voxel indices of a sorted series to patient coordinates. The first voxel lies at the
+/// position of the first slice, rows and columns run along its orientation. Consecutive slices are
+/// as far apart as the positions of the first and last slice are over the `steps` slices between
+/// them, which also holds for tilted series. Without positions, or with a single slice, slices are
+/// `spacing.z`, the slice thickness, apart along the slice normal.
+fn series_transform(first: &SlicePlacement, last: &SlicePlacement, steps: u32, spacing: Vec3) -> Mat4 {
+    let [row, column] = first.orientation.map(|[row, column]| [row.normalize_or(Vec3::X), column.normalize_or(Vec3::Y)]).unwrap_or([Vec3::X, Vec3::Y]);
+    let normal = row.cross(column).normalize_or(Vec3::Z);
+    let slice_step = match (first.position, last.position) {
+        (Some(first), Some(last)) if steps > 0 => Some((last - first) / steps as f32),
+        _ => None,
+    };
+    let slice_step = slice_step.filter(|step| step.length() > f32::EPSILON).unwrap_or(normal * spacing.z);
+    Mat4::from_cols(
+        (row * spacing.x).extend(0.0),
+        (column * spacing.y).extend(0.0),
+        slice_step.extend(0.0),
+        first.position.unwrap_or(Vec3::ZERO).extend(1.0),
+    )
+}
+
+/// Sorts slices into volume order. When every slice has a position and orientation, they are
+/// sorted by their position along the slice normal, otherwise by instance number when every slice
+/// has one. Slices are kept in the given order when neither is available.
+pub fn sort_dicom_slices(slices: &mut [DicomSlice]) {
+    sort_by_placement(slices, |slice| &slice.placement);
+}
+
+/// Sorts anything that is placed like a slice, see [`sort_dicom_slices`]
+pub fn sort_by_placement<T>(items: &mut [T], placement: impl Fn(&T) -> &SlicePlacement) {
+    let normal = items.first().and_then(|item| placement(item).orientation).map(|[row, column]| row.cross(column));
+    match normal {
+        Some(normal) if items.iter().all(|item| placement(item).position.is_some() && placement(item).orientation.is_some()) => {
+            items.sort_by(|a, b| {
+                let a = placement(a).position.unwrap().dot(normal);
+                let b = placement(b).position.unwrap().dot(normal);
+                a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+            });
+        }
+        _ if items.iter().all(|item| placement(item).instance_number.is_some()) => {
+            items.sort_by_key(|item| placement(item).instance_number);
+        }
+        _ => log_to_console("Slices have neither positions nor instance numbers, keeping them in file order"),
+    }
+}
+
+/// Sorts the slices of a series with [`sort_dicom_slices`] and stacks them into a volume.
+/// Empty slices, like the ones of a DICOMDIR, are skipped.
+pub fn assemble_dicom_slices(mut slices: Vec<DicomSlice>) -> Result<DicomDataInternal, VolumeReadError> {
+    slices.retain(|slice| !slice.data.data.is_empty());
+    sort_dicom_slices(&mut slices);
+
+    let first = slices.first().ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "No slices with pixel data"))?;
+    let (columns, rows) = (first.data.stride.x, first.data.stride.y);
+    if let Some(slice) = slices.iter().find(|slice| slice.data.stride.x != columns || slice.data.stride.y != rows) {
+        return Err(invalid(format!("Slices have different sizes, {}x{} and {}x{}", columns, rows, slice.data.stride.x, slice.data.stride.y)));
+    }
+
+    // I just assume every dicom object has the same spacing and orientation
+    let depth: u32 = slices.iter().map(|slice| slice.data.stride.z).sum();
+    let last = slices.last().expect("There is a first slice");
+    let transform = series_transform(&first.placement, &last.placement, depth - last.data.stride.z, first.spacing);
+    let mut data = Buf3D::new(UVec3::new(columns, rows, 0));
+    data.data.reserve((columns * rows * depth) as usize);
+    let mut histogram: Vec<u32> = Vec::new();
+    let mut max: u16 = 0;
+    let mut context: Option<InMemDicomObject> = None;
+    for mut slice in slices {
+        if context.is_none() {
+            context = Some(std::mem::replace(&mut slice.context, InMemDicomObject::new_empty()));
+        }
+        if histogram.len() < slice.histogram.len() {
+            histogram.resize(slice.histogram.len(), 0);
+        }
+        for (total, count) in histogram.iter_mut().zip(&slice.histogram) {
+            *total += count;
+        }
+        max = max.max(slice.max);
+        data.append_depth_slice(&mut slice.data);
+    }
+
+    log_to_console(format!("Grid Resolution: {} {} {}", data.stride.x, data.stride.y, data.stride.z).as_str());
+    Ok(DicomDataInternal {
+        data,
+        transform,
+        histogram,
+        max,
+        context: context.unwrap_or_else(InMemDicomObject::new_empty)
+    })
+}
+
+/// Builds a brick grid from the files of one DICOM series, like [`assemble_dicom_slices`] followed by
+/// [`BrickGrid::construct`], without holding the whole volume. The headers of all files are read first
+/// to sort them, then the files are decoded in volume order, a batch at a time, and pushed into a
+/// [`BrickGridBuilder`] as they are decoded.
+///
+/// Files are requested through `read_file`, once to read their header and once to decode them, so
+/// callers can keep them outside of this memory and only copy the requested ones in. The grid is
+/// laid out as `config` says.
+pub fn stream_dicom_series<'a>(count: usize, read_file: &dyn Fn(usize) -> Cow<'a, [u8]>, config: BrickGridConfig, progress: LoadProgress) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting streamed volume load");
+    let start = now();
+    let batch_size = decode_batch_size();
+
+    let mut headers: Vec<(usize, DicomSliceHeader)> = Vec::with_capacity(count);
+    for batch_start in (0..count).step_by(batch_size) {
+        progress.step(LoadPhase::ReadingHeaders, batch_start, count)?;
+        let batch: Vec<Cow<[u8]>> = (batch_start..(batch_start + batch_size).min(count)).map(read_file).collect();
+        let batch_headers: Vec<Option<DicomSliceHeader>> = batch.par_iter().map(|bytes| read_dicom_slice_header(bytes)).collect();
+        headers.extend(batch_headers.into_iter().enumerate().filter_map(|(offset, header)| header.map(|header| (batch_start + offset, header))));
+    }
+    progress.report(LoadPhase::ReadingHeaders, count, count);
+    if headers.len() < count {
+        log_to_console(&format!("Skipping {} files without an image", count - headers.len()));
+    }
+
+    sort_by_placement(&mut headers, |(_, header)| &header.placement);
+    let (_, first) = headers.first().ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "No slices with pixel data"))?;
+    let (columns, rows) = (first.size.x, first.size.y);
+    if let Some((_, header)) = headers.iter().find(|(_, header)| header.size.x != columns || header.size.y != rows) {
+        return Err(invalid(format!("Slices have different sizes, {}x{} and {}x{}", columns, rows, header.size.x, header.size.y)));
+    }
+    let index_extent = UVec3::new(columns, rows, headers.iter().map(|(_, header)| header.size.z).sum());
+    let (_, last) = headers.last().expect("There is a first header");
+    let (first_placement, last_placement, steps) = (first.placement, last.placement, index_extent.z - last.size.z);
+
+    let mut builder: Option<BrickGridBuilder> = None;
+    let mut histogram: Vec<u32> = Vec::new();
+    let mut max: u16 = 0;
+    for (batch_index, batch) in headers.chunks(batch_size).enumerate() {
+        progress.step(LoadPhase::DecodingFiles, batch_index * batch_size, headers.len())?;
+        let files: Vec<Cow<[u8]>> = batch.iter().map(|(index, _)| read_file(*index)).collect();
+        let slices = decode_dicom_slices(&files)?;
+        drop(files);
+
+        for (slice, (_, header)) in slices.into_iter().zip(batch) {
+            if slice.data.stride != header.size {
+                return Err(invalid(format!("Slice of {} doesn't match its header's size {}", slice.data.stride, header.size)));
+            }
+            // I just assume every dicom object has the same spacing and orientation
+            let builder = builder.get_or_insert_with(|| {
+                BrickGridBuilder::with_config(index_extent, series_transform(&first_placement, &last_placement, steps, slice.spacing), config)
+            });
+            if histogram.len() < slice.histogram.len() {
+                histogram.resize(slice.histogram.len(), 0);
+            }
+            for (total, count) in histogram.iter_mut().zip(&slice.histogram) {
+                *total += count;
+            }
+            max = max.max(slice.max);
+            for frame in slice.data.data.chunks_exact((columns * rows) as usize) {
+                builder.push_slice(frame.iter().map(|sample| *sample as f32).collect())?;
+            }
+        }
+    }
+    progress.report(LoadPhase::DecodingFiles, headers.len(), headers.len());
+    log_to_console(&format!("Grid Resolution: {} {} {}", index_extent.x, index_extent.y, index_extent.z));
+
+    // the same normalization as the lookup of an assembled series, which keeps all-black series at 0
+    let builder = builder.expect("A builder is created for the first slice");
+    let scale = if max == 0 { 0.0 } else { 1.0 / max as f32 };
+    let grid = builder.finish(scale, (0.0, 1.0), histogram, progress)?;
+    let end = now();
+    log_to_console(&format!("Finished streamed loading in {}", end - start));
+    Ok(grid)
+}
+
+struct RecordReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> RecordReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], VolumeReadError> {
+        let bytes = self
+            .offset
+            .checked_add(length)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Slice record is truncated"))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, VolumeReadError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, VolumeReadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, VolumeReadError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn vec3(&mut self) -> Result<Vec3, VolumeReadError> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+impl DicomSlice {
+    /// Encodes the slice as a record, see the layout at the top of this file
+    pub fn to_record(&self) -> Result<Vec<u8>, DicomWriteError> {
+        let sop_class_uid = self
+            .context
+            .get(SOP_CLASS_UID)
+            .and_then(|element| element.to_str().ok())
+            .map(|value| value.trim().to_string())
+            .unwrap_or_else(|| SECONDARY_CAPTURE_IMAGE_STORAGE.to_string());
+        let context = write_file(self.context.clone(), EXPLICIT_VR_LITTLE_ENDIAN, &sop_class_uid, &generate_uid())?;
+
+        let mut bytes = Vec::with_capacity(96 + self.histogram.len() * 4 + self.data.data.len() * 2 + context.len());
+        let put_u32 = |bytes: &mut Vec<u8>, value: u32| bytes.extend_from_slice(&value.to_le_bytes());
+        let put_f32s = |bytes: &mut Vec<u8>, values: &[f32]| values.iter().for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+        bytes.extend_from_slice(RECORD_MAGIC);
+        put_u32(&mut bytes, RECORD_VERSION);
+        self.data.stride.to_array().into_iter().for_each(|value| put_u32(&mut bytes, value));
+        bytes.extend_from_slice(&self.max.to_le_bytes());
+        put_f32s(&mut bytes, &self.spacing.to_array());
+
+        let placement = &self.placement;
+        let mut flags = 0;
+        if placement.position.is_some() { flags |= HAS_POSITION; }
+        if placement.orientation.is_some() { flags |= HAS_ORIENTATION; }
+        if placement.instance_number.is_some() { flags |= HAS_INSTANCE_NUMBER; }
+        put_u32(&mut bytes, flags);
+        put_f32s(&mut bytes, &placement.position.unwrap_or_default().to_array());
+        let [row, column] = placement.orientation.unwrap_or_default();
+        put_f32s(&mut bytes, &[row.to_array(), column.to_array()].concat());
+        bytes.extend_from_slice(&placement.instance_number.unwrap_or_default().to_le_bytes());
+
+        put_u32(&mut bytes, self.histogram.len() as u32);
+        self.histogram.iter().for_each(|count| put_u32(&mut bytes, *count));
+        self.data.data.iter().for_each(|sample| bytes.extend_from_slice(&sample.to_le_bytes()));
+        put_u32(&mut bytes, context.len() as u32);
+        bytes.extend_from_slice(&context);
+        Ok(bytes)
+    }
+
+    /// Decodes a record written by [`DicomSlice::to_record`]
+    pub fn from_record(record: &[u8]) -> Result<Self, VolumeReadError> {
+        let mut reader = RecordReader { bytes: record, offset: 0 };
+        if reader.take(RECORD_MAGIC.len())? != RECORD_MAGIC {
+            return Err(invalid("Not a slice record"));
+        }
+        let version = reader.u32()?;
+        if version != RECORD_VERSION {
+            return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Slice record version {} isn't supported", version)));
+        }
+        let stride = UVec3::new(reader.u32()?, reader.u32()?, reader.u32()?);
+        let max = reader.u16()?;
+        let spacing = reader.vec3()?;
+        let flags = reader.u32()?;
+        let position = reader.vec3()?;
+        let orientation = [reader.vec3()?, reader.vec3()?];
+        let instance_number = reader.u32()? as i32;
+
+        let too_large = || invalid("Slice record sizes are too large");
+        let histogram_length = reader.u32()? as usize;
+        let histogram_size = histogram_length.checked_mul(4).ok_or_else(too_large)?;
+        let histogram = reader.take(histogram_size)?.chunks_exact(4).map(|count| u32::from_le_bytes(count.try_into().unwrap())).collect();
+        let samples_size = (stride.x as usize)
+            .checked_mul(stride.y as usize)
+            .and_then(|samples| samples.checked_mul(stride.z as usize))
+            .and_then(|samples| samples.checked_mul(2))
+            .ok_or_else(too_large)?;
+        let mut data = Buf3D::new(UVec3::ZERO);
+        data.stride = stride;
+        data.data = reader.take(samples_size)?.chunks_exact(2).map(|sample| u16::from_le_bytes(sample.try_into().unwrap())).collect();
+
+        let context_length = reader.u32()? as usize;
+        let context = reader.take(context_length)?;
+        let context = dicom_object::from_reader(context).map_err(|e| invalid(format!("Couldn't read slice context: {}", e)))?;
+
+        Ok(Self {
+            data,
+            histogram,
+            max,
+            spacing,
+            placement: SlicePlacement {
+                position: (flags & HAS_POSITION != 0).then_some(position),
+                orientation: (flags & HAS_ORIENTATION != 0).then_some(orientation),
+                instance_number: (flags & HAS_INSTANCE_NUMBER != 0).then_some(instance_number),
+            },
+            context: extract_context(&context),
+        })
+    }
+}
+
+/// Decodes one DICOM file into a slice record, which can be sent to another worker and assembled
+/// there with [`assemble_dicom_slice_records`]
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn decode_dicom_slice_record(file: &Uint8Array) -> Result<Uint8Array, VolumeReadError> {
+    let record = decode_dicom_slice(&file.to_vec(), false)?
+        .to_record()
+        .map_err(|e| invalid(format!("Couldn't encode slice record: {}", e.message())))?;
+    Ok(Uint8Array::from(record.as_slice()))
+}
+
+/// Sorts and stacks slice records of one series, in any order, into a volume
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn assemble_dicom_slice_records(records: Vec<Uint8Array>) -> Result<DicomResult, VolumeReadError> {
+    let slices = records.iter().map(|record| DicomSlice::from_record(&record.to_vec())).collect::<Result<Vec<_>, _>>()?;
+    Ok(DicomResult { internal: assemble_dicom_slices(slices)? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phantom::{generate_phantom, PhantomOptions, PhantomShape};
+
+    fn slice() -> DicomSlice {
+        let mut options = PhantomOptions::new(PhantomShape::Spheres, 6, 4, 2);
+        options.spacing_z = 2.5;
+        let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        decode_dicom_slice(&files[1].1, false).unwrap_or_else(|e| panic!("{}", e.message()))
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let slice = slice();
+        let record = slice.to_record().unwrap_or_else(|e| panic!("{}", e.message()));
+        let decoded = DicomSlice::from_record(&record).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(decoded.data.stride, slice.data.stride);
+        assert_eq!(decoded.data.data, slice.data.data);
+        assert_eq!((decoded.histogram, decoded.max, decoded.spacing), (slice.histogram, slice.max, slice.spacing));
+        assert_eq!(decoded.placement.position, slice.placement.position);
+        assert_eq!(decoded.placement.orientation, slice.placement.orientation);
+        assert_eq!(decoded.placement.instance_number, slice.placement.instance_number);
+        assert_eq!(decoded.context, slice.context);
+    }
+
+    #[test]
+    fn corrupt_records_are_errors() {
+        let record = slice().to_record().unwrap_or_else(|e| panic!("{}", e.message()));
+        let truncated = DicomSlice::from_record(&record[..record.len() - 1]).err().expect("the context is truncated");
+        assert!(matches!(truncated.0, VolumeReadErrorType::NotEnoughData));
+
+        // a stride whose sample count overflows
+        let mut oversized = record.clone();
+        let stride = RECORD_MAGIC.len() + 4;
+        oversized[stride..stride + 12].fill(0xff);
+        let error = DicomSlice::from_record(&oversized).err().expect("the stride is too large");
+        assert!(matches!(error.0, VolumeReadErrorType::InvalidHeader));
+    }
+}
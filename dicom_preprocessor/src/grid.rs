@@ -1,6 +1,7 @@
-use glam::{Mat4, UVec3};
+use glam::{IVec3, Mat4, UVec3};
 
-pub trait Grid {
+/// Grids are shared between threads while a [`BrickGrid`](crate::brick::BrickGrid) is constructed from them
+pub trait Grid: Sync {
     /// index-space grid lookup
     fn lookup(&self, ipos: UVec3) -> f32;
     /// global minorant and majorant
@@ -17,4 +18,60 @@ pub trait Grid {
     fn histogram_gradient(&self) -> (Vec<i32>, u32, u32);
     /// transform of the grid
     fn transform(&self) -> Mat4;
+    /// Value shared by all voxels from `min` up to `max`, exclusive, if the grid knows without
+    /// looking them up. Sparse grids skip their empty space this way while they are bricked.
+    /// The bounds hold the voxels a brick's range is dilated by, so they may lie outside the index extent.
+    fn uniform_value(&self, _min: IVec3, _max: IVec3) -> Option<f32> {
+        None
+    }
+}
+
+/// Computes the discretized gradient of a histogram, smoothed for display, as returned by
+/// [`Grid::histogram_gradient`]. An empty histogram has an empty gradient.
+pub fn compute_histogram_gradient(histogram: &[u32]) -> (Vec<i32>, u32, u32) {
+    if histogram.is_empty() {
+        return (Vec::new(), 0, 0);
+    }
+    let mut gradient: Vec<i32> = Vec::with_capacity(histogram.len());
+    let mut last: u32 = 0;
+    let mut gradmin: u32 = u32::MAX;
+    let mut gradmax: u32 = u32::MIN;
+    for histogram_step in histogram {
+        let gradient_step: i32 = *histogram_step as i32 - last as i32;
+        let abs_step = gradient_step.abs_diff(0);
+        if abs_step > gradmax {
+            gradmax = abs_step;
+        }
+        if abs_step < gradmin {
+            gradmin = abs_step;
+        }
+        gradient.push(gradient_step);
+        last = *histogram_step;
+    }
+
+    // smoothes the gradient a bit for nicer display
+    let mut smoothed: Vec<i32> = Vec::with_capacity(gradient.len());
+    smoothed.push(gradient[0]);
+    for i in 1..(gradient.len() - 1) {
+        let avg = gradient[i - 1] + gradient[i] + gradient[i + 1];
+        smoothed.push(avg / 3);
+    }
+    if gradient.len() > 1 {
+        smoothed.push(gradient[gradient.len() - 1]);
+    }
+    (smoothed, gradmin, gradmax)
+}
+
+/// Looks up every voxel of a grid, in x-fastest order
+pub fn sample_grid(grid: &dyn Grid) -> Vec<f32> {
+    let extent = grid.index_extent();
+    let mut values = Vec::with_capacity((extent.x * extent.y * extent.z) as usize);
+    for z in 0..extent.z {
+        for y in 0..extent.y {
+            for x in 0..extent.x {
+                values.push(grid.lookup(UVec3::new(x, y, z)));
+            }
+        }
+    }
+    values
 }
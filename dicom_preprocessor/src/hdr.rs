@@ -1,29 +1,25 @@
+#[cfg(feature = "wasm")]
 use js_sys::{Float32Array, Uint8Array};
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::wasm_bindgen;
+#[cfg(feature = "wasm")]
 use wasm_bindgen::JsValue;
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct ExrImage {
     data: Vec<f32>,
     width: u32,
     height: u32
 }
 
-#[wasm_bindgen]
 impl ExrImage {
-    #[wasm_bindgen(getter)]
-    pub fn width(&self) -> u32 { self.width }
-    #[wasm_bindgen(getter)]
-    pub fn height(&self) -> u32 { self.height }
-
-    pub fn data(&self) -> Float32Array {
-        Float32Array::from(self.data.as_slice())
+    /// RGBA samples, row by row
+    pub fn pixels(&self) -> &[f32] {
+        &self.data
     }
 
-    pub fn decode_from_bytes(bytes: &Uint8Array) -> Result<ExrImage, JsValue> {
-        let vec = bytes.to_vec();
-
-        let image = image::load_from_memory(&vec).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    pub fn decode(bytes: &[u8]) -> Result<ExrImage, image::ImageError> {
+        let image = image::load_from_memory(bytes)?;
         let width = image.width();
         let height = image.height();
         let floats = image.into_rgba32f().into_raw();
@@ -34,4 +30,24 @@ impl ExrImage {
             width
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl ExrImage {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn width(&self) -> u32 { self.width }
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn height(&self) -> u32 { self.height }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl ExrImage {
+    pub fn data(&self) -> Float32Array {
+        Float32Array::from(self.data.as_slice())
+    }
+
+    pub fn decode_from_bytes(bytes: &Uint8Array) -> Result<ExrImage, JsValue> {
+        ExrImage::decode(&bytes.to_vec()).map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+}
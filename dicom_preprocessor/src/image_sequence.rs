@@ -0,0 +1,123 @@
+use crate::buf3d::Buf3D;
+use crate::utils::natural_cmp;
+use crate::volume::{DenseGrid, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+use image::DynamicImage;
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Which value of a color slice is used as the density
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SliceChannel {
+    /// luminance of the color, grayscale slices are used as is
+    Luminance,
+    Red,
+    Green,
+    Blue,
+    Alpha,
+}
+
+fn is_16bit(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.bytes_per_pixel() / color.channel_count() >= 2
+}
+
+fn slice_values(image: &DynamicImage, channel: SliceChannel) -> Vec<f32> {
+    let channel_index = match channel {
+        SliceChannel::Luminance => {
+            return if is_16bit(image) {
+                image.to_luma16().into_raw().into_iter().map(|v| v as f32).collect()
+            } else {
+                image.to_luma8().into_raw().into_iter().map(|v| v as f32).collect()
+            };
+        }
+        SliceChannel::Red => 0,
+        SliceChannel::Green => 1,
+        SliceChannel::Blue => 2,
+        SliceChannel::Alpha => 3,
+    };
+    if is_16bit(image) {
+        image.to_rgba16().pixels().map(|pixel| pixel.0[channel_index] as f32).collect()
+    } else {
+        image.to_rgba8().pixels().map(|pixel| pixel.0[channel_index] as f32).collect()
+    }
+}
+
+/// Stacks PNG or JPEG slices in natural file name order into a volume with the given voxel spacing
+pub fn parse_image_sequence(mut files: Vec<(String, Vec<u8>)>, spacing: Vec3, channel: SliceChannel) -> Result<DenseGrid, VolumeReadError> {
+    files.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+
+    let mut extent: Option<UVec3> = None;
+    let mut samples = Vec::new();
+    for (name, bytes) in &files {
+        let image = image::load_from_memory(bytes)
+            .map_err(|e| VolumeReadError::new(VolumeReadErrorType::InvalidHeader, format!("Couldn't decode \"{}\": {}", name, e)))?;
+        match &mut extent {
+            None => extent = Some(UVec3::new(image.width(), image.height(), 1)),
+            Some(extent) if extent.x == image.width() && extent.y == image.height() => extent.z += 1,
+            Some(extent) => {
+                return Err(VolumeReadError::new(
+                    VolumeReadErrorType::InvalidHeader,
+                    format!("Slice \"{}\" is {}x{}, expected {}x{}", name, image.width(), image.height(), extent.x, extent.y),
+                ))
+            }
+        }
+        samples.append(&mut slice_values(&image, channel));
+    }
+
+    let extent = extent.ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "No slices were provided"))?;
+    let mut data = Buf3D::new(extent);
+    data.data = samples;
+    Ok(DenseGrid::new(data, Mat4::from_scale(spacing)))
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_image_sequence(
+    file_names: Vec<String>,
+    files: Vec<Uint8Array>,
+    spacing_x: f32,
+    spacing_y: f32,
+    spacing_z: f32,
+    channel: SliceChannel,
+) -> Result<DenseGrid, VolumeReadError> {
+    let files = file_names.into_iter().zip(files.iter().map(|file| file.to_vec())).collect();
+    parse_image_sequence(files, Vec3::new(spacing_x, spacing_y, spacing_z), channel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    /// a 2x1 PNG whose pixels have the given green values
+    fn png(green: [u8; 2]) -> Vec<u8> {
+        let image = RgbImage::from_fn(2, 1, |x, _| image::Rgb([255, green[x as usize], 0]));
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn slices_are_stacked_in_natural_file_order() {
+        let files = vec![("slice10.png".to_string(), png([80, 100])), ("slice9.png".to_string(), png([0, 20]))];
+        let volume = parse_image_sequence(files, Vec3::new(1.0, 1.0, 2.5), SliceChannel::Green).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(2, 1, 2));
+        assert_eq!(volume.transform(), Mat4::from_scale(Vec3::new(1.0, 1.0, 2.5)));
+        let values: Vec<f32> = [(0, 0), (1, 0), (0, 1), (1, 1)].iter().map(|(x, z)| volume.lookup(UVec3::new(*x, 0, *z))).collect();
+        assert_eq!(values, [0.0, 0.2, 0.8, 1.0]);
+    }
+
+    #[test]
+    fn mismatched_slices_are_rejected() {
+        let mut small = Cursor::new(Vec::new());
+        RgbImage::new(1, 1).write_to(&mut small, ImageFormat::Png).unwrap();
+        let files = vec![("a.png".to_string(), png([0, 0])), ("b.png".to_string(), small.into_inner())];
+        assert!(matches!(parse_image_sequence(files, Vec3::ONE, SliceChannel::Luminance).err().map(|e| e.0), Some(VolumeReadErrorType::InvalidHeader)));
+    }
+}
@@ -1,33 +1,75 @@
-mod utils;
-mod brick;
-mod buf3d;
-mod dicom;
-mod grid;
-mod zip;
-mod hdr;
-
-use js_sys::Date;
+pub mod utils;
+pub mod brick;
+pub mod brick_builder;
+pub mod brick_config;
+pub mod compression;
+pub mod buf3d;
+pub mod dicom;
+pub mod grid;
+pub mod zip;
+pub mod hdr;
+pub mod volume;
+pub mod nrrd;
+pub mod metaimage;
+pub mod raw;
+pub mod blosc;
+pub mod vdb;
+pub mod tiff_stack;
+pub mod image_sequence;
+pub mod mrc;
+pub mod vtk;
+pub mod zarr;
+pub mod mitsuba;
+pub mod pbrt;
+pub mod nifti;
+pub mod volxel;
+pub mod dicom_export;
+pub mod dicom_slice;
+pub mod phantom;
+pub mod progress;
+
 use dicom_core::Tag;
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
 use crate::brick::BrickGrid;
+use crate::dicom_slice::{assemble_dicom_slices, decode_batch_size, decode_dicom_slice, decode_dicom_slices};
+#[cfg(feature = "wasm")]
+use crate::dicom_export::DicomWriteError;
 use crate::buf3d::Buf3D;
-use crate::utils::{debug_print_tags, log_to_console};
-use dicom_pixeldata::{PixelDecoder, PixelRepresentation};
-use glam::{Mat4, UVec3, Vec3};
-use js_sys::Uint8Array;
-
-#[wasm_bindgen]
+use crate::progress::{LoadPhase, LoadProgress};
+#[cfg(feature = "wasm")]
+use crate::progress::{js_progress_callback, CancellationToken};
+use crate::utils::{log_to_console, natural_cmp, now};
+use dicom_object::InMemDicomObject;
+use glam::Mat4;
+use crate::brick_config::BrickGridConfig;
+#[cfg(feature = "wasm")]
+use crate::compression::CompressionMetrics;
+#[cfg(feature = "wasm")]
+use crate::dicom_slice::stream_dicom_series;
+use crate::volume::VolumeReadError;
+#[cfg(feature = "wasm")]
+use js_sys::{Function, Uint8Array};
+#[cfg(feature = "wasm")]
+use std::borrow::Cow;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub fn init() {
     utils::set_panic_hook();
 }
 
+// exported to JS as `initThreadPool(threads)`, which has to be awaited before anything runs in parallel
+#[cfg(feature = "wasm-threads")]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
 pub struct DicomDataInternal {
     data: Buf3D<u16>,
     histogram: Vec<u32>,
-    min: u16,
     max: u16,
-    transform: Mat4
+    transform: Mat4,
+    /// patient and study attributes of the source, carried over into exported series
+    context: InMemDicomObject
 }
 
 // relevant tags
@@ -35,6 +77,8 @@ pub struct DicomDataInternal {
 // const REFERENCED_IMAGE_SEQUENCE: Tag = Tag(0x0008, 0x1140);
 const PIXEL_SPACING: Tag = Tag(0x0028, 0x0030);
 const SLICE_THICKNESS: Tag = Tag(0x0018, 0x0050);
+const SERIES_INSTANCE_UID: Tag = Tag(0x0020, 0x000E);
+const SERIES_DESCRIPTION: Tag = Tag(0x0008, 0x103E);
 
 const FLOAT_PIXEL_DATA: Tag = Tag(0x7fe0, 0x0008);
 const DOUBLE_FLOAT_PIXEL_DATA: Tag = Tag(0x7fe0, 0x0009);
@@ -44,159 +88,157 @@ const PIXEL_DATA: Tag = Tag(0x7fe0, 0x0010);
 const DICOMDIR_IMAGE_SEQUENCE: Tag = Tag(0x0004, 0x1220);
 const DICOMDIR_IMAGE_REFERENCE: Tag = Tag(0x0004, 0x1500);
 
-fn read_dicom(bytes: Uint8Array, debug_print: bool) -> DicomDataInternal {
-    let result_obj = dicom_object::from_reader(bytes.to_vec().as_slice()).unwrap();
-    let sequence = result_obj.get(DICOMDIR_IMAGE_SEQUENCE);
-
-    if let Some(Some(sequence)) = sequence.map(|seq| seq.items()) {
-        for item in sequence {
-            let image_reference = item.get(DICOMDIR_IMAGE_REFERENCE);
-            if let Some(image_reference) = image_reference {
-                let reference = image_reference
-                    .strings()
-                    .expect("Image Reference should be Strs");
-                log_to_console(&format!("Reference: {}", reference.join("/")));
-            } else {
-                log_to_console("No Image Reference, printing debug");
-                log_to_console(debug_print_tags(item, 0).as_str());
-            }
-        }
-
-        return DicomDataInternal {
-            data: Buf3D::empty(),
-            histogram: vec![],
-            min: 0,
-            max: u16::MAX,
-            transform: Mat4::IDENTITY
-        };
-    }
+pub fn read_dicom(bytes: &[u8], debug_print: bool) -> Result<DicomDataInternal, VolumeReadError> {
+    Ok(decode_dicom_slice(bytes, debug_print)?.into())
+}
 
-    // the result object does not contain an image sequence, so we assume it is an image
-    let pixel_data = result_obj.decode_pixel_data().unwrap();
+/// Loads the files of one DICOM series, sorted by their position, see [`assemble_dicom_slices`]
+pub fn read_dicom_series<B: AsRef<[u8]> + Sync>(all_bytes: impl IntoIterator<Item = B, IntoIter: ExactSizeIterator>) -> Result<DicomDataInternal, VolumeReadError> {
+    read_dicom_series_with_progress(all_bytes, LoadProgress::none())
+}
 
-    if pixel_data.samples_per_pixel() != 1 {
-        panic!("More than one sample per pixel not currently supported")
-    }
-    if pixel_data.bits_allocated() != 16 {
-        panic!("Currently only 16bit samples are supported")
-    }
-    if pixel_data.pixel_representation() != PixelRepresentation::Unsigned {
-        panic!("Currently only unsigned samples are supported")
+/// Like [`read_dicom_series`], reporting decoded files and checking for cancellation in between.
+/// Files are decoded in parallel, a batch at a time, so only a batch of them is needed at once.
+pub fn read_dicom_series_with_progress<B: AsRef<[u8]> + Sync>(
+    all_bytes: impl IntoIterator<Item = B, IntoIter: ExactSizeIterator>,
+    progress: LoadProgress,
+) -> Result<DicomDataInternal, VolumeReadError> {
+    log_to_console("Starting volume load");
+    let start = now();
+    let mut all_bytes = all_bytes.into_iter();
+    let total = all_bytes.len();
+    let mut slices = Vec::with_capacity(total);
+    loop {
+        progress.step(LoadPhase::DecodingFiles, slices.len(), total)?;
+        let batch: Vec<B> = all_bytes.by_ref().take(decode_batch_size()).collect();
+        if batch.is_empty() {
+            break;
+        }
+        slices.append(&mut decode_dicom_slices(&batch)?);
     }
+    let end = now();
+    let elapsed = end - start;
+    log_to_console(&format!("Finished loading in {}", elapsed));
 
-    let max_density = 2usize.pow(pixel_data.bits_stored() as u32);
+    assemble_dicom_slices(slices)
+}
 
-    let mut histogram: Vec<u32> = vec![0; max_density];
+/// The files of one series, out of a set of DICOM files
+pub struct DicomSeries {
+    pub series_instance_uid: String,
+    pub description: String,
+    /// named files in natural order of their names
+    pub files: Vec<(String, Vec<u8>)>,
+}
 
-    let shorts = bytemuck::cast_slice::<u8, u16>(pixel_data.data());
-    let mut min_sample = u16::MAX;
-    let mut max_sample = u16::MIN;
-    for short in shorts {
-        histogram[*short as usize] += 1;
-        if *short < min_sample {
-            min_sample = *short;
-        }
-        if *short > max_sample {
-            max_sample = *short;
+/// Groups DICOM files by their series, in the order the series first appear.
+/// Files that can't be parsed or don't belong to a series, like a DICOMDIR, are skipped.
+pub fn split_dicom_series(mut files: Vec<(String, Vec<u8>)>) -> Vec<DicomSeries> {
+    files.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+    let mut series: Vec<DicomSeries> = Vec::new();
+    for (name, bytes) in files {
+        let Ok(object) = dicom_object::from_reader(bytes.as_slice()) else {
+            log_to_console(&format!("Skipping {}, it isn't a DICOM file", name));
+            continue;
+        };
+        let string = |tag: Tag| object.get(tag).and_then(|element| element.to_str().ok()).map(|value| value.trim().to_string());
+        let Some(series_instance_uid) = string(SERIES_INSTANCE_UID) else {
+            log_to_console(&format!("Skipping {}, it has no series instance UID", name));
+            continue;
+        };
+        match series.iter_mut().find(|series| series.series_instance_uid == series_instance_uid) {
+            Some(series) => series.files.push((name, bytes)),
+            None => series.push(DicomSeries {
+                series_instance_uid,
+                description: string(SERIES_DESCRIPTION).unwrap_or_default(),
+                files: vec![(name, bytes)],
+            }),
         }
     }
-    let collected_data = Vec::from(shorts);
-
-    let pixel_spacing = result_obj
-        .get(PIXEL_SPACING)
-        .expect("Image did not contain pixel spacing information");
-    let [x, y] = pixel_spacing
-        .strings()
-        .expect("Pixel Spacing was not a String sequence")
-    else {
-        panic!("Pixel spacing did not contain two values x and y")
-    };
-    let pixel_sizing_x: f32 = x.trim().parse().expect("Couldn't parse x spacing to float");
-    let pixel_sizing_y: f32 = y.trim().parse().expect("Couldn't parse y spacing to float");
-
-    let slice_thickness = result_obj.get(SLICE_THICKNESS).map(|obj| {
-        obj.strings()
-            .expect("Slice thickness was not a string sequence")
-            .get(0)
-            .expect("Slice thickness didn't contain anything")
-            .trim()
-            .parse::<f32>().expect("Couldn't parse slice thickness to float")
-    }).unwrap_or(pixel_sizing_x.min(pixel_sizing_y));
-
-    if debug_print {
-        log_to_console(&format!("Pixel Spacing: x={}, y={}, z={}", pixel_sizing_x, pixel_sizing_y, slice_thickness));
-    }
+    series
+}
 
-    let mut data = Buf3D::new(UVec3::new(pixel_data.columns(), pixel_data.rows(), pixel_data.number_of_frames()));
-    data.data = collected_data;
+/// A loaded DICOM series, which can be exported or turned into a brick grid
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct DicomResult {
+    internal: DicomDataInternal
+}
 
-    DicomDataInternal {
-        data,
-        histogram,
-        min: min_sample,
-        max: max_sample,
-        transform: Mat4::from_scale(Vec3::new(pixel_sizing_x, pixel_sizing_y, slice_thickness))
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl DicomResult {
+    pub fn to_nifti(&self) -> Result<Uint8Array, DicomWriteError> {
+        Ok(Uint8Array::from(self.internal.to_nifti_gz()?.as_slice()))
     }
-}
 
-fn read_dicoms_internal(all_bytes: Vec<Uint8Array>) -> DicomDataInternal {
-    log_to_console("Starting volume load");
-    let start = Date::now();
-    let mut result: Option<Buf3D<u16>> = None;
-    let mut transform: Mat4 = Mat4::IDENTITY;
-    let mut histogram: Vec<u32> = Vec::new();
-    let mut min: u16 = u16::MAX;
-    let mut max: u16 = 0;
-    for bytes in all_bytes {
-        let mut dicom = read_dicom(bytes, false);
-
-        // I just assume every dicom object has the same transform
-        transform = dicom.transform;
-
-        if histogram.is_empty() {
-            histogram.append(&mut dicom.histogram)
-        } else {
-            for i in 0..histogram.len() {
-                histogram[i] = histogram[i] + dicom.histogram[i];
-            }
-        }
-        
-        if dicom.min < min {
-            min = dicom.min
-        }
-        if dicom.max > max {
-            max = dicom.max
-        }
+    pub fn to_nrrd(&self) -> Uint8Array {
+        Uint8Array::from(self.internal.to_nrrd().as_slice())
+    }
 
-        if let Some(result) = &mut result {
-            result.append_depth_slice(&mut dicom.data)
-        } else {
-            result = Some(dicom.data)
-        }
+    pub fn to_dicom_series(&self, series_description: String) -> Result<Uint8Array, DicomWriteError> {
+        Ok(Uint8Array::from(self.internal.to_dicom_series(&series_description)?.as_slice()))
     }
-    let end = Date::now();
-    let elapsed = end - start;
-    log_to_console(&format!("Finished loading in {}", elapsed));
-    
-    let data = result.expect("No dicom data collected");
-
-    log_to_console(format!("Grid Resolution: {} {} {}", data.stride.x, data.stride.y, data.stride.z).as_str());
-    DicomDataInternal {
-        data,
-        transform,
-        histogram,
-        min,
-        max
+
+    /// Bricks the series as `config` says and measures how far the brick grid is off from it
+    pub fn measure_compression(&self, config: &BrickGridConfig) -> Result<CompressionMetrics, VolumeReadError> {
+        let grid = BrickGrid::construct_with_config(&self.internal, *config, LoadProgress::none())?;
+        Ok(CompressionMetrics::measure(&self.internal, &grid))
     }
 }
 
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_dicoms(all_bytes: Vec<Uint8Array>) -> Result<DicomResult, VolumeReadError> {
+    Ok(DicomResult { internal: read_dicom_series(all_bytes.iter().map(Uint8Array::to_vec))? })
+}
+
+/// Bricks a loaded series, laid out as `config` says
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn dicoms_to_grid(dicoms: DicomResult, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting brick grid construction");
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&dicoms.internal, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+/// Loads the files of one series and bricks it, laid out as `config` says
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_dicoms_to_grid(all_bytes: Vec<Uint8Array>, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    let dicom = read_dicom_series(all_bytes.iter().map(Uint8Array::to_vec))?;
+    log_to_console("Starting brick grid construction");
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&dicom, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+/// Like [`read_dicoms_to_grid`], calling `on_progress(phase, done, total)` while loading and
+/// stopping with an error once `token` is cancelled
+#[cfg(feature = "wasm")]
 #[wasm_bindgen]
-pub fn read_dicoms_to_grid(all_bytes: Vec<Uint8Array>) -> BrickGrid {
-    let dicom = read_dicoms_internal(all_bytes);
+pub fn read_dicoms_to_grid_with_progress(all_bytes: Vec<Uint8Array>, config: &BrickGridConfig, on_progress: &Function, token: &CancellationToken) -> Result<BrickGrid, VolumeReadError> {
+    let callback = js_progress_callback(on_progress);
+    let progress = LoadProgress::none().with_callback(&callback).with_token(token);
+    let dicom = read_dicom_series_with_progress(all_bytes.iter().map(Uint8Array::to_vec), progress)?;
     log_to_console("Starting brick grid construction");
-    let start = Date::now();
-    let grid = BrickGrid::construct(&dicom);
-    let end = Date::now();
-    log_to_console(&format!("Brick grid construction took {}", end - start).as_str());
-    grid
-}
\ No newline at end of file
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&dicom, *config, progress)?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+/// Like [`read_dicoms_to_grid_with_progress`], but bricks the series while decoding it, see
+/// [`dicom_slice::stream_dicom_series`]. Files are only copied into wasm memory while they are read,
+/// and the decoded volume is never held as a whole. The grid is laid out as `config` says.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn stream_dicoms_to_grid(all_bytes: Vec<Uint8Array>, config: &BrickGridConfig, on_progress: &Function, token: &CancellationToken) -> Result<BrickGrid, VolumeReadError> {
+    let callback = js_progress_callback(on_progress);
+    let progress = LoadProgress::none().with_callback(&callback).with_token(token);
+    stream_dicom_series(all_bytes.len(), &|index| Cow::Owned(all_bytes[index].to_vec()), *config, progress)
+}
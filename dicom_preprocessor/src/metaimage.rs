@@ -0,0 +1,330 @@
+use crate::buf3d::Buf3D;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, NamedFiles, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use std::collections::HashMap;
+use std::io::Read;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// MetaImage format description: https://itk.org/Wiki/ITK/MetaIO/Documentation
+
+struct MetaHeader {
+    fields: HashMap<String, String>,
+    /// file names following an `ElementDataFile = LIST` field
+    list: Vec<String>,
+    /// offset of the local data in `.mha` files
+    data_offset: usize,
+}
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+fn parse_header(bytes: &[u8]) -> Result<MetaHeader, VolumeReadError> {
+    let mut fields = HashMap::new();
+    let mut list = Vec::new();
+    let mut offset = 0;
+    let mut in_list = false;
+    while offset < bytes.len() {
+        let line_end = bytes[offset..].iter().position(|b| *b == b'\n').map_or(bytes.len(), |pos| offset + pos);
+        let line = String::from_utf8_lossy(&bytes[offset..line_end]);
+        let line = line.trim();
+        offset = (line_end + 1).min(bytes.len());
+
+        if in_list {
+            if !line.is_empty() {
+                list.push(line.to_string());
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(invalid(format!("Malformed header line \"{}\"", line)));
+        };
+        let key = key.trim().to_string();
+        let value = value.trim().to_string();
+
+        // ElementDataFile is always the last field of the header
+        if key == "ElementDataFile" {
+            let is_list = value.split_whitespace().next() == Some("LIST");
+            fields.insert(key, value);
+            if is_list {
+                in_list = true;
+                continue;
+            }
+            break;
+        }
+        fields.insert(key, value);
+    }
+
+    if !fields.contains_key("ElementDataFile") {
+        return Err(invalid("Missing required field \"ElementDataFile\""));
+    }
+
+    Ok(MetaHeader { fields, list, data_offset: offset })
+}
+
+fn parse_element_type(value: &str) -> Result<SampleType, VolumeReadError> {
+    Ok(match value {
+        "MET_UCHAR" => SampleType::U8,
+        "MET_CHAR" => SampleType::I8,
+        "MET_USHORT" => SampleType::U16,
+        "MET_SHORT" => SampleType::I16,
+        "MET_UINT" | "MET_ULONG" => SampleType::U32,
+        "MET_INT" | "MET_LONG" => SampleType::I32,
+        "MET_ULONG_LONG" => SampleType::U64,
+        "MET_LONG_LONG" => SampleType::I64,
+        "MET_FLOAT" => SampleType::F32,
+        "MET_DOUBLE" => SampleType::F64,
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported element type \"{}\"", value))),
+    })
+}
+
+fn parse_floats(value: &str) -> Result<Vec<f32>, VolumeReadError> {
+    value
+        .split_whitespace()
+        .map(|component| component.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid(format!("Couldn't parse \"{}\" as numbers", value)))
+}
+
+fn parse_bool(value: &str) -> bool {
+    value.eq_ignore_ascii_case("true") || value == "1"
+}
+
+/// Resolves the element data file field into the names of all data files
+fn expand_data_files(header: &MetaHeader, value: &str) -> Result<Vec<String>, VolumeReadError> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.first() == Some(&"LIST") {
+        return Ok(header.list.clone());
+    }
+    if parts.len() >= 3 && parts[0].contains('%') {
+        // printf style pattern: "<format> <min> <max> [<step>]"
+        let parse = |index: usize| parts[index].parse::<i64>().map_err(|_| invalid(format!("Invalid data file pattern \"{}\"", value)));
+        let (min, max) = (parse(1)?, parse(2)?);
+        let step = if parts.len() > 3 { parse(3)? } else { 1 };
+        if step <= 0 {
+            return Err(invalid("Data file pattern step must be positive"));
+        }
+        let start = parts[0].find('%').unwrap();
+        let rest = &parts[0][start + 1..];
+        let conversion = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(|| invalid(format!("Invalid data file pattern \"{}\"", value)))?;
+        let width: usize = rest[..conversion].parse().unwrap_or(0);
+        let mut files = Vec::new();
+        let mut i = min;
+        while i <= max {
+            files.push(format!("{}{:0width$}{}", &parts[0][..start], i, &rest[conversion + 1..], width = width));
+            i += step;
+        }
+        return Ok(files);
+    }
+    Ok(vec![value.to_string()])
+}
+
+fn read_data(bytes: &[u8], compressed: bool, header_size: i64, needed: usize) -> Result<Vec<u8>, VolumeReadError> {
+    if compressed {
+        let mut decompressed = Vec::new();
+        flate2::read::ZlibDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+        return Ok(decompressed);
+    }
+    let skip = if header_size < 0 {
+        // -1 means the data is located at the very end of the file
+        bytes.len().saturating_sub(needed)
+    } else {
+        header_size as usize
+    };
+    if skip > bytes.len() {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Header size exceeds the data length"));
+    }
+    Ok(bytes[skip..].to_vec())
+}
+
+/// Parses a MetaImage volume. `bytes` is either a `.mha` file with local data or a `.mhd` header,
+/// in which case the referenced `.raw`/`.zraw` files are looked up in `files`.
+pub fn parse_metaimage(bytes: &[u8], files: &NamedFiles) -> Result<DenseGrid, VolumeReadError> {
+    let header = parse_header(bytes)?;
+    let field = |name: &str| header.fields.get(name).map(|value| value.as_str());
+    let required = |name: &str| field(name).ok_or_else(|| invalid(format!("Missing required field \"{}\"", name)));
+
+    if let Some(object_type) = field("ObjectType") && object_type != "Image" {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported object type \"{}\"", object_type)));
+    }
+    if field("ElementNumberOfChannels").is_some_and(|channels| channels != "1") {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "Only single channel images are supported"));
+    }
+
+    let dimensions: usize = required("NDims")?.parse().map_err(|_| invalid("Couldn't parse NDims"))?;
+    let sizes = required("DimSize")?
+        .split_whitespace()
+        .map(|size| size.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid("Couldn't parse DimSize"))?;
+    if sizes.len() != dimensions {
+        return Err(invalid("Number of sizes does not match NDims"));
+    }
+    let extent = match sizes.as_slice() {
+        [x, y, z] => UVec3::new(*x, *y, *z),
+        [x, y] => UVec3::new(*x, *y, 1),
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Only 2D and 3D images are supported, got {} dimensions", dimensions))),
+    };
+
+    let sample_type = parse_element_type(required("ElementType")?)?;
+    let endianness = match field("BinaryDataByteOrderMSB").or(field("ElementByteOrderMSB")) {
+        Some(msb) if parse_bool(msb) => Endianness::Big,
+        _ => Endianness::Little,
+    };
+    let compressed = field("CompressedData").is_some_and(parse_bool);
+    let header_size: i64 = field("HeaderSize").map_or(Ok(0), str::parse).map_err(|_| invalid("Couldn't parse HeaderSize"))?;
+
+    let count = voxel_count(extent)?;
+    let size = count
+        .checked_mul(sample_type.size())
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("An image of {} voxels is too large", extent)))?;
+    let data_file = required("ElementDataFile")?;
+    let data = if data_file == "LOCAL" {
+        read_data(&bytes[header.data_offset..], compressed, header_size, size)?
+    } else {
+        let data_files = expand_data_files(&header, data_file)?;
+        if data_files.is_empty() {
+            return Err(invalid("No data files listed"));
+        }
+        let per_file = size / data_files.len();
+        let mut data = Vec::new();
+        for name in &data_files {
+            let file = files.get(name).ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::MissingDataFile, format!("Data file \"{}\" was not provided", name)))?;
+            data.append(&mut read_data(file, compressed, header_size, per_file)?);
+        }
+        data
+    };
+    let samples = decode_samples(&data, sample_type, endianness, count)?;
+
+    // ElementSpacing takes precedence over ElementSize, which is the physical size of a voxel
+    let spacing = match field("ElementSpacing").or(field("ElementSize")) {
+        Some(spacing) => parse_floats(spacing)?,
+        None => vec![1.0; dimensions],
+    };
+    let spacing = Vec3::new(
+        spacing.first().copied().unwrap_or(1.0),
+        spacing.get(1).copied().unwrap_or(1.0),
+        spacing.get(2).copied().unwrap_or(1.0),
+    );
+
+    let origin = match field("Offset").or(field("Origin")).or(field("Position")) {
+        Some(offset) => parse_floats(offset)?,
+        None => vec![0.0; dimensions],
+    };
+    let origin = Vec3::new(origin.first().copied().unwrap_or(0.0), origin.get(1).copied().unwrap_or(0.0), origin.get(2).copied().unwrap_or(0.0));
+
+    // every consecutive group of NDims values is the direction of one axis
+    let mut axes = [Vec3::X, Vec3::Y, Vec3::Z];
+    if let Some(matrix) = field("TransformMatrix").or(field("Rotation")).or(field("Orientation")) {
+        let matrix = parse_floats(matrix)?;
+        if matrix.len() != dimensions * dimensions {
+            return Err(invalid("TransformMatrix does not have NDims * NDims entries"));
+        }
+        for (i, axis) in axes.iter_mut().take(dimensions).enumerate() {
+            let column = &matrix[i * dimensions..(i + 1) * dimensions];
+            *axis = Vec3::new(column[0], column[1], column.get(2).copied().unwrap_or(0.0));
+        }
+    }
+
+    // MetaImage coordinates are in the same LPS space as DICOM
+    let transform = Mat4::from_cols(
+        (axes[0] * spacing.x).extend(0.0),
+        (axes[1] * spacing.y).extend(0.0),
+        (axes[2] * spacing.z).extend(0.0),
+        origin.extend(1.0),
+    );
+
+    let mut grid = Buf3D::new(extent);
+    grid.data = samples;
+    Ok(DenseGrid::new(grid, transform))
+}
+
+/// Finds the MetaImage header among the given files and parses it
+pub fn parse_metaimage_files(files: &NamedFiles) -> Result<DenseGrid, VolumeReadError> {
+    let header_name = files
+        .names()
+        .find(|name| {
+            let name = name.to_lowercase();
+            name.ends_with(".mha") || name.ends_with(".mhd")
+        })
+        .map(str::to_string)
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::MissingDataFile, "No .mha or .mhd file was provided"))?;
+    parse_metaimage(files.get(&header_name).unwrap(), files)
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_metaimage(file_names: Vec<String>, files: Vec<Uint8Array>) -> Result<DenseGrid, VolumeReadError> {
+    let files = NamedFiles::new(file_names, files.iter().map(|file| file.to_vec()).collect());
+    parse_metaimage_files(&files)
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_metaimage_zip(zip: Uint8Array) -> Result<DenseGrid, VolumeReadError> {
+    let files = NamedFiles::from_zip(zip.to_vec())?;
+    parse_metaimage_files(&files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use glam::Vec4;
+    use std::io::Write;
+
+    const HEADER: &str = "ObjectType = Image\nNDims = 3\nDimSize = 2 2 2\nElementType = MET_UCHAR\nElementSpacing = 1 2 3\nOffset = -10 20 30\n";
+
+    fn no_files() -> NamedFiles {
+        NamedFiles::new(Vec::new(), Vec::new())
+    }
+
+    fn assert_ramp(volume: &DenseGrid) {
+        assert_eq!(volume.index_extent(), UVec3::splat(2));
+        for i in 0..8u32 {
+            let value = volume.lookup(UVec3::new(i % 2, i / 2 % 2, i / 4));
+            assert!((value - i as f32 / 7.0).abs() < 1e-6, "voxel {} is {}", i, value);
+        }
+        let transform = Mat4::from_cols(Vec4::X, Vec4::Y * 2.0, Vec4::Z * 3.0, Vec4::new(-10.0, 20.0, 30.0, 1.0));
+        assert_eq!(volume.transform(), transform);
+    }
+
+    #[test]
+    fn local_data_follows_the_header() {
+        let mut file = format!("{}ElementDataFile = LOCAL\n", HEADER).into_bytes();
+        file.extend(0..8u8);
+        assert_ramp(&parse_metaimage(&file, &no_files()).unwrap_or_else(|e| panic!("{}", e.message())));
+    }
+
+    #[test]
+    fn detached_data_is_found_next_to_the_header() {
+        let header = format!("{}ElementDataFile = ramp.raw\n", HEADER).into_bytes();
+        let files = NamedFiles::new(vec!["ramp.mhd".to_string(), "ramp.raw".to_string()], vec![header, (0..8u8).collect()]);
+        assert_ramp(&parse_metaimage_files(&files).unwrap_or_else(|e| panic!("{}", e.message())));
+    }
+
+    #[test]
+    fn compressed_data_is_inflated() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&(0..8u8).collect::<Vec<_>>()).unwrap();
+        let mut file = format!("{}CompressedData = True\nElementDataFile = LOCAL\n", HEADER).into_bytes();
+        file.append(&mut encoder.finish().unwrap());
+        assert_ramp(&parse_metaimage(&file, &no_files()).unwrap_or_else(|e| panic!("{}", e.message())));
+    }
+
+    #[test]
+    fn oversized_images_are_unsupported() {
+        let huge = "NDims = 3\nDimSize = 4294967295 4294967295 4294967295\nElementType = MET_DOUBLE\nElementDataFile = LOCAL\n";
+        assert!(matches!(parse_metaimage(huge.as_bytes(), &no_files()).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+    }
+}
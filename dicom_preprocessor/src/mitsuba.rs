@@ -0,0 +1,160 @@
+use crate::buf3d::Buf3D;
+use crate::grid::{sample_grid, Grid};
+use crate::volume::{bounds_to_transform, decode_samples, transform_to_bounds, voxel_count, DenseGrid, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{UVec3, Vec3};
+use half::f16;
+#[cfg(feature = "wasm")]
+use crate::brick::BrickGrid;
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Mitsuba's binary grid volume format.
+// Format description: https://mitsuba.readthedocs.io/en/stable/src/generated/plugins_volumes.html#grid-based-volume-data-source-gridvolume
+
+const HEADER_SIZE: usize = 48;
+const VERSION: u8 = 3;
+
+const ENCODING_F32: i32 = 1;
+const ENCODING_F16: i32 = 2;
+const ENCODING_U8: i32 = 3;
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+/// Parses a Mitsuba `.vol` grid. Grids with several channels, e.g. RGB albedo, are reduced to the
+/// mean of their channels.
+pub fn parse_mitsuba_vol(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    if bytes.len() < HEADER_SIZE || &bytes[..3] != b"VOL" {
+        return Err(invalid("File does not start with the VOL magic"));
+    }
+    if bytes[3] != VERSION {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported .vol version {}", bytes[3])));
+    }
+    let int = |index: usize| i32::from_le_bytes(bytes[4 + index * 4..8 + index * 4].try_into().unwrap());
+    let (encoding, size_x, size_y, size_z, channels) = (int(0), int(1), int(2), int(3), int(4));
+    let bounds = decode_samples(&bytes[24..HEADER_SIZE], SampleType::F32, Endianness::Little, 6)?;
+    if size_x <= 0 || size_y <= 0 || size_z <= 0 || channels <= 0 {
+        return Err(invalid(format!("Invalid grid size {}x{}x{} with {} channels", size_x, size_y, size_z, channels)));
+    }
+
+    let extent = UVec3::new(size_x as u32, size_y as u32, size_z as u32);
+    let channels = channels as usize;
+    let count = voxel_count(extent)?
+        .checked_mul(channels)
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("A grid of {} voxels with {} channels is too large", extent, channels)))?;
+    let data = &bytes[HEADER_SIZE..];
+    let samples = match encoding {
+        ENCODING_F32 => decode_samples(data, SampleType::F32, Endianness::Little, count)?,
+        ENCODING_U8 => decode_samples(data, SampleType::U8, Endianness::Little, count)?.into_iter().map(|value| value / 255.0).collect(),
+        ENCODING_F16 => {
+            let data = count.checked_mul(2).and_then(|size| data.get(..size)).ok_or_else(|| {
+                VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Expected {} half float samples, found {} bytes", count, data.len()))
+            })?;
+            data.chunks_exact(2).map(|sample| f16::from_le_bytes(sample.try_into().unwrap()).to_f32()).collect()
+        }
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported .vol encoding {}", encoding))),
+    };
+    let samples = if channels == 1 {
+        samples
+    } else {
+        samples.chunks_exact(channels).map(|voxel| voxel.iter().sum::<f32>() / channels as f32).collect()
+    };
+
+    let min = Vec3::new(bounds[0], bounds[1], bounds[2]);
+    let max = Vec3::new(bounds[3], bounds[4], bounds[5]);
+    let mut volume = Buf3D::new(extent);
+    volume.data = samples;
+    Ok(DenseGrid::new(volume, bounds_to_transform(min, max, extent)))
+}
+
+/// Writes a grid as a single channel float32 Mitsuba `.vol` file, with the bounds taken from the
+/// grid's transform. Densities are the normalized values of the grid.
+pub fn write_mitsuba_vol(grid: &dyn Grid) -> Vec<u8> {
+    let extent = grid.index_extent();
+    let (min, max) = transform_to_bounds(grid.transform(), extent);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + (extent.x * extent.y * extent.z) as usize * size_of::<f32>());
+    bytes.extend_from_slice(b"VOL");
+    bytes.push(VERSION);
+    for value in [ENCODING_F32, extent.x as i32, extent.y as i32, extent.z as i32, 1] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in min.to_array().into_iter().chain(max.to_array()) {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in sample_grid(grid) {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_mitsuba_vol(bytes: Uint8Array) -> Result<DenseGrid, VolumeReadError> {
+    parse_mitsuba_vol(&bytes.to_vec())
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn volume_to_mitsuba_vol(volume: &DenseGrid) -> Uint8Array {
+    Uint8Array::from(write_mitsuba_vol(volume).as_slice())
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn grid_to_mitsuba_vol(grid: &BrickGrid) -> Uint8Array {
+    Uint8Array::from(write_mitsuba_vol(grid).as_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Mat4;
+
+    fn header(encoding: i32, size: [i32; 3], channels: i32) -> Vec<u8> {
+        let mut bytes = b"VOL".to_vec();
+        bytes.push(VERSION);
+        for value in [encoding, size[0], size[1], size[2], channels] {
+            bytes.extend(value.to_le_bytes());
+        }
+        for value in [0.0f32, 0.0, 0.0, 2.0, 1.0, 1.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn channels_are_averaged() {
+        let mut bytes = header(ENCODING_U8, [2, 1, 1], 3);
+        bytes.extend([0, 30, 60, 255, 255, 255]);
+        let volume = parse_mitsuba_vol(&bytes).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(2, 1, 1));
+        assert_eq!(volume.lookup(UVec3::new(1, 0, 0)), 1.0);
+        assert_eq!(volume.transform(), bounds_to_transform(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0), UVec3::new(2, 1, 1)));
+    }
+
+    #[test]
+    fn written_grids_round_trip() {
+        let mut data = Buf3D::new(UVec3::new(3, 2, 1));
+        data.data = vec![0.0, 0.5, 1.0, 2.0, 3.0, 4.0];
+        let transform = Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)) * Mat4::from_scale(Vec3::new(0.5, 1.0, 2.0));
+        let grid = DenseGrid::new(data, transform);
+
+        let volume = parse_mitsuba_vol(&write_mitsuba_vol(&grid)).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(sample_grid(&volume), sample_grid(&grid));
+        assert!(volume.transform().abs_diff_eq(transform, 1e-5), "{} != {}", volume.transform(), transform);
+    }
+
+    #[test]
+    fn oversized_and_short_grids_are_errors() {
+        let huge = header(ENCODING_F32, [i32::MAX; 3], i32::MAX);
+        assert!(matches!(parse_mitsuba_vol(&huge).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+
+        let mut truncated = header(ENCODING_F16, [2, 2, 2], 1);
+        truncated.extend([0; 15]);
+        assert!(matches!(parse_mitsuba_vol(&truncated).err().map(|e| e.0), Some(VolumeReadErrorType::NotEnoughData)));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::buf3d::Buf3D;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+use half::f16;
+#[cfg(feature = "wasm")]
+use crate::{brick::BrickGrid, brick_config::BrickGridConfig, utils::{log_to_console, now}, volume::volume_to_grid};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Reader for MRC2014 and CCP4 maps.
+// Format description: https://www.ccpem.ac.uk/mrc_format/mrc2014.php
+
+const HEADER_SIZE: usize = 1024;
+
+const MODE_I8: i32 = 0;
+const MODE_I16: i32 = 1;
+const MODE_F32: i32 = 2;
+const MODE_U16: i32 = 6;
+const MODE_F16: i32 = 12;
+
+struct Header {
+    endianness: Endianness,
+    words: Vec<[u8; 4]>,
+}
+
+impl Header {
+    fn int(&self, word: usize) -> i32 {
+        match self.endianness {
+            Endianness::Little => i32::from_le_bytes(self.words[word]),
+            Endianness::Big => i32::from_be_bytes(self.words[word]),
+        }
+    }
+
+    fn float(&self, word: usize) -> f32 {
+        match self.endianness {
+            Endianness::Little => f32::from_le_bytes(self.words[word]),
+            Endianness::Big => f32::from_be_bytes(self.words[word]),
+        }
+    }
+
+    fn ivec3(&self, word: usize) -> [i32; 3] {
+        [self.int(word), self.int(word + 1), self.int(word + 2)]
+    }
+
+    fn vec3(&self, word: usize) -> Vec3 {
+        Vec3::new(self.float(word), self.float(word + 1), self.float(word + 2))
+    }
+}
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+/// Parses an MRC or CCP4 map. The transform maps voxel indices to angstrom, the unit of the header.
+pub fn parse_mrc(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(invalid("File is smaller than the MRC header"));
+    }
+    // the machine stamp is 0x44 0x41 or 0x44 0x44 for little endian and 0x11 0x11 for big endian files,
+    // old files without a stamp are little endian
+    let endianness = if bytes[212] == 0x11 { Endianness::Big } else { Endianness::Little };
+    let header = Header {
+        endianness,
+        words: bytes[..HEADER_SIZE].chunks_exact(4).map(|word| word.try_into().unwrap()).collect(),
+    };
+
+    let counts = header.ivec3(0);
+    if counts.iter().any(|&count| count <= 0) {
+        return Err(invalid(format!("Invalid dimensions {:?}", counts)));
+    }
+    let mode = header.int(3);
+    let starts = header.ivec3(4);
+    let sampling = header.ivec3(7);
+    let cell = header.vec3(10);
+    let axis_map = header.ivec3(16);
+    let extended_header_size = header.int(23).max(0) as usize;
+    let origin = header.vec3(49);
+
+    // MAPC, MAPR and MAPS name the axis (1 = x, 2 = y, 3 = z) of columns, rows and sections
+    let mut axes = [0usize; 3];
+    for (i, &axis) in axis_map.iter().enumerate() {
+        axes[i] = match axis {
+            1..=3 => axis as usize - 1,
+            // some writers leave the map empty, which means the default order
+            0 if axis_map == [0, 0, 0] => i,
+            _ => return Err(invalid(format!("Invalid axis mapping {:?}", axis_map))),
+        };
+    }
+    if axes[0] == axes[1] || axes[1] == axes[2] || axes[0] == axes[2] {
+        return Err(invalid(format!("Axis mapping {:?} is not a permutation", axis_map)));
+    }
+
+    let mut extent = UVec3::ZERO;
+    let mut start = Vec3::ZERO;
+    for i in 0..3 {
+        extent[axes[i]] = counts[i] as u32;
+        start[axes[i]] = starts[i] as f32;
+    }
+
+    // cell dimensions span MX, MY and MZ samples along x, y and z
+    let mut spacing = Vec3::ONE;
+    for axis in 0..3 {
+        if sampling[axis] > 0 && cell[axis] > 0.0 {
+            spacing[axis] = cell[axis] / sampling[axis] as f32;
+        }
+    }
+    // ORIGIN is used by MRC2014 files, CCP4 maps only give the start index of the subvolume
+    let origin = if origin != Vec3::ZERO && origin.is_finite() { origin } else { start * spacing };
+
+    let count = voxel_count(extent)?;
+    let data_start = HEADER_SIZE + extended_header_size;
+    let data = bytes.get(data_start..).ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Extended header exceeds the file"))?;
+    let samples = match mode {
+        MODE_I8 => decode_samples(data, SampleType::I8, endianness, count)?,
+        MODE_I16 => decode_samples(data, SampleType::I16, endianness, count)?,
+        MODE_F32 => decode_samples(data, SampleType::F32, endianness, count)?,
+        MODE_U16 => decode_samples(data, SampleType::U16, endianness, count)?,
+        MODE_F16 => {
+            let data = count.checked_mul(2).and_then(|size| data.get(..size)).ok_or_else(|| {
+                VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Expected {} half float samples, found {} bytes", count, data.len()))
+            })?;
+            data.chunks_exact(2)
+                .map(|sample| {
+                    let sample = sample.try_into().unwrap();
+                    match endianness {
+                        Endianness::Little => f16::from_le_bytes(sample).to_f32(),
+                        Endianness::Big => f16::from_be_bytes(sample).to_f32(),
+                    }
+                })
+                .collect()
+        }
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported MRC mode {}", mode))),
+    };
+
+    // reorder from column, row, section order to x, y, z order
+    let mut volume = Buf3D::new(extent);
+    if axes == [0, 1, 2] {
+        volume.data = samples;
+    } else {
+        // the samples were all read, so their indices fit
+        let mut stride = UVec3::ZERO;
+        for i in 0..3 {
+            stride[axes[i]] = match i {
+                0 => 1,
+                1 => extent[axes[0]],
+                _ => extent[axes[0]] * extent[axes[1]],
+            };
+        }
+        for (index, value) in volume.data.iter_mut().enumerate() {
+            let coord = UVec3::new(
+                index as u32 % extent.x,
+                (index as u32 / extent.x) % extent.y,
+                index as u32 / (extent.x * extent.y),
+            );
+            *value = samples[coord.dot(stride) as usize];
+        }
+    }
+
+    let transform = Mat4::from_translation(origin) * Mat4::from_scale(spacing);
+    Ok(DenseGrid::new(volume, transform))
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_mrc(bytes: Uint8Array) -> Result<DenseGrid, VolumeReadError> {
+    parse_mrc(&bytes.to_vec())
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_mrc_to_grid(bytes: Uint8Array, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting MRC load");
+    let start = now();
+    let volume = read_mrc(bytes)?;
+    let end = now();
+    log_to_console(&format!("Finished loading in {}", end - start));
+    volume_to_grid(volume, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    /// little endian float map of 2x3x1 columns, rows and sections, the columns running along `column_axis`
+    fn map(column_axis: i32) -> Vec<u8> {
+        let mut words = [0i32; HEADER_SIZE / 4];
+        words[..4].copy_from_slice(&[2, 3, 1, MODE_F32]);
+        words[7..10].copy_from_slice(&[2, 3, 1]);
+        words[16..19].copy_from_slice(&[column_axis, 3 - column_axis, 3]);
+        let mut bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        // cell dimensions in angstrom
+        for (word, cell) in [(10, 2.0f32), (11, 6.0), (12, 1.0)] {
+            bytes[word * 4..word * 4 + 4].copy_from_slice(&cell.to_le_bytes());
+        }
+        bytes[208..212].copy_from_slice(b"MAP ");
+        bytes[212..214].copy_from_slice(&[0x44, 0x41]);
+        bytes.extend((0..6).flat_map(|i| (i as f32).to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn maps_are_reordered_to_x_y_z() {
+        let volume = parse_mrc(&map(1)).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(2, 3, 1));
+        assert_eq!(volume.transform(), Mat4::from_scale(Vec3::new(1.0, 2.0, 1.0)));
+        assert_eq!(volume.lookup(UVec3::new(1, 2, 0)), 1.0);
+
+        // columns along y: the sample at column 1, row 2 is the last one
+        let volume = parse_mrc(&map(2)).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(3, 2, 1));
+        assert_eq!(volume.lookup(UVec3::new(2, 1, 0)), 1.0);
+        assert_eq!(volume.lookup(UVec3::new(1, 0, 0)), 2.0 / 5.0);
+    }
+
+    #[test]
+    fn oversized_maps_are_errors_before_reordering() {
+        // 65536 x 65536 voxels overflow the u32 strides used to reorder columns along y
+        let mut wide = map(2);
+        for word in 0..2 {
+            wide[word * 4..word * 4 + 4].copy_from_slice(&65536i32.to_le_bytes());
+        }
+        assert!(matches!(parse_mrc(&wide).err().map(|e| e.0), Some(VolumeReadErrorType::NotEnoughData)));
+
+        let mut huge = map(1);
+        for word in 0..3 {
+            huge[word * 4..word * 4 + 4].copy_from_slice(&i32::MAX.to_le_bytes());
+        }
+        assert!(matches!(parse_mrc(&huge).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+    }
+}
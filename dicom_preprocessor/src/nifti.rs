@@ -0,0 +1,261 @@
+use crate::buf3d::Buf3D;
+use crate::dicom_export::{error, DicomWriteError, DicomWriteErrorType};
+use crate::utils::log_to_console;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use glam::{Mat3, Mat4, Quat, UVec3, Vec3, Vec4};
+use std::io::{Read, Write};
+
+// Reader and writer for NIfTI-1 single files (.nii and .nii.gz).
+// Format description: https://nifti.nimh.nih.gov/pub/dist/src/niftilib/nifti1.h
+
+const HEADER_SIZE: usize = 348;
+/// header, followed by the four byte extension flag
+const VOX_OFFSET: usize = HEADER_SIZE + 4;
+
+const DT_UINT16: i16 = 512;
+const NIFTI_UNITS_METER: u8 = 1;
+const NIFTI_UNITS_MM: u8 = 2;
+const NIFTI_UNITS_MICRON: u8 = 3;
+const NIFTI_XFORM_SCANNER_ANAT: i16 = 1;
+
+struct HeaderWriter {
+    bytes: Vec<u8>,
+}
+
+impl HeaderWriter {
+    fn put(&mut self, offset: usize, value: &[u8]) {
+        self.bytes[offset..offset + value.len()].copy_from_slice(value);
+    }
+
+    fn i16(&mut self, offset: usize, value: i16) {
+        self.put(offset, &value.to_le_bytes());
+    }
+
+    fn f32(&mut self, offset: usize, value: f32) {
+        self.put(offset, &value.to_le_bytes());
+    }
+
+    fn f32s(&mut self, offset: usize, values: &[f32]) {
+        for (i, value) in values.iter().enumerate() {
+            self.f32(offset + i * 4, *value);
+        }
+    }
+}
+
+/// Writes 16 bit unsigned samples as a `.nii.gz` file. The transform maps voxel indices to DICOM
+/// patient coordinates (LPS) in millimeters, which are converted to the RAS coordinates of NIfTI.
+/// `rescale` is the slope and intercept that map stored samples to real values, like the DICOM
+/// rescale attributes, and is kept as `scl_slope` and `scl_inter`. NIfTI-1 stores sizes as i16, so
+/// volumes with more than 32767 voxels along an axis can't be written.
+pub fn write_nifti_gz(data: &Buf3D<u16>, transform: Mat4, rescale: (f32, f32)) -> Result<Vec<u8>, DicomWriteError> {
+    let dim = |size: u32| i16::try_from(size).map_err(|_| error(DicomWriteErrorType::InvalidOptions, format!("A volume of {} voxels is too large for NIfTI-1", data.stride)));
+    let dims = [3, dim(data.stride.x)?, dim(data.stride.y)?, dim(data.stride.z)?, 1, 1, 1, 1];
+    let lps_to_ras = Mat4::from_scale(Vec3::new(-1.0, -1.0, 1.0));
+    let affine = lps_to_ras * transform;
+
+    let mut header = HeaderWriter { bytes: vec![0; VOX_OFFSET] };
+    header.put(0, &(HEADER_SIZE as i32).to_le_bytes());
+    header.put(38, b"r");
+    for (i, dim) in dims.iter().enumerate() {
+        header.i16(40 + i * 2, *dim);
+    }
+    header.i16(70, DT_UINT16);
+    header.i16(72, 16);
+
+    // quaternion form, which only holds rotation, spacing and a possible flip of the z axis
+    let spacing = Vec3::new(affine.x_axis.truncate().length(), affine.y_axis.truncate().length(), affine.z_axis.truncate().length());
+    let mut rotation = Mat3::from_cols(
+        affine.x_axis.truncate() / spacing.x,
+        affine.y_axis.truncate() / spacing.y,
+        affine.z_axis.truncate() / spacing.z,
+    );
+    let qfac = if rotation.determinant() < 0.0 {
+        rotation.z_axis = -rotation.z_axis;
+        -1.0
+    } else {
+        1.0
+    };
+    let mut quaternion = Quat::from_mat3(&rotation);
+    if quaternion.w < 0.0 {
+        quaternion = -quaternion;
+    }
+    header.f32s(76, &[qfac, spacing.x, spacing.y, spacing.z, 0.0, 0.0, 0.0, 0.0]);
+    header.f32(108, VOX_OFFSET as f32);
+    header.f32s(112, &[rescale.0, rescale.1]);
+    header.put(123, &[NIFTI_UNITS_MM]);
+    header.put(148, b"Exported by Volxel");
+    header.i16(252, NIFTI_XFORM_SCANNER_ANAT);
+    header.i16(254, NIFTI_XFORM_SCANNER_ANAT);
+    header.f32s(256, &[quaternion.x, quaternion.y, quaternion.z]);
+    header.f32s(268, &affine.w_axis.truncate().to_array());
+
+    // the affine also goes into the rows of the sform
+    let rows = affine.transpose();
+    for (i, row) in [rows.x_axis, rows.y_axis, rows.z_axis].iter().enumerate() {
+        header.f32s(280 + i * 16, &row.to_array());
+    }
+    header.put(344, b"n+1\0");
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // writing into a Vec can't fail
+    encoder.write_all(&header.bytes).unwrap();
+    encoder.write_all(&data.data.iter().flat_map(|sample| sample.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+    Ok(encoder.finish().unwrap())
+}
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+    endianness: Endianness,
+}
+
+impl HeaderReader<'_> {
+    fn i16(&self, offset: usize) -> i16 {
+        let bytes = self.bytes[offset..offset + 2].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => i16::from_le_bytes(bytes),
+            Endianness::Big => i16::from_be_bytes(bytes),
+        }
+    }
+
+    fn f32(&self, offset: usize) -> f32 {
+        let bytes = self.bytes[offset..offset + 4].try_into().unwrap();
+        match self.endianness {
+            Endianness::Little => f32::from_le_bytes(bytes),
+            Endianness::Big => f32::from_be_bytes(bytes),
+        }
+    }
+
+    fn vec4(&self, offset: usize) -> Vec4 {
+        Vec4::new(self.f32(offset), self.f32(offset + 4), self.f32(offset + 8), self.f32(offset + 12))
+    }
+}
+
+/// Reads the first volume of a NIfTI-1 single file, gzipped or not. The transform is taken from the
+/// sform if set, else from the qform, and converted from RAS to the LPS patient coordinates of DICOM.
+pub fn parse_nifti(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    let mut decompressed = Vec::new();
+    let bytes = if bytes.starts_with(&[0x1f, 0x8b]) {
+        MultiGzDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+        decompressed.as_slice()
+    } else {
+        bytes
+    };
+    if bytes.len() < HEADER_SIZE {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "File is smaller than a NIfTI header"));
+    }
+
+    let endianness = match bytes[0..4].try_into().unwrap() {
+        size if i32::from_le_bytes(size) == HEADER_SIZE as i32 => Endianness::Little,
+        size if i32::from_be_bytes(size) == HEADER_SIZE as i32 => Endianness::Big,
+        size if i32::from_le_bytes(size) == 540 || i32::from_be_bytes(size) == 540 => {
+            return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "NIfTI-2 files are not supported"));
+        }
+        _ => return Err(invalid("Header size is not 348, this isn't a NIfTI-1 file")),
+    };
+    match &bytes[344..348] {
+        b"n+1\0" => {}
+        b"ni1\0" => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "Separate .hdr/.img pairs are not supported")),
+        _ => return Err(invalid("Missing NIfTI magic")),
+    }
+    let header = HeaderReader { bytes, endianness };
+
+    let dimensions = header.i16(40);
+    if !(1..=7).contains(&dimensions) {
+        return Err(invalid(format!("Invalid number of dimensions {}", dimensions)));
+    }
+    let dim = |axis: i16| if axis <= dimensions { header.i16(40 + axis as usize * 2).max(1) as u32 } else { 1 };
+    let extent = UVec3::new(dim(1), dim(2), dim(3));
+    if (4..=dimensions).any(|axis| dim(axis) > 1) {
+        log_to_console("NIfTI file has more than one volume, only the first one is read");
+    }
+
+    let sample_type = match header.i16(70) {
+        2 => SampleType::U8,
+        4 => SampleType::I16,
+        8 => SampleType::I32,
+        16 => SampleType::F32,
+        64 => SampleType::F64,
+        256 => SampleType::I8,
+        DT_UINT16 => SampleType::U16,
+        768 => SampleType::U32,
+        1024 => SampleType::I64,
+        1280 => SampleType::U64,
+        datatype => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported datatype {}", datatype))),
+    };
+    let vox_offset = header.f32(108) as usize;
+    let count = voxel_count(extent)?;
+    let mut samples = decode_samples(bytes.get(vox_offset..).unwrap_or_default(), sample_type, endianness, count)?;
+    let (slope, intercept) = (header.f32(112), header.f32(116));
+    if slope != 0.0 && slope.is_finite() && intercept.is_finite() && (slope, intercept) != (1.0, 0.0) {
+        samples.iter_mut().for_each(|sample| *sample = *sample * slope + intercept);
+    }
+
+    let unit_scale = match bytes[123] & 0x07 {
+        NIFTI_UNITS_METER => 1000.0,
+        NIFTI_UNITS_MICRON => 0.001,
+        _ => 1.0,
+    };
+    let spacing = Vec3::new(header.f32(80), header.f32(84), header.f32(88)).abs();
+    let spacing = Vec3::select(spacing.cmpgt(Vec3::ZERO), spacing, Vec3::ONE);
+    let affine = if header.i16(254) > 0 {
+        let rows = Mat4::from_cols(header.vec4(280), header.vec4(296), header.vec4(312), Vec4::W);
+        rows.transpose()
+    } else if header.i16(252) > 0 {
+        let (b, c, d) = (header.f32(256), header.f32(260), header.f32(264));
+        let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+        let qfac = if header.f32(76) < 0.0 { -1.0 } else { 1.0 };
+        let rotation = Mat4::from_quat(Quat::from_xyzw(b, c, d, a));
+        let offset = Vec3::new(header.f32(268), header.f32(272), header.f32(276));
+        Mat4::from_translation(offset) * rotation * Mat4::from_scale(spacing * Vec3::new(1.0, 1.0, qfac))
+    } else {
+        Mat4::from_scale(spacing)
+    };
+    let ras_to_lps = Mat4::from_scale(Vec3::new(-1.0, -1.0, 1.0));
+
+    let mut volume = Buf3D::new(extent);
+    volume.data = samples;
+    Ok(DenseGrid::new(volume, Mat4::from_scale(Vec3::splat(unit_scale)) * ras_to_lps * affine))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    #[test]
+    fn written_files_keep_rescale_and_placement() {
+        let mut data = Buf3D::new(UVec3::new(3, 2, 2));
+        data.data = (0..12).collect();
+        let transform = Mat4::from_cols(
+            Vec3::new(0.0, 0.5, 0.0).extend(0.0),
+            Vec3::new(-0.5, 0.0, 0.0).extend(0.0),
+            Vec3::new(0.0, 0.0, 2.0).extend(0.0),
+            Vec3::new(-10.0, 20.0, 30.0).extend(1.0),
+        );
+        let file = write_nifti_gz(&data, transform, (2.0, -1024.0)).unwrap_or_else(|e| panic!("{}", e.message()));
+
+        let mut bytes = Vec::new();
+        MultiGzDecoder::new(file.as_slice()).read_to_end(&mut bytes).unwrap();
+        let header = HeaderReader { bytes: &bytes, endianness: Endianness::Little };
+        assert_eq!((header.f32(112), header.f32(116)), (2.0, -1024.0));
+
+        let volume = parse_nifti(&file).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), data.stride);
+        assert!(volume.transform().abs_diff_eq(transform, 1e-5), "{} != {}", volume.transform(), transform);
+    }
+
+    #[test]
+    fn sizes_beyond_i16_are_errors() {
+        let data = Buf3D::new(UVec3::new(32768, 1, 1));
+        assert!(write_nifti_gz(&data, Mat4::IDENTITY, (1.0, 0.0)).is_err());
+    }
+}
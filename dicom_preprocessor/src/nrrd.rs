@@ -0,0 +1,446 @@
+use crate::buf3d::Buf3D;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, NamedFiles, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// NRRD format specification: https://teem.sourceforge.net/nrrd/format.html
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Raw,
+    Ascii,
+    Gzip,
+    Bzip2,
+}
+
+struct NrrdHeader {
+    fields: HashMap<String, String>,
+    /// file names following a `data file: LIST` field
+    list: Vec<String>,
+    /// offset of the attached data, if there is any
+    data_offset: usize,
+}
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+fn parse_header(bytes: &[u8]) -> Result<NrrdHeader, VolumeReadError> {
+    if !bytes.starts_with(b"NRRD") {
+        return Err(invalid("File does not start with the NRRD magic"));
+    }
+
+    let mut fields = HashMap::new();
+    let mut list = Vec::new();
+    let mut in_list = false;
+    let mut offset = 0;
+    let mut first = true;
+    while offset < bytes.len() {
+        let line_end = bytes[offset..].iter().position(|b| *b == b'\n').map_or(bytes.len(), |pos| offset + pos);
+        let line = String::from_utf8_lossy(&bytes[offset..line_end]);
+        let line = line.trim_end_matches('\r');
+        offset = (line_end + 1).min(bytes.len());
+
+        if first {
+            // magic line, e.g. "NRRD0004"
+            first = false;
+            continue;
+        }
+        // an empty line separates the header from attached data
+        if line.is_empty() {
+            break;
+        }
+        if in_list {
+            list.push(line.trim().to_string());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        // key/value pairs ("key:=value") aren't relevant for the volume
+        if line.contains(":=") {
+            continue;
+        }
+        let Some((field, value)) = line.split_once(": ") else {
+            return Err(invalid(format!("Malformed header line \"{}\"", line)));
+        };
+        let field = field.trim().to_lowercase().replace("datafile", "data file");
+        let value = value.trim().to_string();
+        if field == "data file" && value.split_whitespace().next() == Some("LIST") {
+            in_list = true;
+        }
+        fields.insert(field, value);
+    }
+
+    Ok(NrrdHeader { fields, list, data_offset: offset })
+}
+
+fn parse_sample_type(value: &str) -> Result<SampleType, VolumeReadError> {
+    Ok(match value {
+        "signed char" | "int8" | "int8_t" => SampleType::I8,
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => SampleType::U8,
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => SampleType::I16,
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => SampleType::U16,
+        "int" | "signed int" | "int32" | "int32_t" => SampleType::I32,
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => SampleType::U32,
+        "longlong" | "long long" | "long long int" | "signed long long" | "signed long long int" | "int64" | "int64_t" => SampleType::I64,
+        "ulonglong" | "unsigned long long" | "unsigned long long int" | "uint64" | "uint64_t" => SampleType::U64,
+        "float" => SampleType::F32,
+        "double" => SampleType::F64,
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported NRRD type \"{}\"", value))),
+    })
+}
+
+fn parse_encoding(value: &str) -> Result<Encoding, VolumeReadError> {
+    Ok(match value {
+        "raw" => Encoding::Raw,
+        "ascii" | "text" | "txt" => Encoding::Ascii,
+        "gzip" | "gz" => Encoding::Gzip,
+        "bzip2" | "bz2" => Encoding::Bzip2,
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported NRRD encoding \"{}\"", value))),
+    })
+}
+
+/// parses a vector of the form "(1.0,0,0)", returning None for "none"
+fn parse_vector(value: &str) -> Result<Option<Vec3>, VolumeReadError> {
+    if value == "none" {
+        return Ok(None);
+    }
+    let components = value
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .map(|component| component.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid(format!("Couldn't parse vector \"{}\"", value)))?;
+    match components.as_slice() {
+        [x, y, z] => Ok(Some(Vec3::new(*x, *y, *z))),
+        [x, y] => Ok(Some(Vec3::new(*x, *y, 0.0))),
+        _ => Err(invalid(format!("Vector \"{}\" is not two or three dimensional", value))),
+    }
+}
+
+/// splits "(1,0,0) none (0,0,1)" into its vectors
+fn parse_vectors(value: &str) -> Result<Vec<Option<Vec3>>, VolumeReadError> {
+    value.split_whitespace().map(parse_vector).collect()
+}
+
+fn expand_data_files(header: &NrrdHeader, value: &str) -> Result<Vec<String>, VolumeReadError> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    if parts.first() == Some(&"LIST") {
+        return Ok(header.list.clone());
+    }
+    if parts.len() >= 4 && parts[0].contains('%') {
+        // printf style pattern: "<format> <min> <max> <step> [<subdim>]"
+        let parse = |index: usize| parts[index].parse::<i64>().map_err(|_| invalid(format!("Invalid data file pattern \"{}\"", value)));
+        let (min, max, step) = (parse(1)?, parse(2)?, parse(3)?);
+        if step == 0 {
+            return Err(invalid("Data file pattern step may not be zero"));
+        }
+        let mut files = Vec::new();
+        let mut i = min;
+        while (step > 0 && i <= max) || (step < 0 && i >= max) {
+            files.push(format_index(parts[0], i)?);
+            i += step;
+        }
+        return Ok(files);
+    }
+    Ok(vec![value.to_string()])
+}
+
+/// formats a single `%d` style conversion with optional zero padding, e.g. "slice%03d.raw"
+fn format_index(pattern: &str, index: i64) -> Result<String, VolumeReadError> {
+    let start = pattern.find('%').unwrap();
+    let rest = &pattern[start + 1..];
+    let conversion = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(|| invalid(format!("Invalid data file pattern \"{}\"", pattern)))?;
+    if &rest[conversion..conversion + 1] != "d" && &rest[conversion..conversion + 1] != "u" {
+        return Err(invalid(format!("Unsupported data file pattern \"{}\"", pattern)));
+    }
+    let width_spec = &rest[..conversion];
+    let width: usize = width_spec.parse().unwrap_or(0);
+    let formatted = if width_spec.starts_with('0') {
+        format!("{:0width$}", index, width = width)
+    } else {
+        format!("{:width$}", index, width = width)
+    };
+    Ok(format!("{}{}{}", &pattern[..start], formatted, &rest[conversion + 1..]))
+}
+
+fn skip_lines(bytes: &[u8], lines: usize) -> &[u8] {
+    let mut offset = 0;
+    for _ in 0..lines {
+        match bytes[offset..].iter().position(|b| *b == b'\n') {
+            Some(pos) => offset += pos + 1,
+            None => return &[],
+        }
+    }
+    &bytes[offset..]
+}
+
+fn decompress(bytes: &[u8], encoding: Encoding) -> Result<Vec<u8>, VolumeReadError> {
+    let mut decompressed = Vec::new();
+    let result = match encoding {
+        Encoding::Gzip => flate2::read::MultiGzDecoder::new(bytes).read_to_end(&mut decompressed),
+        Encoding::Bzip2 => bzip2::read::MultiBzDecoder::new(bytes).read_to_end(&mut decompressed),
+        Encoding::Raw | Encoding::Ascii => return Ok(bytes.to_vec()),
+    };
+    result.map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+    Ok(decompressed)
+}
+
+/// Reads the samples of a single data file, applying line and byte skips
+fn read_samples(
+    bytes: &[u8],
+    encoding: Encoding,
+    sample_type: SampleType,
+    endianness: Endianness,
+    line_skip: usize,
+    byte_skip: i64,
+    count: usize,
+) -> Result<Vec<f32>, VolumeReadError> {
+    let bytes = skip_lines(bytes, line_skip);
+
+    if encoding == Encoding::Ascii {
+        let text = String::from_utf8_lossy(bytes);
+        let samples = text
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty())
+            .take(count)
+            .map(|token| token.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid("Couldn't parse ASCII sample data"))?;
+        if samples.len() < count {
+            return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Expected {} ASCII samples, got {}", count, samples.len())));
+        }
+        return Ok(samples);
+    }
+
+    let data = decompress(bytes, encoding)?;
+    let needed = count
+        .checked_mul(sample_type.size())
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("{} samples don't fit into a file", count)))?;
+    let skip = if byte_skip < 0 {
+        // -1 means the data is located at the very end of the file
+        if encoding != Encoding::Raw {
+            return Err(invalid("A byte skip of -1 is only allowed with raw encoding"));
+        }
+        data.len().saturating_sub(needed)
+    } else {
+        byte_skip as usize
+    };
+    if skip > data.len() {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Byte skip exceeds the data length"));
+    }
+    decode_samples(&data[skip..], sample_type, endianness, count)
+}
+
+/// Parses an NRRD volume. `bytes` is either an attached `.nrrd` file or a detached `.nhdr` header,
+/// in which case the data files it references are looked up in `files`.
+pub fn parse_nrrd(bytes: &[u8], files: &NamedFiles) -> Result<DenseGrid, VolumeReadError> {
+    let header = parse_header(bytes)?;
+    let field = |name: &str| header.fields.get(name).map(|value| value.as_str());
+    let required = |name: &str| field(name).ok_or_else(|| invalid(format!("Missing required field \"{}\"", name)));
+
+    let sample_type = parse_sample_type(required("type")?)?;
+    let encoding = parse_encoding(required("encoding")?)?;
+    let endianness = match field("endian") {
+        Some("big") => Endianness::Big,
+        Some("little") => Endianness::Little,
+        Some(other) => return Err(invalid(format!("Unknown endianness \"{}\"", other))),
+        None if sample_type.size() > 1 && encoding != Encoding::Ascii => return Err(invalid("Missing required field \"endian\"")),
+        None => Endianness::Little,
+    };
+
+    let dimension: usize = required("dimension")?.parse().map_err(|_| invalid("Couldn't parse dimension"))?;
+    let sizes = required("sizes")?
+        .split_whitespace()
+        .map(|size| size.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid("Couldn't parse sizes"))?;
+    if sizes.len() != dimension {
+        return Err(invalid("Number of sizes does not match the dimension"));
+    }
+    // a leading axis of size one (e.g. a scalar "list" axis) can be dropped
+    let axis_offset = if dimension == 4 && sizes[0] == 1 { 1 } else { 0 };
+    let spatial_sizes = &sizes[axis_offset..];
+    let extent = match spatial_sizes {
+        [x, y, z] => UVec3::new(*x, *y, *z),
+        [x, y] => UVec3::new(*x, *y, 1),
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Only scalar 2D and 3D volumes are supported, got dimension {}", dimension))),
+    };
+
+    let line_skip: usize = field("line skip").map_or(Ok(0), str::parse).map_err(|_| invalid("Couldn't parse line skip"))?;
+    let byte_skip: i64 = field("byte skip").map_or(Ok(0), str::parse).map_err(|_| invalid("Couldn't parse byte skip"))?;
+
+    let count = voxel_count(extent)?;
+    let samples = if let Some(data_file) = field("data file") {
+        let data_files = expand_data_files(&header, data_file)?;
+        if data_files.is_empty() {
+            return Err(invalid("No data files listed"));
+        }
+        let per_file = count / data_files.len();
+        let mut samples = Vec::new();
+        for name in &data_files {
+            let data = files.get(name).ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::MissingDataFile, format!("Data file \"{}\" was not provided", name)))?;
+            samples.append(&mut read_samples(data, encoding, sample_type, endianness, line_skip, byte_skip, per_file)?);
+        }
+        samples
+    } else {
+        read_samples(&bytes[header.data_offset..], encoding, sample_type, endianness, line_skip, byte_skip, count)?
+    };
+    if samples.len() != count {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Expected {} samples, got {}", count, samples.len())));
+    }
+
+    // index to world transform, the columns being the directions of each axis followed by the origin
+    let mut axes = [Vec3::X, Vec3::Y, Vec3::Z];
+    if let Some(directions) = field("space directions") {
+        let directions: Vec<Vec3> = parse_vectors(directions)?.into_iter().flatten().collect();
+        for (axis, direction) in axes.iter_mut().zip(directions) {
+            *axis = direction;
+        }
+    } else if let Some(spacings) = field("spacings") {
+        let spacings: Vec<f32> = spacings.split_whitespace().skip(axis_offset).map(|spacing| spacing.parse::<f32>().unwrap_or(1.0)).collect();
+        for (axis, spacing) in axes.iter_mut().zip(spacings) {
+            if spacing.is_finite() {
+                *axis *= spacing;
+            }
+        }
+    }
+    let mut origin = match field("space origin") {
+        Some(origin) => parse_vector(origin)?.unwrap_or(Vec3::ZERO),
+        None => Vec3::ZERO,
+    };
+
+    // DICOM data is in LPS, so convert RAS based spaces to match
+    if matches!(field("space"), Some("right-anterior-superior") | Some("RAS") | Some("right-anterior-superior-time") | Some("RAST")) {
+        let ras_to_lps = Vec3::new(-1.0, -1.0, 1.0);
+        for axis in axes.iter_mut() {
+            *axis *= ras_to_lps;
+        }
+        origin *= ras_to_lps;
+    }
+
+    let transform = Mat4::from_cols(axes[0].extend(0.0), axes[1].extend(0.0), axes[2].extend(0.0), origin.extend(1.0));
+
+    let mut data = Buf3D::new(extent);
+    data.data = samples;
+    Ok(DenseGrid::new(data, transform))
+}
+
+/// Writes 16 bit unsigned samples as a gzip encoded `.nrrd` file. The transform maps voxel indices
+/// to DICOM patient coordinates (LPS) in millimeters. NRRD has no field for a rescale, so samples
+/// with a `rescale` other than a slope of 1 and an intercept of 0 are written as rescaled floats.
+pub fn write_nrrd(data: &Buf3D<u16>, transform: Mat4, rescale: (f32, f32)) -> Vec<u8> {
+    let (slope, intercept) = rescale;
+    let rescaled = (slope, intercept) != (1.0, 0.0);
+    let vector = |v: Vec3| format!("({},{},{})", v.x, v.y, v.z);
+    let mut header = String::new();
+    header += "NRRD0004\n";
+    header += "# Complete NRRD file format specification at:\n";
+    header += "# http://teem.sourceforge.net/nrrd/format.html\n";
+    header += if rescaled { "type: float\n" } else { "type: uint16\n" };
+    header += "dimension: 3\n";
+    header += "space: left-posterior-superior\n";
+    header += &format!("sizes: {} {} {}\n", data.stride.x, data.stride.y, data.stride.z);
+    header += &format!(
+        "space directions: {} {} {}\n",
+        vector(transform.x_axis.truncate()),
+        vector(transform.y_axis.truncate()),
+        vector(transform.z_axis.truncate())
+    );
+    header += "kinds: domain domain domain\n";
+    header += "endian: little\n";
+    header += "encoding: gzip\n";
+    header += &format!("space origin: {}\n", vector(transform.w_axis.truncate()));
+    header += "space units: \"mm\" \"mm\" \"mm\"\n";
+    header += "\n";
+
+    // the header stays uncompressed in front of the gzip stream
+    let mut encoder = GzEncoder::new(header.into_bytes(), Compression::default());
+    // writing into a Vec can't fail
+    let samples: Vec<u8> = if rescaled {
+        data.data.iter().flat_map(|sample| (*sample as f32 * slope + intercept).to_le_bytes()).collect()
+    } else {
+        data.data.iter().flat_map(|sample| sample.to_le_bytes()).collect()
+    };
+    encoder.write_all(&samples).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_nrrd(bytes: Uint8Array, file_names: Vec<String>, files: Vec<Uint8Array>) -> Result<DenseGrid, VolumeReadError> {
+    let files = NamedFiles::new(file_names, files.iter().map(|file| file.to_vec()).collect());
+    parse_nrrd(&bytes.to_vec(), &files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    const HEADER: &str = "NRRD0004\ntype: uint8\ndimension: 3\nsizes: 2 2 2\nencoding: raw\nspacings: 1 2 3\n";
+
+    fn no_files() -> NamedFiles {
+        NamedFiles::new(Vec::new(), Vec::new())
+    }
+
+    fn assert_ramp(volume: &DenseGrid) {
+        assert_eq!(volume.index_extent(), UVec3::splat(2));
+        for i in 0..8u32 {
+            let value = volume.lookup(UVec3::new(i % 2, i / 2 % 2, i / 4));
+            assert!((value - i as f32 / 7.0).abs() < 1e-6, "voxel {} is {}", i, value);
+        }
+    }
+
+    #[test]
+    fn attached_data_follows_the_header() {
+        let mut file = format!("{}\n", HEADER).into_bytes();
+        file.extend(0..8u8);
+        let volume = parse_nrrd(&file, &no_files()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_ramp(&volume);
+        assert_eq!(volume.transform(), Mat4::from_scale(Vec3::new(1.0, 2.0, 3.0)));
+    }
+
+    #[test]
+    fn detached_data_is_looked_up_by_name() {
+        let header = format!("{}data file: data/ramp.raw\n", HEADER).into_bytes();
+        let files = NamedFiles::new(vec!["upload/ramp.raw".to_string()], vec![(0..8u8).collect()]);
+        assert_ramp(&parse_nrrd(&header, &files).unwrap_or_else(|e| panic!("{}", e.message())));
+
+        let error = parse_nrrd(&header, &no_files()).err().expect("the data file is missing");
+        assert!(matches!(error.0, VolumeReadErrorType::MissingDataFile));
+    }
+
+    #[test]
+    fn gzip_data_round_trips_through_the_writer() {
+        let mut data = Buf3D::new(UVec3::splat(2));
+        data.data = (0..8).map(|i| i * 1000).collect();
+        let transform = Mat4::from_cols(
+            Vec3::new(0.5, 0.0, 0.0).extend(0.0),
+            Vec3::new(0.0, 0.5, 0.0).extend(0.0),
+            Vec3::new(0.0, 0.0, 2.0).extend(0.0),
+            Vec3::new(-10.0, 20.0, 30.0).extend(1.0),
+        );
+        let volume = parse_nrrd(&write_nrrd(&data, transform, (1.0, 0.0)), &no_files()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_ramp(&volume);
+        assert!(volume.transform().abs_diff_eq(transform, 1e-5), "{} != {}", volume.transform(), transform);
+    }
+
+    #[test]
+    fn oversized_and_short_volumes_are_errors() {
+        let huge = "NRRD0004\ntype: uint8\ndimension: 3\nsizes: 4294967295 4294967295 4294967295\nencoding: raw\n\n";
+        assert!(matches!(parse_nrrd(huge.as_bytes(), &no_files()).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+
+        let short = format!("{}\n\x00\x01", HEADER).into_bytes();
+        assert!(matches!(parse_nrrd(&short, &no_files()).err().map(|e| e.0), Some(VolumeReadErrorType::NotEnoughData)));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::brick::BrickGrid;
+use crate::buf3d::Buf3D;
+use crate::grid::{sample_grid, Grid};
+use crate::volume::{bounds_to_transform, voxel_count, DenseGrid, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// PBRT-v4 "uniformgrid" media, as defined by MakeNamedMedium in a scene file.
+// Format description: https://pbrt.org/fileformat-v4#media
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    /// directive or other unquoted word
+    Word(String),
+    Quoted(String),
+    Number(f32),
+    OpenBracket,
+    CloseBracket,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, VolumeReadError> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => {
+                while chars.next_if(|(_, c)| *c != '\n').is_some() {}
+            }
+            '[' => tokens.push(Token::OpenBracket),
+            ']' => tokens.push(Token::CloseBracket),
+            '"' => {
+                let length = text[start + 1..].find('"').ok_or_else(|| invalid("Unterminated string"))?;
+                let end = start + 1 + length;
+                tokens.push(Token::Quoted(text[start + 1..end].to_string()));
+                while chars.next_if(|(index, _)| *index <= end).is_some() {}
+            }
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some((index, c)) = chars.next_if(|(_, c)| !c.is_whitespace() && !matches!(c, '[' | ']' | '"' | '#')) {
+                    end = index + c.len_utf8();
+                }
+                let word = &text[start..end];
+                tokens.push(match word.parse::<f32>() {
+                    Ok(number) => Token::Number(number),
+                    Err(_) => Token::Word(word.to_string()),
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parameter list of a directive, e.g. `"integer nx" 4` or `"float density" [ 0 1 ]`
+struct Parameters {
+    /// values by parameter name, the type is dropped
+    values: HashMap<String, Vec<Token>>,
+}
+
+impl Parameters {
+    fn parse(tokens: &[Token], index: &mut usize) -> Result<Self, VolumeReadError> {
+        let mut values = HashMap::new();
+        while let Some(Token::Quoted(declaration)) = tokens.get(*index) {
+            let name = declaration.split_whitespace().last().unwrap_or_default().to_string();
+            *index += 1;
+            let mut parameter_values = Vec::new();
+            match tokens.get(*index) {
+                Some(Token::OpenBracket) => {
+                    *index += 1;
+                    while let Some(token) = tokens.get(*index) {
+                        *index += 1;
+                        if *token == Token::CloseBracket {
+                            break;
+                        }
+                        parameter_values.push(token.clone());
+                    }
+                }
+                Some(token @ (Token::Quoted(_) | Token::Number(_) | Token::Word(_))) => {
+                    parameter_values.push(token.clone());
+                    *index += 1;
+                }
+                _ => return Err(invalid(format!("Parameter \"{}\" has no value", declaration))),
+            }
+            values.insert(name, parameter_values);
+        }
+        Ok(Self { values })
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.values.get(name)?.first()? {
+            Token::Quoted(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn floats(&self, name: &str) -> Option<Vec<f32>> {
+        self.values
+            .get(name)?
+            .iter()
+            .map(|token| match token {
+                Token::Number(number) => Some(*number),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn vec3(&self, name: &str) -> Option<Vec3> {
+        match self.floats(name)?.as_slice() {
+            [x, y, z] => Some(Vec3::new(*x, *y, *z)),
+            _ => None,
+        }
+    }
+}
+
+/// Numbers following a directive, either bare or within brackets
+fn directive_numbers(tokens: &[Token], index: &mut usize) -> Vec<f32> {
+    let mut numbers = Vec::new();
+    let bracketed = tokens.get(*index) == Some(&Token::OpenBracket);
+    if bracketed {
+        *index += 1;
+    }
+    while let Some(Token::Number(number)) = tokens.get(*index) {
+        numbers.push(*number);
+        *index += 1;
+    }
+    if bracketed && tokens.get(*index) == Some(&Token::CloseBracket) {
+        *index += 1;
+    }
+    numbers
+}
+
+/// Reads the uniformgrid medium called `name`, or the first one if no name is given, from PBRT-v4
+/// scene text. The transform combines the medium's `p0`/`p1` bounds with the current transformation
+/// at its definition, as far as it is set by Identity, Transform, ConcatTransform, Translate and Scale.
+pub fn parse_pbrt_medium(text: &str, name: Option<&str>) -> Result<DenseGrid, VolumeReadError> {
+    let tokens = tokenize(text)?;
+    let mut ctm = Mat4::IDENTITY;
+    let mut ctm_stack = Vec::new();
+    let mut index = 0;
+    while let Some(token) = tokens.get(index) {
+        index += 1;
+        let Token::Word(directive) = token else {
+            continue;
+        };
+        match directive.as_str() {
+            "AttributeBegin" => ctm_stack.push(ctm),
+            "AttributeEnd" => ctm = ctm_stack.pop().unwrap_or(Mat4::IDENTITY),
+            "Identity" => ctm = Mat4::IDENTITY,
+            // matrices are given column by column
+            "Transform" | "ConcatTransform" => {
+                let values: [f32; 16] = directive_numbers(&tokens, &mut index).try_into().map_err(|_| invalid(format!("{} needs 16 values", directive)))?;
+                let matrix = Mat4::from_cols_array(&values);
+                ctm = if directive == "Transform" { matrix } else { ctm * matrix };
+            }
+            "Translate" | "Scale" => {
+                let [x, y, z] = directive_numbers(&tokens, &mut index).try_into().map_err(|_| invalid(format!("{} needs 3 values", directive)))?;
+                let vector = Vec3::new(x, y, z);
+                ctm *= if directive == "Translate" { Mat4::from_translation(vector) } else { Mat4::from_scale(vector) };
+            }
+            "MakeNamedMedium" => {
+                let Some(Token::Quoted(medium_name)) = tokens.get(index) else {
+                    return Err(invalid("MakeNamedMedium is missing its name"));
+                };
+                index += 1;
+                let parameters = Parameters::parse(&tokens, &mut index)?;
+                if parameters.string("type") != Some("uniformgrid") || name.is_some_and(|name| name != medium_name) {
+                    continue;
+                }
+                return read_uniform_grid(&parameters, ctm);
+            }
+            _ => {}
+        }
+    }
+    Err(VolumeReadError::new(
+        VolumeReadErrorType::NotEnoughData,
+        match name {
+            Some(name) => format!("No uniformgrid medium named \"{}\" found", name),
+            None => "No uniformgrid medium found".to_string(),
+        },
+    ))
+}
+
+fn read_uniform_grid(parameters: &Parameters, ctm: Mat4) -> Result<DenseGrid, VolumeReadError> {
+    let size = |name: &str| match parameters.floats(name).as_deref() {
+        Some([size]) if *size >= 1.0 => Ok(*size as u32),
+        _ => Err(invalid(format!("Missing or invalid \"{}\"", name))),
+    };
+    let extent = UVec3::new(size("nx")?, size("ny")?, size("nz")?);
+    let density = parameters.floats("density").ok_or_else(|| invalid("Medium has no \"density\" values"))?;
+    let count = voxel_count(extent)?;
+    if density.len() != count {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Expected {} density values, got {}", count, density.len())));
+    }
+    let p0 = parameters.vec3("p0").unwrap_or(Vec3::ZERO);
+    let p1 = parameters.vec3("p1").unwrap_or(Vec3::ONE);
+
+    let mut volume = Buf3D::new(extent);
+    volume.data = density;
+    Ok(DenseGrid::new(volume, ctm * bounds_to_transform(p0, p1, extent)))
+}
+
+/// Writes a grid as a PBRT-v4 uniformgrid medium called `name`. The medium's bounds are set so its
+/// voxels lie on the grid's index space, with the grid's transform applied as the medium transform.
+pub fn write_pbrt_medium(grid: &dyn Grid, name: &str) -> String {
+    let extent = grid.index_extent();
+    let p0 = Vec3::splat(-0.5);
+    let p1 = extent.as_vec3() - 0.5;
+    let join = |values: &[f32]| values.iter().map(f32::to_string).collect::<Vec<_>>().join(" ");
+
+    let mut text = String::new();
+    text += "AttributeBegin\n";
+    text += &format!("    Transform [ {} ]\n", join(&grid.transform().to_cols_array()));
+    text += &format!("    MakeNamedMedium \"{}\"\n", name);
+    text += "        \"string type\" \"uniformgrid\"\n";
+    text += &format!("        \"integer nx\" {} \"integer ny\" {} \"integer nz\" {}\n", extent.x, extent.y, extent.z);
+    text += &format!("        \"point3 p0\" [ {} ] \"point3 p1\" [ {} ]\n", join(&p0.to_array()), join(&p1.to_array()));
+    text += "        \"float density\" [\n";
+    for row in sample_grid(grid).chunks(extent.x as usize) {
+        text += &format!("            {}\n", join(row));
+    }
+    text += "        ]\n";
+    text += "AttributeEnd\n";
+    text
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn read_pbrt_medium(text: String, name: Option<String>) -> Result<DenseGrid, VolumeReadError> {
+    parse_pbrt_medium(&text, name.as_deref())
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn volume_to_pbrt_medium(volume: &DenseGrid, name: String) -> String {
+    write_pbrt_medium(volume, &name)
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn grid_to_pbrt_medium(grid: &BrickGrid, name: String) -> String {
+    write_pbrt_medium(grid, &name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENE: &str = r#"
+        # a cloud and a smaller puff
+        AttributeBegin
+            Translate 1 2 3
+            MakeNamedMedium "cloud" "string type" "uniformgrid"
+                "integer nx" 2 "integer ny" 1 "integer nz" 1
+                "point3 p0" [ 0 0 0 ] "point3 p1" [ 2 1 1 ]
+                "float density" [ 0 4 ]
+        AttributeEnd
+        MakeNamedMedium "puff" "string type" "uniformgrid"
+            "integer nx" 1 "integer ny" 1 "integer nz" 2 "float density" [ 1 3 ]
+    "#;
+
+    #[test]
+    fn media_are_found_by_name() {
+        let cloud = parse_pbrt_medium(SCENE, None).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(cloud.index_extent(), UVec3::new(2, 1, 1));
+        assert_eq!(cloud.lookup(UVec3::new(1, 0, 0)), 1.0);
+        let transform = Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)) * bounds_to_transform(Vec3::ZERO, Vec3::new(2.0, 1.0, 1.0), cloud.index_extent());
+        assert_eq!(cloud.transform(), transform);
+
+        // the transform of the cloud ended with its attribute block
+        let puff = parse_pbrt_medium(SCENE, Some("puff")).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(puff.index_extent(), UVec3::new(1, 1, 2));
+        assert_eq!(puff.transform(), bounds_to_transform(Vec3::ZERO, Vec3::ONE, puff.index_extent()));
+    }
+
+    #[test]
+    fn written_media_round_trip() {
+        let mut data = Buf3D::new(UVec3::new(3, 2, 1));
+        data.data = vec![0.0, 0.5, 1.0, 2.0, 3.0, 4.0];
+        let transform = Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)) * Mat4::from_scale(Vec3::new(0.5, 1.0, 2.0));
+        let grid = DenseGrid::new(data, transform);
+
+        let medium = parse_pbrt_medium(&write_pbrt_medium(&grid, "volume"), Some("volume")).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(sample_grid(&medium), sample_grid(&grid));
+        assert!(medium.transform().abs_diff_eq(transform, 1e-5), "{} != {}", medium.transform(), transform);
+    }
+
+    #[test]
+    fn oversized_and_short_media_are_errors() {
+        let huge = SCENE.replace("\"integer nx\" 2 \"integer ny\" 1 \"integer nz\" 1", "\"integer nx\" 4e9 \"integer ny\" 4e9 \"integer nz\" 4e9");
+        assert!(matches!(parse_pbrt_medium(&huge, None).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+
+        let short = SCENE.replace("[ 0 4 ]", "[ 0 ]");
+        assert!(matches!(parse_pbrt_medium(&short, None).err().map(|e| e.0), Some(VolumeReadErrorType::NotEnoughData)));
+    }
+}
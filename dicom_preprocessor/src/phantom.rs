@@ -0,0 +1,472 @@
+use crate::dicom_export::{
+    decimal_string, error, put_string, put_u16, uid_from_random, write_file, DicomWriteError, DicomWriteErrorType, BITS_ALLOCATED, BITS_STORED, COLUMNS,
+    EXPLICIT_VR_LITTLE_ENDIAN, FRAME_OF_REFERENCE_UID, HIGH_BIT, IMAGE_ORIENTATION_PATIENT, IMAGE_POSITION_PATIENT, IMAGE_TYPE, INSTANCE_NUMBER, MODALITY,
+    PHOTOMETRIC_INTERPRETATION, PIXEL_DATA, PIXEL_REPRESENTATION, PIXEL_SPACING, RESCALE_INTERCEPT, RESCALE_SLOPE, ROWS, SAMPLES_PER_PIXEL, SERIES_DESCRIPTION,
+    SERIES_INSTANCE_UID, SERIES_NUMBER, SLICE_THICKNESS, SOP_CLASS_UID, SOP_INSTANCE_UID, STUDY_INSTANCE_UID,
+};
+use dicom_core::{DataElement, PrimitiveValue, Tag, VR};
+use dicom_object::InMemDicomObject;
+use glam::{EulerRot, Quat, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use crate::dicom_export::write_zip;
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Synthetic DICOM series of analytic phantoms, for testing the loaders and as a public demo dataset.
+// Shepp-Logan parameters are the modified 3D set from Toft, "The Radon Transform" (1996).
+
+const CT_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.2";
+
+const PATIENT_NAME: Tag = Tag(0x0010, 0x0010);
+const PATIENT_ID: Tag = Tag(0x0010, 0x0020);
+const STUDY_ID: Tag = Tag(0x0020, 0x0010);
+const SLICE_LOCATION: Tag = Tag(0x0020, 0x1041);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PhantomShape {
+    /// ellipsoids of the modified 3D Shepp-Logan head phantom
+    SheppLogan,
+    /// eight spheres with increasing densities, one per octant
+    Spheres,
+    /// linear ramp along the volume's diagonal
+    Gradient,
+}
+
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+
+/// Description of a synthetic series. The phantom's densities from 0 to 1 are spread over the
+/// range of the stored values, the rescale parameters are only written as given.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct PhantomOptions {
+    pub shape: PhantomShape,
+    pub size_x: u32,
+    pub size_y: u32,
+    pub size_z: u32,
+    pub spacing_x: f32,
+    pub spacing_y: f32,
+    pub spacing_z: f32,
+    /// 8 or 16
+    pub bits_allocated: u16,
+    pub bits_stored: u16,
+    pub signed: bool,
+    pub rescale_slope: f32,
+    pub rescale_intercept: f32,
+    /// rotation of the volume around the patient axes in degrees, applied in x, y, z order
+    pub rotation_x: f32,
+    pub rotation_y: f32,
+    pub rotation_z: f32,
+    /// selects the transfer syntax, big endian is only defined with explicit VRs
+    pub implicit_vr: bool,
+    pub big_endian: bool,
+    /// write the slices in random order instead of by position
+    pub shuffle_slices: bool,
+    /// seed for the shuffle and the UIDs, so the same options produce the same files
+    pub seed: u32,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl PhantomOptions {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(shape: PhantomShape, size_x: u32, size_y: u32, size_z: u32) -> Self {
+        Self {
+            shape,
+            size_x,
+            size_y,
+            size_z,
+            spacing_x: 1.0,
+            spacing_y: 1.0,
+            spacing_z: 1.0,
+            bits_allocated: 16,
+            bits_stored: 12,
+            signed: false,
+            rescale_slope: 1.0,
+            rescale_intercept: 0.0,
+            rotation_x: 0.0,
+            rotation_y: 0.0,
+            rotation_z: 0.0,
+            implicit_vr: false,
+            big_endian: false,
+            shuffle_slices: false,
+            seed: 0,
+        }
+    }
+}
+
+/// xorshift64*, good enough for shuffling and unique UIDs
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u32) -> Self {
+        // the constant keeps the state from being zero
+        Self(seed as u64 ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn uid(&mut self) -> String {
+        uid_from_random(((self.next() as u128) << 64) | self.next() as u128)
+    }
+}
+
+struct Ellipsoid {
+    density: f32,
+    semi_axes: [f32; 3],
+    center: [f32; 3],
+    /// z-x-z euler angles in degrees
+    angles: [f32; 3],
+}
+
+const fn ellipsoid(density: f32, semi_axes: [f32; 3], center: [f32; 3], angles: [f32; 3]) -> Ellipsoid {
+    Ellipsoid { density, semi_axes, center, angles }
+}
+
+const SHEPP_LOGAN: [Ellipsoid; 10] = [
+    ellipsoid(1.0, [0.69, 0.92, 0.81], [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]),
+    ellipsoid(-0.8, [0.6624, 0.874, 0.78], [0.0, -0.0184, 0.0], [0.0, 0.0, 0.0]),
+    ellipsoid(-0.2, [0.11, 0.31, 0.22], [0.22, 0.0, 0.0], [-18.0, 0.0, 10.0]),
+    ellipsoid(-0.2, [0.16, 0.41, 0.28], [-0.22, 0.0, 0.0], [18.0, 0.0, 10.0]),
+    ellipsoid(0.1, [0.21, 0.25, 0.41], [0.0, 0.35, -0.15], [0.0, 0.0, 0.0]),
+    ellipsoid(0.1, [0.046, 0.046, 0.05], [0.0, 0.1, 0.25], [0.0, 0.0, 0.0]),
+    ellipsoid(0.1, [0.046, 0.046, 0.05], [0.0, -0.1, 0.25], [0.0, 0.0, 0.0]),
+    ellipsoid(0.1, [0.046, 0.023, 0.05], [-0.08, -0.605, 0.0], [0.0, 0.0, 0.0]),
+    ellipsoid(0.1, [0.023, 0.023, 0.02], [0.0, -0.606, 0.0], [0.0, 0.0, 0.0]),
+    ellipsoid(0.1, [0.023, 0.046, 0.02], [0.06, -0.605, 0.0], [0.0, 0.0, 0.0]),
+];
+
+/// Density of the phantom at a position within [-1, 1] on each axis
+fn density(shape: PhantomShape, position: Vec3) -> f32 {
+    match shape {
+        PhantomShape::SheppLogan => SHEPP_LOGAN
+            .iter()
+            .filter(|ellipsoid| {
+                let [phi, theta, psi] = ellipsoid.angles.map(f32::to_radians);
+                let rotation = Quat::from_euler(EulerRot::ZXZ, phi, theta, psi);
+                let local = rotation.inverse() * (position - Vec3::from_array(ellipsoid.center)) / Vec3::from_array(ellipsoid.semi_axes);
+                local.length_squared() <= 1.0
+            })
+            .map(|ellipsoid| ellipsoid.density)
+            .sum::<f32>(),
+        PhantomShape::Spheres => {
+            let octant = position.cmpge(Vec3::ZERO).bitmask();
+            let center = Vec3::new(0.45, 0.45, 0.45) * Vec3::select(position.cmpge(Vec3::ZERO), Vec3::ONE, Vec3::NEG_ONE);
+            if position.distance(center) <= 0.35 { (octant + 1) as f32 / 8.0 } else { 0.0 }
+        }
+        PhantomShape::Gradient => (position.element_sum() + 3.0) / 6.0,
+    }
+    .clamp(0.0, 1.0)
+}
+
+/// Generates the phantom as named DICOM files, one per slice
+pub fn generate_phantom(options: &PhantomOptions) -> Result<Vec<(String, Vec<u8>)>, DicomWriteError> {
+    let extent = UVec3::new(options.size_x, options.size_y, options.size_z);
+    if extent.min_element() == 0 || extent.x > u16::MAX as u32 || extent.y > u16::MAX as u32 {
+        return Err(error(DicomWriteErrorType::InvalidOptions, format!("Invalid phantom size {}", extent)));
+    }
+    if !matches!(options.bits_allocated, 8 | 16) || options.bits_stored == 0 || options.bits_stored > options.bits_allocated {
+        return Err(error(
+            DicomWriteErrorType::InvalidOptions,
+            format!("Can't store {} bits in {} allocated bits", options.bits_stored, options.bits_allocated),
+        ));
+    }
+    let transfer_syntax = match (options.implicit_vr, options.big_endian) {
+        (false, false) => EXPLICIT_VR_LITTLE_ENDIAN,
+        (true, false) => IMPLICIT_VR_LITTLE_ENDIAN,
+        (false, true) => EXPLICIT_VR_BIG_ENDIAN,
+        (true, true) => return Err(error(DicomWriteErrorType::InvalidOptions, "There is no implicit VR big endian transfer syntax")),
+    };
+
+    // stored value range, signed values are two's complement within the allocated bits
+    let bits = options.bits_stored as u32;
+    let (low, high) = if options.signed { (-(1i32 << (bits - 1)), (1i32 << (bits - 1)) - 1) } else { (0, (1i32 << bits) - 1) };
+    let mask = (1u32 << options.bits_allocated) - 1;
+
+    let spacing = Vec3::new(options.spacing_x, options.spacing_y, options.spacing_z);
+    let rotation = Quat::from_euler(
+        EulerRot::XYZ,
+        options.rotation_x.to_radians(),
+        options.rotation_y.to_radians(),
+        options.rotation_z.to_radians(),
+    );
+    let (row_direction, column_direction, slice_direction) = (rotation * Vec3::X, rotation * Vec3::Y, rotation * Vec3::Z);
+    // the volume is centered on the patient origin
+    let origin = rotation * (-(extent.as_vec3() - 1.0) * spacing / 2.0);
+    let orientation = decimal_string(&[row_direction.to_array(), column_direction.to_array()].concat());
+
+    let mut rng = Rng::new(options.seed);
+    let study_instance_uid = rng.uid();
+    let series_instance_uid = rng.uid();
+    let frame_of_reference_uid = rng.uid();
+    let shape_name = format!("{:?}", options.shape);
+
+    // slices are shuffled before the files are named, so sorting the files by name doesn't undo it
+    let mut order: Vec<u32> = (0..extent.z).collect();
+    if options.shuffle_slices {
+        for i in (1..order.len()).rev() {
+            order.swap(i, (rng.next() % (i as u64 + 1)) as usize);
+        }
+    }
+
+    let mut files = Vec::new();
+    for (file_index, z) in order.into_iter().enumerate() {
+        let mut samples = Vec::with_capacity((extent.x * extent.y) as usize);
+        for y in 0..extent.y {
+            for x in 0..extent.x {
+                let position = (UVec3::new(x, y, z).as_vec3() + 0.5) / extent.as_vec3() * 2.0 - 1.0;
+                let value = low + (density(options.shape, position) * (high - low) as f32).round() as i32;
+                samples.push(value as u32 & mask);
+            }
+        }
+
+        let sop_instance_uid = rng.uid();
+        let position = origin + slice_direction * spacing.z * z as f32;
+        let mut object = InMemDicomObject::new_empty();
+        put_string(&mut object, PATIENT_NAME, VR::PN, format!("Phantom^{}", shape_name));
+        put_string(&mut object, PATIENT_ID, VR::LO, "PHANTOM");
+        put_string(&mut object, STUDY_INSTANCE_UID, VR::UI, study_instance_uid.as_str());
+        put_string(&mut object, STUDY_ID, VR::SH, "1");
+        put_string(&mut object, IMAGE_TYPE, VR::CS, "ORIGINAL\\PRIMARY\\AXIAL");
+        put_string(&mut object, SOP_CLASS_UID, VR::UI, CT_IMAGE_STORAGE);
+        put_string(&mut object, SOP_INSTANCE_UID, VR::UI, sop_instance_uid.as_str());
+        put_string(&mut object, MODALITY, VR::CS, "CT");
+        put_string(&mut object, SERIES_DESCRIPTION, VR::LO, format!("{} phantom", shape_name));
+        put_string(&mut object, SERIES_INSTANCE_UID, VR::UI, series_instance_uid.as_str());
+        put_string(&mut object, SERIES_NUMBER, VR::IS, "1");
+        put_string(&mut object, INSTANCE_NUMBER, VR::IS, (z + 1).to_string());
+        put_string(&mut object, FRAME_OF_REFERENCE_UID, VR::UI, frame_of_reference_uid.as_str());
+        put_string(&mut object, IMAGE_POSITION_PATIENT, VR::DS, decimal_string(&position.to_array()));
+        put_string(&mut object, IMAGE_ORIENTATION_PATIENT, VR::DS, orientation.as_str());
+        put_string(&mut object, SLICE_LOCATION, VR::DS, decimal_string(&[position.dot(slice_direction)]));
+        put_string(&mut object, PIXEL_SPACING, VR::DS, decimal_string(&[spacing.y, spacing.x]));
+        put_string(&mut object, SLICE_THICKNESS, VR::DS, decimal_string(&[spacing.z]));
+        put_string(&mut object, RESCALE_INTERCEPT, VR::DS, decimal_string(&[options.rescale_intercept]));
+        put_string(&mut object, RESCALE_SLOPE, VR::DS, decimal_string(&[options.rescale_slope]));
+        put_u16(&mut object, SAMPLES_PER_PIXEL, 1);
+        put_string(&mut object, PHOTOMETRIC_INTERPRETATION, VR::CS, "MONOCHROME2");
+        put_u16(&mut object, ROWS, extent.y as u16);
+        put_u16(&mut object, COLUMNS, extent.x as u16);
+        put_u16(&mut object, BITS_ALLOCATED, options.bits_allocated);
+        put_u16(&mut object, BITS_STORED, options.bits_stored);
+        put_u16(&mut object, HIGH_BIT, options.bits_stored - 1);
+        put_u16(&mut object, PIXEL_REPRESENTATION, options.signed as u16);
+        // 16 bit samples are kept as words, so they get swapped for big endian transfer syntaxes
+        let pixel_data = if options.bits_allocated == 8 {
+            DataElement::new(PIXEL_DATA, VR::OB, PrimitiveValue::from(samples.iter().map(|sample| *sample as u8).collect::<Vec<u8>>()))
+        } else {
+            DataElement::new(PIXEL_DATA, VR::OW, PrimitiveValue::U16(samples.iter().map(|sample| *sample as u16).collect()))
+        };
+        object.put(pixel_data);
+
+        let bytes = write_file(object, transfer_syntax, CT_IMAGE_STORAGE, &sop_instance_uid)?;
+        files.push((format!("IM{:05}.dcm", file_index + 1), bytes));
+    }
+    Ok(files)
+}
+
+/// Generates a phantom series and loads it, for tests of what is built from a loaded series
+#[cfg(test)]
+pub(crate) fn read_phantom(options: &PhantomOptions) -> crate::DicomDataInternal {
+    let files = generate_phantom(options).unwrap_or_else(|e| panic!("{}", e.message()));
+    crate::read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).unwrap_or_else(|e| panic!("{}", e.message()))
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn generate_phantom_files(options: &PhantomOptions) -> Result<Vec<Uint8Array>, DicomWriteError> {
+    Ok(generate_phantom(options)?.iter().map(|(_, bytes)| Uint8Array::from(bytes.as_slice())).collect())
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn generate_phantom_zip(options: &PhantomOptions) -> Result<Uint8Array, DicomWriteError> {
+    Ok(Uint8Array::from(write_zip(&generate_phantom(options)?)?.as_slice()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickGrid;
+    use crate::brick_config::{AtlasPrecision, BrickGridConfig};
+    use crate::dicom_export::write_zip;
+    use crate::grid::Grid;
+    use crate::progress::LoadProgress;
+    use crate::dicom_slice::stream_dicom_series;
+    use crate::zip::{read_dicom_zip, stream_dicom_zip, ZipReadErrorType};
+    use std::borrow::Cow;
+    use crate::{read_dicom_series, split_dicom_series, DicomDataInternal};
+    use glam::Mat4;
+
+    /// Stored values as the reader returns them, signed ones raised to start at 0, in volume order
+    fn expected_samples(options: &PhantomOptions) -> Vec<u16> {
+        let extent = UVec3::new(options.size_x, options.size_y, options.size_z);
+        let range = ((1u32 << options.bits_stored) - 1) as f32;
+        let mut samples = Vec::new();
+        for z in 0..extent.z {
+            for y in 0..extent.y {
+                for x in 0..extent.x {
+                    let position = (UVec3::new(x, y, z).as_vec3() + 0.5) / extent.as_vec3() * 2.0 - 1.0;
+                    samples.push((density(options.shape, position) * range).round() as u16);
+                }
+            }
+        }
+        samples
+    }
+
+    fn expected_transform(options: &PhantomOptions) -> Mat4 {
+        let extent = UVec3::new(options.size_x, options.size_y, options.size_z);
+        let spacing = Vec3::new(options.spacing_x, options.spacing_y, options.spacing_z);
+        let rotation = Quat::from_euler(EulerRot::XYZ, options.rotation_x.to_radians(), options.rotation_y.to_radians(), options.rotation_z.to_radians());
+        Mat4::from_translation(rotation * (-(extent.as_vec3() - 1.0) * spacing / 2.0)) * Mat4::from_quat(rotation) * Mat4::from_scale(spacing)
+    }
+
+    fn read(files: &[(String, Vec<u8>)]) -> DicomDataInternal {
+        read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).unwrap_or_else(|e| panic!("{}", e.message()))
+    }
+
+    fn assert_matches(dicom: &DicomDataInternal, options: &PhantomOptions) {
+        assert_eq!(dicom.data.stride, UVec3::new(options.size_x, options.size_y, options.size_z));
+        assert!(dicom.data.data == expected_samples(options), "samples of {:?} differ", options);
+        let transform = expected_transform(options);
+        assert!(dicom.transform.abs_diff_eq(transform, 1e-4), "{} != {}", dicom.transform, transform);
+    }
+
+    fn shuffled(shape: PhantomShape) -> PhantomOptions {
+        let mut options = PhantomOptions::new(shape, 12, 10, 9);
+        options.spacing_x = 0.75;
+        options.spacing_y = 0.5;
+        options.spacing_z = 2.0;
+        options.rotation_x = 10.0;
+        options.rotation_z = -35.0;
+        options.shuffle_slices = true;
+        options.seed = 7;
+        options
+    }
+
+    #[test]
+    fn series_are_read_back_in_slice_order() {
+        let mut variants = Vec::new();
+        for (bits_allocated, bits_stored, signed) in [(16, 12, false), (16, 16, true), (16, 10, true), (8, 8, false), (8, 6, true)] {
+            for (implicit_vr, big_endian) in [(false, false), (true, false), (false, true)] {
+                let mut options = shuffled(PhantomShape::SheppLogan);
+                options.bits_allocated = bits_allocated;
+                options.bits_stored = bits_stored;
+                options.signed = signed;
+                options.implicit_vr = implicit_vr;
+                options.big_endian = big_endian;
+                variants.push(options);
+            }
+        }
+        for options in variants {
+            let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+            assert_matches(&read(&files), &options);
+        }
+    }
+
+    #[test]
+    fn shuffled_files_stay_shuffled_by_name() {
+        let options = shuffled(PhantomShape::Gradient);
+        let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        let series = split_dicom_series(files.clone());
+        assert_eq!(series.len(), 1);
+        let names: Vec<&str> = series[0].files.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, files.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>());
+
+        // the files in name order aren't in slice order, but reading sorts them
+        let first_slice = read(&series[0].files[..1]);
+        let first_expected = &expected_samples(&options)[..(options.size_x * options.size_y) as usize];
+        assert!(series[0].files.len() > 1 && first_slice.data.data != first_expected);
+        assert_matches(&read(&series[0].files), &options);
+    }
+
+    #[test]
+    fn zipped_series_are_read_back_in_slice_order() {
+        let options = shuffled(PhantomShape::Spheres);
+        let zip = write_zip(&generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()))).unwrap_or_else(|e| panic!("{}", e.message()));
+        let dicom = read_dicom_zip(&zip).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_matches(&dicom, &options);
+    }
+
+    #[test]
+    fn zipped_series_stream_like_their_files() {
+        let options = shuffled(PhantomShape::Spheres);
+        let files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        let zip = write_zip(&files).unwrap_or_else(|e| panic!("{}", e.message()));
+        let config = BrickGridConfig::new(4, 2, 4, 4, 4).unwrap_or_else(|e| panic!("{}", e.message()));
+        let streamed = stream_dicom_zip(&zip, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+        let expected = stream_dicom_series(files.len(), &|index| Cow::Borrowed(files[index].1.as_slice()), config, LoadProgress::none())
+            .unwrap_or_else(|e| panic!("{}", e.message()));
+
+        assert_eq!(streamed.index_extent(), expected.index_extent());
+        assert_eq!(Grid::transform(&streamed), Grid::transform(&expected));
+        let extent = expected.index_extent();
+        for z in 0..extent.z {
+            for y in 0..extent.y {
+                for x in 0..extent.x {
+                    let ipos = UVec3::new(x, y, z);
+                    assert_eq!(streamed.lookup(ipos), expected.lookup(ipos), "voxel {}", ipos);
+                }
+            }
+        }
+
+        let length = zip.len();
+        assert!(stream_dicom_zip(&zip[..length / 2], config, LoadProgress::none()).is_err());
+    }
+
+    #[test]
+    fn broken_files_fail_the_series_instead_of_panicking() {
+        let options = shuffled(PhantomShape::Gradient);
+        let mut files = generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message()));
+        let length = files[1].1.len();
+        files[1].1.truncate(length / 2);
+        assert!(read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).is_err());
+        files[1].1 = b"not a DICOM file".to_vec();
+        assert!(read_dicom_series(files.iter().map(|(_, bytes)| bytes.as_slice())).is_err());
+
+        // slices of a different size are an invalid series, not a missing one
+        let other = PhantomOptions::new(PhantomShape::Gradient, 6, 5, 1);
+        files[1].1 = generate_phantom(&other).unwrap_or_else(|e| panic!("{}", e.message())).remove(0).1;
+        let zip = write_zip(&files).unwrap_or_else(|e| panic!("{}", e.message()));
+        let Err(error) = read_dicom_zip(&zip) else { panic!("slices of different sizes were read") };
+        assert!(matches!(error.0, ZipReadErrorType::InvalidSeries), "{:?}", error.0);
+    }
+
+    #[test]
+    fn signed_samples_keep_their_rescaled_values() {
+        let mut options = shuffled(PhantomShape::Spheres);
+        options.signed = true;
+        options.rescale_slope = 2.0;
+        options.rescale_intercept = -100.0;
+        let dicom = read(&generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message())));
+        // the lowest stored value, -2048, is read as 0
+        assert_eq!(dicom.rescale(), (2.0, -100.0 - 2.0 * 2048.0));
+    }
+
+    #[test]
+    fn bricked_series_match_their_source() {
+        let options = shuffled(PhantomShape::SheppLogan);
+        let dicom = read(&generate_phantom(&options).unwrap_or_else(|e| panic!("{}", e.message())));
+        let mut config = BrickGridConfig::new(4, 2, 4, 4, 4).unwrap_or_else(|e| panic!("{}", e.message()));
+        config.atlas_precision = AtlasPrecision::U16;
+        let grid = BrickGrid::construct_with_config(&dicom, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+
+        assert_eq!(Grid::transform(&grid), dicom.transform);
+        let extent = dicom.index_extent();
+        for z in 0..extent.z {
+            for y in 0..extent.y {
+                for x in 0..extent.x {
+                    let ipos = UVec3::new(x, y, z);
+                    assert!((grid.lookup(ipos) - dicom.lookup(ipos)).abs() < 1e-3, "voxel {} differs", ipos);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(feature = "wasm")]
+use js_sys::Function;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::JsValue;
+
+/// The phase of a load that progress is reported for
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadPhase {
+    /// counts files whose headers were read to sort them
+    ReadingHeaders,
+    /// counts decoded files
+    DecodingFiles,
+    /// counts processed rows of bricks
+    BuildingBricks,
+    /// counts finished range mipmap levels
+    BuildingMipmaps,
+}
+
+/// Aborts a running load when cancelled, clones share the same state
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl CancellationToken {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl CancellationToken {
+    pub fn check(&self) -> Result<(), LoadCancelled> {
+        if self.cancelled() { Err(LoadCancelled) } else { Ok(()) }
+    }
+}
+
+/// Returned by loads that were stopped through their [`CancellationToken`]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct LoadCancelled;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl LoadCancelled {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn message(self) -> String {
+        "Cancelled: The load was cancelled".to_string()
+    }
+}
+
+/// Where a load reports its progress to and checks for cancellation, both are optional
+#[derive(Clone, Copy, Default)]
+pub struct LoadProgress<'a> {
+    callback: Option<&'a dyn Fn(LoadPhase, usize, usize)>,
+    token: Option<&'a CancellationToken>,
+}
+
+impl<'a> LoadProgress<'a> {
+    /// Neither reports progress nor can be cancelled
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// Calls `callback` with the phase, the steps done and the total steps of the phase
+    pub fn with_callback(self, callback: &'a dyn Fn(LoadPhase, usize, usize)) -> Self {
+        Self { callback: Some(callback), ..self }
+    }
+
+    pub fn with_token(self, token: &'a CancellationToken) -> Self {
+        Self { token: Some(token), ..self }
+    }
+
+    pub fn report(&self, phase: LoadPhase, done: usize, total: usize) {
+        if let Some(callback) = self.callback {
+            callback(phase, done, total);
+        }
+    }
+
+    pub fn check(&self) -> Result<(), LoadCancelled> {
+        self.token.map_or(Ok(()), CancellationToken::check)
+    }
+
+    /// The token alone, which unlike the callback can be checked from other threads
+    pub fn token(&self) -> Option<&'a CancellationToken> {
+        self.token
+    }
+
+    /// Reports progress, then checks for cancellation, since the callback may be what cancels
+    pub fn step(&self, phase: LoadPhase, done: usize, total: usize) -> Result<(), LoadCancelled> {
+        self.report(phase, done, total);
+        self.check()
+    }
+}
+
+/// Wraps a JS function taking `(phase: LoadPhase, done: number, total: number)` as progress callback
+#[cfg(feature = "wasm")]
+pub fn js_progress_callback(on_progress: &Function) -> impl Fn(LoadPhase, usize, usize) + '_ {
+    move |phase, done, total| {
+        // exceptions thrown by the callback aren't our problem, the load continues
+        let _ = on_progress.call3(&JsValue::NULL, &JsValue::from(phase as u32), &JsValue::from(done as u32), &JsValue::from(total as u32));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brick::BrickGrid;
+    use crate::brick_config::BrickGridConfig;
+    use crate::buf3d::Buf3D;
+    use crate::dicom_slice::stream_dicom_series;
+    use crate::grid::Grid;
+    use crate::phantom::{generate_phantom, PhantomOptions, PhantomShape};
+    use crate::volume::{DenseGrid, VolumeReadErrorType};
+    use glam::{Mat4, UVec3};
+    use std::borrow::Cow;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Cancels `token` on its first lookup and counts the lookups
+    struct CancellingGrid<'a> {
+        grid: DenseGrid,
+        token: &'a CancellationToken,
+        lookups: AtomicUsize,
+    }
+
+    impl Grid for CancellingGrid<'_> {
+        fn lookup(&self, ipos: UVec3) -> f32 {
+            self.token.cancel();
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            self.grid.lookup(ipos)
+        }
+
+        fn minorant_majorant(&self) -> (f32, f32) {
+            self.grid.minorant_majorant()
+        }
+
+        fn index_extent(&self) -> UVec3 {
+            self.grid.index_extent()
+        }
+
+        fn num_voxels(&self) -> usize {
+            self.grid.num_voxels()
+        }
+
+        fn size_bytes(&self) -> usize {
+            self.grid.size_bytes()
+        }
+
+        fn histogram(&self) -> Vec<u32> {
+            self.grid.histogram()
+        }
+
+        fn histogram_gradient(&self) -> (Vec<i32>, u32, u32) {
+            self.grid.histogram_gradient()
+        }
+
+        fn transform(&self) -> Mat4 {
+            self.grid.transform()
+        }
+    }
+
+    fn cancel_on(phase: LoadPhase, token: &CancellationToken) -> impl Fn(LoadPhase, usize, usize) + '_ {
+        move |reported, _, _| {
+            assert!(!token.cancelled(), "{:?} was reported after cancelling", reported);
+            if reported == phase {
+                token.cancel();
+            }
+        }
+    }
+
+    #[test]
+    fn cancelling_from_the_callback_stops_bricking() {
+        let grid = DenseGrid::new(Buf3D::new(UVec3::splat(16)), Mat4::IDENTITY);
+        let token = CancellationToken::new();
+        let callback = cancel_on(LoadPhase::BuildingBricks, &token);
+        let progress = LoadProgress::none().with_callback(&callback).with_token(&token);
+        let bricked = BrickGrid::construct_with_config(&grid, BrickGridConfig::default(), progress);
+        assert!(matches!(bricked.err().map(|e| e.0), Some(VolumeReadErrorType::Cancelled)));
+    }
+
+    #[test]
+    fn cancelling_from_the_callback_stops_streaming() {
+        let files = generate_phantom(&PhantomOptions::new(PhantomShape::Spheres, 6, 4, 2)).unwrap_or_else(|e| panic!("{}", e.message()));
+        let token = CancellationToken::new();
+        let callback = cancel_on(LoadPhase::DecodingFiles, &token);
+        let progress = LoadProgress::none().with_callback(&callback).with_token(&token);
+        let streamed = stream_dicom_series(files.len(), &|index| Cow::Borrowed(files[index].1.as_slice()), BrickGridConfig::default(), progress);
+        assert!(matches!(streamed.err().map(|e| e.0), Some(VolumeReadErrorType::Cancelled)));
+    }
+
+    #[test]
+    fn bricking_stops_within_a_slab() {
+        // the first slab has 256 rows of 8 bricks, padded for the mip levels, far more rows than run at once
+        let token = CancellationToken::new();
+        let grid = CancellingGrid { grid: DenseGrid::new(Buf3D::new(UVec3::new(8, 2048, 8)), Mat4::IDENTITY), token: &token, lookups: AtomicUsize::new(0) };
+        let bricked = BrickGrid::construct_with_config(&grid, BrickGridConfig::default(), LoadProgress::none().with_token(&token));
+        assert!(matches!(bricked.err().map(|e| e.0), Some(VolumeReadErrorType::Cancelled)));
+
+        let row_lookups = 8 * 12usize.pow(3);
+        let lookups = grid.lookups.load(Ordering::Relaxed);
+        assert!(lookups < 128 * row_lookups, "{} rows were looked up after cancelling", lookups / row_lookups);
+    }
+}
@@ -0,0 +1,130 @@
+use crate::buf3d::Buf3D;
+use crate::utils::log_to_console;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use crate::{brick::BrickGrid, brick_config::BrickGridConfig, utils::now, volume::volume_to_grid};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// User supplied description of a headerless volume file
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug)]
+pub struct RawLayout {
+    pub size_x: u32,
+    pub size_y: u32,
+    pub size_z: u32,
+    pub sample_type: SampleType,
+    pub big_endian: bool,
+    pub spacing_x: f32,
+    pub spacing_y: f32,
+    pub spacing_z: f32,
+    /// number of bytes to skip before the sample data begins
+    pub header_offset: usize,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl RawLayout {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(size_x: u32, size_y: u32, size_z: u32, sample_type: SampleType) -> Self {
+        Self {
+            size_x,
+            size_y,
+            size_z,
+            sample_type,
+            big_endian: false,
+            spacing_x: 1.0,
+            spacing_y: 1.0,
+            spacing_z: 1.0,
+            header_offset: 0,
+        }
+    }
+}
+
+pub fn parse_raw(bytes: &[u8], layout: &RawLayout) -> Result<DenseGrid, VolumeReadError> {
+    let extent = UVec3::new(layout.size_x, layout.size_y, layout.size_z);
+    if extent.min_element() == 0 {
+        return Err(VolumeReadError::new(VolumeReadErrorType::InvalidHeader, "Volume dimensions may not be zero"));
+    }
+    let count = voxel_count(extent)?;
+    let needed = count
+        .checked_mul(layout.sample_type.size())
+        .and_then(|size| size.checked_add(layout.header_offset))
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("A volume of {} voxels is too large", extent)))?;
+    if bytes.len() < needed {
+        return Err(VolumeReadError::new(
+            VolumeReadErrorType::NotEnoughData,
+            format!("Layout requires {} bytes, but the file only has {}", needed, bytes.len()),
+        ));
+    }
+    if bytes.len() > needed {
+        log_to_console(&format!("Ignoring {} trailing bytes of raw volume", bytes.len() - needed));
+    }
+
+    let endianness = if layout.big_endian { Endianness::Big } else { Endianness::Little };
+    let samples = decode_samples(&bytes[layout.header_offset..], layout.sample_type, endianness, count)?;
+
+    let mut data = Buf3D::new(extent);
+    data.data = samples;
+    Ok(DenseGrid::new(data, Mat4::from_scale(Vec3::new(layout.spacing_x, layout.spacing_y, layout.spacing_z))))
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_raw(bytes: Uint8Array, layout: &RawLayout) -> Result<DenseGrid, VolumeReadError> {
+    parse_raw(&bytes.to_vec(), layout)
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_raw_to_grid(bytes: Uint8Array, layout: &RawLayout, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting raw volume load");
+    let start = now();
+    let volume = read_raw(bytes, layout)?;
+    let end = now();
+    log_to_console(&format!("Finished loading in {}", end - start));
+    volume_to_grid(volume, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{sample_grid, Grid};
+
+    fn layout() -> RawLayout {
+        let mut layout = RawLayout::new(2, 2, 1, SampleType::I16);
+        layout.big_endian = true;
+        layout.header_offset = 3;
+        layout.spacing_z = 2.5;
+        layout
+    }
+
+    #[test]
+    fn big_endian_samples_follow_the_header() {
+        let mut bytes = vec![0xff; 3];
+        bytes.extend([-300i16, -100, 100, 300].iter().flat_map(|value| value.to_be_bytes()));
+        let volume = parse_raw(&bytes, &layout()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(2, 2, 1));
+        assert_eq!(sample_grid(&volume), [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+        assert_eq!(volume.transform(), Mat4::from_scale(Vec3::new(1.0, 1.0, 2.5)));
+    }
+
+    #[test]
+    fn short_files_are_not_enough_data() {
+        // the header offset counts towards the needed bytes
+        let error = parse_raw(&[0; 8], &layout()).err().expect("the file is three bytes short");
+        assert!(matches!(error.0, VolumeReadErrorType::NotEnoughData));
+    }
+
+    #[test]
+    fn overflowing_layouts_are_unsupported() {
+        let huge = RawLayout::new(u32::MAX, u32::MAX, u32::MAX, SampleType::F64);
+        assert!(matches!(parse_raw(&[0; 8], &huge).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+
+        let mut offset = RawLayout::new(1, 1, 1, SampleType::U8);
+        offset.header_offset = usize::MAX;
+        assert!(matches!(parse_raw(&[0; 8], &offset).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::buf3d::Buf3D;
+use crate::utils::natural_cmp;
+use crate::volume::{DenseGrid, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use std::io::Cursor;
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+const RESOLUTION_UNIT_INCH: u16 = 2;
+const RESOLUTION_UNIT_CENTIMETER: u16 = 3;
+
+fn tiff_error(error: tiff::TiffError) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, error.to_string())
+}
+
+fn to_floats(result: DecodingResult) -> Vec<f32> {
+    match result {
+        DecodingResult::U8(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U16(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U32(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::U64(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::F16(data) => data.into_iter().map(|v| v.to_f32()).collect(),
+        DecodingResult::F32(data) => data,
+        DecodingResult::F64(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I8(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I16(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I32(data) => data.into_iter().map(|v| v as f32).collect(),
+        DecodingResult::I64(data) => data.into_iter().map(|v| v as f32).collect(),
+    }
+}
+
+/// Size of a unit in millimeters, for the units written by ImageJ
+fn unit_to_mm(unit: &str) -> Option<f32> {
+    match unit {
+        "nm" | "nanometer" => Some(1e-6),
+        "micron" | "um" | "µm" | "micrometer" => Some(1e-3),
+        "mm" | "millimeter" => Some(1.0),
+        "cm" | "centimeter" => Some(10.0),
+        "m" | "meter" => Some(1000.0),
+        "inch" => Some(25.4),
+        _ => None,
+    }
+}
+
+/// Voxel spacing stored in the first page, from the resolution tags and ImageJ's image description
+fn read_spacing(decoder: &mut Decoder<Cursor<&[u8]>>) -> Option<Vec3> {
+    let resolution = |decoder: &mut Decoder<Cursor<&[u8]>>, tag: Tag| match decoder.find_tag(tag).ok()?? {
+        tiff::decoder::ifd::Value::Rational(numerator, denominator) if numerator != 0 && denominator != 0 => Some(numerator as f32 / denominator as f32),
+        tiff::decoder::ifd::Value::Float(value) if value > 0.0 => Some(value),
+        _ => None,
+    };
+    let x_resolution = resolution(decoder, Tag::XResolution)?;
+    let y_resolution = resolution(decoder, Tag::YResolution).unwrap_or(x_resolution);
+
+    // ImageJ stores the unit and slice spacing in the description, e.g. "ImageJ=1.53\nunit=micron\nspacing=2.5\n"
+    let description = decoder.get_tag_ascii_string(Tag::ImageDescription).unwrap_or_default();
+    let imagej_value = |key: &str| {
+        description
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(k, _)| k.trim() == key)
+            .map(|(_, v)| v.trim().to_string())
+    };
+
+    let unit = match decoder.find_tag_unsigned::<u16>(Tag::ResolutionUnit).ok().flatten() {
+        Some(RESOLUTION_UNIT_INCH) => 25.4,
+        Some(RESOLUTION_UNIT_CENTIMETER) => 10.0,
+        _ => imagej_value("unit").and_then(|unit| unit_to_mm(&unit)).unwrap_or(1.0),
+    };
+    let x = unit / x_resolution;
+    let y = unit / y_resolution;
+    let z = imagej_value("spacing").and_then(|spacing| spacing.parse::<f32>().ok()).map_or(x.min(y), |spacing| spacing * unit);
+    Some(Vec3::new(x, y, z))
+}
+
+/// Stacks all grayscale pages of all given files, in natural file name order. The spacing is taken
+/// from `spacing` if given, otherwise from the resolution tags of the first page.
+pub fn parse_tiff_stack(mut files: Vec<(String, Vec<u8>)>, spacing: Option<Vec3>) -> Result<DenseGrid, VolumeReadError> {
+    files.sort_by(|(a, _), (b, _)| natural_cmp(a, b));
+
+    let mut extent: Option<UVec3> = None;
+    let mut tiff_spacing = None;
+    let mut samples = Vec::new();
+    for (name, bytes) in &files {
+        let mut decoder = Decoder::new(Cursor::new(bytes.as_slice())).map_err(tiff_error)?;
+        loop {
+            let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+            match decoder.colortype().map_err(tiff_error)? {
+                ColorType::Gray(_) | ColorType::Multiband { num_samples: 1, .. } => {}
+                other => {
+                    return Err(VolumeReadError::new(
+                        VolumeReadErrorType::Unsupported,
+                        format!("Page of \"{}\" has unsupported color type {:?}, only grayscale is supported", name, other),
+                    ))
+                }
+            }
+
+            match &mut extent {
+                None => {
+                    extent = Some(UVec3::new(width, height, 1));
+                    tiff_spacing = read_spacing(&mut decoder);
+                }
+                Some(extent) if extent.x == width && extent.y == height => extent.z += 1,
+                Some(extent) => {
+                    return Err(VolumeReadError::new(
+                        VolumeReadErrorType::InvalidHeader,
+                        format!("Page of \"{}\" is {}x{}, expected {}x{}", name, width, height, extent.x, extent.y),
+                    ))
+                }
+            }
+            samples.append(&mut to_floats(decoder.read_image().map_err(tiff_error)?));
+
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().map_err(tiff_error)?;
+        }
+    }
+
+    let extent = extent.ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "No TIFF pages were provided"))?;
+    let spacing = spacing.or(tiff_spacing).unwrap_or(Vec3::ONE);
+
+    let mut data = Buf3D::new(extent);
+    data.data = samples;
+    Ok(DenseGrid::new(data, Mat4::from_scale(spacing)))
+}
+
+/// Reads a folder of TIFF slices or a single multi-page TIFF. `spacing` optionally overrides the
+/// x, y and z voxel spacing stored in the files.
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_tiff_stack(file_names: Vec<String>, files: Vec<Uint8Array>, spacing: Option<Vec<f32>>) -> Result<DenseGrid, VolumeReadError> {
+    let spacing = match spacing.as_deref() {
+        Some([x, y, z]) => Some(Vec3::new(*x, *y, *z)),
+        Some(_) => return Err(VolumeReadError::new(VolumeReadErrorType::InvalidHeader, "Spacing needs exactly three components")),
+        None => None,
+    };
+    let files = file_names.into_iter().zip(files.iter().map(|file| file.to_vec())).collect();
+    parse_tiff_stack(files, spacing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    fn gray8(pages: &[[u8; 4]]) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut bytes).unwrap();
+        for page in pages {
+            encoder.write_image::<colortype::Gray8>(2, 2, page).unwrap();
+        }
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn pages_are_stacked_in_natural_file_order() {
+        let files = vec![
+            ("slice10.tif".to_string(), gray8(&[[6, 7, 8, 9]])),
+            ("slice2.tif".to_string(), gray8(&[[0, 1, 2, 3], [3, 4, 5, 6]])),
+        ];
+        let volume = parse_tiff_stack(files, Some(Vec3::new(0.5, 0.5, 2.0))).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(volume.index_extent(), UVec3::new(2, 2, 3));
+        assert_eq!(volume.transform(), Mat4::from_scale(Vec3::new(0.5, 0.5, 2.0)));
+        // the first voxel of every page
+        for (z, first) in [0.0, 3.0, 6.0].into_iter().enumerate() {
+            assert_eq!(volume.lookup(UVec3::new(0, 0, z as u32)), first / 9.0);
+        }
+    }
+
+    #[test]
+    fn mismatched_and_color_pages_are_rejected() {
+        let mut small = Cursor::new(Vec::new());
+        TiffEncoder::new(&mut small).unwrap().write_image::<colortype::Gray8>(1, 1, &[0]).unwrap();
+        let files = vec![("a.tif".to_string(), gray8(&[[0; 4]])), ("b.tif".to_string(), small.into_inner())];
+        assert!(matches!(parse_tiff_stack(files, None).err().map(|e| e.0), Some(VolumeReadErrorType::InvalidHeader)));
+
+        let mut rgb = Cursor::new(Vec::new());
+        TiffEncoder::new(&mut rgb).unwrap().write_image::<colortype::RGB8>(1, 1, &[0, 0, 0]).unwrap();
+        let files = vec![("rgb.tif".to_string(), rgb.into_inner())];
+        assert!(matches!(parse_tiff_stack(files, None).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+    }
+}
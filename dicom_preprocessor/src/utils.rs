@@ -1,6 +1,9 @@
 use crate::{DOUBLE_FLOAT_PIXEL_DATA, FLOAT_PIXEL_DATA, PIXEL_DATA};
 use dicom_core::value::DicomValueType;
 use dicom_object::InMemDicomObject;
+use std::cmp::Ordering;
+use std::sync::RwLock;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
 use wasm_bindgen::prelude::*;
 
 pub fn set_panic_hook() {
@@ -14,10 +17,62 @@ pub fn set_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
+pub type Logger = Box<dyn Fn(&str) + Send + Sync>;
+/// Returns the current time in milliseconds, only differences between calls are used
+pub type Clock = fn() -> f64;
+
+static LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+static CLOCK: RwLock<Option<Clock>> = RwLock::new(None);
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console, js_name = log)]
-    pub fn log_to_console(s: &str);
+    fn console_log(s: &str);
+}
+
+/// Replaces where log messages go, by default that's the browser console in wasm and stderr otherwise
+pub fn set_logger(logger: impl Fn(&str) + Send + Sync + 'static) {
+    *LOGGER.write().unwrap() = Some(Box::new(logger));
+}
+
+/// Replaces the clock used for timing, by default that's `Date.now()` in wasm and the system time otherwise
+pub fn set_clock(clock: Clock) {
+    *CLOCK.write().unwrap() = Some(clock);
+}
+
+pub fn log_to_console(s: &str) {
+    match LOGGER.read().unwrap().as_ref() {
+        Some(logger) => logger(s),
+        None => default_log(s),
+    }
+}
+
+pub fn now() -> f64 {
+    match *CLOCK.read().unwrap() {
+        Some(clock) => clock(),
+        None => default_now(),
+    }
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn default_log(s: &str) {
+    console_log(s)
+}
+
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn default_log(s: &str) {
+    eprintln!("{}", s)
+}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+fn default_now() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(all(feature = "wasm", target_arch = "wasm32")))]
+fn default_now() -> f64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0.0, |time| time.as_secs_f64() * 1000.0)
 }
 
 pub fn debug_print_tags(obj: &InMemDicomObject, inset: usize) -> String {
@@ -62,4 +117,40 @@ pub fn debug_print_tags(obj: &InMemDicomObject, inset: usize) -> String {
         }
     }
     result
-}
\ No newline at end of file
+}
+/// Compares two strings treating runs of digits as numbers, so "slice_2" sorts before "slice_10"
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let mut x_digits = String::new();
+                while let Some(digit) = a.next_if(char::is_ascii_digit) {
+                    x_digits.push(digit);
+                }
+                let mut y_digits = String::new();
+                while let Some(digit) = b.next_if(char::is_ascii_digit) {
+                    y_digits.push(digit);
+                }
+                let x_trimmed = x_digits.trim_start_matches('0');
+                let y_trimmed = y_digits.trim_start_matches('0');
+                let ordering = x_trimmed.len().cmp(&y_trimmed.len()).then_with(|| x_trimmed.cmp(y_trimmed));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(&y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
@@ -0,0 +1,763 @@
+use crate::blosc;
+use crate::brick::BrickGrid;
+use crate::brick_config::BrickGridConfig;
+use crate::grid::{compute_histogram_gradient, Grid};
+use crate::progress::LoadProgress;
+use crate::utils::{log_to_console, now};
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use glam::{DMat4, DVec3, IVec3, Mat4, UVec3};
+use half::f16;
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use std::collections::HashMap;
+use std::io::Read;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Reader for OpenVDB files, following the layout written by openvdb/io/Archive.cc.
+// Only float grids with the standard 5-4-3 tree configuration are supported.
+
+const MAGIC: u64 = 0x56444220;
+/// file version from which per grid compression and node mask compression are stored
+const FILE_VERSION_NODE_MASK_COMPRESSION: u32 = 222;
+
+const COMPRESS_ZIP: u32 = 0x1;
+const COMPRESS_ACTIVE_MASK: u32 = 0x2;
+const COMPRESS_BLOSC: u32 = 0x4;
+
+// per node metadata describing how inactive values are stored
+const NO_MASK_OR_INACTIVE_VALS: u8 = 0;
+const NO_MASK_AND_ONE_INACTIVE_VAL: u8 = 2;
+const MASK_AND_NO_INACTIVE_VALS: u8 = 3;
+const MASK_AND_ONE_INACTIVE_VAL: u8 = 4;
+const MASK_AND_TWO_INACTIVE_VALS: u8 = 5;
+const NO_MASK_AND_ALL_VALS: u8 = 6;
+
+const LEAF_LOG2: u32 = 3;
+const LEAF_SIZE: usize = 1 << (3 * LEAF_LOG2);
+const INTERNAL1_LOG2: u32 = 4;
+const INTERNAL2_LOG2: u32 = 5;
+/// sizes of tiles larger than a leaf, held by the lower internal nodes and by the root
+const TILE_LOG2S: [u32; 2] = [INTERNAL1_LOG2 + LEAF_LOG2, INTERNAL2_LOG2 + INTERNAL1_LOG2 + LEAF_LOG2];
+
+const HISTOGRAM_BINS: usize = 4096;
+
+const FLOAT_GRID_TYPE: &str = "Tree_float_5_4_3";
+const HALF_FLOAT_SUFFIX: &str = "_HalfFloat";
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], VolumeReadError> {
+        let end = self.pos.checked_add(count).filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Unexpected end of VDB file"))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, VolumeReadError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, VolumeReadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn i32(&mut self) -> Result<i32, VolumeReadError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn i64(&mut self) -> Result<i64, VolumeReadError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    /// reads a size or position, which may not be negative
+    fn offset(&mut self) -> Result<usize, VolumeReadError> {
+        let value = self.i64()?;
+        usize::try_from(value).map_err(|_| invalid(format!("Invalid VDB offset {}", value)))
+    }
+    fn f32(&mut self) -> Result<f32, VolumeReadError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn f64(&mut self) -> Result<f64, VolumeReadError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+    fn dvec3(&mut self) -> Result<DVec3, VolumeReadError> {
+        Ok(DVec3::new(self.f64()?, self.f64()?, self.f64()?))
+    }
+    fn ivec3(&mut self) -> Result<IVec3, VolumeReadError> {
+        Ok(IVec3::new(self.i32()?, self.i32()?, self.i32()?))
+    }
+    fn string(&mut self) -> Result<String, VolumeReadError> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+    /// reads a node mask with the given number of bits
+    fn mask(&mut self, bits: usize) -> Result<Vec<u64>, VolumeReadError> {
+        Ok(self.take(bits / 8)?.chunks_exact(8).map(|word| u64::from_le_bytes(word.try_into().unwrap())).collect())
+    }
+    /// skips a metadata map, as none of the metadata is needed
+    fn skip_metadata(&mut self) -> Result<(), VolumeReadError> {
+        let count = self.u32()?;
+        for _ in 0..count {
+            self.string()?;
+            self.string()?;
+            let size = self.u32()? as usize;
+            self.take(size)?;
+        }
+        Ok(())
+    }
+}
+
+fn is_on(mask: &[u64], index: usize) -> bool {
+    mask[index >> 6] & (1 << (index & 63)) != 0
+}
+
+fn count_on(mask: &[u64]) -> usize {
+    mask.iter().map(|word| word.count_ones() as usize).sum()
+}
+
+/// coordinate of the n-th child of a node, x being the slowest and z the fastest axis
+fn offset_to_coord(offset: usize, log2: u32, child_log2: u32) -> IVec3 {
+    let dim_mask = (1 << log2) - 1;
+    let x = (offset >> (2 * log2)) as i32;
+    let y = ((offset >> log2) & dim_mask) as i32;
+    let z = (offset & dim_mask) as i32;
+    IVec3::new(x, y, z) << child_log2 as i32
+}
+
+struct StreamState {
+    compression: u32,
+    half: bool,
+    background: f32,
+}
+
+/// Reads a buffer of `count` values, see `readData` in openvdb/io/Compression.h
+fn read_data(reader: &mut Reader, count: usize, state: &StreamState) -> Result<Vec<f32>, VolumeReadError> {
+    let value_size = if state.half { 2 } else { 4 };
+    let size = count.checked_mul(value_size).ok_or_else(|| invalid(format!("VDB buffer of {} values is too large", count)))?;
+    let bytes = if state.compression & (COMPRESS_BLOSC | COMPRESS_ZIP) != 0 {
+        let compressed_size = reader.i64()?;
+        let stored_size = compressed_size
+            .checked_abs()
+            .and_then(|size| usize::try_from(size).ok())
+            .ok_or_else(|| invalid(format!("Invalid VDB buffer size {}", compressed_size)))?;
+        if compressed_size <= 0 {
+            // the data was stored uncompressed
+            reader.take(stored_size)?.to_vec()
+        } else {
+            let compressed = reader.take(stored_size)?;
+            if state.compression & COMPRESS_BLOSC != 0 {
+                blosc::decompress(compressed)?
+            } else {
+                let mut decompressed = Vec::with_capacity(size);
+                flate2::read::ZlibDecoder::new(compressed)
+                    .read_to_end(&mut decompressed)
+                    .map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+                decompressed
+            }
+        }
+    } else {
+        reader.take(size)?.to_vec()
+    };
+    if bytes.len() < size {
+        return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "VDB buffer is smaller than expected"));
+    }
+
+    Ok(if state.half {
+        bytes[..size].chunks_exact(2).map(|value| f16::from_le_bytes(value.try_into().unwrap()).to_f32()).collect()
+    } else {
+        bytes[..size].chunks_exact(4).map(|value| f32::from_le_bytes(value.try_into().unwrap())).collect()
+    })
+}
+
+/// Reads node values including restoring the inactive values, see `readCompressedValues` in openvdb/io/Compression.h
+fn read_compressed_values(reader: &mut Reader, count: usize, value_mask: &[u64], state: &StreamState) -> Result<Vec<f32>, VolumeReadError> {
+    let mask_compressed = state.compression & COMPRESS_ACTIVE_MASK != 0;
+    let metadata = reader.u8()?;
+
+    let mut inactive_value_1 = state.background;
+    let mut inactive_value_0 = if metadata == NO_MASK_OR_INACTIVE_VALS { state.background } else { -state.background };
+    if matches!(metadata, NO_MASK_AND_ONE_INACTIVE_VAL | MASK_AND_ONE_INACTIVE_VAL | MASK_AND_TWO_INACTIVE_VALS) {
+        inactive_value_0 = reader.f32()?;
+        if metadata == MASK_AND_TWO_INACTIVE_VALS {
+            inactive_value_1 = reader.f32()?;
+        }
+    }
+    let selection_mask = if matches!(metadata, MASK_AND_NO_INACTIVE_VALS | MASK_AND_ONE_INACTIVE_VAL | MASK_AND_TWO_INACTIVE_VALS) {
+        Some(reader.mask(count)?)
+    } else {
+        None
+    };
+
+    let stored_count = if mask_compressed && metadata != NO_MASK_AND_ALL_VALS { count_on(value_mask) } else { count };
+    let stored = read_data(reader, stored_count, state)?;
+    if stored_count == count {
+        return Ok(stored);
+    }
+
+    // only active values were stored, so fill in the inactive ones
+    let mut values = Vec::with_capacity(count);
+    let mut stored = stored.into_iter();
+    for i in 0..count {
+        if is_on(value_mask, i) {
+            values.push(stored.next().unwrap_or(state.background));
+        } else if selection_mask.as_ref().is_some_and(|mask| is_on(mask, i)) {
+            values.push(inactive_value_1);
+        } else {
+            values.push(inactive_value_0);
+        }
+    }
+    Ok(values)
+}
+
+enum LeafData {
+    Dense(Box<[f32]>),
+    Constant(f32),
+}
+
+/// A tile of constant value larger than a leaf
+struct Tile {
+    origin: IVec3,
+    size: i32,
+    value: f32,
+}
+
+/// Origins of the cells of `1 << log2` voxels per axis that overlap the voxels from `min` up to
+/// `max`, exclusive
+fn overlapped_cells(min: IVec3, max: IVec3, log2: u32) -> impl Iterator<Item = IVec3> {
+    let (first, last) = (min >> log2 as i32, (max - 1) >> log2 as i32);
+    (first.z..=last.z).flat_map(move |z| (first.y..=last.y).flat_map(move |y| (first.x..=last.x).map(move |x| IVec3::new(x, y, z) << log2 as i32)))
+}
+
+#[derive(Default)]
+struct Tree {
+    /// keyed by the leaf origin, holds both leaves and tiles of leaf size
+    leaves: HashMap<IVec3, LeafData>,
+    /// keyed by the tile origin, tiles of different sizes never share one as they don't overlap
+    tiles: HashMap<IVec3, Tile>,
+    /// leaf origins in the order their buffers are stored
+    leaf_order: Vec<IVec3>,
+}
+
+impl Tree {
+    fn add_tile(&mut self, origin: IVec3, log2: u32, value: f32) {
+        if log2 == LEAF_LOG2 {
+            self.leaves.insert(origin, LeafData::Constant(value));
+        } else {
+            self.tiles.insert(origin, Tile { origin, size: 1 << log2, value });
+        }
+    }
+
+    /// The tile of `log2` voxels per axis that holds a coordinate
+    fn tile(&self, coord: IVec3, log2: u32) -> Option<&Tile> {
+        let origin = coord & !((1 << log2) - 1);
+        self.tiles.get(&origin).filter(|tile| tile.size == 1 << log2)
+    }
+
+    /// Reads the topology of an internal node with `log2` children per axis
+    fn read_internal_topology(&mut self, reader: &mut Reader, origin: IVec3, log2: u32, state: &StreamState) -> Result<(), VolumeReadError> {
+        let child_log2 = if log2 == INTERNAL2_LOG2 { INTERNAL1_LOG2 + LEAF_LOG2 } else { LEAF_LOG2 };
+        let count = 1 << (3 * log2);
+        let child_mask = reader.mask(count)?;
+        let value_mask = reader.mask(count)?;
+        let values = read_compressed_values(reader, count, &value_mask, state)?;
+
+        for (i, value) in values.iter().enumerate() {
+            if !is_on(&child_mask, i) && is_on(&value_mask, i) {
+                self.add_tile(origin + offset_to_coord(i, log2, child_log2), child_log2, *value);
+            }
+        }
+        for i in 0..count {
+            if !is_on(&child_mask, i) {
+                continue;
+            }
+            let child_origin = origin + offset_to_coord(i, log2, child_log2);
+            if log2 == INTERNAL2_LOG2 {
+                self.read_internal_topology(reader, child_origin, INTERNAL1_LOG2, state)?;
+            } else {
+                // leaf topology is only the value mask, the values follow with the buffers
+                reader.mask(LEAF_SIZE)?;
+                self.leaf_order.push(child_origin);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_buffers(&mut self, reader: &mut Reader, state: &StreamState) -> Result<(), VolumeReadError> {
+        for origin in std::mem::take(&mut self.leaf_order) {
+            let value_mask = reader.mask(LEAF_SIZE)?;
+            let values = read_compressed_values(reader, LEAF_SIZE, &value_mask, state)?;
+            self.leaves.insert(origin, LeafData::Dense(values.into_boxed_slice()));
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, coord: IVec3) -> Option<f32> {
+        let leaf_origin = coord & !((1 << LEAF_LOG2) - 1);
+        if let Some(leaf) = self.leaves.get(&leaf_origin) {
+            return Some(match leaf {
+                LeafData::Constant(value) => *value,
+                LeafData::Dense(values) => {
+                    let local = coord - leaf_origin;
+                    values[((local.x << (2 * LEAF_LOG2)) | (local.y << LEAF_LOG2) | local.z) as usize]
+                }
+            });
+        }
+        TILE_LOG2S.iter().find_map(|log2| self.tile(coord, *log2)).map(|tile| tile.value)
+    }
+
+    /// Value of the voxels from `min` up to `max`, exclusive, if they lie in the background or in
+    /// a single tile, so all of them are known to be the same
+    fn uniform_value(&self, min: IVec3, max: IVec3, background: f32) -> Option<f32> {
+        if overlapped_cells(min, max, LEAF_LOG2).any(|origin| self.leaves.contains_key(&origin)) {
+            return None;
+        }
+        for log2 in TILE_LOG2S {
+            if let Some(tile) = overlapped_cells(min, max, log2).find_map(|origin| self.tile(origin, log2)) {
+                let covered = min.cmpge(tile.origin).all() && max.cmple(tile.origin + tile.size).all();
+                return covered.then_some(tile.value);
+            }
+        }
+        Some(background)
+    }
+}
+
+/// Sparse float grid read from an OpenVDB file.
+/// Lookups are normalized to [0, 1] using the minimum and maximum of the stored values.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct VdbGrid {
+    tree: Tree,
+    background: f32,
+    /// index space bounding box of all leaves and tiles
+    bbox_min: IVec3,
+    bbox_max: IVec3,
+    min: f32,
+    max: f32,
+    active_voxels: usize,
+    histogram: Vec<u32>,
+    transform: Mat4,
+}
+
+impl VdbGrid {
+    fn new(tree: Tree, background: f32, index_to_world: Mat4) -> Result<Self, VolumeReadError> {
+        let mut bbox_min = IVec3::MAX;
+        let mut bbox_max = IVec3::MIN;
+        let mut min = background;
+        let mut max = background;
+        let mut active_voxels = 0usize;
+        for (origin, leaf) in &tree.leaves {
+            bbox_min = bbox_min.min(*origin);
+            bbox_max = bbox_max.max(*origin + (1 << LEAF_LOG2));
+            active_voxels += LEAF_SIZE;
+            match leaf {
+                LeafData::Dense(values) => {
+                    for value in values.iter().filter(|value| value.is_finite()) {
+                        min = min.min(*value);
+                        max = max.max(*value);
+                    }
+                }
+                LeafData::Constant(value) => {
+                    min = min.min(*value);
+                    max = max.max(*value);
+                }
+            }
+        }
+        for tile in tree.tiles.values() {
+            bbox_min = bbox_min.min(tile.origin);
+            bbox_max = bbox_max.max(tile.origin + tile.size);
+            active_voxels = active_voxels.saturating_add((tile.size as usize).pow(3));
+            min = min.min(tile.value);
+            max = max.max(tile.value);
+        }
+        if bbox_min.cmpgt(bbox_max).any() {
+            return Err(VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "VDB grid does not contain any leaves or active tiles"));
+        }
+
+        let mut grid = Self {
+            tree,
+            background,
+            bbox_min,
+            bbox_max,
+            min,
+            max,
+            active_voxels,
+            histogram: vec![0; HISTOGRAM_BINS],
+            transform: index_to_world * Mat4::from_translation(bbox_min.as_vec3()),
+        };
+
+        // only voxels stored in leaves and tiles are counted, not the background between them
+        let mut histogram = vec![0u32; HISTOGRAM_BINS];
+        for leaf in grid.tree.leaves.values() {
+            match leaf {
+                LeafData::Dense(values) => {
+                    for value in values.iter().filter(|value| value.is_finite()) {
+                        histogram[grid.bin(*value)] += 1;
+                    }
+                }
+                LeafData::Constant(value) => histogram[grid.bin(*value)] += LEAF_SIZE as u32,
+            }
+        }
+        for tile in grid.tree.tiles.values() {
+            let bin = grid.bin(tile.value);
+            histogram[bin] = histogram[bin].saturating_add((tile.size as u32).saturating_pow(3));
+        }
+        grid.histogram = histogram;
+        Ok(grid)
+    }
+
+    fn bin(&self, value: f32) -> usize {
+        if self.max <= self.min {
+            return 0;
+        }
+        (((value - self.min) / (self.max - self.min)) * (HISTOGRAM_BINS - 1) as f32).round() as usize
+    }
+
+    fn normalize(&self, value: f32) -> f32 {
+        if !value.is_finite() || self.max <= self.min {
+            return 0.0;
+        }
+        (value - self.min) / (self.max - self.min)
+    }
+}
+
+impl Grid for VdbGrid {
+    fn lookup(&self, ipos: UVec3) -> f32 {
+        let coord = self.bbox_min + ipos.as_ivec3();
+        self.normalize(self.tree.lookup(coord).unwrap_or(self.background))
+    }
+
+    /// Bricks touching no leaf lie in the background or a single tile, and are skipped
+    fn uniform_value(&self, min: IVec3, max: IVec3) -> Option<f32> {
+        self.tree.uniform_value(self.bbox_min + min, self.bbox_min + max, self.background).map(|value| self.normalize(value))
+    }
+
+    fn minorant_majorant(&self) -> (f32, f32) {
+        (0.0, 1.0)
+    }
+
+    fn index_extent(&self) -> UVec3 {
+        (self.bbox_max - self.bbox_min).as_uvec3()
+    }
+
+    fn num_voxels(&self) -> usize {
+        self.active_voxels
+    }
+
+    fn size_bytes(&self) -> usize {
+        let dense_leaves = self.tree.leaves.values().filter(|leaf| matches!(leaf, LeafData::Dense(_))).count();
+        dense_leaves * LEAF_SIZE * size_of::<f32>() + self.tree.tiles.len() * size_of::<Tile>()
+    }
+
+    fn histogram(&self) -> Vec<u32> {
+        self.histogram.clone()
+    }
+
+    fn histogram_gradient(&self) -> (Vec<i32>, u32, u32) {
+        compute_histogram_gradient(&self.histogram)
+    }
+
+    fn transform(&self) -> Mat4 {
+        self.transform
+    }
+}
+
+/// Reads the index to world transform, see the `read` implementations of the maps in openvdb/math/Maps.h
+fn read_transform(reader: &mut Reader) -> Result<Mat4, VolumeReadError> {
+    let map_type = reader.string()?;
+    let matrix = match map_type.as_str() {
+        "UniformScaleMap" | "ScaleMap" => {
+            let scale = reader.dvec3()?;
+            // voxel size, inverse scale, inverse squared scale and inverse twice scale
+            for _ in 0..4 {
+                reader.dvec3()?;
+            }
+            DMat4::from_scale(scale)
+        }
+        "UniformScaleTranslateMap" | "ScaleTranslateMap" => {
+            let translation = reader.dvec3()?;
+            let scale = reader.dvec3()?;
+            for _ in 0..4 {
+                reader.dvec3()?;
+            }
+            DMat4::from_translation(translation) * DMat4::from_scale(scale)
+        }
+        "TranslationMap" => DMat4::from_translation(reader.dvec3()?),
+        "AffineMap" | "UnitaryMap" => {
+            // stored row major for row vectors, which is column major for column vectors
+            let mut values = [0.0; 16];
+            for value in values.iter_mut() {
+                *value = reader.f64()?;
+            }
+            DMat4::from_cols_array(&values)
+        }
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported VDB transform \"{}\"", map_type))),
+    };
+    Ok(matrix.as_mat4())
+}
+
+struct GridDescriptor {
+    name: String,
+    grid_type: String,
+    half: bool,
+    instance_parent: String,
+    grid_pos: usize,
+}
+
+/// Parses a float grid of an OpenVDB file. If `grid_name` is None, the first float grid is used.
+pub fn parse_vdb(bytes: &[u8], grid_name: Option<&str>) -> Result<VdbGrid, VolumeReadError> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.i64()? as u64 != MAGIC {
+        return Err(invalid("File does not start with the VDB magic"));
+    }
+    let file_version = reader.u32()?;
+    if file_version < FILE_VERSION_NODE_MASK_COMPRESSION {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("VDB file version {} is too old", file_version)));
+    }
+    // library major and minor version
+    reader.u32()?;
+    reader.u32()?;
+    let has_grid_offsets = reader.u8()? != 0;
+    // uuid
+    reader.take(36)?;
+    reader.skip_metadata()?;
+
+    if !has_grid_offsets {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "VDB streams without grid offsets are not supported"));
+    }
+
+    let grid_count = reader.i32()?;
+    let mut descriptors = Vec::new();
+    for _ in 0..grid_count {
+        let unique_name = reader.string()?;
+        let mut grid_type = reader.string()?;
+        let half = grid_type.ends_with(HALF_FLOAT_SUFFIX);
+        if half {
+            grid_type.truncate(grid_type.len() - HALF_FLOAT_SUFFIX.len());
+        }
+        let instance_parent = reader.string()?;
+        let grid_pos = reader.offset()?;
+        // block position
+        reader.i64()?;
+        let end_pos = reader.offset()?;
+        // unique names may have a suffix separated by a record separator
+        let name = unique_name.split('\u{1e}').next().unwrap_or_default().to_string();
+        descriptors.push(GridDescriptor { name, grid_type, half, instance_parent, grid_pos });
+        reader.pos = end_pos;
+    }
+
+    let descriptor = descriptors
+        .iter()
+        .filter(|descriptor| descriptor.grid_type == FLOAT_GRID_TYPE)
+        .find(|descriptor| grid_name.is_none_or(|name| descriptor.name == name))
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::Unsupported, "VDB file does not contain a matching float grid"))?;
+    // instanced grids share the topology and buffers of their parent, but may have their own transform
+    let data_descriptor = if descriptor.instance_parent.is_empty() {
+        descriptor
+    } else {
+        descriptors
+            .iter()
+            .find(|parent| parent.name == descriptor.instance_parent)
+            .ok_or_else(|| invalid(format!("Instance parent \"{}\" not found", descriptor.instance_parent)))?
+    };
+
+    reader.pos = descriptor.grid_pos;
+    reader.u32()?;
+    reader.skip_metadata()?;
+    let transform = read_transform(&mut reader)?;
+
+    reader.pos = data_descriptor.grid_pos;
+    let compression = reader.u32()?;
+    reader.skip_metadata()?;
+    read_transform(&mut reader)?;
+
+    // topology of the root node
+    let buffer_count = reader.i32()?;
+    if buffer_count != 1 {
+        log_to_console(&format!("VDB tree has {} buffers, only reading the first", buffer_count));
+    }
+    let background = reader.f32()?;
+    let state = StreamState { compression, half: data_descriptor.half, background };
+    let tile_count = reader.u32()?;
+    let child_count = reader.u32()?;
+
+    let mut tree = Tree::default();
+    let root_tile_log2 = INTERNAL2_LOG2 + INTERNAL1_LOG2 + LEAF_LOG2;
+    for _ in 0..tile_count {
+        let origin = reader.ivec3()?;
+        let value = reader.f32()?;
+        let active = reader.u8()? != 0;
+        if active {
+            tree.add_tile(origin, root_tile_log2, value);
+        }
+    }
+    for _ in 0..child_count {
+        let origin = reader.ivec3()?;
+        tree.read_internal_topology(&mut reader, origin, INTERNAL2_LOG2, &state)?;
+    }
+
+    tree.read_buffers(&mut reader, &state)?;
+
+    VdbGrid::new(tree, background, transform)
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_vdb(bytes: Uint8Array, grid_name: Option<String>) -> Result<VdbGrid, VolumeReadError> {
+    parse_vdb(&bytes.to_vec(), grid_name.as_deref())
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn vdb_to_grid(volume: VdbGrid, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting brick grid construction");
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&volume, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Vec3;
+
+    fn state(compression: u32) -> StreamState {
+        StreamState { compression, half: false, background: 0.0 }
+    }
+
+    fn string(bytes: &mut Vec<u8>, value: &str) {
+        bytes.extend((value.len() as u32).to_le_bytes());
+        bytes.extend(value.as_bytes());
+    }
+
+    fn mask(bytes: &mut Vec<u8>, bits: usize, on: impl Fn(usize) -> bool) {
+        for word in 0..bits / 64 {
+            let bits = (0..64).filter(|bit| on(word * 64 + bit)).fold(0u64, |bits, bit| bits | 1 << bit);
+            bytes.extend(bits.to_le_bytes());
+        }
+    }
+
+    /// A file whose root holds an active tile at (4096, 0, 0) and one internal node at the origin,
+    /// leading down to a single dense leaf at (8, 0, 16)
+    fn tree_file() -> Vec<u8> {
+        let mut bytes = (MAGIC as i64).to_le_bytes().to_vec();
+        for value in [FILE_VERSION_NODE_MASK_COMPRESSION, 11, 0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.push(1);
+        bytes.extend([b'0'; 36]);
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(1i32.to_le_bytes());
+        string(&mut bytes, "density");
+        string(&mut bytes, FLOAT_GRID_TYPE);
+        string(&mut bytes, "");
+        // the grid directly follows its descriptor
+        let grid_pos = (bytes.len() + 24) as i64;
+        for position in [grid_pos, 0, grid_pos] {
+            bytes.extend(position.to_le_bytes());
+        }
+
+        bytes.extend(COMPRESS_ACTIVE_MASK.to_le_bytes());
+        bytes.extend(0u32.to_le_bytes());
+        string(&mut bytes, "UniformScaleMap");
+        for _ in 0..5 {
+            bytes.extend([0.5f64; 3].iter().flat_map(|value| value.to_le_bytes()));
+        }
+
+        // root with background 0, one tile of 2 and one child
+        bytes.extend(1i32.to_le_bytes());
+        bytes.extend(0f32.to_le_bytes());
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend([4096, 0, 0].iter().flat_map(|value: &i32| value.to_le_bytes()));
+        bytes.extend(2f32.to_le_bytes());
+        bytes.push(1);
+        bytes.extend([0i32; 3].iter().flat_map(|value| value.to_le_bytes()));
+
+        // internal nodes without tiles, only the masks and the metadata of their values are stored
+        let leaf_offset = (1 << (2 * INTERNAL1_LOG2)) | 2;
+        for (log2, child) in [(INTERNAL2_LOG2, 0), (INTERNAL1_LOG2, leaf_offset)] {
+            let count = 1 << (3 * log2);
+            mask(&mut bytes, count, |i| i == child);
+            mask(&mut bytes, count, |_| false);
+            bytes.push(NO_MASK_OR_INACTIVE_VALS);
+        }
+        mask(&mut bytes, LEAF_SIZE, |_| false);
+
+        // the leaf buffer, of which the first half is active and the inactive values are -1 or 3
+        mask(&mut bytes, LEAF_SIZE, |i| i < 256);
+        bytes.push(MASK_AND_TWO_INACTIVE_VALS);
+        bytes.extend((-1f32).to_le_bytes());
+        bytes.extend(3f32.to_le_bytes());
+        mask(&mut bytes, LEAF_SIZE, |i| i >= 384);
+        bytes.extend((0..256).flat_map(|i| (i as f32 / 256.0).to_le_bytes()));
+        bytes
+    }
+
+    #[test]
+    fn trees_of_tiles_nodes_and_leaves_are_read() {
+        let grid = parse_vdb(&tree_file(), None).unwrap_or_else(|e| panic!("{}", e.message()));
+        // from the leaf's x to the end of the tile, values are normalized from [-1, 3]
+        let bbox_min = IVec3::new(8, 0, 0);
+        assert_eq!(grid.index_extent(), UVec3::new(8192 - 8, 4096, 4096));
+        let lookup = |coord: IVec3| grid.lookup((coord - bbox_min).as_uvec3());
+        assert_eq!(lookup(IVec3::new(9, 2, 19)), (83.0 / 256.0 + 1.0) / 4.0);
+        assert_eq!(lookup(IVec3::new(13, 0, 16)), 0.0);
+        assert_eq!(lookup(IVec3::new(15, 7, 23)), 1.0);
+        assert_eq!(lookup(IVec3::new(5000, 10, 100)), 0.75);
+        assert_eq!(lookup(IVec3::new(100, 100, 100)), 0.25);
+        assert_eq!(grid.transform(), Mat4::from_scale(Vec3::splat(0.5)) * Mat4::from_translation(bbox_min.as_vec3()));
+
+        let uniform = |min: IVec3, max: IVec3| grid.uniform_value(min - bbox_min, max - bbox_min);
+        assert_eq!(uniform(IVec3::new(6, 0, 16), IVec3::new(10, 4, 20)), None);
+        assert_eq!(uniform(IVec3::new(5000, 10, 100), IVec3::new(5008, 18, 108)), Some(0.75));
+        assert_eq!(uniform(IVec3::new(4092, 10, 100), IVec3::new(4100, 18, 108)), None);
+        assert_eq!(uniform(IVec3::new(100, 100, 100), IVec3::new(108, 108, 108)), Some(0.25));
+    }
+
+    #[test]
+    fn buffers_stored_without_compression_are_read() {
+        let values = [1.0f32, -2.0, 0.5];
+        let raw: Vec<u8> = values.iter().flat_map(|value| value.to_le_bytes()).collect();
+        let mut bytes = (-(raw.len() as i64)).to_le_bytes().to_vec();
+        bytes.extend(&raw);
+
+        let mut reader = Reader { bytes: &bytes, pos: 0 };
+        assert_eq!(read_data(&mut reader, 3, &state(COMPRESS_BLOSC)).unwrap_or_else(|e| panic!("{}", e.message())), values);
+        let mut reader = Reader { bytes: &raw, pos: 0 };
+        assert_eq!(read_data(&mut reader, 3, &state(0)).unwrap_or_else(|e| panic!("{}", e.message())), values);
+    }
+
+    #[test]
+    fn corrupt_sizes_are_errors() {
+        // the size of an uncompressed buffer is stored negated, which overflows for i64::MIN
+        let bytes = i64::MIN.to_le_bytes();
+        let mut reader = Reader { bytes: &bytes, pos: 0 };
+        assert!(matches!(read_data(&mut reader, 3, &state(COMPRESS_ZIP)).err().map(|e| e.0), Some(VolumeReadErrorType::InvalidHeader)));
+
+        let mut reader = Reader { bytes: &[0; 4], pos: 2 };
+        assert!(matches!(reader.take(usize::MAX).err().map(|e| e.0), Some(VolumeReadErrorType::NotEnoughData)));
+    }
+
+    #[test]
+    fn negative_grid_positions_are_invalid() {
+        let mut bytes = (MAGIC as i64).to_le_bytes().to_vec();
+        for value in [FILE_VERSION_NODE_MASK_COMPRESSION, 11, 0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.push(1);
+        bytes.extend([b'0'; 36]);
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(1i32.to_le_bytes());
+        string(&mut bytes, "density");
+        string(&mut bytes, FLOAT_GRID_TYPE);
+        string(&mut bytes, "");
+        bytes.extend((-1i64).to_le_bytes());
+        let error = parse_vdb(&bytes, None).err().expect("the grid position is negative");
+        assert!(matches!(error.0, VolumeReadErrorType::InvalidHeader));
+    }
+}
@@ -0,0 +1,298 @@
+use crate::brick::BrickGrid;
+use crate::buf3d::Buf3D;
+use crate::grid::{compute_histogram_gradient, Grid};
+use crate::brick_config::BrickGridConfig;
+use crate::progress::{LoadCancelled, LoadProgress};
+use crate::utils::{log_to_console, now};
+use glam::{Mat4, Quat, UVec3, Vec3};
+use std::io::{Cursor, Read};
+use std::path::Path;
+#[cfg(feature = "wasm")]
+use crate::compression::CompressionMetrics;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+/// Number of bins used for the histogram of volumes that don't have integer densities
+const HISTOGRAM_BINS: usize = 4096;
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl SampleType {
+    /// size of a single sample in bytes
+    pub fn size(self) -> usize {
+        match self {
+            SampleType::U8 | SampleType::I8 => 1,
+            SampleType::U16 | SampleType::I16 => 2,
+            SampleType::U32 | SampleType::I32 | SampleType::F32 => 4,
+            SampleType::U64 | SampleType::I64 | SampleType::F64 => 8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Debug)]
+pub enum VolumeReadErrorType {
+    InvalidHeader,
+    Unsupported,
+    MissingDataFile,
+    DecompressionFailed,
+    NotEnoughData,
+    ExtractFailed,
+    Cancelled,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct VolumeReadError(pub(crate) VolumeReadErrorType, pub(crate) Option<String>);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl VolumeReadError {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
+    pub fn message(self) -> String {
+        format!("{:?}: {}", self.0, self.1.unwrap_or_else(|| "No Message Specified".to_string()))
+    }
+}
+
+impl VolumeReadError {
+    pub fn new(error_type: VolumeReadErrorType, message: impl Into<String>) -> Self {
+        Self(error_type, Some(message.into()))
+    }
+}
+
+impl From<LoadCancelled> for VolumeReadError {
+    fn from(_: LoadCancelled) -> Self {
+        VolumeReadError(VolumeReadErrorType::Cancelled, None)
+    }
+}
+
+/// Number of voxels of a volume, failing for extents from a header that are too large to be held
+pub fn voxel_count(extent: UVec3) -> Result<usize, VolumeReadError> {
+    (extent.x as usize)
+        .checked_mul(extent.y as usize)
+        .and_then(|count| count.checked_mul(extent.z as usize))
+        .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("A volume of {} voxels is too large", extent)))
+}
+
+/// Decodes `count` samples of the given type from the start of `bytes` into floats
+pub fn decode_samples(bytes: &[u8], sample_type: SampleType, endianness: Endianness, count: usize) -> Result<Vec<f32>, VolumeReadError> {
+    let size = sample_type.size();
+    if count.checked_mul(size).is_none_or(|needed| bytes.len() < needed) {
+        return Err(VolumeReadError::new(
+            VolumeReadErrorType::NotEnoughData,
+            format!("Expected {} samples of {} bytes, got {} bytes", count, size, bytes.len()),
+        ));
+    }
+
+    macro_rules! decode {
+        ($t:ty) => {
+            bytes[..count * size]
+                .chunks_exact(size)
+                .map(|chunk| {
+                    let chunk = chunk.try_into().unwrap();
+                    (match endianness {
+                        Endianness::Little => <$t>::from_le_bytes(chunk),
+                        Endianness::Big => <$t>::from_be_bytes(chunk),
+                    }) as f32
+                })
+                .collect()
+        };
+    }
+
+    Ok(match sample_type {
+        SampleType::U8 => decode!(u8),
+        SampleType::I8 => decode!(i8),
+        SampleType::U16 => decode!(u16),
+        SampleType::I16 => decode!(i16),
+        SampleType::U32 => decode!(u32),
+        SampleType::I32 => decode!(i32),
+        SampleType::U64 => decode!(u64),
+        SampleType::I64 => decode!(i64),
+        SampleType::F32 => decode!(f32),
+        SampleType::F64 => decode!(f64),
+    })
+}
+
+/// A set of files uploaded together, used to resolve files referenced by detached headers
+pub struct NamedFiles {
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl NamedFiles {
+    pub fn new(names: Vec<String>, files: Vec<Vec<u8>>) -> Self {
+        Self { files: names.into_iter().zip(files).collect() }
+    }
+
+    /// Extracts all files of a zip archive
+    pub fn from_zip(bytes: Vec<u8>) -> Result<Self, VolumeReadError> {
+        let extract_failed = |e: zip::result::ZipError| VolumeReadError::new(VolumeReadErrorType::ExtractFailed, e.to_string());
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).map_err(extract_failed)?;
+        let mut files = Vec::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).map_err(extract_failed)?;
+            if file.is_dir() {
+                continue;
+            }
+            let name = file.name().to_string();
+            let mut file_bytes = Vec::new();
+            file.read_to_end(&mut file_bytes).map_err(|e| VolumeReadError::new(VolumeReadErrorType::ExtractFailed, e.to_string()))?;
+            files.push((name, file_bytes));
+        }
+        Ok(Self { files })
+    }
+
+    /// all files of this set with their full paths
+    pub fn into_files(self) -> Vec<(String, Vec<u8>)> {
+        self.files
+    }
+
+    /// names of all files in this set
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Looks up a file by its file name, ignoring any directories in both the stored and the requested path
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        let requested = Path::new(name).file_name()?;
+        self.files
+            .iter()
+            .find(|(file_name, _)| Path::new(file_name).file_name() == Some(requested))
+            .map(|(_, bytes)| bytes.as_slice())
+    }
+}
+
+/// Transform placing the voxel centers of a grid of `extent` voxels evenly inside the given bounds,
+/// as the grids of path tracers like Mitsuba and PBRT do
+pub fn bounds_to_transform(min: Vec3, max: Vec3, extent: UVec3) -> Mat4 {
+    let voxel_size = (max - min) / extent.as_vec3();
+    Mat4::from_translation(min + voxel_size * 0.5) * Mat4::from_scale(voxel_size)
+}
+
+/// Inverse of [`bounds_to_transform`], only exact for transforms without rotation
+pub fn transform_to_bounds(transform: Mat4, extent: UVec3) -> (Vec3, Vec3) {
+    let (voxel_size, rotation, _) = transform.to_scale_rotation_translation();
+    if !rotation.abs_diff_eq(Quat::IDENTITY, 1e-4) {
+        log_to_console("Grid transform is rotated, the exported bounds only cover its scale and translation");
+    }
+    let min = transform.transform_point3(Vec3::ZERO) - voxel_size * 0.5;
+    (min, min + voxel_size * extent.as_vec3())
+}
+
+/// Dense volume of scalar samples read from one of the non-DICOM formats.
+/// Lookups are normalized to [0, 1] using the minimum and maximum of the data.
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct DenseGrid {
+    data: Buf3D<f32>,
+    min: f32,
+    max: f32,
+    histogram: Vec<u32>,
+    transform: Mat4,
+}
+
+impl DenseGrid {
+    pub fn new(data: Buf3D<f32>, transform: Mat4) -> Self {
+        let mut min = f32::MAX;
+        let mut max = f32::MIN;
+        for value in data.data.iter().filter(|value| value.is_finite()) {
+            min = min.min(*value);
+            max = max.max(*value);
+        }
+        if min > max {
+            // only non-finite values
+            min = 0.0;
+            max = 0.0;
+        }
+
+        let mut histogram = vec![0u32; HISTOGRAM_BINS];
+        for value in data.data.iter().filter(|value| value.is_finite()) {
+            histogram[Self::bin(*value, min, max)] += 1;
+        }
+
+        Self { data, min, max, histogram, transform }
+    }
+
+    fn bin(value: f32, min: f32, max: f32) -> usize {
+        if max <= min {
+            return 0;
+        }
+        (((value - min) / (max - min)) * (HISTOGRAM_BINS - 1) as f32).round() as usize
+    }
+}
+
+impl Grid for DenseGrid {
+    fn lookup(&self, ipos: UVec3) -> f32 {
+        if ipos.z >= self.data.stride.z || ipos.y >= self.data.stride.y || ipos.x >= self.data.stride.x {
+            return 0.0;
+        }
+        let raw = self.data.data[self.data.calculate_index(ipos)];
+        if !raw.is_finite() || self.max <= self.min {
+            return 0.0;
+        }
+        (raw - self.min) / (self.max - self.min)
+    }
+
+    fn minorant_majorant(&self) -> (f32, f32) {
+        (0.0, 1.0)
+    }
+
+    fn index_extent(&self) -> UVec3 {
+        self.data.stride
+    }
+
+    fn num_voxels(&self) -> usize {
+        (self.data.stride.x as usize) * (self.data.stride.y as usize) * (self.data.stride.z as usize)
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.data.data.len() * size_of::<f32>()
+    }
+
+    fn histogram(&self) -> Vec<u32> {
+        self.histogram.clone()
+    }
+
+    fn histogram_gradient(&self) -> (Vec<i32>, u32, u32) {
+        compute_histogram_gradient(&self.histogram)
+    }
+
+    fn transform(&self) -> Mat4 {
+        self.transform
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl DenseGrid {
+    /// Bricks the volume as `config` says and measures how far the brick grid is off from it
+    pub fn measure_compression(&self, config: &BrickGridConfig) -> Result<CompressionMetrics, VolumeReadError> {
+        let grid = BrickGrid::construct_with_config(self, *config, LoadProgress::none())?;
+        Ok(CompressionMetrics::measure(self, &grid))
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn volume_to_grid(volume: DenseGrid, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting brick grid construction");
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&volume, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
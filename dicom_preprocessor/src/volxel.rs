@@ -0,0 +1,461 @@
+use crate::brick::{decode_range, Atlas, BrickGrid};
+use crate::brick_config::{AtlasPrecision, BrickGridConfig};
+use crate::buf3d::Buf3D;
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use std::collections::HashMap;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// The .volxel cache format stores a constructed BrickGrid, so it doesn't need to be rebuilt from its source.
+//
+// Layout, all integers little endian:
+//   magic "VOLXEL\0\0", format version (u32), section count (u32)
+//   per section: tag (4 bytes), uncompressed size (u64), compressed size (u64), zstd compressed data
+// Sections with unknown tags are skipped, so readers stay compatible with added sections.
+// The version is only increased for changes that old readers can't handle, files of other
+// versions are rejected.
+
+const MAGIC: &[u8; 8] = b"VOLXEL\0\0";
+const VERSION: u32 = 1;
+const ZSTD_LEVEL: i32 = 3;
+/// A zstd block of up to 128 KiB compresses to 4 bytes at best, so sections that claim to grow
+/// more than this when decompressed are corrupt
+const MAX_ZSTD_RATIO: usize = 1 << 15;
+/// histograms have a bin per 16 bit value at most
+const MAX_HISTOGRAM_BINS: usize = 1 << 16;
+
+const SECTION_META: &[u8; 4] = b"META";
+const SECTION_CONFIG: &[u8; 4] = b"CNFG";
+const SECTION_INDIRECTION: &[u8; 4] = b"INDR";
+const SECTION_RANGE: &[u8; 4] = b"RANG";
+const SECTION_RANGE_MIPMAPS: &[u8; 4] = b"MIPS";
+const SECTION_ATLAS: &[u8; 4] = b"ATLS";
+const SECTION_HISTOGRAM: &[u8; 4] = b"HIST";
+const SECTION_HISTOGRAM_GRADIENT: &[u8; 4] = b"HGRD";
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+/// number of cells in a block of the given size, `None` if they can't be counted in memory
+fn checked_volume(size: UVec3) -> Option<usize> {
+    (size.x as usize).checked_mul(size.y as usize)?.checked_mul(size.z as usize)
+}
+
+/// bytes of a [`SectionWriter::u32_buffer`] of the given size
+fn u32_buffer_size(stride: UVec3) -> Option<usize> {
+    checked_volume(stride)?.checked_mul(4)?.checked_add(12)
+}
+
+fn too_large() -> VolumeReadError {
+    invalid("Buffer sizes don't fit into memory")
+}
+
+fn encode_precision(precision: AtlasPrecision) -> u32 {
+    match precision {
+        AtlasPrecision::U8 => 0,
+        AtlasPrecision::U16 => 1,
+        AtlasPrecision::F16 => 2,
+    }
+}
+
+fn decode_precision(value: u32) -> Result<AtlasPrecision, VolumeReadError> {
+    match value {
+        0 => Ok(AtlasPrecision::U8),
+        1 => Ok(AtlasPrecision::U16),
+        2 => Ok(AtlasPrecision::F16),
+        _ => Err(invalid(format!("Unknown atlas precision {}", value))),
+    }
+}
+
+#[derive(Default)]
+struct SectionWriter {
+    bytes: Vec<u8>,
+}
+
+impl SectionWriter {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn uvec3(&mut self, value: UVec3) {
+        value.to_array().into_iter().for_each(|v| self.u32(v));
+    }
+
+    fn u32_buffer(&mut self, buffer: &Buf3D<u32>) {
+        self.uvec3(buffer.stride);
+        buffer.data.iter().for_each(|v| self.u32(*v));
+    }
+}
+
+struct SectionReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> SectionReader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], VolumeReadError> {
+        let bytes = self
+            .offset
+            .checked_add(length)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, "Section is truncated"))?;
+        self.offset += length;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, VolumeReadError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, VolumeReadError> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, VolumeReadError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> Result<f32, VolumeReadError> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn uvec3(&mut self) -> Result<UVec3, VolumeReadError> {
+        Ok(UVec3::new(self.u32()?, self.u32()?, self.u32()?))
+    }
+
+    fn u32_buffer(&mut self) -> Result<Buf3D<u32>, VolumeReadError> {
+        let stride = self.uvec3()?;
+        let length = checked_volume(stride).and_then(|count| count.checked_mul(4)).ok_or_else(too_large)?;
+        let data = self.take(length)?.chunks_exact(4).map(|v| u32::from_le_bytes(v.try_into().unwrap())).collect();
+        Ok(Buf3D { stride, data })
+    }
+}
+
+fn write_section(output: &mut Vec<u8>, tag: &[u8; 4], section: &SectionWriter) {
+    // compressing from memory into memory only fails for invalid levels
+    let compressed = zstd::bulk::compress(&section.bytes, ZSTD_LEVEL).unwrap();
+    output.extend_from_slice(tag);
+    output.extend_from_slice(&(section.bytes.len() as u64).to_le_bytes());
+    output.extend_from_slice(&(compressed.len() as u64).to_le_bytes());
+    output.extend_from_slice(&compressed);
+}
+
+impl BrickGrid {
+    /// Serializes the grid into the `.volxel` format
+    pub fn to_volxel(&self) -> Vec<u8> {
+        let mut meta = SectionWriter::default();
+        meta.uvec3(self.brick_count);
+        meta.f32(self.min_maj.0);
+        meta.f32(self.min_maj.1);
+        meta.u64(self.brick_counter as u64);
+        self.transform.to_cols_array().into_iter().for_each(|v| meta.f32(v));
+
+        let mut config = SectionWriter::default();
+        config.u32(self.config.brick_size());
+        config.u32(self.config.mip_levels());
+        config.uvec3(self.config.pointer_bits());
+        config.u32(encode_precision(self.config.atlas_precision));
+        config.u32(self.config.max_atlas_size());
+
+        let mut indirection = SectionWriter::default();
+        indirection.u32_buffer(&self.indirection);
+
+        let mut range = SectionWriter::default();
+        range.u32_buffer(&self.range);
+
+        let mut mipmaps = SectionWriter::default();
+        mipmaps.u32(self.range_mipmaps.len() as u32);
+        self.range_mipmaps.iter().for_each(|mipmap| mipmaps.u32_buffer(mipmap));
+
+        let mut atlas = SectionWriter::default();
+        atlas.u32(self.atlas.pages() as u32);
+        for page in 0..self.atlas.pages() {
+            atlas.uvec3(self.atlas.page_stride(page));
+            atlas.bytes.extend_from_slice(&self.atlas.page_to_le_bytes(page));
+        }
+
+        let mut histogram = SectionWriter::default();
+        histogram.u32(self.histogram.len() as u32);
+        self.histogram.iter().for_each(|v| histogram.u32(*v));
+
+        let mut gradient = SectionWriter::default();
+        gradient.u32(self.histogram_gradient.1);
+        gradient.u32(self.histogram_gradient.2);
+        gradient.u32(self.histogram_gradient.0.len() as u32);
+        self.histogram_gradient.0.iter().for_each(|v| gradient.bytes.extend_from_slice(&v.to_le_bytes()));
+
+        let sections = [
+            (SECTION_META, meta),
+            (SECTION_CONFIG, config),
+            (SECTION_INDIRECTION, indirection),
+            (SECTION_RANGE, range),
+            (SECTION_RANGE_MIPMAPS, mipmaps),
+            (SECTION_ATLAS, atlas),
+            (SECTION_HISTOGRAM, histogram),
+            (SECTION_HISTOGRAM_GRADIENT, gradient),
+        ];
+        let mut output = Vec::new();
+        output.extend_from_slice(MAGIC);
+        output.extend_from_slice(&VERSION.to_le_bytes());
+        output.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        for (tag, section) in &sections {
+            write_section(&mut output, tag, section);
+        }
+        output
+    }
+
+    /// Deserializes a grid from the `.volxel` format. The buffers are checked against each other,
+    /// so lookups into the grid stay within them.
+    pub fn from_volxel(bytes: &[u8]) -> Result<BrickGrid, VolumeReadError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(invalid("File does not start with the volxel magic"));
+        }
+        let mut header = SectionReader { bytes, offset: MAGIC.len() };
+        let version = header.u32()?;
+        if version != VERSION {
+            return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported volxel version {}, expected {}", version, VERSION)));
+        }
+        let section_count = header.u32()?;
+
+        let mut sections = HashMap::new();
+        for _ in 0..section_count {
+            let tag: [u8; 4] = header.take(4)?.try_into().unwrap();
+            let size = header.u64()?;
+            let compressed_size = usize::try_from(header.u64()?).map_err(|_| too_large())?;
+            let compressed = header.take(compressed_size)?;
+            sections.insert(tag, (size, compressed));
+        }
+        // sections are only decompressed up to the size their contents can have
+        let section = |tag: &[u8; 4], max_size: usize| -> Result<Vec<u8>, VolumeReadError> {
+            let (size, compressed) = sections
+                .get(tag)
+                .ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::NotEnoughData, format!("Missing section {}", String::from_utf8_lossy(tag))))?;
+            let size = usize::try_from(*size).ok().filter(|size| *size <= max_size && *size <= compressed.len().saturating_mul(MAX_ZSTD_RATIO));
+            let size = size.ok_or_else(|| invalid(format!("Section {} is larger than its contents can be", String::from_utf8_lossy(tag))))?;
+            let data = zstd::bulk::decompress(compressed, size).map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+            if data.len() != size {
+                return Err(VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, "Section decompressed to an unexpected size"));
+            }
+            Ok(data)
+        };
+
+        let data = section(SECTION_META, 92)?;
+        let mut meta = SectionReader { bytes: &data, offset: 0 };
+        let brick_count = meta.uvec3()?;
+        let min_maj = (meta.f32()?, meta.f32()?);
+        let brick_counter = usize::try_from(meta.u64()?).map_err(|_| too_large())?;
+        let mut transform = [0f32; 16];
+        for value in transform.iter_mut() {
+            *value = meta.f32()?;
+        }
+
+        let data = section(SECTION_CONFIG, 28)?;
+        let mut reader = SectionReader { bytes: &data, offset: 0 };
+        let brick_size = reader.u32()?;
+        let mip_levels = reader.u32()?;
+        let pointer_bits = reader.uvec3()?;
+        let mut config = BrickGridConfig::new(brick_size, mip_levels, pointer_bits.x, pointer_bits.y, pointer_bits.z).map_err(|e| invalid(e.message()))?;
+        config.atlas_precision = decode_precision(reader.u32()?)?;
+        config.set_max_atlas_size(reader.u32()?).map_err(|e| invalid(e.message()))?;
+
+        let buffer_size = u32_buffer_size(brick_count).ok_or_else(too_large)?;
+        let data = section(SECTION_INDIRECTION, buffer_size)?;
+        let indirection = SectionReader { bytes: &data, offset: 0 }.u32_buffer()?;
+        let data = section(SECTION_RANGE, buffer_size)?;
+        let range = SectionReader { bytes: &data, offset: 0 }.u32_buffer()?;
+        if indirection.stride != brick_count || range.stride != brick_count {
+            return Err(invalid("Indirection and range sizes don't match the brick count"));
+        }
+
+        // mipmaps halve the range buffer, which the brick count is padded for
+        if brick_count % (1 << config.mip_levels()) != UVec3::ZERO {
+            return Err(invalid(format!("Brick count {} can't be halved {} times", brick_count, config.mip_levels())));
+        }
+        let mip_strides: Vec<UVec3> = (0..config.mip_levels()).map(|level| brick_count / (1 << (level + 1))).collect();
+        let mipmaps_size = mip_strides.iter().try_fold(4usize, |size, stride| size.checked_add(u32_buffer_size(*stride)?)).ok_or_else(too_large)?;
+        let data = section(SECTION_RANGE_MIPMAPS, mipmaps_size)?;
+        let mut reader = SectionReader { bytes: &data, offset: 0 };
+        let range_mipmaps = (0..reader.u32()?).map(|_| reader.u32_buffer()).collect::<Result<Vec<_>, _>>()?;
+        if range_mipmaps.iter().map(|mipmap| mipmap.stride).ne(mip_strides) {
+            return Err(invalid("Range mipmaps don't match the range buffer and brick layout"));
+        }
+
+        // the atlas has to be laid out the way construction lays out the allocated bricks
+        let layout = config.atlas_layout(brick_counter).ok_or_else(|| invalid(format!("{} allocated bricks don't fit into the atlas", brick_counter)))?;
+        let page_strides: Vec<UVec3> = (0..layout.pages()).map(|page| layout.page_bricks(page) * config.brick_size()).collect();
+        let atlas_size = page_strides
+            .iter()
+            .try_fold(4usize, |size, stride| size.checked_add(checked_volume(*stride)?.checked_mul(config.atlas_precision.bytes_per_voxel())?.checked_add(12)?))
+            .ok_or_else(too_large)?;
+        let data = section(SECTION_ATLAS, atlas_size)?;
+        let mut reader = SectionReader { bytes: &data, offset: 0 };
+        if reader.u32()? as usize != layout.pages() {
+            return Err(invalid(format!("Atlas doesn't have the {} pages its {} bricks need", layout.pages(), brick_counter)));
+        }
+        let mut pages = Vec::new();
+        for expected in &page_strides {
+            let stride = reader.uvec3()?;
+            if stride != *expected {
+                return Err(invalid(format!("Atlas page of {} voxels doesn't match the expected {}", stride, expected)));
+            }
+            let voxels = checked_volume(stride).ok_or_else(too_large)?;
+            pages.push((stride, reader.take(voxels * config.atlas_precision.bytes_per_voxel())?));
+        }
+        let atlas = Atlas::from_le_bytes(config.atlas_precision, &pages);
+
+        // every brick that isn't uniform is looked up in the atlas
+        let mut looked_up = 0;
+        for (pointer, range) in indirection.data.iter().zip(&range.data) {
+            let range = decode_range(*range);
+            if range.x == range.y {
+                continue;
+            }
+            looked_up += 1;
+            let (page, pointer) = config.decode_ptr(*pointer);
+            if page >= page_strides.len() || ((pointer + 1) * config.brick_size()).cmpgt(page_strides[page]).any() {
+                return Err(invalid(format!("Brick pointer {} of page {} lies outside of the atlas", pointer, page)));
+            }
+        }
+        if looked_up > brick_counter {
+            return Err(invalid(format!("{} bricks aren't uniform, but only {} are allocated", looked_up, brick_counter)));
+        }
+
+        let data = section(SECTION_HISTOGRAM, 4 + MAX_HISTOGRAM_BINS * 4)?;
+        let mut reader = SectionReader { bytes: &data, offset: 0 };
+        let histogram = (0..reader.u32()?).map(|_| reader.u32()).collect::<Result<Vec<_>, _>>()?;
+
+        let data = section(SECTION_HISTOGRAM_GRADIENT, 12 + MAX_HISTOGRAM_BINS * 4)?;
+        let mut reader = SectionReader { bytes: &data, offset: 0 };
+        let gradient_min = reader.u32()?;
+        let gradient_max = reader.u32()?;
+        let gradient = (0..reader.u32()?).map(|_| reader.i32()).collect::<Result<Vec<_>, _>>()?;
+
+        Ok(BrickGrid {
+            config,
+            brick_count,
+            min_maj,
+            brick_counter,
+            indirection,
+            range,
+            atlas,
+            range_mipmaps,
+            transform: Mat4::from_cols_array(&transform),
+            histogram,
+            histogram_gradient: (gradient, gradient_min, gradient_max),
+        })
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+impl BrickGrid {
+    pub fn to_bytes(&self) -> Uint8Array {
+        Uint8Array::from(self.to_volxel().as_slice())
+    }
+
+    pub fn from_bytes(bytes: Uint8Array) -> Result<BrickGrid, VolumeReadError> {
+        BrickGrid::from_volxel(&bytes.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::phantom::{read_phantom, PhantomOptions, PhantomShape};
+    use crate::progress::LoadProgress;
+
+    fn phantom_grid(precision: AtlasPrecision) -> BrickGrid {
+        let mut config = BrickGridConfig::new(4, 2, 3, 3, 3).unwrap_or_else(|e| panic!("{}", e.message()));
+        config.atlas_precision = precision;
+        config.set_max_atlas_size(16).unwrap_or_else(|e| panic!("{}", e.message()));
+        let dicom = read_phantom(&PhantomOptions::new(PhantomShape::SheppLogan, 24, 20, 18));
+        BrickGrid::construct_with_config(&dicom, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()))
+    }
+
+    fn assert_same_atlas(read: &Atlas, written: &Atlas) {
+        assert_eq!(read.precision(), written.precision());
+        assert_eq!(read.pages(), written.pages());
+        for page in 0..written.pages() {
+            assert_eq!(read.page_stride(page), written.page_stride(page));
+            assert!(read.page_to_le_bytes(page) == written.page_to_le_bytes(page), "page {} differs", page);
+        }
+    }
+
+    #[test]
+    fn grids_are_read_back_unchanged() {
+        for precision in [AtlasPrecision::U8, AtlasPrecision::U16, AtlasPrecision::F16] {
+            let grid = phantom_grid(precision);
+            assert!(grid.atlas.pages() > 1, "the phantom should need several pages");
+            let read = BrickGrid::from_volxel(&grid.to_volxel()).unwrap_or_else(|e| panic!("{}", e.message()));
+
+            assert_eq!(read.config, grid.config);
+            assert_eq!(read.brick_count, grid.brick_count);
+            assert_eq!(read.brick_counter, grid.brick_counter);
+            assert_eq!(read.min_maj, grid.min_maj);
+            assert_eq!(read.transform, grid.transform);
+            assert_eq!((read.indirection.stride, &read.indirection.data), (grid.indirection.stride, &grid.indirection.data));
+            assert_eq!((read.range.stride, &read.range.data), (grid.range.stride, &grid.range.data));
+            assert_eq!(read.range_mipmaps.len(), grid.range_mipmaps.len());
+            for (read, written) in read.range_mipmaps.iter().zip(&grid.range_mipmaps) {
+                assert_eq!((read.stride, &read.data), (written.stride, &written.data));
+            }
+            assert_same_atlas(&read.atlas, &grid.atlas);
+            assert_eq!(read.histogram, grid.histogram);
+            assert_eq!(read.histogram_gradient, grid.histogram_gradient);
+        }
+    }
+
+    #[test]
+    fn inconsistent_grids_are_rejected() {
+        let grid = phantom_grid(AtlasPrecision::U8);
+        let allocated = (0..grid.range.data.len()).find(|index| {
+            let range = decode_range(grid.range.data[*index]);
+            range.x != range.y
+        });
+        let allocated = allocated.expect("the phantom has bricks that aren't uniform");
+
+        let mut corrupt = phantom_grid(AtlasPrecision::U8);
+        corrupt.indirection.data[allocated] = corrupt.config.encode_ptr(corrupt.atlas.pages() - 1, &(corrupt.config.max_bricks() - 1)).unwrap();
+        assert!(BrickGrid::from_volxel(&corrupt.to_volxel()).is_err(), "pointer outside of the atlas was read");
+
+        let mut corrupt = phantom_grid(AtlasPrecision::U8);
+        corrupt.brick_counter -= 1;
+        assert!(BrickGrid::from_volxel(&corrupt.to_volxel()).is_err(), "atlas with too few bricks was read");
+
+        let mut corrupt = phantom_grid(AtlasPrecision::U8);
+        corrupt.range_mipmaps.pop();
+        assert!(BrickGrid::from_volxel(&corrupt.to_volxel()).is_err(), "missing mipmap was read");
+    }
+
+    #[test]
+    fn corrupt_headers_are_rejected() {
+        let bytes = phantom_grid(AtlasPrecision::U8).to_volxel();
+
+        let mut other_version = bytes.clone();
+        other_version[8..12].copy_from_slice(&4u32.to_le_bytes());
+        assert!(BrickGrid::from_volxel(&other_version).is_err());
+
+        // the first section is META, whose uncompressed size follows its tag
+        let mut huge_section = bytes.clone();
+        huge_section[20..28].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(BrickGrid::from_volxel(&huge_section).is_err());
+
+        let mut huge_compressed = bytes.clone();
+        huge_compressed[28..36].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(BrickGrid::from_volxel(&huge_compressed).is_err());
+
+        assert!(BrickGrid::from_volxel(&bytes[..bytes.len() / 2]).is_err());
+    }
+}
@@ -0,0 +1,661 @@
+use crate::buf3d::Buf3D;
+use crate::volume::{decode_samples, voxel_count, DenseGrid, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use base64::Engine;
+use glam::{Mat3, Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::Read;
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Readers for VTK image data, both the legacy STRUCTURED_POINTS and the XML ImageData (.vti) format.
+// Format description: https://docs.vtk.org/en/latest/design_documents/VTKFileFormats.html
+// Arrays with several components (e.g. velocity vectors) are reduced to their magnitude.
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+fn not_enough_data(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::NotEnoughData, message)
+}
+
+/// number of values of `tuples` tuples with `components` each, failing for counts from a corrupt file
+fn value_count(tuples: usize, components: usize) -> Result<usize, VolumeReadError> {
+    if components == 0 {
+        return Err(invalid("Arrays need at least one component"));
+    }
+    tuples.checked_mul(components).ok_or_else(|| invalid(format!("{} tuples of {} components are too many", tuples, components)))
+}
+
+/// Reduces interleaved tuples to their magnitude
+fn combine_components(values: Vec<f32>, components: usize) -> Vec<f32> {
+    if components <= 1 {
+        return values;
+    }
+    values.chunks_exact(components).map(|tuple| tuple.iter().map(|v| v * v).sum::<f32>().sqrt()).collect()
+}
+
+fn parse_ascii_values(text: &str, count: usize) -> Result<Vec<f32>, VolumeReadError> {
+    let values = text
+        .split_ascii_whitespace()
+        .take(count)
+        .map(|value| value.parse::<f64>().map(|value| value as f32))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid("Couldn't parse ASCII values"))?;
+    if values.len() != count {
+        return Err(not_enough_data(format!("Expected {} values, found {}", count, values.len())));
+    }
+    Ok(values)
+}
+
+fn parse_triple(value: &str) -> Result<Vec3, VolumeReadError> {
+    let values = value.split_whitespace().map(str::parse::<f32>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid(format!("Couldn't parse \"{}\"", value)))?;
+    match values.as_slice() {
+        [x, y, z] => Ok(Vec3::new(*x, *y, *z)),
+        _ => Err(invalid(format!("Expected three values, got \"{}\"", value))),
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// legacy format
+
+fn parse_legacy_type(name: &str) -> Result<SampleType, VolumeReadError> {
+    Ok(match name.to_lowercase().as_str() {
+        "unsigned_char" | "vtktypeuint8" => SampleType::U8,
+        "char" | "signed_char" | "vtktypeint8" => SampleType::I8,
+        "unsigned_short" | "vtktypeuint16" => SampleType::U16,
+        "short" | "vtktypeint16" => SampleType::I16,
+        "unsigned_int" | "vtktypeuint32" => SampleType::U32,
+        "int" | "vtktypeint32" => SampleType::I32,
+        "unsigned_long" | "vtktypeuint64" | "vtkidtype" => SampleType::U64,
+        "long" | "vtktypeint64" => SampleType::I64,
+        "float" | "vtktypefloat32" => SampleType::F32,
+        "double" | "vtktypefloat64" => SampleType::F64,
+        other => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported data type \"{}\"", other))),
+    })
+}
+
+struct LegacyReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    binary: bool,
+}
+
+impl<'a> LegacyReader<'a> {
+    fn raw_line(&mut self) -> Option<String> {
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        let rest = &self.bytes[self.offset..];
+        let end = rest.iter().position(|b| *b == b'\n').unwrap_or(rest.len());
+        self.offset += (end + 1).min(rest.len());
+        Some(String::from_utf8_lossy(&rest[..end]).trim().to_string())
+    }
+
+    /// next line that isn't empty
+    fn line(&mut self) -> Option<String> {
+        loop {
+            let line = self.raw_line()?;
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+    }
+
+    fn values(&mut self, sample_type: SampleType, count: usize) -> Result<Vec<f32>, VolumeReadError> {
+        if self.binary {
+            // binary legacy files are always big endian
+            let values = decode_samples(&self.bytes[self.offset..], sample_type, Endianness::Big, count)?;
+            self.offset += count * sample_type.size();
+            Ok(values)
+        } else {
+            let mut values = Vec::new();
+            while values.len() < count {
+                let line = self.raw_line().ok_or_else(|| not_enough_data(format!("Expected {} values, found {}", count, values.len())))?;
+                values.append(&mut parse_ascii_values(&line, line.split_ascii_whitespace().count().min(count - values.len()))?);
+            }
+            Ok(values)
+        }
+    }
+}
+
+/// Parses a legacy `.vtk` file containing STRUCTURED_POINTS. The first scalar point data array is used.
+pub fn parse_legacy_vtk(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    let mut reader = LegacyReader { bytes, offset: 0, binary: false };
+    if !reader.raw_line().is_some_and(|line| line.starts_with("# vtk DataFile")) {
+        return Err(invalid("File does not start with the VTK magic"));
+    }
+    // title
+    reader.raw_line();
+    reader.binary = match reader.line().map(|line| line.to_uppercase()).as_deref() {
+        Some("ASCII") => false,
+        Some("BINARY") => true,
+        other => return Err(invalid(format!("Unknown file type {:?}", other))),
+    };
+
+    let mut extent = None;
+    let mut origin = Vec3::ZERO;
+    let mut spacing = Vec3::ONE;
+    let mut point_count = None;
+    // number of tuples of the attributes that follow, either points or cells
+    let mut attribute_count = 0;
+    let mut in_point_data = false;
+    while let Some(line) = reader.line() {
+        let mut parts = line.split_whitespace();
+        let keyword = parts.next().unwrap_or_default().to_uppercase();
+        let arguments: Vec<&str> = parts.collect();
+        let argument = |index: usize| arguments.get(index).copied().ok_or_else(|| invalid(format!("Missing argument for {}", keyword)));
+        let count_argument = |index: usize| argument(index)?.parse::<usize>().map_err(|_| invalid(format!("Couldn't parse count of {}", keyword)));
+
+        match keyword.as_str() {
+            "DATASET" => {
+                if !argument(0)?.eq_ignore_ascii_case("STRUCTURED_POINTS") {
+                    return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported dataset type \"{}\"", argument(0)?)));
+                }
+            }
+            "DIMENSIONS" => {
+                let dimensions = parse_triple(&arguments.join(" "))?;
+                extent = Some(UVec3::new(dimensions.x as u32, dimensions.y as u32, dimensions.z as u32));
+            }
+            "ORIGIN" => origin = parse_triple(&arguments.join(" "))?,
+            "SPACING" | "ASPECT_RATIO" => spacing = parse_triple(&arguments.join(" "))?,
+            "POINT_DATA" => {
+                attribute_count = count_argument(0)?;
+                point_count = Some(attribute_count);
+                in_point_data = true;
+            }
+            "CELL_DATA" => {
+                attribute_count = count_argument(0)?;
+                in_point_data = false;
+            }
+            "SCALARS" => {
+                let sample_type = parse_legacy_type(argument(1)?)?;
+                let components = if arguments.len() > 2 { count_argument(2)? } else { 1 };
+                // the lookup table line is optional in files written by some tools
+                let before_lookup = reader.offset;
+                if !reader.line().is_some_and(|line| line.to_uppercase().starts_with("LOOKUP_TABLE")) {
+                    reader.offset = before_lookup;
+                }
+                let values = reader.values(sample_type, value_count(attribute_count, components)?)?;
+                if in_point_data {
+                    let extent = extent.ok_or_else(|| invalid("Missing DIMENSIONS"))?;
+                    let count = voxel_count(extent)?;
+                    if point_count != Some(count) {
+                        return Err(invalid(format!("POINT_DATA count {:?} does not match the dimensions {}", point_count, extent)));
+                    }
+                    let mut data = Buf3D::new(extent);
+                    data.data = combine_components(values, components);
+                    return Ok(DenseGrid::new(data, Mat4::from_translation(origin) * Mat4::from_scale(spacing)));
+                }
+            }
+            // other attributes are skipped
+            "COLOR_SCALARS" => {
+                let components = count_argument(1)?;
+                reader.values(if reader.binary { SampleType::U8 } else { SampleType::F32 }, value_count(attribute_count, components)?)?;
+            }
+            "LOOKUP_TABLE" => {
+                let size = count_argument(1)?;
+                reader.values(if reader.binary { SampleType::U8 } else { SampleType::F32 }, value_count(size, 4)?)?;
+            }
+            "VECTORS" | "NORMALS" => {
+                reader.values(parse_legacy_type(argument(1)?)?, value_count(attribute_count, 3)?)?;
+            }
+            "TENSORS" => {
+                reader.values(parse_legacy_type(argument(1)?)?, value_count(attribute_count, 9)?)?;
+            }
+            "TEXTURE_COORDINATES" => {
+                reader.values(parse_legacy_type(argument(2)?)?, value_count(attribute_count, count_argument(1)?)?)?;
+            }
+            "FIELD" => {
+                for _ in 0..count_argument(1)? {
+                    let array = reader.line().ok_or_else(|| not_enough_data("Field data ended early"))?;
+                    let array: Vec<&str> = array.split_whitespace().collect();
+                    let [_, components, tuples, sample_type] = array.as_slice() else {
+                        return Err(invalid("Malformed field array"));
+                    };
+                    let count = value_count(
+                        tuples.parse::<usize>().map_err(|_| invalid("Malformed field array"))?,
+                        components.parse::<usize>().map_err(|_| invalid("Malformed field array"))?,
+                    )?;
+                    reader.values(parse_legacy_type(sample_type)?, count)?;
+                }
+            }
+            "METADATA" => {
+                // metadata blocks end with an empty line
+                while reader.raw_line().is_some_and(|line| !line.is_empty()) {}
+            }
+            _ => return Err(invalid(format!("Unknown keyword \"{}\"", keyword))),
+        }
+    }
+    Err(not_enough_data("File contains no scalar point data"))
+}
+
+// ---------------------------------------------------------------------------------------------
+// XML format
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArrayFormat {
+    Ascii,
+    Binary,
+    Appended,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compressor {
+    Zlib,
+    Lz4,
+}
+
+struct DataArray {
+    name: String,
+    sample_type: SampleType,
+    components: usize,
+    format: ArrayFormat,
+    offset: usize,
+    text: String,
+}
+
+struct Piece {
+    extent: [i32; 6],
+    array: Option<DataArray>,
+}
+
+fn parse_xml_type(name: &str) -> Result<SampleType, VolumeReadError> {
+    Ok(match name {
+        "UInt8" => SampleType::U8,
+        "Int8" => SampleType::I8,
+        "UInt16" => SampleType::U16,
+        "Int16" => SampleType::I16,
+        "UInt32" => SampleType::U32,
+        "Int32" => SampleType::I32,
+        "UInt64" => SampleType::U64,
+        "Int64" => SampleType::I64,
+        "Float32" => SampleType::F32,
+        "Float64" => SampleType::F64,
+        other => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported data type \"{}\"", other))),
+    })
+}
+
+fn parse_extent(value: &str) -> Result<[i32; 6], VolumeReadError> {
+    value
+        .split_whitespace()
+        .map(str::parse::<i32>)
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .and_then(|values| values.try_into().ok())
+        .ok_or_else(|| invalid(format!("Couldn't parse extent \"{}\"", value)))
+}
+
+fn extent_size(extent: &[i32; 6]) -> Result<UVec3, VolumeReadError> {
+    let size = |axis: usize| {
+        let size = extent[axis * 2 + 1] as i64 - extent[axis * 2] as i64 + 1;
+        u32::try_from(size).ok().filter(|size| *size > 0).ok_or_else(|| invalid(format!("Invalid extent {:?}", extent)))
+    };
+    Ok(UVec3::new(size(0)?, size(1)?, size(2)?))
+}
+
+fn attributes(element: &BytesStart) -> Result<HashMap<String, String>, VolumeReadError> {
+    element
+        .attributes()
+        .map(|attribute| {
+            let attribute = attribute.map_err(|e| invalid(e.to_string()))?;
+            let value = attribute.unescape_value().map_err(|e| invalid(e.to_string()))?;
+            Ok((String::from_utf8_lossy(attribute.key.as_ref()).into_owned(), value.into_owned()))
+        })
+        .collect()
+}
+
+/// Decodes base64 text that may consist of several separately encoded, padded blocks
+fn decode_base64(text: &str) -> Result<Vec<u8>, VolumeReadError> {
+    let text: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    let mut decoded = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        // a block ends after its padding
+        let mut end = text[start..].iter().position(|b| *b == b'=').map_or(text.len(), |pos| start + pos);
+        while end < text.len() && text[end] == b'=' {
+            end += 1;
+        }
+        base64::engine::general_purpose::STANDARD
+            .decode_vec(&text[start..end], &mut decoded)
+            .map_err(|e| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e.to_string()))?;
+        start = end;
+    }
+    Ok(decoded)
+}
+
+struct BlockReader {
+    header_size: usize,
+    endianness: Endianness,
+    compressor: Option<Compressor>,
+}
+
+impl BlockReader {
+    fn header_int(&self, bytes: &[u8], index: usize) -> Result<usize, VolumeReadError> {
+        let sample_type = if self.header_size == 8 { SampleType::U64 } else { SampleType::U32 };
+        let bytes = index.checked_mul(self.header_size).and_then(|offset| bytes.get(offset..)).ok_or_else(|| not_enough_data("Data block header is truncated"))?;
+        Ok(decode_samples(bytes, sample_type, self.endianness, 1)?[0] as usize)
+    }
+
+    /// Reads the data of a binary block, which is prefixed by its size or, if compressed, its block table
+    fn read(&self, bytes: &[u8]) -> Result<Vec<u8>, VolumeReadError> {
+        let Some(compressor) = self.compressor else {
+            let size = self.header_int(bytes, 0)?;
+            return self
+                .header_size
+                .checked_add(size)
+                .and_then(|end| bytes.get(self.header_size..end))
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| not_enough_data(format!("Expected {} bytes of array data", size)));
+        };
+
+        let blocks = self.header_int(bytes, 0)?;
+        let block_size = self.header_int(bytes, 1)?;
+        let last_block_size = self.header_int(bytes, 2)?;
+        let truncated = || not_enough_data("Compressed block is truncated");
+        let mut offset = blocks.checked_add(3).and_then(|headers| headers.checked_mul(self.header_size)).ok_or_else(truncated)?;
+        let mut data = Vec::new();
+        for block in 0..blocks {
+            let compressed_size = self.header_int(bytes, 3 + block)?;
+            let end = offset.checked_add(compressed_size).ok_or_else(truncated)?;
+            let compressed = bytes.get(offset..end).ok_or_else(truncated)?;
+            offset = end;
+            let size = if block + 1 == blocks && last_block_size != 0 { last_block_size } else { block_size };
+            let failed = |e: String| VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, e);
+            match compressor {
+                Compressor::Zlib => {
+                    flate2::read::ZlibDecoder::new(compressed).read_to_end(&mut data).map_err(|e| failed(e.to_string()))?;
+                }
+                Compressor::Lz4 => data.append(&mut lz4_flex::block::decompress(compressed, size).map_err(|e| failed(e.to_string()))?),
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Parses an XML ImageData (`.vti`) file. The active scalars of the point data are used, or the first
+/// point data array if none are marked active. Inline and appended data, raw or base64 encoded and
+/// optionally zlib or LZ4 compressed, are supported.
+pub fn parse_vti(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    let mut reader = Reader::from_reader(bytes);
+    let mut endianness = Endianness::Little;
+    let mut header_size = 4;
+    let mut compressor = None;
+    let mut whole_extent = None;
+    let mut origin = Vec3::ZERO;
+    let mut spacing = Vec3::ONE;
+    let mut direction = Mat3::IDENTITY;
+    let mut pieces: Vec<Piece> = Vec::new();
+    let mut active_scalars: Option<String> = None;
+    let mut in_point_data = false;
+    let mut current_array: Option<DataArray> = None;
+    let mut appended: Option<(usize, String)> = None;
+    // offsets of all appended arrays, needed to find where base64 encoded arrays end
+    let mut appended_offsets = Vec::new();
+
+    loop {
+        let event = reader.read_event().map_err(|e| invalid(e.to_string()))?;
+        let (element, empty) = match &event {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::Text(text) => {
+                if let Some(array) = &mut current_array {
+                    array.text.push_str(&text.unescape().map_err(|e| invalid(e.to_string()))?);
+                }
+                continue;
+            }
+            Event::End(element) => {
+                match element.name().as_ref() {
+                    b"PointData" => in_point_data = false,
+                    b"DataArray" => {
+                        if let (Some(array), Some(piece)) = (current_array.take(), pieces.last_mut()) {
+                            select_array(piece, array, &active_scalars);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        let attributes = attributes(element)?;
+        let attribute = |name: &str| attributes.get(name).map(String::as_str);
+        match element.name().as_ref() {
+            b"VTKFile" => {
+                if attribute("type") != Some("ImageData") {
+                    return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported VTK file type {:?}", attribute("type"))));
+                }
+                if attribute("byte_order") == Some("BigEndian") {
+                    endianness = Endianness::Big;
+                }
+                if attribute("header_type") == Some("UInt64") {
+                    header_size = 8;
+                }
+                compressor = match attribute("compressor") {
+                    None | Some("") => None,
+                    Some("vtkZLibDataCompressor") => Some(Compressor::Zlib),
+                    Some("vtkLZ4DataCompressor") => Some(Compressor::Lz4),
+                    Some(other) => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported compressor \"{}\"", other))),
+                };
+            }
+            b"ImageData" => {
+                whole_extent = Some(parse_extent(attribute("WholeExtent").ok_or_else(|| invalid("Missing WholeExtent"))?)?);
+                if let Some(value) = attribute("Origin") {
+                    origin = parse_triple(value)?;
+                }
+                if let Some(value) = attribute("Spacing") {
+                    spacing = parse_triple(value)?;
+                }
+                if let Some(value) = attribute("Direction") {
+                    let values: Vec<f32> = value.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+                    let values: [f32; 9] = values.try_into().map_err(|_| invalid("Direction needs nine values"))?;
+                    // the direction matrix is stored row by row
+                    direction = Mat3::from_cols_array(&values).transpose();
+                }
+            }
+            b"Piece" => pieces.push(Piece {
+                extent: parse_extent(attribute("Extent").ok_or_else(|| invalid("Missing piece Extent"))?)?,
+                array: None,
+            }),
+            b"PointData" if !empty => {
+                in_point_data = true;
+                active_scalars = attribute("Scalars").map(str::to_string);
+            }
+            b"DataArray" => {
+                let format = match attribute("format") {
+                    Some("ascii") => ArrayFormat::Ascii,
+                    Some("binary") => ArrayFormat::Binary,
+                    Some("appended") => ArrayFormat::Appended,
+                    other => return Err(invalid(format!("Unknown array format {:?}", other))),
+                };
+                let offset = attribute("offset").map_or(Ok(0), str::parse).map_err(|_| invalid("Couldn't parse array offset"))?;
+                if format == ArrayFormat::Appended {
+                    appended_offsets.push(offset);
+                }
+                if in_point_data {
+                    let array = DataArray {
+                        name: attribute("Name").unwrap_or_default().to_string(),
+                        sample_type: parse_xml_type(attribute("type").ok_or_else(|| invalid("Missing array type"))?)?,
+                        components: attribute("NumberOfComponents").map_or(Ok(1), str::parse).map_err(|_| invalid("Couldn't parse NumberOfComponents"))?,
+                        format,
+                        offset,
+                        text: String::new(),
+                    };
+                    if empty {
+                        if let Some(piece) = pieces.last_mut() {
+                            select_array(piece, array, &active_scalars);
+                        }
+                    } else {
+                        current_array = Some(array);
+                    }
+                }
+            }
+            b"AppendedData" => {
+                // appended raw data isn't valid XML, so parsing stops here. The data starts after an underscore.
+                let position = reader.buffer_position() as usize;
+                let start = bytes[position..].iter().position(|b| *b == b'_').ok_or_else(|| invalid("Appended data is missing its '_' marker"))?;
+                appended = Some((position + start + 1, attribute("encoding").unwrap_or("raw").to_string()));
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let whole_extent = whole_extent.ok_or_else(|| invalid("Missing ImageData element"))?;
+    let size = extent_size(&whole_extent)?;
+    voxel_count(size)?;
+    let block_reader = BlockReader { header_size, endianness, compressor };
+    appended_offsets.sort_unstable();
+
+    let mut volume = Buf3D::new(size);
+    for piece in &pieces {
+        let Some(array) = &piece.array else {
+            return Err(not_enough_data("Piece has no point data"));
+        };
+        let piece_size = extent_size(&piece.extent)?;
+        let count = value_count(voxel_count(piece_size)?, array.components)?;
+        let values = match array.format {
+            ArrayFormat::Ascii => parse_ascii_values(&array.text, count)?,
+            ArrayFormat::Binary => decode_samples(&block_reader.read(&decode_base64(&array.text)?)?, array.sample_type, endianness, count)?,
+            ArrayFormat::Appended => {
+                let (start, encoding) = appended.as_ref().ok_or_else(|| not_enough_data("Array refers to missing appended data"))?;
+                let data = start.checked_add(array.offset).and_then(|start| bytes.get(start..)).ok_or_else(|| not_enough_data("Array offset exceeds the appended data"))?;
+                let block = if encoding == "base64" {
+                    let end = appended_offsets
+                        .iter()
+                        .find(|offset| **offset > array.offset)
+                        .map_or_else(|| data.iter().position(|b| *b == b'<').unwrap_or(data.len()), |offset| offset - array.offset);
+                    decode_base64(&String::from_utf8_lossy(&data[..end.min(data.len())]))?
+                } else {
+                    data.to_vec()
+                };
+                decode_samples(&block_reader.read(&block)?, array.sample_type, endianness, count)?
+            }
+        };
+        let values = combine_components(values, array.components);
+
+        // copy the piece into its place in the whole extent
+        let outside = || invalid(format!("Piece extent {:?} lies outside of the whole extent {:?}", piece.extent, whole_extent));
+        let axis_offset = |axis: usize| u32::try_from(piece.extent[axis * 2] as i64 - whole_extent[axis * 2] as i64).map_err(|_| outside());
+        let offset = UVec3::new(axis_offset(0)?, axis_offset(1)?, axis_offset(2)?);
+        if (offset.as_u64vec3() + piece_size.as_u64vec3()).cmpgt(size.as_u64vec3()).any() {
+            return Err(outside());
+        }
+        for z in 0..piece_size.z {
+            for y in 0..piece_size.y {
+                let row = ((z * piece_size.y + y) * piece_size.x) as usize;
+                let target = volume.calculate_index(offset + UVec3::new(0, y, z));
+                volume.data[target..target + piece_size.x as usize].copy_from_slice(&values[row..row + piece_size.x as usize]);
+            }
+        }
+    }
+    if pieces.is_empty() {
+        return Err(not_enough_data("File contains no pieces"));
+    }
+
+    let start = Vec3::new(whole_extent[0] as f32, whole_extent[2] as f32, whole_extent[4] as f32);
+    let transform = Mat4::from_translation(origin) * Mat4::from_mat3(direction) * Mat4::from_scale(spacing) * Mat4::from_translation(start);
+    Ok(DenseGrid::new(volume, transform))
+}
+
+/// Keeps the active scalars, or otherwise the first array of the piece
+fn select_array(piece: &mut Piece, array: DataArray, active_scalars: &Option<String>) {
+    let is_active = |array: &DataArray| active_scalars.as_deref() == Some(array.name.as_str());
+    if piece.array.as_ref().is_none_or(|current| !is_active(current) && is_active(&array)) {
+        piece.array = Some(array);
+    }
+}
+
+/// Reads either a legacy `.vtk` or an XML `.vti` file, distinguished by their content
+pub fn parse_vtk(bytes: &[u8]) -> Result<DenseGrid, VolumeReadError> {
+    if bytes.starts_with(b"# vtk DataFile") {
+        parse_legacy_vtk(bytes)
+    } else {
+        parse_vti(bytes)
+    }
+}
+
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_vtk(bytes: Uint8Array) -> Result<DenseGrid, VolumeReadError> {
+    parse_vtk(&bytes.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::Grid;
+
+    const LEGACY_HEADER: &str = "# vtk DataFile Version 3.0\nramp\n";
+    const STRUCTURE: &str = "DATASET STRUCTURED_POINTS\nDIMENSIONS 2 2 1\nORIGIN 1 2 3\nSPACING 0.5 0.5 1\nPOINT_DATA 4\n";
+
+    fn assert_ramp(volume: &DenseGrid) {
+        assert_eq!(volume.index_extent(), UVec3::new(2, 2, 1));
+        let values: Vec<f32> = [(0, 0), (1, 0), (0, 1), (1, 1)].iter().map(|(x, y)| volume.lookup(UVec3::new(*x, *y, 0))).collect();
+        assert_eq!(values, [0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]);
+    }
+
+    #[test]
+    fn legacy_files_are_read() {
+        let ascii = format!("{}ASCII\n{}SCALARS density float\nLOOKUP_TABLE default\n0 1\n2 3\n", LEGACY_HEADER, STRUCTURE);
+        let volume = parse_vtk(ascii.as_bytes()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_ramp(&volume);
+        assert_eq!(volume.transform(), Mat4::from_translation(Vec3::new(1.0, 2.0, 3.0)) * Mat4::from_scale(Vec3::new(0.5, 0.5, 1.0)));
+
+        // binary data is big endian, and the field data before the scalars is skipped
+        let mut binary = format!("{}BINARY\n{}FIELD FieldData 1\nlabels 1 4 unsigned_char\n", LEGACY_HEADER, STRUCTURE).into_bytes();
+        binary.extend([9; 4]);
+        binary.extend(b"\nSCALARS density short 1\n");
+        binary.extend([0i16, 10, 20, 30].iter().flat_map(|value| value.to_be_bytes()));
+        assert_ramp(&parse_vtk(&binary).unwrap_or_else(|e| panic!("{}", e.message())));
+    }
+
+    #[test]
+    fn xml_files_are_read() {
+        let inline = r#"<VTKFile type="ImageData" byte_order="LittleEndian">
+            <ImageData WholeExtent="1 2 0 1 0 0" Origin="0 0 0" Spacing="1 1 1">
+                <Piece Extent="1 2 0 1 0 0">
+                    <PointData Scalars="density">
+                        <DataArray type="Float32" Name="labels" format="ascii">9 9 9 9</DataArray>
+                        <DataArray type="Float32" Name="density" format="ascii">0 1 2 3</DataArray>
+                    </PointData>
+                </Piece>
+            </ImageData>
+        </VTKFile>"#;
+        let volume = parse_vtk(inline.as_bytes()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_ramp(&volume);
+        assert_eq!(volume.transform(), Mat4::from_translation(Vec3::X));
+
+        let mut appended = br#"<VTKFile type="ImageData" byte_order="LittleEndian" header_type="UInt32">
+            <ImageData WholeExtent="0 1 0 1 0 0">
+                <Piece Extent="0 1 0 1 0 0">
+                    <PointData><DataArray type="UInt16" Name="density" format="appended" offset="0"/></PointData>
+                </Piece>
+            </ImageData>
+            <AppendedData encoding="raw">_"#
+            .to_vec();
+        appended.extend(8u32.to_le_bytes());
+        appended.extend([0u16, 1, 2, 3].iter().flat_map(|value| value.to_le_bytes()));
+        appended.extend(b"</AppendedData></VTKFile>");
+        assert_ramp(&parse_vtk(&appended).unwrap_or_else(|e| panic!("{}", e.message())));
+    }
+
+    #[test]
+    fn corrupt_counts_and_extents_are_invalid() {
+        let invalid_header = |text: &str| matches!(parse_vtk(text.as_bytes()).err().map(|e| e.0), Some(VolumeReadErrorType::InvalidHeader));
+
+        assert!(invalid_header(&format!("{}ASCII\n{}SCALARS density float 0\n", LEGACY_HEADER, STRUCTURE)));
+        let tuples = STRUCTURE.replace("POINT_DATA 4", &format!("POINT_DATA {}", usize::MAX));
+        assert!(invalid_header(&format!("{}ASCII\n{}VECTORS velocity float\n", LEGACY_HEADER, tuples)));
+
+        let outside = r#"<VTKFile type="ImageData"><ImageData WholeExtent="0 1 0 1 0 0"><Piece Extent="-2147483648 2147483647 0 1 0 0">
+            <PointData><DataArray type="Float32" Name="density" format="ascii">0 1 2 3</DataArray></PointData>
+        </Piece></ImageData></VTKFile>"#;
+        assert!(invalid_header(outside));
+    }
+}
@@ -0,0 +1,598 @@
+use crate::blosc;
+use crate::brick::BrickGrid;
+use crate::brick_config::BrickGridConfig;
+use crate::grid::{compute_histogram_gradient, Grid};
+use crate::progress::LoadProgress;
+use crate::utils::{log_to_console, now};
+use crate::volume::{decode_samples, Endianness, SampleType, VolumeReadError, VolumeReadErrorType};
+use glam::{Mat4, UVec3, Vec3};
+#[cfg(feature = "wasm")]
+use crate::volume::NamedFiles;
+#[cfg(feature = "wasm")]
+use js_sys::Uint8Array;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Read;
+use std::sync::{Arc, OnceLock, RwLock};
+#[cfg(test)]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "wasm")]
+use wasm_bindgen::prelude::wasm_bindgen;
+
+// Reader for Zarr v2 arrays and OME-Zarr (NGFF 0.4) multiscale images.
+// Zarr v2 specification: https://zarr-specs.readthedocs.io/en/latest/v2/v2.0.html
+// OME-Zarr specification: https://ngff.openmicroscopy.org/0.4/
+
+const HISTOGRAM_BINS: usize = 4096;
+
+fn invalid(message: impl Into<String>) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::InvalidHeader, message)
+}
+
+fn decompression_failed(error: impl ToString) -> VolumeReadError {
+    VolumeReadError::new(VolumeReadErrorType::DecompressionFailed, error.to_string())
+}
+
+/// Key value store holding the files of a Zarr hierarchy, keyed by their path relative to the root
+struct ZarrStore {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl ZarrStore {
+    /// Finds the root of the hierarchy, which is the shallowest group with multiscales metadata or
+    /// otherwise the shallowest array
+    fn new(files: Vec<(String, Vec<u8>)>) -> Result<Self, VolumeReadError> {
+        let files: HashMap<String, Vec<u8>> = files
+            .into_iter()
+            .map(|(name, bytes)| (name.replace('\\', "/").trim_start_matches("./").trim_start_matches('/').to_string(), bytes))
+            .collect();
+
+        let depth = |key: &&String| key.matches('/').count();
+        let multiscales = files
+            .iter()
+            .filter(|(key, bytes)| {
+                (key.as_str() == ".zattrs" || key.ends_with("/.zattrs"))
+                    && serde_json::from_slice::<Value>(bytes).is_ok_and(|attributes| attributes.get("multiscales").is_some())
+            })
+            .map(|(key, _)| key)
+            .min_by_key(depth);
+        let root_key = multiscales
+            .or_else(|| files.keys().filter(|key| key.as_str() == ".zarray" || key.ends_with("/.zarray")).min_by_key(depth))
+            .ok_or_else(|| invalid("No Zarr array or OME-Zarr group found"))?;
+        let root = root_key.rsplit_once('/').map_or(String::new(), |(root, _)| format!("{}/", root));
+
+        let files = files.into_iter().filter_map(|(key, bytes)| key.strip_prefix(&root).map(|key| (key.to_string(), bytes))).collect();
+        Ok(Self { files })
+    }
+
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.files.get(key).map(Vec::as_slice)
+    }
+
+    fn json(&self, key: &str) -> Result<Option<Value>, VolumeReadError> {
+        self.get(key)
+            .map(|bytes| serde_json::from_slice(bytes).map_err(|e| invalid(format!("Couldn't parse \"{}\": {}", key, e))))
+            .transpose()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Compressor {
+    Blosc,
+    Zstd,
+    Gzip,
+    Zlib,
+    Lz4,
+}
+
+/// Metadata of a single array, as stored in its `.zarray`
+struct ArrayMeta {
+    path: String,
+    shape: Vec<usize>,
+    chunks: Vec<usize>,
+    sample_type: SampleType,
+    endianness: Endianness,
+    compressor: Option<Compressor>,
+    fill_value: f32,
+    fortran_order: bool,
+    separator: String,
+}
+
+fn parse_dtype(dtype: &str) -> Result<(SampleType, Endianness), VolumeReadError> {
+    let endianness = if dtype.starts_with('>') { Endianness::Big } else { Endianness::Little };
+    let sample_type = match dtype.trim_start_matches(['<', '>', '|']) {
+        "u1" => SampleType::U8,
+        "i1" => SampleType::I8,
+        "u2" => SampleType::U16,
+        "i2" => SampleType::I16,
+        "u4" => SampleType::U32,
+        "i4" => SampleType::I32,
+        "u8" => SampleType::U64,
+        "i8" => SampleType::I64,
+        "f4" => SampleType::F32,
+        "f8" => SampleType::F64,
+        _ => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported dtype \"{}\"", dtype))),
+    };
+    Ok((sample_type, endianness))
+}
+
+fn parse_array_meta(store: &ZarrStore, path: &str) -> Result<ArrayMeta, VolumeReadError> {
+    let key = if path.is_empty() { ".zarray".to_string() } else { format!("{}/.zarray", path) };
+    let meta = store.json(&key)?.ok_or_else(|| VolumeReadError::new(VolumeReadErrorType::MissingDataFile, format!("\"{}\" was not provided", key)))?;
+
+    let usizes = |name: &str| -> Result<Vec<usize>, VolumeReadError> {
+        meta.get(name)
+            .and_then(Value::as_array)
+            .and_then(|values| values.iter().map(|value| value.as_u64().map(|value| value as usize)).collect())
+            .ok_or_else(|| invalid(format!("Missing or invalid \"{}\" in \"{}\"", name, key)))
+    };
+    let shape = usizes("shape")?;
+    let chunks = usizes("chunks")?;
+    if shape.len() != chunks.len() || shape.len() < 2 || chunks.contains(&0) {
+        return Err(invalid(format!("Shape {:?} and chunks {:?} don't describe a volume", shape, chunks)));
+    }
+    // the volume axes are indexed with u32, and whole chunks are decoded into memory
+    let spatial = shape.len().saturating_sub(3);
+    let oversized = shape[spatial..].iter().chain(&chunks[spatial..]).any(|size| u32::try_from(*size).is_err());
+    if oversized || chunks.iter().try_fold(1usize, |count, chunk| count.checked_mul(*chunk)).is_none() {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Shape {:?} with chunks {:?} is too large", shape, chunks)));
+    }
+
+    let (sample_type, endianness) = parse_dtype(meta.get("dtype").and_then(Value::as_str).ok_or_else(|| invalid("Missing dtype"))?)?;
+    let compressor = match meta.get("compressor") {
+        None | Some(Value::Null) => None,
+        Some(compressor) => Some(match compressor.get("id").and_then(Value::as_str) {
+            Some("blosc") => Compressor::Blosc,
+            Some("zstd") => Compressor::Zstd,
+            Some("gzip") => Compressor::Gzip,
+            Some("zlib") => Compressor::Zlib,
+            Some("lz4") => Compressor::Lz4,
+            other => return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Unsupported compressor {:?}", other))),
+        }),
+    };
+    if meta.get("filters").and_then(Value::as_array).is_some_and(|filters| !filters.is_empty()) {
+        return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "Zarr filters are not supported"));
+    }
+    let fill_value = match meta.get("fill_value") {
+        Some(Value::Number(number)) => number.as_f64().unwrap_or(0.0) as f32,
+        Some(Value::String(special)) if special == "NaN" => f32::NAN,
+        _ => 0.0,
+    };
+
+    Ok(ArrayMeta {
+        path: path.to_string(),
+        shape,
+        chunks,
+        sample_type,
+        endianness,
+        compressor,
+        fill_value,
+        fortran_order: meta.get("order").and_then(Value::as_str) == Some("F"),
+        separator: meta.get("dimension_separator").and_then(Value::as_str).unwrap_or(".").to_string(),
+    })
+}
+
+impl ArrayMeta {
+    /// size of the volume, the last three dimensions being z, y and x. Leading dimensions like time
+    /// or channel are fixed to their first index.
+    fn extent(&self) -> UVec3 {
+        let n = self.shape.len();
+        let z = if n >= 3 { self.shape[n - 3] } else { 1 };
+        UVec3::new(self.shape[n - 1] as u32, self.shape[n - 2] as u32, z as u32)
+    }
+
+    fn chunk_extent(&self) -> UVec3 {
+        let n = self.chunks.len();
+        let z = if n >= 3 { self.chunks[n - 3] } else { 1 };
+        UVec3::new(self.chunks[n - 1] as u32, self.chunks[n - 2] as u32, z as u32)
+    }
+
+    fn chunk_key(&self, chunk: UVec3) -> String {
+        let n = self.shape.len();
+        let mut indices = vec![0u32; n];
+        indices[n - 1] = chunk.x;
+        indices[n - 2] = chunk.y;
+        if n >= 3 {
+            indices[n - 3] = chunk.z;
+        }
+        let name = indices.iter().map(u32::to_string).collect::<Vec<_>>().join(&self.separator);
+        if self.path.is_empty() { name } else { format!("{}/{}", self.path, name) }
+    }
+
+    /// Decodes a chunk into x-fastest order, returning `None` for chunks that aren't stored
+    fn read_chunk(&self, store: &ZarrStore, chunk: UVec3) -> Result<Option<Vec<f32>>, VolumeReadError> {
+        let Some(bytes) = store.get(&self.chunk_key(chunk)) else {
+            return Ok(None);
+        };
+        let decompressed;
+        let data = match self.compressor {
+            None => bytes,
+            Some(compressor) => {
+                decompressed = match compressor {
+                    Compressor::Blosc => blosc::decompress(bytes)?,
+                    Compressor::Zstd => zstd::stream::decode_all(bytes).map_err(decompression_failed)?,
+                    Compressor::Gzip => {
+                        let mut data = Vec::new();
+                        flate2::read::MultiGzDecoder::new(bytes).read_to_end(&mut data).map_err(decompression_failed)?;
+                        data
+                    }
+                    Compressor::Zlib => {
+                        let mut data = Vec::new();
+                        flate2::read::ZlibDecoder::new(bytes).read_to_end(&mut data).map_err(decompression_failed)?;
+                        data
+                    }
+                    // numcodecs prefixes LZ4 blocks with their decompressed size
+                    Compressor::Lz4 => lz4_flex::block::decompress_size_prepended(bytes).map_err(decompression_failed)?,
+                };
+                decompressed.as_slice()
+            }
+        };
+
+        let count = self.chunks.iter().product();
+        let samples = decode_samples(data, self.sample_type, self.endianness, count)?;
+
+        // strides of all dimensions, of which only the last three are used
+        let n = self.chunks.len();
+        let mut strides = vec![1usize; n];
+        if self.fortran_order {
+            for i in 1..n {
+                strides[i] = strides[i - 1] * self.chunks[i - 1];
+            }
+        } else {
+            for i in (0..n - 1).rev() {
+                strides[i] = strides[i + 1] * self.chunks[i + 1];
+            }
+        }
+        let stride_z = if n >= 3 { strides[n - 3] } else { 0 };
+        let extent = self.chunk_extent();
+        if self.fortran_order || n > 3 {
+            let mut chunk_data = Vec::with_capacity((extent.x * extent.y * extent.z) as usize);
+            for z in 0..extent.z as usize {
+                for y in 0..extent.y as usize {
+                    for x in 0..extent.x as usize {
+                        chunk_data.push(samples[z * stride_z + y * strides[n - 2] + x * strides[n - 1]]);
+                    }
+                }
+            }
+            Ok(Some(chunk_data))
+        } else {
+            Ok(Some(samples))
+        }
+    }
+}
+
+/// Chunk that is decoded by the first thread asking for it, the others wait for it instead of decoding it again
+type CachedChunk = Arc<OnceLock<Arc<Vec<f32>>>>;
+
+/// Decoded chunks, of which only the ones near the currently bricked slab are kept
+#[derive(Default)]
+struct ChunkCache {
+    chunks: HashMap<UVec3, CachedChunk>,
+}
+
+/// Zarr array read chunk by chunk. Only decoded chunks are held in memory, never the whole volume.
+/// Lookups are normalized to [0, 1] using the minimum and maximum of the data.
+pub struct ZarrGrid {
+    store: ZarrStore,
+    meta: ArrayMeta,
+    extent: UVec3,
+    chunk_extent: UVec3,
+    min: f32,
+    max: f32,
+    histogram: Vec<u32>,
+    transform: Mat4,
+    cache: RwLock<ChunkCache>,
+    /// number of chunk layers along z that the lookups of one slab of bricks can touch
+    cached_layers: u32,
+    #[cfg(test)]
+    decodes: AtomicUsize,
+}
+
+impl ZarrGrid {
+    fn new(store: ZarrStore, meta: ArrayMeta, transform: Mat4, config: &BrickGridConfig) -> Result<Self, VolumeReadError> {
+        let extent = meta.extent();
+        let chunk_extent = meta.chunk_extent();
+        // bricks are looked up dilated by 2 voxels on both sides, a span that may start inside a layer
+        let cached_layers = (config.brick_size() + 4).div_ceil(chunk_extent.z) + 1;
+        let mut grid = Self {
+            store,
+            meta,
+            extent,
+            chunk_extent,
+            min: 0.0,
+            max: 0.0,
+            histogram: vec![0; HISTOGRAM_BINS],
+            transform,
+            cache: RwLock::new(ChunkCache::default()),
+            cached_layers,
+            #[cfg(test)]
+            decodes: AtomicUsize::new(0),
+        };
+
+        // the value range has to be known before the histogram can be computed, so chunks are decoded twice
+        let mut min = f32::MAX;
+        let mut max = f32::MIN;
+        grid.for_each_value(|value| {
+            min = min.min(value);
+            max = max.max(value);
+        })?;
+        if min > max {
+            min = 0.0;
+            max = 0.0;
+        }
+        let mut histogram = vec![0u32; HISTOGRAM_BINS];
+        grid.for_each_value(|value| {
+            let bin = if max > min { (((value - min) / (max - min)) * (HISTOGRAM_BINS - 1) as f32).round() as usize } else { 0 };
+            histogram[bin] += 1;
+        })?;
+        grid.min = min;
+        grid.max = max;
+        grid.histogram = histogram;
+        Ok(grid)
+    }
+
+    fn chunk_count(&self) -> UVec3 {
+        UVec3::new(
+            self.extent.x.div_ceil(self.chunk_extent.x),
+            self.extent.y.div_ceil(self.chunk_extent.y),
+            self.extent.z.div_ceil(self.chunk_extent.z),
+        )
+    }
+
+    /// Calls `f` with every finite value inside the volume
+    fn for_each_value(&self, mut f: impl FnMut(f32)) -> Result<(), VolumeReadError> {
+        let chunk_count = self.chunk_count();
+        for z in 0..chunk_count.z {
+            for y in 0..chunk_count.y {
+                for x in 0..chunk_count.x {
+                    let chunk = UVec3::new(x, y, z);
+                    let start = chunk * self.chunk_extent;
+                    // edge chunks are padded to the full chunk size
+                    let valid = (self.extent - start).min(self.chunk_extent);
+                    match self.meta.read_chunk(&self.store, chunk)? {
+                        Some(data) => {
+                            for local_z in 0..valid.z {
+                                for local_y in 0..valid.y {
+                                    let row = ((local_z * self.chunk_extent.y + local_y) * self.chunk_extent.x) as usize;
+                                    data[row..row + valid.x as usize].iter().filter(|value| value.is_finite()).for_each(|value| f(*value));
+                                }
+                            }
+                        }
+                        None if self.meta.fill_value.is_finite() => {
+                            for _ in 0..valid.x * valid.y * valid.z {
+                                f(self.meta.fill_value);
+                            }
+                        }
+                        None => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn chunk(&self, chunk: UVec3) -> Arc<Vec<f32>> {
+        let cached = self.cache.read().unwrap().chunks.get(&chunk).cloned();
+        let cached = cached.unwrap_or_else(|| {
+            let mut cache = self.cache.write().unwrap();
+            // the brick grid is built slab by slab along z, so layers behind the current slab aren't needed again
+            cache.chunks.retain(|cached, _| cached.z + self.cached_layers > chunk.z);
+            cache.chunks.entry(chunk).or_default().clone()
+        });
+        // decoded outside of the lock, so threads looking up other chunks aren't blocked
+        cached
+            .get_or_init(|| {
+                #[cfg(test)]
+                self.decodes.fetch_add(1, Ordering::Relaxed);
+                let data = match self.meta.read_chunk(&self.store, chunk) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        log_to_console(&format!("Couldn't read chunk {}: {}", chunk, e.message()));
+                        None
+                    }
+                };
+                Arc::new(data.unwrap_or_else(|| vec![self.meta.fill_value; (self.chunk_extent.x * self.chunk_extent.y * self.chunk_extent.z) as usize]))
+            })
+            .clone()
+    }
+}
+
+impl Grid for ZarrGrid {
+    fn lookup(&self, ipos: UVec3) -> f32 {
+        if ipos.cmpge(self.extent).any() || self.max <= self.min {
+            return 0.0;
+        }
+        let chunk = ipos / self.chunk_extent;
+        let data = self.chunk(chunk);
+        let local = ipos - chunk * self.chunk_extent;
+        let raw = data[((local.z * self.chunk_extent.y + local.y) * self.chunk_extent.x + local.x) as usize];
+        if !raw.is_finite() {
+            return 0.0;
+        }
+        (raw - self.min) / (self.max - self.min)
+    }
+
+    fn minorant_majorant(&self) -> (f32, f32) {
+        (0.0, 1.0)
+    }
+
+    fn index_extent(&self) -> UVec3 {
+        self.extent
+    }
+
+    fn num_voxels(&self) -> usize {
+        (self.extent.x as usize) * (self.extent.y as usize) * (self.extent.z as usize)
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.num_voxels() * self.meta.sample_type.size()
+    }
+
+    fn histogram(&self) -> Vec<u32> {
+        self.histogram.clone()
+    }
+
+    fn histogram_gradient(&self) -> (Vec<i32>, u32, u32) {
+        compute_histogram_gradient(&self.histogram)
+    }
+
+    fn transform(&self) -> Mat4 {
+        self.transform
+    }
+}
+
+/// Scale and translation of the last three (z, y, x) axes described by OME-Zarr coordinate transformations
+fn parse_coordinate_transformations(transformations: Option<&Value>) -> Mat4 {
+    let mut transform = Mat4::IDENTITY;
+    for transformation in transformations.and_then(Value::as_array).into_iter().flatten() {
+        let values = |name: &str| -> Option<Vec3> {
+            let values: Vec<f32> = transformation.get(name)?.as_array()?.iter().filter_map(|value| value.as_f64().map(|value| value as f32)).collect();
+            let n = values.len();
+            (n >= 3).then(|| Vec3::new(values[n - 1], values[n - 2], values[n - 3]))
+        };
+        match transformation.get("type").and_then(Value::as_str) {
+            Some("scale") => transform = Mat4::from_scale(values("scale").unwrap_or(Vec3::ONE)) * transform,
+            Some("translation") => transform = Mat4::from_translation(values("translation").unwrap_or(Vec3::ZERO)) * transform,
+            _ => {}
+        }
+    }
+    transform
+}
+
+/// Opens the Zarr hierarchy in `files`. For OME-Zarr multiscale images, the highest resolution
+/// that fits into the brick limits of `config` is chosen.
+pub fn parse_zarr(files: Vec<(String, Vec<u8>)>, config: &BrickGridConfig) -> Result<ZarrGrid, VolumeReadError> {
+    let store = ZarrStore::new(files)?;
+
+    let Some(multiscales) = store.json(".zattrs")?.and_then(|attributes| attributes.get("multiscales").cloned()) else {
+        let meta = parse_array_meta(&store, "")?;
+        if !config.fits(meta.extent()) {
+            return Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, format!("Array of size {} exceeds the brick limits", meta.extent())));
+        }
+        return ZarrGrid::new(store, meta, Mat4::IDENTITY, config);
+    };
+
+    let multiscale = multiscales.get(0).ok_or_else(|| invalid("Empty multiscales list"))?;
+    let datasets = multiscale.get("datasets").and_then(Value::as_array).ok_or_else(|| invalid("Multiscale image has no datasets"))?;
+    // datasets are ordered from highest to lowest resolution
+    for dataset in datasets {
+        let path = dataset.get("path").and_then(Value::as_str).ok_or_else(|| invalid("Dataset has no path"))?;
+        let meta = parse_array_meta(&store, path)?;
+        if !config.fits(meta.extent()) {
+            log_to_console(&format!("Skipping pyramid level \"{}\" of size {}", path, meta.extent()));
+            continue;
+        }
+        log_to_console(&format!("Using pyramid level \"{}\" of size {}", path, meta.extent()));
+        let transform = parse_coordinate_transformations(multiscale.get("coordinateTransformations"))
+            * parse_coordinate_transformations(dataset.get("coordinateTransformations"));
+        return ZarrGrid::new(store, meta, transform, config);
+    }
+    Err(VolumeReadError::new(VolumeReadErrorType::Unsupported, "No pyramid level fits into the brick limits"))
+}
+
+/// Reads a Zarr store from its files, keyed by their path within the store, and bricks it as
+/// `config` says
+pub fn zarr_to_grid(files: Vec<(String, Vec<u8>)>, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting Zarr load");
+    let start = now();
+    let zarr = parse_zarr(files, config)?;
+    let end = now();
+    log_to_console(&format!("Finished loading in {}", end - start));
+
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&zarr, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+/// Reads a Zarr store from a directory upload, `file_names` being the paths relative to the selected directory
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_zarr(file_names: Vec<String>, files: Vec<Uint8Array>, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    zarr_to_grid(file_names.into_iter().zip(files.iter().map(|file| file.to_vec())).collect(), config)
+}
+
+/// Reads a Zarr store packed into a zip archive
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_zarr_zip(zip: Uint8Array, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    zarr_to_grid(NamedFiles::from_zip(zip.to_vec())?.into_files(), config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    /// a 2x2x2 array of big endian u16 in chunks of 2x2x1, of which only the first is stored
+    fn array(path: &str, compressor: &str, chunk: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        let zarray = format!(
+            r#"{{"zarr_format": 2, "shape": [2, 2, 2], "chunks": [1, 2, 2], "dtype": ">u2", "compressor": {}, "fill_value": 3, "order": "C", "filters": null}}"#,
+            compressor
+        );
+        vec![(format!("{}.zarray", path), zarray.into_bytes()), (format!("{}0.0.0", path), chunk)]
+    }
+
+    fn chunk() -> Vec<u8> {
+        [0u16, 1, 2, 4].iter().flat_map(|value| value.to_be_bytes()).collect()
+    }
+
+    fn config() -> BrickGridConfig {
+        BrickGridConfig::default()
+    }
+
+    #[test]
+    fn missing_chunks_have_the_fill_value() {
+        let grid = parse_zarr(array("", "null", chunk()), &config()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(grid.index_extent(), UVec3::splat(2));
+        assert_eq!(grid.lookup(UVec3::new(1, 0, 0)), 0.25);
+        assert_eq!(grid.lookup(UVec3::new(1, 1, 0)), 1.0);
+        assert_eq!(grid.lookup(UVec3::new(1, 1, 1)), 0.75);
+        assert_eq!(grid.histogram()[HISTOGRAM_BINS - 1], 1);
+    }
+
+    #[test]
+    fn ome_zarr_levels_are_compressed_and_scaled() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&chunk()).unwrap();
+        let mut files = array("image.zarr/0/", r#"{"id": "gzip", "level": 1}"#, encoder.finish().unwrap());
+        let zattrs = r#"{"multiscales": [{"version": "0.4", "datasets": [{"path": "0", "coordinateTransformations": [
+            {"type": "scale", "scale": [1.0, 4.0, 2.0, 0.5]}, {"type": "translation", "translation": [0.0, 1.0, 2.0, 3.0]}]}]}]}"#;
+        files.push(("image.zarr/.zattrs".to_string(), zattrs.as_bytes().to_vec()));
+
+        let grid = parse_zarr(files, &config()).unwrap_or_else(|e| panic!("{}", e.message()));
+        assert_eq!(grid.lookup(UVec3::new(1, 0, 0)), 0.25);
+        assert_eq!(grid.transform(), Mat4::from_translation(Vec3::new(3.0, 2.0, 1.0)) * Mat4::from_scale(Vec3::new(0.5, 2.0, 4.0)));
+    }
+
+    #[test]
+    fn oversized_chunks_are_unsupported() {
+        let huge = r#"{"shape": [2, 2, 2], "chunks": [4294967296, 4294967296, 4294967296], "dtype": "<f4", "compressor": null}"#;
+        let files = vec![(".zarray".to_string(), huge.as_bytes().to_vec())];
+        assert!(matches!(parse_zarr(files, &config()).err().map(|e| e.0), Some(VolumeReadErrorType::Unsupported)));
+    }
+
+    #[test]
+    fn chunks_are_decoded_once_while_bricking() {
+        for depth in [1u32, 4] {
+            let zarray = format!(
+                r#"{{"zarr_format": 2, "shape": [12, 4, 4], "chunks": [{}, 4, 4], "dtype": ">u2", "compressor": null, "fill_value": 0, "order": "C", "filters": null}}"#,
+                depth
+            );
+            let mut files = vec![(".zarray".to_string(), zarray.into_bytes())];
+            for layer in 0..12 / depth {
+                let chunk: Vec<u8> = (0..depth * 16).flat_map(|i| ((layer * depth * 16 + i) as u16).to_be_bytes()).collect();
+                files.push((format!("{}.0.0", layer), chunk));
+            }
+            let config = BrickGridConfig::new(4, 2, 4, 4, 4).unwrap_or_else(|e| panic!("{}", e.message()));
+            let zarr = parse_zarr(files, &config).unwrap_or_else(|e| panic!("{}", e.message()));
+            BrickGrid::construct_with_config(&zarr, config, LoadProgress::none()).unwrap_or_else(|e| panic!("{}", e.message()));
+
+            assert_eq!(zarr.decodes.load(Ordering::Relaxed), 12 / depth as usize, "chunks of depth {} were decoded again", depth);
+            assert_eq!(zarr.lookup(UVec3::new(1, 2, 9)), (9 * 16 + 2 * 4 + 1) as f32 / 191.0);
+        }
+    }
+}
@@ -1,125 +1,238 @@
-use js_sys::{Date, Uint8Array};
+#[cfg(feature = "wasm")]
+use js_sys::{Function, Uint8Array};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::io::{Cursor, Read};
 use std::path::PathBuf;
-use glam::Mat4;
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::wasm_bindgen;
+#[cfg(feature = "wasm")]
+use crate::dicom_export::DicomWriteError;
 use crate::brick::BrickGrid;
-use crate::buf3d::Buf3D;
-use crate::{read_dicom, DicomDataInternal};
-use crate::utils::log_to_console;
-
-#[wasm_bindgen]
+use crate::brick_config::BrickGridConfig;
+use crate::volume::{VolumeReadError, VolumeReadErrorType};
+use crate::dicom_slice::{assemble_dicom_slices, decode_batch_size, decode_dicom_slices, stream_dicom_series, DicomSlice};
+use crate::progress::{LoadCancelled, LoadPhase, LoadProgress};
+#[cfg(feature = "wasm")]
+use crate::progress::{js_progress_callback, CancellationToken};
+use crate::DicomDataInternal;
+use crate::utils::{log_to_console, now};
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Debug)]
 pub enum ZipReadErrorType {
     ExtractFailed,
     MoreThanOneFolder,
     NoFiles,
+    /// files that aren't DICOM images, are truncated or don't form a single series
+    InvalidSeries,
+    Cancelled,
 }
 
-#[wasm_bindgen]
-pub struct ZipReadError(ZipReadErrorType, Option<String>);
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct ZipReadError(pub(crate) ZipReadErrorType, pub(crate) Option<String>);
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl ZipReadError {
-    #[wasm_bindgen(getter)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(getter))]
     pub fn message(self) -> String {
         format!("{:?}: {}", self.0, self.1.unwrap_or_else(|| "No Message Specified".to_string()))
     }
 }
 
-#[wasm_bindgen]
+impl From<LoadCancelled> for ZipReadError {
+    fn from(_: LoadCancelled) -> Self {
+        ZipReadError(ZipReadErrorType::Cancelled, None)
+    }
+}
+
+impl From<VolumeReadError> for ZipReadError {
+    fn from(error: VolumeReadError) -> Self {
+        let error_type = match error.0 {
+            VolumeReadErrorType::Cancelled => ZipReadErrorType::Cancelled,
+            _ => ZipReadErrorType::InvalidSeries,
+        };
+        ZipReadError(error_type, Some(error.message()))
+    }
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct ZipResult {
     internal: DicomDataInternal
 }
 
+#[cfg(feature = "wasm")]
 #[wasm_bindgen]
-pub fn read_zip_to_grid(zip: Uint8Array) -> Result<ZipResult, ZipReadError> {
+impl ZipResult {
+    pub fn to_nifti(&self) -> Result<Uint8Array, DicomWriteError> {
+        Ok(Uint8Array::from(self.internal.to_nifti_gz()?.as_slice()))
+    }
+
+    pub fn to_nrrd(&self) -> Uint8Array {
+        Uint8Array::from(self.internal.to_nrrd().as_slice())
+    }
+
+    pub fn to_dicom_series(&self, series_description: String) -> Result<Uint8Array, DicomWriteError> {
+        Ok(Uint8Array::from(self.internal.to_dicom_series(&series_description)?.as_slice()))
+    }
+}
+
+/// Loads a zip containing the files of one DICOM series, either at its root or in a single folder
+pub fn read_dicom_zip(zip: &[u8]) -> Result<DicomDataInternal, ZipReadError> {
+    read_dicom_zip_with_progress(zip, LoadProgress::none())
+}
+
+/// Like [`read_dicom_zip`], reporting each decoded file and checking for cancellation in between
+pub fn read_dicom_zip_with_progress(zip: &[u8], progress: LoadProgress) -> Result<DicomDataInternal, ZipReadError> {
     log_to_console("Starting ZIP volume load");
-    let start = Date::now();
-    let mut result: Option<Buf3D<u16>> = None;
-    let mut transform: Mat4 = Mat4::IDENTITY;
-    let mut histogram: Vec<u32> = Vec::new();
-    let mut min: u16 = u16::MAX;
-    let mut max: u16 = 0;
-
-    let buffer = Cursor::new(zip.to_vec());
+    let start = now();
+    let mut slices: Vec<DicomSlice> = Vec::new();
+    let mut batch: Vec<Vec<u8>> = Vec::new();
+
+    let buffer = Cursor::new(zip);
     let mut archive = zip::ZipArchive::new(buffer)
         .map_err(|x| ZipReadError(ZipReadErrorType::ExtractFailed, Some(x.to_string())))?;
 
-    if archive.len() < 1 {
+    if archive.is_empty() {
         return Err(ZipReadError(ZipReadErrorType::NoFiles, None))
     }
 
     let mut directory: Option<PathBuf> = None;
-    for i in 0..archive.len() {
+    let total = archive.len();
+    for i in 0..total {
+        progress.step(LoadPhase::DecodingFiles, i, total)?;
         let mut f = archive.by_index(i).map_err(|x| ZipReadError(ZipReadErrorType::ExtractFailed, Some(x.to_string())))?;
-        let path = f.enclosed_name().map_or_else(|| Err(ZipReadError(ZipReadErrorType::ExtractFailed, Some("No enclosed name was able to be found".into()))), |x| Ok(x))?;
+        let path = f.enclosed_name().ok_or_else(|| ZipReadError(ZipReadErrorType::ExtractFailed, Some("No enclosed name was able to be found".into())))?;
         if f.is_dir() {
-            if let Some(_) = &directory {
+            if directory.is_some() {
                 return Err(ZipReadError(ZipReadErrorType::MoreThanOneFolder, None))
             }
             directory = Some(path);
             continue;
         }
-        if let Some(dir) = &directory {
-            if let Some(parent) = path.parent() {
-                if dir != parent {
-                    return Err(ZipReadError(ZipReadErrorType::MoreThanOneFolder, None))
-                }
-            }
+        if let (Some(dir), Some(parent)) = (&directory, path.parent()) && dir != parent {
+            return Err(ZipReadError(ZipReadErrorType::MoreThanOneFolder, None))
         }
         let mut file_bytes: Vec<u8> = Vec::new();
-        f.read_to_end(&mut file_bytes).unwrap();
-        let mut dicom = read_dicom(Uint8Array::from(file_bytes.as_slice()),false);
-
-        // I just assume every dicom object has the same transform
-        transform = dicom.transform;
+        f.read_to_end(&mut file_bytes).map_err(|x| ZipReadError(ZipReadErrorType::ExtractFailed, Some(x.to_string())))?;
+        batch.push(file_bytes);
 
-        if histogram.is_empty() {
-            histogram.append(&mut dicom.histogram)
-        } else {
-            for i in 0..histogram.len() {
-                histogram[i] = histogram[i] + dicom.histogram[i];
-            }
+        // extracting stays sequential, the extracted files are decoded in parallel
+        if batch.len() >= decode_batch_size() {
+            slices.append(&mut decode_dicom_slices(&batch)?);
+            batch.clear();
         }
+    }
+    slices.append(&mut decode_dicom_slices(&batch)?);
+    progress.report(LoadPhase::DecodingFiles, total, total);
+
+    let end = now();
+    let elapsed = end - start;
+    log_to_console(&format!("Finished loading in {}", elapsed));
 
-        if dicom.min < min {
-            min = dicom.min
+    Ok(assemble_dicom_slices(slices)?)
+}
+
+/// Builds a brick grid from a zip like [`read_dicom_zip`] does for its volume, through
+/// [`stream_dicom_series`]. Files are extracted while they are read instead of all up front, and the
+/// decoded volume is never held as a whole. The grid is laid out as `config` says.
+pub fn stream_dicom_zip(zip: &[u8], config: BrickGridConfig, progress: LoadProgress) -> Result<BrickGrid, ZipReadError> {
+    let extract_failed = |x: zip::result::ZipError| ZipReadError(ZipReadErrorType::ExtractFailed, Some(x.to_string()));
+    let mut archive = zip::ZipArchive::new(Cursor::new(zip)).map_err(extract_failed)?;
+
+    if archive.is_empty() {
+        return Err(ZipReadError(ZipReadErrorType::NoFiles, None))
+    }
+
+    let mut directory: Option<PathBuf> = None;
+    let mut files: Vec<usize> = Vec::new();
+    for i in 0..archive.len() {
+        let f = archive.by_index(i).map_err(extract_failed)?;
+        let path = f.enclosed_name().ok_or_else(|| ZipReadError(ZipReadErrorType::ExtractFailed, Some("No enclosed name was able to be found".into())))?;
+        if f.is_dir() {
+            if directory.is_some() {
+                return Err(ZipReadError(ZipReadErrorType::MoreThanOneFolder, None))
+            }
+            directory = Some(path);
+            continue;
         }
-        if dicom.max > max {
-            max = dicom.max
+        if let (Some(dir), Some(parent)) = (&directory, path.parent()) && dir != parent {
+            return Err(ZipReadError(ZipReadErrorType::MoreThanOneFolder, None))
         }
+        files.push(i);
+    }
 
-        if let Some(result) = &mut result {
-            result.append_depth_slice(&mut dicom.data)
-        } else {
-            result = Some(dicom.data)
+    // the series only asks for bytes, so the first failed extraction is kept and reported after it
+    let archive = RefCell::new(archive);
+    let failure: RefCell<Option<ZipReadError>> = RefCell::new(None);
+    let read_file = |index: usize| -> Cow<[u8]> {
+        let mut file_bytes: Vec<u8> = Vec::new();
+        let extracted = archive
+            .borrow_mut()
+            .by_index(files[index])
+            .map_err(extract_failed)
+            .and_then(|mut f| f.read_to_end(&mut file_bytes).map_err(|x| ZipReadError(ZipReadErrorType::ExtractFailed, Some(x.to_string()))));
+        if let Err(error) = extracted {
+            failure.borrow_mut().get_or_insert(error);
+            file_bytes.clear();
         }
+        Cow::Owned(file_bytes)
+    };
+    let grid = stream_dicom_series(files.len(), &read_file, config, progress);
+    match failure.into_inner() {
+        Some(error) => Err(error),
+        None => Ok(grid?),
     }
+}
 
-    let end = Date::now();
-    let elapsed = end - start;
-    log_to_console(&format!("Finished loading in {}", elapsed));
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_zip_to_grid(zip: Uint8Array) -> Result<ZipResult, ZipReadError> {
+    Ok(ZipResult { internal: read_dicom_zip(&zip.to_vec())? })
+}
 
-    let data = result.expect("No dicom data collected");
+/// Bricks a loaded zip, laid out as `config` says
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub fn zip_to_dicom(zip: ZipResult, config: &BrickGridConfig) -> Result<BrickGrid, VolumeReadError> {
+    log_to_console("Starting brick grid construction");
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&zip.internal, *config, LoadProgress::none())?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
 
-    log_to_console(format!("Grid Resolution: {} {} {}", data.stride.x, data.stride.y, data.stride.z).as_str());
-    let internal = DicomDataInternal {
-        data,
-        transform,
-        histogram,
-        min,
-        max
-    };
-    Ok(ZipResult { internal })
+/// Like [`read_zip_to_grid`], calling `on_progress(phase, done, total)` for each file and
+/// stopping with an error once `token` is cancelled
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn read_zip_to_grid_with_progress(zip: Uint8Array, on_progress: &Function, token: &CancellationToken) -> Result<ZipResult, ZipReadError> {
+    let callback = js_progress_callback(on_progress);
+    let progress = LoadProgress::none().with_callback(&callback).with_token(token);
+    Ok(ZipResult { internal: read_dicom_zip_with_progress(&zip.to_vec(), progress)? })
 }
 
+/// Like [`zip_to_dicom`], calling `on_progress(phase, done, total)` for each row of bricks and
+/// stopping with an error once `token` is cancelled. The grid is laid out as `config` says.
+#[cfg(feature = "wasm")]
 #[wasm_bindgen]
-pub fn zip_to_dicom(zip: ZipResult) -> BrickGrid {
+pub fn zip_to_dicom_with_progress(zip: ZipResult, config: &BrickGridConfig, on_progress: &Function, token: &CancellationToken) -> Result<BrickGrid, VolumeReadError> {
+    let callback = js_progress_callback(on_progress);
+    let progress = LoadProgress::none().with_callback(&callback).with_token(token);
     log_to_console("Starting brick grid construction");
-    let start = Date::now();
-    let grid = BrickGrid::construct(&zip.internal);
-    let end = Date::now();
-    log_to_console(&format!("Brick grid construction took {}", end - start).as_str());
-    grid
-}
\ No newline at end of file
+    let start = now();
+    let grid = BrickGrid::construct_with_config(&zip.internal, *config, progress)?;
+    let end = now();
+    log_to_console(&format!("Brick grid construction took {}", end - start));
+    Ok(grid)
+}
+
+/// Like [`stream_dicom_zip`], calling `on_progress(phase, done, total)` as files are read and
+/// bricks are built, and stopping with an error once `token` is cancelled
+#[cfg(feature = "wasm")]
+#[wasm_bindgen]
+pub fn stream_zip_to_grid(zip: Uint8Array, config: &BrickGridConfig, on_progress: &Function, token: &CancellationToken) -> Result<BrickGrid, ZipReadError> {
+    let callback = js_progress_callback(on_progress);
+    let progress = LoadProgress::none().with_callback(&callback).with_token(token);
+    stream_dicom_zip(&zip.to_vec(), *config, progress)
+}
@@ -5,40 +5,87 @@ export enum WasmWorkerMessageType {
     LOAD_FROM_ZIP_URL = "zip_url",
     LOAD_FROM_BYTES = "bytes",
     RETURN_DICOM = "return_dicom",
+    PROGRESS = "progress",
+    CANCELLED = "cancelled",
     LOAD_ENV = "load_env",
     RETURN_ENV = "return_env",
     ERROR = "error",
     INIT = "init"
 }
 
-export type WasmWorkerMessageUrls = {
+/**
+ * Set to a non-zero value by the viewer to cancel a load, only available in cross-origin isolated contexts
+ * since it needs to be backed by a SharedArrayBuffer to be visible while the worker is busy.
+ */
+export type WasmWorkerCancelFlag = {
+    cancelFlag?: Int32Array
+}
+
+/**
+ * How atlas voxels are stored relative to the range of their brick. u16 and f16 need twice the memory of u8,
+ * but don't band in bricks with a high dynamic range.
+ */
+export type AtlasPrecision = "u8" | "u16" | "f16"
+
+/**
+ * Layout of a brick grid, see BrickGridConfig in the preprocessor. Smaller bricks skip more empty space
+ * and need less atlas memory, larger ones need fewer traversal steps.
+ */
+export type BrickGridLayout = {
+    brickSize: number,
+    mipLevels: number,
+    pointerBits: [x: number, y: number, z: number],
+    atlasPrecision?: AtlasPrecision
+}
+
+/**
+ * Layout of the brick grid to build, the preprocessor's default layout if not given. The atlas is split into
+ * pages of at most maxAtlasSize voxels along each axis, usually the max 3D texture size of the GPU.
+ */
+export type WasmWorkerBrickGridLayout = {
+    brickGridLayout?: BrickGridLayout,
+    maxAtlasSize?: number
+}
+
+export type WasmWorkerMessageUrls = WasmWorkerCancelFlag & WasmWorkerBrickGridLayout & {
     type: WasmWorkerMessageType.LOAD_FROM_URLS;
     urls: string[];
 }
 
-export type WasmWorkerMessageFiles = {
+export type WasmWorkerMessageFiles = WasmWorkerCancelFlag & WasmWorkerBrickGridLayout & {
     type: WasmWorkerMessageType.LOAD_FROM_FILES;
     files: File[] | FileList;
 }
-export type WasmWorkerMessageZip = {
+export type WasmWorkerMessageZip = WasmWorkerCancelFlag & WasmWorkerBrickGridLayout & {
     type: WasmWorkerMessageType.LOAD_FROM_ZIP;
     zip: File
 }
-export type WasmWorkerMessageZipUrl = {
+export type WasmWorkerMessageZipUrl = WasmWorkerCancelFlag & WasmWorkerBrickGridLayout & {
     type: WasmWorkerMessageType.LOAD_FROM_ZIP_URL;
     zipUrl: string
 }
 
-export type WasmWorkerMessageBytes = {
+export type WasmWorkerMessageBytes = WasmWorkerCancelFlag & WasmWorkerBrickGridLayout & {
     type: WasmWorkerMessageType.LOAD_FROM_BYTES;
     bytes: Uint8Array[]
 }
 
+export type WasmWorkerMessageProgress = {
+    type: WasmWorkerMessageType.PROGRESS;
+    phase: string;
+    done: number;
+    total: number;
+}
+
+export type WasmWorkerMessageCancelled = {
+    type: WasmWorkerMessageType.CANCELLED;
+}
+
 export type WasmWorkerMessageDicomReturn = {
     type: WasmWorkerMessageType.RETURN_DICOM;
+    brickGridLayout: BrickGridLayout;
     indirectionSize: [x: number, y: number, z: number];
     rangeSize: [x: number, y: number, z: number];
-    atlasSize: [x: number, y: number, z: number];
     transform: Float32Array;
     histogram: Uint32Array;
     histogramGradientRange: [min: number, max: number];
@@ -51,7 +98,11 @@ export type WasmWorkerMessageDicomReturn = {
     }[];
     indirection: Uint32Array
     range: Uint16Array,
-    atlas: Uint8Array
+    // u16 and f16 atlases are both 16 bit, f16 voxels are stored as their bits
+    atlasPages: {
+        page: Uint8Array | Uint16Array,
+        stride: [x: number, y: number, z: number]
+    }[]
 }
 
 export type WasmWorkerMessageLoadEnv = {
@@ -82,6 +133,8 @@ export type WasmWorkerMessage =
     | WasmWorkerMessageZipUrl
     | WasmWorkerMessageBytes
     | WasmWorkerMessageDicomReturn
+    | WasmWorkerMessageProgress
+    | WasmWorkerMessageCancelled
     | WasmWorkerMessageLoadEnv
     | WasmWorkerMessageEnvReturn
     | WasmWorkerMessageError
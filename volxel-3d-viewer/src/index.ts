@@ -2,4 +2,5 @@ export * from "./viewer";
 export * from "./utils/data";
 export * from "./elements/colorramp";
 export * from "./elements/cubeDirection";
-export * from "./elements/histogramViewer";
\ No newline at end of file
+export * from "./elements/histogramViewer";
+export type {BrickGridLayout} from "./common";
\ No newline at end of file
@@ -1,6 +1,6 @@
 import {Matrix4, Vector3, Vector4} from "math.gl";
 import {Grid} from "./grid";
-import {WasmWorkerMessageDicomReturn} from "../common";
+import {BrickGridLayout, WasmWorkerMessageDicomReturn} from "../common";
 
 export class Volume {
     private transform: Matrix4 = new Matrix4().identity();
@@ -38,6 +38,9 @@ export class Volume {
     minMaj() {
         return this.grid.minMaj;
     }
+    brickGridLayout(): BrickGridLayout {
+        return this.grid.layout;
+    }
 
     setTransform(from: Matrix4) {
         // @ts-expect-error matrix4f is enough to fill a matrix4f
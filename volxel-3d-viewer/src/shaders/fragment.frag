@@ -36,7 +36,18 @@ uniform mat4 u_volume_density_transform_inv;
 
 uniform usampler3D u_density_indirection;
 uniform sampler3D u_density_range;
-uniform sampler3D u_density_atlas;
+// the atlas is split into pages that each fit into a 3D texture
+#define MAX_ATLAS_PAGES 4
+#ifdef ATLAS_U16
+uniform usampler3D u_density_atlas[MAX_ATLAS_PAGES];
+#else
+uniform sampler3D u_density_atlas[MAX_ATLAS_PAGES];
+#endif
+
+// brick grid layout
+uniform int u_brick_size_log2;
+uniform ivec3 u_pointer_bits;
+uniform int u_range_mip_levels;
 
 // ----------------------------
 
@@ -31,13 +31,46 @@ ivec3 stochastic_tricubic_filter(const vec3 ipos, inout rand_seed seed) {
     return iipos + idx - 1;
 }
 
+// brick pointer, x in the lowest bits, then y, then z, the atlas page in the bits left over
+ivec3 decode_brick_pointer(uint data, out int page) {
+    uvec3 shift = uvec3(0, u_pointer_bits.x, u_pointer_bits.x + u_pointer_bits.y);
+    uvec3 mask = (uvec3(1) << uvec3(u_pointer_bits)) - 1u;
+    int page_shift = u_pointer_bits.x + u_pointer_bits.y + u_pointer_bits.z;
+    // shifting by 32 is undefined, pointers with 32 bits always point into the first page
+    page = page_shift < 32 ? int(data >> uint(page_shift)) : 0;
+    return ivec3((uvec3(data) >> shift) & mask);
+}
+
+// atlas voxel in [0, 1], samplers in arrays can only be indexed with constants
+float fetch_atlas(int page, ivec3 voxel) {
+#ifdef ATLAS_U16
+    uint value;
+#else
+    float value;
+#endif
+    switch (page) {
+        case 1: value = texelFetch(u_density_atlas[1], voxel, 0).x; break;
+        case 2: value = texelFetch(u_density_atlas[2], voxel, 0).x; break;
+        case 3: value = texelFetch(u_density_atlas[3], voxel, 0).x; break;
+        default: value = texelFetch(u_density_atlas[0], voxel, 0).x; break;
+    }
+#ifdef ATLAS_U16
+    return float(value) * (1.0 / 65535.0);
+#else
+    return value;
+#endif
+}
+
 // density lookup
 float lookup_density_brick(const vec3 index_pos) {
     ivec3 iipos = ivec3(floor(index_pos));
-    ivec3 brick = iipos >> 3;
+    ivec3 brick = iipos >> u_brick_size_log2;
     vec2 range = texelFetch(u_density_range, brick, 0).yx;
-    uvec3 ptr = texelFetch(u_density_indirection, brick, 0).xyz;
-    float value_unorm = texelFetch(u_density_atlas, ivec3(ptr << 3) + (iipos & 7), 0).x;
+    int page;
+    ivec3 ptr = decode_brick_pointer(texelFetch(u_density_indirection, brick, 0).x, page);
+    int brick_mask = (1 << u_brick_size_log2) - 1;
+    ivec3 voxel = (ptr << u_brick_size_log2) + (iipos & brick_mask);
+    float value_unorm = fetch_atlas(page, voxel);
 
     return range.x + value_unorm * (range.y - range.x);
 }
@@ -48,7 +81,7 @@ float lookup_density_brick(ivec3 index_pos) {
 
 // brick majorant lookup (nearest neighbor)
 float lookup_majorant(vec3 ipos, int mip) {
-    ivec3 brick = ivec3(floor(ipos)) >> (3 + mip);
+    ivec3 brick = ivec3(floor(ipos)) >> (u_brick_size_log2 + mip);
     return u_volume_density_scale * texelFetch(u_density_range, brick, mip).x;
 }
 
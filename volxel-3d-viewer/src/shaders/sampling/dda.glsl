@@ -3,13 +3,13 @@
 // --------------------------------------------------------------
 // DDA-based null-collision methods
 
-#define MIP_START 3.0
+// traversal starts on the coarsest range mipmap level, u_range_mip_levels
 #define MIP_SPEED_UP 0.25
 #define MIP_SPEED_DOWN 2.0
 
 // perform DDA step on given mip level
 float stepDDA(vec3 pos, vec3 inv_dir, int mip) {
-    float dim = float(8 << mip);
+    float dim = float(1 << (u_brick_size_log2 + mip));
     vec3 offs = mix(vec3(-0.5f), vec3(dim + 0.5f), greaterThanEqual(inv_dir, vec3(0)));
     vec3 tmax = (floor(pos * (1.f / dim)) * dim + offs - pos) * inv_dir;
     return min(tmax.x, min(tmax.y, tmax.z));
@@ -28,7 +28,8 @@ float transmittanceDDA(Ray ray, inout rand_seed seed) {
 
     vec3 ri = 1.f / idir;
     // march brick grid
-    float t = near_far.x + 1e-6f, Tr = 1.f, tau = -log(1.f - rng(seed)), mip = MIP_START;
+    float max_mip = float(u_range_mip_levels);
+    float t = near_far.x + 1e-6f, Tr = 1.f, tau = -log(1.f - rng(seed)), mip = max_mip;
     uint step = 0u;
     while (t < near_far.y && (step++ < max_steps)) {
         vec3 curr = ipos + t * idir;
@@ -38,7 +39,7 @@ float transmittanceDDA(Ray ray, inout rand_seed seed) {
         float dt = stepDDA(curr, ri, int(round(mip)));
         t += dt;
         tau -= majorant * dt;
-        mip = min(mip + MIP_SPEED_UP, 3.f);
+        mip = min(mip + MIP_SPEED_UP, max_mip);
         if (tau > 0.0) continue; // no collision, step ahead
         t += tau / majorant; // step back to point of collision
         if (t >= near_far.y) break;
@@ -72,14 +73,15 @@ bool sample_volumeDDA(Ray ray, out float t, inout vec3 throughput, inout vec3 Le
     vec3 ri = 1.f / idir;
     // march brick grid
     t = near_far.x + 1e-6f;
-    float tau = -log(1.f - rng(seed)), mip = MIP_START;
+    float max_mip = float(u_range_mip_levels);
+    float tau = -log(1.f - rng(seed)), mip = max_mip;
     while (t < near_far.y) {
         vec3 curr = ipos + t * idir;
         float majorant = u_volume_maj * lookup_transfer(lookup_majorant(curr, int(round(mip))) * u_volume_inv_maj).a;
         float dt = stepDDA(curr, ri, int(round(mip)));
         t += dt;
         tau -= majorant * dt;
-        mip = min(mip + MIP_SPEED_UP, 3.f);
+        mip = min(mip + MIP_SPEED_UP, max_mip);
         if (tau > 0.0) continue; // no collision, step ahead
         t += tau / majorant; // step back to point of collision
         if (t >= near_far.y) break;
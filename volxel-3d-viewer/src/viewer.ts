@@ -29,6 +29,7 @@ import {
 import {UnitCubeDisplay} from "./elements/cubeDirection";
 import {volxelStyles, volxelTemplate} from "./template";
 import {
+    BrickGridLayout,
     WasmWorkerMessage,
     WasmWorkerMessageDicomReturn,
     WasmWorkerMessageEnvReturn,
@@ -63,6 +64,9 @@ declare global {
 
 let workerFactory: (() => Worker) | undefined = undefined;
 
+// atlas textures the shader can sample from, matches MAX_ATLAS_PAGES in fragment.frag
+const MAX_ATLAS_PAGES = 4;
+
 export enum VolxelRenderMode {
     DEFAULT = "default",
     NO_DDA = "no_dda",
@@ -72,6 +76,7 @@ export enum VolxelRenderMode {
 export type VolxelBenchmarkSettings = {
     zip?: string,
     env?: string,
+    brickGridLayout?: BrickGridLayout,
     renderMode: VolxelRenderMode,
     settings: number | SettingsExport,
     name?: string
@@ -101,6 +106,7 @@ export type VolxelBenchmarkDeviceResult = {
 export type VolxelBenchmarkResult = {
     name?: string,
     settings: ViewerSettings,
+    brickGridLayout?: BrickGridLayout,
     totalTime: number,
     timePerSample: number,
     viewport: [number, number, number, number],
@@ -115,6 +121,14 @@ export class Volxel3DDicomRenderer extends HTMLElement {
     private workerInitialized = new Promise<void>(resolve => this.worker.addEventListener("message", () => {
         resolve();
     }))
+    private runningLoad: { cancelFlag: Int32Array | undefined, finished: Promise<unknown> } | undefined;
+    // u16 atlases are integer textures, which need a different sampler in the shader
+    private programAtlasU16: boolean = false;
+
+    /**
+     * Layout of the brick grids built by the next loads, the preprocessor's default layout if not set
+     */
+    public brickGridLayout: BrickGridLayout | undefined;
 
     private canvas: HTMLCanvasElement;
     private gl: WebGL2RenderingContext | undefined;
@@ -140,7 +154,9 @@ export class Volxel3DDicomRenderer extends HTMLElement {
 
     private indirection: WebGLTexture | undefined;
     private range: WebGLTexture | undefined;
-    private atlas: WebGLTexture | undefined;
+    private atlas: WebGLTexture[] = [];
+    // number of atlas textures the current volume uses
+    private atlasPages: number = 1;
     private transfer: WebGLTexture | undefined;
 
     // settings, these can be exported and reimported
@@ -408,10 +424,13 @@ export class Volxel3DDicomRenderer extends HTMLElement {
             gl.texParameteri(gl.TEXTURE_3D, gl.TEXTURE_WRAP_S, gl.CLAMP_TO_EDGE);
             gl.texParameteri(gl.TEXTURE_3D, gl.TEXTURE_WRAP_T, gl.CLAMP_TO_EDGE);
             gl.texParameteri(gl.TEXTURE_3D, gl.TEXTURE_WRAP_R, gl.CLAMP_TO_EDGE);
-            this.atlas = gl.createTexture();
             this.gl.activeTexture(this.gl.TEXTURE0 + 3);
-            this.gl.bindTexture(this.gl.TEXTURE_3D, this.atlas);
-            setupImage();
+            this.atlas = Array.from({length: MAX_ATLAS_PAGES}, () => {
+                const page = gl.createTexture();
+                this.gl.bindTexture(this.gl.TEXTURE_3D, page);
+                setupImage();
+                return page;
+            });
             // TODO: Initial data somehow?
 
             // Setup camera
@@ -782,6 +801,8 @@ export class Volxel3DDicomRenderer extends HTMLElement {
             default:
                 break;
         }
+        this.programAtlasU16 = this.volume?.brickGridLayout().atlasPrecision === "u16";
+        if (this.programAtlasU16) defines += "\n#define ATLAS_U16";
         const fragment = createShader(this.gl, this.gl.FRAGMENT_SHADER, fragmentShader.replace("// DEFINES", defines));
         return createProgram(this.gl, vertex, fragment);
     }
@@ -869,6 +890,7 @@ export class Volxel3DDicomRenderer extends HTMLElement {
                 await this.restartRendering(async () => {
                     this.benchmarkCurrentName = benchmark.name;
                     console.log("Starting benchmark", benchmark.name)
+                    this.brickGridLayout = benchmark.brickGridLayout
                     if (benchmark.zip) await this.restartFromZipUrl(benchmark.zip)
                     if (benchmark.env) await this.loadEnvFromUrl(benchmark.env)
                     if (typeof benchmark.settings === "number") {
@@ -961,59 +983,61 @@ export class Volxel3DDicomRenderer extends HTMLElement {
     }
 
     public async restartFromFiles(files: File[] | FileList) {
-        await this.workerInitialized;
-        await this.restartRendering(async () => {
-            await new Promise<void>((resolve, reject) => {
-                const message: WasmWorkerMessageFiles = {
-                    type: WasmWorkerMessageType.LOAD_FROM_FILES,
-                    files
-                }
-                this.worker.postMessage(message)
-                this.setupWorkerListener(resolve, reject)
-            })
-        }, "Loading DICOM data from multiple files")
+        const message: WasmWorkerMessageFiles = {
+            type: WasmWorkerMessageType.LOAD_FROM_FILES,
+            files
+        }
+        await this.loadInWorker(message, "Loading DICOM data from multiple files")
     }
 
     public async restartFromZip(zip: File) {
-        await this.workerInitialized;
-        await this.restartRendering(async () => {
-            await new Promise<void>((resolve, reject) => {
-                const message: WasmWorkerMessageZip = {
-                    type: WasmWorkerMessageType.LOAD_FROM_ZIP,
-                    zip
-                }
-                this.worker.postMessage(message)
-                this.setupWorkerListener(resolve, reject)
-            })
-        }, "Loading DICOM data from ZIP...")
+        const message: WasmWorkerMessageZip = {
+            type: WasmWorkerMessageType.LOAD_FROM_ZIP,
+            zip
+        }
+        await this.loadInWorker(message, "Loading DICOM data from ZIP...")
     }
 
     public async restartFromZipUrl(url: string) {
-        await this.workerInitialized;
-        await this.restartRendering(async () => {
-            await new Promise<void>((resolve, reject) => {
-                const message: WasmWorkerMessageZipUrl = {
-                    type: WasmWorkerMessageType.LOAD_FROM_ZIP_URL,
-                    zipUrl: url
-                }
-                this.worker.postMessage(message)
-                this.setupWorkerListener(resolve, reject)
-            })
-        }, "Loading DICOM data from ZIP via URL...")
+        const message: WasmWorkerMessageZipUrl = {
+            type: WasmWorkerMessageType.LOAD_FROM_ZIP_URL,
+            zipUrl: url
+        }
+        await this.loadInWorker(message, "Loading DICOM data from ZIP via URL...")
     }
 
     public async restartFromURLs(urls: string[]) {
+        const message: WasmWorkerMessageUrls = {
+            type: WasmWorkerMessageType.LOAD_FROM_URLS,
+            urls
+        }
+        await this.loadInWorker(message, "Loading DICOM data from multiple URLs...")
+    }
+
+    /**
+     * Cancels the volume load currently running in the worker, if any, and waits until it stopped.
+     * Without cross-origin isolation there is no cancel flag, so the running load is waited for instead.
+     */
+    private async cancelRunningLoad() {
+        const running = this.runningLoad;
+        if (!running) return;
+        if (running.cancelFlag) Atomics.store(running.cancelFlag, 0, 1);
+        await running.finished;
+    }
+
+    private async loadInWorker(message: WasmWorkerMessageFiles | WasmWorkerMessageZip | WasmWorkerMessageZipUrl | WasmWorkerMessageUrls, loadingMessage: string) {
         await this.workerInitialized;
-        await this.restartRendering(async () => {
+        await this.cancelRunningLoad();
+        const cancelFlag = self.crossOriginIsolated ? new Int32Array(new SharedArrayBuffer(Int32Array.BYTES_PER_ELEMENT)) : undefined;
+        const finished = Promise.resolve(this.restartRendering(async () => {
             await new Promise<void>((resolve, reject) => {
-                const message: WasmWorkerMessageUrls = {
-                    type: WasmWorkerMessageType.LOAD_FROM_URLS,
-                    urls
-                }
-                this.worker.postMessage(message)
-                this.setupWorkerListener(resolve, reject)
+                this.worker.postMessage({...message, cancelFlag, brickGridLayout: this.brickGridLayout, maxAtlasSize: this.gl?.getParameter(this.gl.MAX_3D_TEXTURE_SIZE)})
+                this.setupWorkerListener(resolve, reject, loadingMessage)
             })
-        }, "Loading DICOM data from multiple URLs...")
+        }, loadingMessage));
+        this.runningLoad = {cancelFlag, finished};
+        await finished;
+        if (this.runningLoad?.finished === finished) this.runningLoad = undefined;
     }
 
     public async loadEnv(bytes: Uint8Array) {
@@ -1039,8 +1063,15 @@ export class Volxel3DDicomRenderer extends HTMLElement {
         await this.loadEnv(await exportResponseBytes(response));
     }
 
-    private setupWorkerListener(resolve: () => void, reject: (e: unknown) => void) {
+    private setupWorkerListener(resolve: () => void, reject: (e: unknown) => void, loadingMessage?: string) {
         const handler = (event: MessageEvent<WasmWorkerMessage>) => {
+            if (event.data.type === WasmWorkerMessageType.PROGRESS) {
+                const {phase, done, total} = event.data;
+                if (loadingMessage) {
+                    this.shadowRoot!.getElementById("loadingIndicator")!.innerText = `${loadingMessage}\n${phase}: ${done} / ${total}`;
+                }
+                return;
+            }
             this.worker.removeEventListener("message", handler);
             switch (event.data.type) {
                 case WasmWorkerMessageType.LOAD_FROM_FILES:
@@ -1054,8 +1085,12 @@ export class Volxel3DDicomRenderer extends HTMLElement {
                     break;
                 }
                 case WasmWorkerMessageType.RETURN_DICOM: {
-                    this.setupFromGrid(event.data);
-                    resolve()
+                    try {
+                        this.setupFromGrid(event.data);
+                        resolve()
+                    } catch (e) {
+                        reject(e)
+                    }
                     break;
                 }
                 case WasmWorkerMessageType.RETURN_ENV: {
@@ -1063,6 +1098,11 @@ export class Volxel3DDicomRenderer extends HTMLElement {
                     resolve();
                     break;
                 }
+                case WasmWorkerMessageType.CANCELLED: {
+                    // superseded by another load, which will replace what's displayed
+                    resolve();
+                    break;
+                }
                 default: {
                     reject("Reached default case in Wasm Worker Message Handler")
                 }
@@ -1078,12 +1118,25 @@ export class Volxel3DDicomRenderer extends HTMLElement {
     }
 
     private setupFromGrid(grid: WasmWorkerMessageDicomReturn) {
-        if (!this.gl || !this.indirection || !this.range || !this.atlas) throw new Error("Trying to setup from grid without GL context being initialized")
+        if (!this.gl || !this.indirection || !this.range || !this.atlas.length) throw new Error("Trying to setup from grid without GL context being initialized")
+        // checked before anything is replaced, so the previous volume stays displayed
+        if (grid.atlasPages.length > MAX_ATLAS_PAGES) {
+            throw new Error(`The brick atlas has ${grid.atlasPages.length} pages, more than the ${MAX_ATLAS_PAGES} the viewer can sample from`)
+        }
+        const maxTextureSize: number = this.gl.getParameter(this.gl.MAX_3D_TEXTURE_SIZE);
+        for (const [name, size] of [["indirection", grid.indirectionSize], ...grid.atlasPages.map(({stride}, i) => [`atlas page ${i}`, stride] as const)] as const) {
+            if (Math.max(...size) > maxTextureSize) {
+                throw new Error(`The brick ${name} of ${size.join(" x ")} exceeds the max 3D texture size of ${maxTextureSize} of this GPU`)
+            }
+        }
         this.densityScale = 1.0;
         this.settings.volumeClipMax = new Vector3(1, 1, 1);
         this.settings.volumeClipMin = new Vector3(0, 0, 0);
 
         this.volume = Volume.fromWasm(grid);
+        if (this.vertexShader && (grid.brickGridLayout.atlasPrecision === "u16") !== this.programAtlasU16) {
+            this.program = this.createShaderProgram(this.vertexShader);
+        }
 
         // prepare rescale matrix for AABB of volume (it's not rescaled yet so we can safely call the aabb function)
         const [box_min, box_max] = this.volume.aabb();
@@ -1101,14 +1154,14 @@ export class Volxel3DDicomRenderer extends HTMLElement {
         // upload data to respective images
         const ind = grid.indirection
         const range = grid.range
-        const atlas = grid.atlas
 
         // upload indirection buffer
         const [indX, indY, indZ] = grid.indirectionSize;
         this.gl.activeTexture(this.gl.TEXTURE0 + 1)
         this.gl.bindTexture(this.gl.TEXTURE_3D, this.indirection);
         this.gl.pixelStorei(this.gl.UNPACK_ALIGNMENT, 1);
-        this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.RGB10_A2UI, indX, indY, indZ, 0, this.gl.RGBA_INTEGER, this.gl.UNSIGNED_INT_2_10_10_10_REV, ind) // TODO: Check the last parameter again
+        // pointers are packed with the grid's pointer bits, so they are unpacked in the shader
+        this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.R32UI, indX, indY, indZ, 0, this.gl.RED_INTEGER, this.gl.UNSIGNED_INT, ind)
 
         // upload range buffer
         const [rangeX, rangeY, rangeZ] = grid.rangeSize
@@ -1134,12 +1187,25 @@ export class Volxel3DDicomRenderer extends HTMLElement {
             level++;
         }
 
-        // upload atlas buffer
-        const [atlasX, atlasY, atlasZ] = grid.atlasSize;
+        // upload atlas pages
         this.gl.activeTexture(this.gl.TEXTURE0 + 3)
-        this.gl.bindTexture(this.gl.TEXTURE_3D, this.atlas);
         this.gl.pixelStorei(this.gl.UNPACK_ALIGNMENT, 1);
-        this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.R8, atlasX, atlasY, atlasZ, 0, this.gl.RED, this.gl.UNSIGNED_BYTE, atlas)
+        for (const [i, {page, stride: [atlasX, atlasY, atlasZ]}] of grid.atlasPages.entries()) {
+            this.gl.bindTexture(this.gl.TEXTURE_3D, this.atlas[i]);
+            switch (grid.brickGridLayout.atlasPrecision) {
+                case "u16":
+                    this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.R16UI, atlasX, atlasY, atlasZ, 0, this.gl.RED_INTEGER, this.gl.UNSIGNED_SHORT, page)
+                    break;
+                case "f16":
+                    this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.R16F, atlasX, atlasY, atlasZ, 0, this.gl.RED, this.gl.HALF_FLOAT, page)
+                    break;
+                case "u8":
+                default:
+                    this.gl.texImage3D(this.gl.TEXTURE_3D, 0, this.gl.R8, atlasX, atlasY, atlasZ, 0, this.gl.RED, this.gl.UNSIGNED_BYTE, page)
+                    break;
+            }
+        }
+        this.atlasPages = grid.atlasPages.length;
 
         this.histogram?.renderHistogram(grid.histogram, grid.histogramGradient, grid.histogramGradientRange[1])
     }
@@ -1234,6 +1300,7 @@ export class Volxel3DDicomRenderer extends HTMLElement {
                 const result: VolxelBenchmarkResult = {
                     name: this.benchmarkCurrentName,
                     settings: this.settings,
+                    brickGridLayout: this.volume?.brickGridLayout(),
                     timePerSample: this.benchmarkTime / this.frameIndex,
                     totalTime: this.benchmarkTime,
                     viewport: [0, 0, this.settings.resolutionFactor * this.canvas.width, this.settings.resolutionFactor * this.canvas.height],
@@ -1293,7 +1360,7 @@ export class Volxel3DDicomRenderer extends HTMLElement {
     }
 
     private bindUniforms(framebuffer: number) {
-        if (!this.gl || !this.transfer || !this.indirection || !this.range || !this.atlas || !this.program) throw new Error("Trying to bind uniforms to uninitialized GL context.")
+        if (!this.gl || !this.transfer || !this.indirection || !this.range || !this.atlas.length || !this.program) throw new Error("Trying to bind uniforms to uninitialized GL context.")
         let textureOffset = 0;
         this.gl.activeTexture(this.gl.TEXTURE0 + textureOffset);
         this.gl.bindTexture(this.gl.TEXTURE_2D, this.transfer);
@@ -1309,9 +1376,15 @@ export class Volxel3DDicomRenderer extends HTMLElement {
         this.gl.activeTexture(this.gl.TEXTURE0 + textureOffset);
         this.gl.bindTexture(this.gl.TEXTURE_3D, this.range);
         this.gl.uniform1i(this.getUniformLocation("u_density_range"), textureOffset++);
-        this.gl.activeTexture(this.gl.TEXTURE0 + textureOffset);
-        this.gl.bindTexture(this.gl.TEXTURE_3D, this.atlas);
-        this.gl.uniform1i(this.getUniformLocation("u_density_atlas"), textureOffset++);
+        // pages the grid doesn't have sample the first one, so every sampler has a texture of its type
+        const atlasUnits: number[] = [];
+        for (let i = 0; i < MAX_ATLAS_PAGES; ++i) {
+            this.gl.activeTexture(this.gl.TEXTURE0 + textureOffset + i);
+            this.gl.bindTexture(this.gl.TEXTURE_3D, this.atlas[i]);
+            atlasUnits.push(textureOffset + (i < this.atlasPages ? i : 0));
+        }
+        this.gl.uniform1iv(this.getUniformLocation("u_density_atlas"), atlasUnits);
+        textureOffset += MAX_ATLAS_PAGES;
 
         // bind volume
         if (this.volume) {
@@ -1329,6 +1402,11 @@ export class Volxel3DDicomRenderer extends HTMLElement {
             const combinedMatrix = this.volume.combinedTransform()
             this.gl.uniformMatrix4fv(this.getUniformLocation("u_volume_density_transform"), false, combinedMatrix)
             this.gl.uniformMatrix4fv(this.getUniformLocation("u_volume_density_transform_inv"), false, combinedMatrix.invert())
+
+            const layout = this.volume.brickGridLayout();
+            this.gl.uniform1i(this.getUniformLocation("u_brick_size_log2"), Math.log2(layout.brickSize));
+            this.gl.uniform3i(this.getUniformLocation("u_pointer_bits"), ...layout.pointerBits);
+            this.gl.uniform1i(this.getUniformLocation("u_range_mip_levels"), layout.mipLevels);
         }
 
         // bind environment
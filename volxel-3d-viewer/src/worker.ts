@@ -1,8 +1,12 @@
 import {
+    AtlasPrecision,
+    BrickGridLayout,
     WasmWorkerMessage,
+    WasmWorkerMessageCancelled,
     WasmWorkerMessageDicomReturn,
     WasmWorkerMessageEnvReturn,
     WasmWorkerMessageError,
+    WasmWorkerMessageProgress,
     WasmWorkerMessageType
 } from "./common";
 import * as wasm from "@volxel/dicom_preprocessor";
@@ -11,14 +15,99 @@ import {exportResponseBytes} from "./util";
 export {}
 
 wasm.init()
-function buildFromBytesAndReturn(bytes: Uint8Array[]) {
-    const grid = wasm.read_dicoms_to_grid(bytes);
+
+// initThreadPool only exists in builds with the wasm-threads feature and needs shared memory to work
+const threadedWasm = wasm as typeof wasm & { initThreadPool?: (threads: number) => Promise<void> };
+const threadPoolReady = threadedWasm.initThreadPool && self.crossOriginIsolated
+    ? threadedWasm.initThreadPool(navigator.hardwareConcurrency)
+    : Promise.resolve();
+
+class LoadCancelledError extends Error {}
+
+const phaseNames: Record<wasm.LoadPhase, string> = {
+    [wasm.LoadPhase.ReadingHeaders]: "Reading headers",
+    [wasm.LoadPhase.DecodingFiles]: "Decoding files",
+    [wasm.LoadPhase.BuildingBricks]: "Building bricks",
+    [wasm.LoadPhase.BuildingMipmaps]: "Building mipmaps"
+}
+
+/**
+ * Runs a load with a progress callback posting progress messages and a cancellation token
+ * that is cancelled once the viewer sets the cancel flag
+ */
+function withProgress<T>(cancelFlag: Int32Array | undefined, load: (onProgress: (phase: wasm.LoadPhase, done: number, total: number) => void, token: wasm.CancellationToken) => T): T {
+    const token = new wasm.CancellationToken();
+    const onProgress = (phase: wasm.LoadPhase, done: number, total: number) => {
+        if (cancelFlag && Atomics.load(cancelFlag, 0) !== 0) token.cancel();
+        const message: WasmWorkerMessageProgress = {
+            type: WasmWorkerMessageType.PROGRESS,
+            phase: phaseNames[phase],
+            done, total
+        }
+        self.postMessage(message);
+    }
+    try {
+        return load(onProgress, token);
+    } catch (e) {
+        if (e instanceof wasm.LoadCancelled || token.cancelled) throw new LoadCancelledError();
+        throw e;
+    } finally {
+        token.free();
+    }
+}
+
+const atlasPrecisions: Record<AtlasPrecision, wasm.AtlasPrecision> = {
+    u8: wasm.AtlasPrecision.U8,
+    u16: wasm.AtlasPrecision.U16,
+    f16: wasm.AtlasPrecision.F16
+}
+
+const atlasPrecisionNames: Record<wasm.AtlasPrecision, AtlasPrecision> = {
+    [wasm.AtlasPrecision.U8]: "u8",
+    [wasm.AtlasPrecision.U16]: "u16",
+    [wasm.AtlasPrecision.F16]: "f16"
+}
+
+/**
+ * Runs a load with the brick grid config for the requested layout and max atlas size, freeing it afterwards
+ */
+function withBrickGridConfig<T>(layout: BrickGridLayout | undefined, maxAtlasSize: number | undefined, load: (config: wasm.BrickGridConfig) => T): T {
+    let config: wasm.BrickGridConfig;
+    try {
+        config = layout
+            ? new wasm.BrickGridConfig(layout.brickSize, layout.mipLevels, ...layout.pointerBits)
+            : wasm.BrickGridConfig.default_config();
+    } catch (e) {
+        if (e instanceof wasm.BrickGridConfigError) throw new Error(e.message);
+        throw e;
+    }
+    if (layout?.atlasPrecision) config.atlas_precision = atlasPrecisions[layout.atlasPrecision];
+    try {
+        if (maxAtlasSize) config.set_max_atlas_size(maxAtlasSize);
+        return load(config);
+    } catch (e) {
+        if (e instanceof wasm.BrickGridConfigError) throw new Error(e.message);
+        throw e;
+    } finally {
+        config.free();
+    }
+}
+
+function buildFromBytesAndReturn(bytes: Uint8Array[], cancelFlag?: Int32Array, layout?: BrickGridLayout, maxAtlasSize?: number) {
+    let grid: wasm.BrickGrid;
+    try {
+        // bricks the files while decoding them, so the decoded volume never has to fit into memory at once
+        grid = withBrickGridConfig(layout, maxAtlasSize, config =>
+            withProgress(cancelFlag, (onProgress, token) => wasm.stream_dicoms_to_grid(bytes, config, onProgress, token)));
+    } catch (e) {
+        if (e instanceof wasm.VolumeReadError) throw new Error(e.message);
+        throw e;
+    }
     buildFromGridAndReturn(grid);
 }
 
 function buildFromGridAndReturn(grid: wasm.BrickGrid) {
     const indirection = grid.indirection_data();
-    const atlas = grid.atlas_data();
     const transform = grid.transform();
     const histogram = grid.histogram();
     const histogramGradient = grid.histogram_gradient();
@@ -33,14 +122,32 @@ function buildFromGridAndReturn(grid: wasm.BrickGrid) {
         })
     }
 
+    const atlasPages: WasmWorkerMessageDicomReturn["atlasPages"] = [];
+    const pages = grid.atlas_pages();
+    for (let i = 0; i < pages; ++i) {
+        atlasPages.push({
+            page: grid.atlas_page(i),
+            stride: [grid.atlas_page_stride_x(i), grid.atlas_page_stride_y(i), grid.atlas_page_stride_z(i)]
+        })
+    }
+
     const range = grid.range_data();
 
+    const config = grid.config;
+    const brickGridLayout: BrickGridLayout = {
+        brickSize: config.brick_size,
+        mipLevels: config.mip_levels,
+        pointerBits: [config.pointer_bits_x, config.pointer_bits_y, config.pointer_bits_z],
+        atlasPrecision: atlasPrecisionNames[config.atlas_precision]
+    };
+    config.free();
+
     const returnMessage: WasmWorkerMessageDicomReturn = {
         type: WasmWorkerMessageType.RETURN_DICOM,
+        brickGridLayout,
         indirectionSize: [grid.ind_x(), grid.ind_y(), grid.ind_z()],
         indirection,
-        atlasSize: [grid.atlas_x(), grid.atlas_y(), grid.atlas_z()],
-        atlas,
+        atlasPages,
         transform,
         histogram,
         histogramGradient,
@@ -53,16 +160,19 @@ function buildFromGridAndReturn(grid: wasm.BrickGrid) {
     };
     grid.free()
     self.postMessage(returnMessage, {
-        transfer: [indirection.buffer, atlas.buffer, transform.buffer, histogram.buffer, histogramGradient.buffer, ...rangeMipmaps.map(range => range.mipmap.buffer), range.buffer]
+        transfer: [indirection.buffer, ...atlasPages.map(atlas => atlas.page.buffer), transform.buffer, histogram.buffer, histogramGradient.buffer, ...rangeMipmaps.map(range => range.mipmap.buffer), range.buffer]
     })
 }
 
-function buildFromZipBytesAndReturn(zipBytes: Uint8Array) {
-    let result: wasm.ZipResult;
+function buildFromZipBytesAndReturn(zipBytes: Uint8Array, cancelFlag?: Int32Array, layout?: BrickGridLayout, maxAtlasSize?: number) {
+    let grid: wasm.BrickGrid;
     try {
-        result = wasm.read_zip_to_grid(zipBytes);
+        grid = withBrickGridConfig(layout, maxAtlasSize, config =>
+            withProgress(cancelFlag, (onProgress, token) => wasm.stream_zip_to_grid(zipBytes, config, onProgress, token)));
     } catch (e) {
-        if (e instanceof Error) {
+        if (e instanceof LoadCancelledError) {
+            throw e;
+        } else if (e instanceof Error) {
             console.error("Received error during zip read", e.message, e.stack);
             // TODO: For some reason firefox just outright ignores any statement where e is used directly
             // I also cannot just print e, that print statement would get entirely ignored too...
@@ -72,7 +182,7 @@ function buildFromZipBytesAndReturn(zipBytes: Uint8Array) {
             throw new Error(error.message);
         }
     }
-    buildFromGridAndReturn(wasm.zip_to_dicom(result));
+    buildFromGridAndReturn(grid);
 }
 
 function loadEnv(bytes: Uint8Array) {
@@ -97,29 +207,31 @@ self.onmessage = async (ev: MessageEvent<WasmWorkerMessage>) => {
             case WasmWorkerMessageType.ERROR:
             case WasmWorkerMessageType.INIT:
             case WasmWorkerMessageType.RETURN_ENV:
+            case WasmWorkerMessageType.PROGRESS:
+            case WasmWorkerMessageType.CANCELLED:
                 throw new Error(`Worker received ${type} message, this is invalid.`)
             case WasmWorkerMessageType.LOAD_FROM_BYTES: {
-                buildFromBytesAndReturn(ev.data.bytes);
+                buildFromBytesAndReturn(ev.data.bytes, ev.data.cancelFlag, ev.data.brickGridLayout, ev.data.maxAtlasSize);
                 break;
             }
             case WasmWorkerMessageType.LOAD_FROM_FILES: {
                 const bytes = (await Promise.all([...ev.data.files].map(file => file.arrayBuffer()))).map(arrayBuffer => new Uint8Array(arrayBuffer))
-                buildFromBytesAndReturn(bytes);
+                buildFromBytesAndReturn(bytes, ev.data.cancelFlag, ev.data.brickGridLayout, ev.data.maxAtlasSize);
                 break;
             }
             case WasmWorkerMessageType.LOAD_FROM_ZIP: {
                 const zipBytes = new Uint8Array(await ev.data.zip.arrayBuffer());
-                buildFromZipBytesAndReturn(zipBytes)
+                buildFromZipBytesAndReturn(zipBytes, ev.data.cancelFlag, ev.data.brickGridLayout, ev.data.maxAtlasSize)
                 break;
             }
             case WasmWorkerMessageType.LOAD_FROM_ZIP_URL: {
                 const zipBytes = await exportResponseBytes(await fetch(ev.data.zipUrl))
-                buildFromZipBytesAndReturn(zipBytes)
+                buildFromZipBytesAndReturn(zipBytes, ev.data.cancelFlag, ev.data.brickGridLayout, ev.data.maxAtlasSize)
                 break;
             }
             case WasmWorkerMessageType.LOAD_FROM_URLS: {
                 const bytes = await Promise.all(ev.data.urls.map(url => fetch(url).then(exportResponseBytes)));
-                buildFromBytesAndReturn(bytes);
+                buildFromBytesAndReturn(bytes, ev.data.cancelFlag, ev.data.brickGridLayout, ev.data.maxAtlasSize);
                 break;
             }
             case WasmWorkerMessageType.LOAD_ENV: {
@@ -130,6 +242,12 @@ self.onmessage = async (ev: MessageEvent<WasmWorkerMessage>) => {
                 throw new Error(`Unknown message type ${type}`);
         }
     } catch (e) {
+        if (e instanceof LoadCancelledError) {
+            self.postMessage({
+                type: WasmWorkerMessageType.CANCELLED
+            } as WasmWorkerMessageCancelled)
+            return;
+        }
         console.error(e);
         self.postMessage({
             type: WasmWorkerMessageType.ERROR,
@@ -138,6 +256,6 @@ self.onmessage = async (ev: MessageEvent<WasmWorkerMessage>) => {
     }
 }
 
-self.postMessage({
+threadPoolReady.then(() => self.postMessage({
     type: WasmWorkerMessageType.INIT
-})
\ No newline at end of file
+}))
\ No newline at end of file